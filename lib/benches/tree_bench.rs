@@ -0,0 +1,184 @@
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use jujutsu_lib::matchers::EverythingMatcher;
+use jujutsu_lib::repo::ReadonlyRepo;
+use jujutsu_lib::repo_path::RepoPath;
+use jujutsu_lib::tree::Tree;
+use testutils::TestRepo;
+
+/// Creates two trees with 100 directories of 100 files each. The file with
+/// index `i` (counting across all directories) has different contents in the
+/// second tree if `modify(i)` is true.
+fn wide_tree_pair(repo: &Arc<ReadonlyRepo>, modify: impl Fn(usize) -> bool) -> (Tree, Tree) {
+    let mut paths = vec![];
+    for dir in 0..100 {
+        for file in 0..100 {
+            paths.push(RepoPath::from_internal_string(&format!(
+                "dir{dir}/file{file}"
+            )));
+        }
+    }
+    let contents1 = paths
+        .iter()
+        .map(|path| (path, "contents\n"))
+        .collect::<Vec<_>>();
+    let contents2 = paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            if modify(i) {
+                (path, "modified\n")
+            } else {
+                (path, "contents\n")
+            }
+        })
+        .collect::<Vec<_>>();
+    (
+        testutils::create_tree(repo, &contents1),
+        testutils::create_tree(repo, &contents2),
+    )
+}
+
+fn bench_diff_summary(c: &mut Criterion) {
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+
+    // One file modified in each directory
+    let (tree1, tree2) = wide_tree_pair(repo, |i| i % 100 == 0);
+
+    let mut group = c.benchmark_group("bench_diff_summary");
+    group.bench_function("sequential", |b| {
+        b.iter(|| tree1.diff_summary(&tree2, &EverythingMatcher))
+    });
+    group.bench_function("parallel", |b| {
+        b.iter(|| {
+            tree1
+                .diff_summary_parallel(&tree2, &EverythingMatcher)
+                .unwrap()
+        })
+    });
+}
+
+fn bench_changed_paths(c: &mut Criterion) {
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+
+    // Every file modified
+    let (tree1, tree2) = wide_tree_pair(repo, |_| true);
+
+    let mut group = c.benchmark_group("bench_changed_paths");
+    group.bench_function("diff", |b| {
+        b.iter(|| {
+            tree1
+                .diff(&tree2, &EverythingMatcher)
+                .map(|item| item.map(|(path, _diff)| path))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+                .len()
+        })
+    });
+    group.bench_function("changed_paths", |b| {
+        b.iter(|| {
+            tree1
+                .changed_paths(&tree2, &EverythingMatcher)
+                .unwrap()
+                .len()
+        })
+    });
+}
+
+fn bench_for_each_diff_ref(c: &mut Criterion) {
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+
+    // Every file modified
+    let (tree1, tree2) = wide_tree_pair(repo, |_| true);
+
+    let mut group = c.benchmark_group("bench_for_each_diff_ref");
+    group.bench_function("collect", |b| {
+        b.iter(|| {
+            tree1
+                .diff(&tree2, &EverythingMatcher)
+                .collect::<Vec<_>>()
+                .len()
+        })
+    });
+    group.bench_function("for_each_diff", |b| {
+        b.iter(|| {
+            let mut count = 0;
+            let _ = tree1
+                .for_each_diff(&tree2, &EverythingMatcher, |_path, _diff| {
+                    count += 1;
+                    ControlFlow::Continue(())
+                })
+                .unwrap();
+            count
+        })
+    });
+    group.bench_function("for_each_diff_ref", |b| {
+        b.iter(|| {
+            let mut count = 0;
+            let _ = tree1
+                .for_each_diff_ref(&tree2, &EverythingMatcher, |_path, _diff| {
+                    count += 1;
+                    ControlFlow::Continue(())
+                })
+                .unwrap();
+            count
+        })
+    });
+}
+
+fn bench_reachable_file_ids(c: &mut Criterion) {
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+
+    // 100 directories with 10 files each
+    let mut paths = vec![];
+    for dir in 0..100 {
+        for file in 0..10 {
+            paths.push(RepoPath::from_internal_string(&format!(
+                "dir{dir}/file{file}"
+            )));
+        }
+    }
+    let contents = paths
+        .iter()
+        .map(|path| (path, "contents\n"))
+        .collect::<Vec<_>>();
+    let tree_id = testutils::create_tree(repo, &contents).id().clone();
+    // Read the tree through a store where each tree read takes a while, as it
+    // would with a remote backend. The store caches trees, so each iteration
+    // gets a fresh one.
+    let latent_tree = || {
+        test_repo
+            .latent_store(Duration::from_millis(1))
+            .get_tree(&RepoPath::root(), &tree_id)
+            .unwrap()
+    };
+
+    let mut group = c.benchmark_group("bench_reachable_file_ids");
+    group.bench_function("sequential", |b| {
+        b.iter_with_setup(latent_tree, |tree| {
+            tree.reachable_file_ids(&EverythingMatcher).unwrap()
+        })
+    });
+    group.bench_function("parallel", |b| {
+        b.iter_with_setup(latent_tree, |tree| {
+            tree.reachable_file_ids_parallel(&EverythingMatcher)
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_diff_summary,
+    bench_changed_paths,
+    bench_for_each_diff_ref,
+    bench_reachable_file_ids
+);
+criterion_main!(benches);
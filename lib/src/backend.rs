@@ -19,6 +19,8 @@ use std::io::Read;
 use std::result::Result;
 use std::vec::Vec;
 
+#[cfg(feature = "async")]
+use async_trait::async_trait;
 use thiserror::Error;
 
 use crate::content_hash::ContentHash;
@@ -172,6 +174,58 @@ content_hash! {
     }
 }
 
+impl Conflict {
+    /// The number of terms added, i.e. the sides of the conflict.
+    pub fn num_sides(&self) -> usize {
+        self.adds.len()
+    }
+
+    /// The number of terms removed, i.e. the bases of the conflict.
+    pub fn num_bases(&self) -> usize {
+        self.removes.len()
+    }
+
+    /// Returns true if the conflict is a single value, i.e. not really a
+    /// conflict.
+    pub fn is_resolved(&self) -> bool {
+        self.removes.is_empty() && self.adds.len() == 1
+    }
+
+    /// Returns true if nothing is added, meaning that the path doesn't exist.
+    pub fn is_absent(&self) -> bool {
+        self.adds.is_empty()
+    }
+
+    /// Returns a copy of the conflict with the terms sorted by value, so that
+    /// conflicts that differ only in the order of their terms compare equal.
+    pub fn canonicalize(&self) -> Conflict {
+        let mut conflict = self.clone();
+        conflict.removes.sort_by(|a, b| a.value.cmp(&b.value));
+        conflict.adds.sort_by(|a, b| a.value.cmp(&b.value));
+        conflict
+    }
+
+    /// Returns true if the conflicts have the same terms, in any order.
+    pub fn content_eq(&self, other: &Conflict) -> bool {
+        self.canonicalize() == other.canonicalize()
+    }
+
+    /// Returns true if the path is a directory in some terms and not in
+    /// others, such as when one side added a file where the other side added
+    /// a directory.
+    pub fn is_file_dir_conflict(&self) -> bool {
+        let mut terms = self.removes.iter().chain(&self.adds);
+        let is_tree = |term: &ConflictTerm| matches!(term.value, TreeValue::Tree(_));
+        terms.clone().any(is_tree) && !terms.all(is_tree)
+    }
+
+    /// Returns true if the conflict has one more add than removes, which is
+    /// the shape of any conflict produced by merging.
+    pub fn is_well_formed(&self) -> bool {
+        self.num_sides() == self.num_bases() + 1
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum BackendError {
     #[error(
@@ -219,7 +273,7 @@ pub enum BackendError {
 
 pub type BackendResult<T> = Result<T, BackendError>;
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Hash)]
 pub enum TreeValue {
     File { id: FileId, executable: bool },
     Symlink(SymlinkId),
@@ -369,6 +423,7 @@ pub fn make_root_commit(root_change_id: ChangeId, empty_tree_id: TreeId) -> Comm
     }
 }
 
+#[cfg_attr(feature = "async", async_trait)]
 pub trait Backend: Send + Sync + Debug {
     fn as_any(&self) -> &dyn Any;
 
@@ -386,6 +441,18 @@ pub trait Backend: Send + Sync + Debug {
 
     fn write_file(&self, path: &RepoPath, contents: &mut dyn Read) -> BackendResult<FileId>;
 
+    /// Returns the size of the file's contents in bytes. The default
+    /// implementation reads the whole file. Backends that can look up the size
+    /// more cheaply should override it.
+    fn file_size(&self, path: &RepoPath, id: &FileId) -> BackendResult<u64> {
+        let mut reader = self.read_file(path, id)?;
+        std::io::copy(&mut reader, &mut std::io::sink()).map_err(|err| BackendError::ReadObject {
+            object_type: id.object_type(),
+            hash: id.hex(),
+            source: Box::new(err),
+        })
+    }
+
     fn read_symlink(&self, path: &RepoPath, id: &SymlinkId) -> BackendResult<String>;
 
     fn write_symlink(&self, path: &RepoPath, target: &str) -> BackendResult<SymlinkId>;
@@ -398,6 +465,14 @@ pub trait Backend: Send + Sync + Debug {
 
     fn read_tree(&self, path: &RepoPath, id: &TreeId) -> BackendResult<Tree>;
 
+    /// Reads a tree without blocking the caller's executor. The default
+    /// implementation calls `read_tree()`, so it blocks. Backends that do
+    /// network I/O should override it.
+    #[cfg(feature = "async")]
+    async fn read_tree_async(&self, path: &RepoPath, id: &TreeId) -> BackendResult<Tree> {
+        self.read_tree(path, id)
+    }
+
     fn write_tree(&self, path: &RepoPath, contents: &Tree) -> BackendResult<TreeId>;
 
     fn read_conflict(&self, path: &RepoPath, id: &ConflictId) -> BackendResult<Conflict>;
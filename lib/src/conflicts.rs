@@ -16,19 +16,87 @@ use std::cmp::max;
 use std::io::{Cursor, Write};
 
 use itertools::Itertools;
+use thiserror::Error;
 
-use crate::backend::{BackendResult, Conflict, ConflictId, ConflictTerm, ObjectId, TreeValue};
+use crate::backend::{
+    BackendError, BackendResult, Conflict, ConflictId, ConflictTerm, ObjectId, TreeValue,
+};
 use crate::diff::{find_line_ranges, Diff, DiffHunk};
 use crate::files;
 use crate::files::{ConflictHunk, MergeHunk, MergeResult};
 use crate::repo_path::RepoPath;
 use crate::store::Store;
 
-const CONFLICT_START_LINE: &[u8] = b"<<<<<<<\n";
-const CONFLICT_END_LINE: &[u8] = b">>>>>>>\n";
-const CONFLICT_DIFF_LINE: &[u8] = b"%%%%%%%\n";
-const CONFLICT_MINUS_LINE: &[u8] = b"-------\n";
-const CONFLICT_PLUS_LINE: &[u8] = b"+++++++\n";
+/// The length of conflict markers unless the content requires longer ones.
+pub const MIN_CONFLICT_MARKER_LEN: usize = 7;
+
+const CONFLICT_START_CHAR: u8 = b'<';
+const CONFLICT_END_CHAR: u8 = b'>';
+const CONFLICT_DIFF_CHAR: u8 = b'%';
+const CONFLICT_MINUS_CHAR: u8 = b'-';
+const CONFLICT_PLUS_CHAR: u8 = b'+';
+
+fn conflict_marker_line(marker_char: u8, marker_len: usize) -> Vec<u8> {
+    let mut line = vec![marker_char; marker_len];
+    line.push(b'\n');
+    line
+}
+
+/// The conflict marker lines of one length.
+struct ConflictMarkers {
+    start: Vec<u8>,
+    end: Vec<u8>,
+    diff: Vec<u8>,
+    minus: Vec<u8>,
+    plus: Vec<u8>,
+}
+
+impl ConflictMarkers {
+    fn new(marker_len: usize) -> Self {
+        ConflictMarkers {
+            start: conflict_marker_line(CONFLICT_START_CHAR, marker_len),
+            end: conflict_marker_line(CONFLICT_END_CHAR, marker_len),
+            diff: conflict_marker_line(CONFLICT_DIFF_CHAR, marker_len),
+            minus: conflict_marker_line(CONFLICT_MINUS_CHAR, marker_len),
+            plus: conflict_marker_line(CONFLICT_PLUS_CHAR, marker_len),
+        }
+    }
+}
+
+/// Returns the length of conflict markers to use when materializing
+/// `single_hunk`. That's `MIN_CONFLICT_MARKER_LEN` unless a term has a line
+/// that looks like a conflict marker, in which case the markers are made
+/// longer than any such line so they can't be confused with it.
+pub fn choose_conflict_marker_len(single_hunk: &ConflictHunk) -> usize {
+    let marker_chars = [
+        CONFLICT_START_CHAR,
+        CONFLICT_END_CHAR,
+        CONFLICT_DIFF_CHAR,
+        CONFLICT_MINUS_CHAR,
+        CONFLICT_PLUS_CHAR,
+    ];
+    let longest_marker_like_line = single_hunk
+        .removes
+        .iter()
+        .chain(&single_hunk.adds)
+        .flat_map(|content| content.split(|b| *b == b'\n'))
+        .filter_map(|line| {
+            let (&first, rest) = line.split_first()?;
+            if !marker_chars.contains(&first) || rest.iter().any(|b| *b != first) {
+                return None;
+            }
+            // In a diff, the line gets a "-" or "+" prefix, which makes a line
+            // of the same character one longer.
+            if first == CONFLICT_MINUS_CHAR || first == CONFLICT_PLUS_CHAR {
+                Some(line.len() + 1)
+            } else {
+                Some(line.len())
+            }
+        })
+        .max()
+        .unwrap_or(0);
+    max(MIN_CONFLICT_MARKER_LEN, longest_marker_like_line + 1)
+}
 
 fn describe_conflict_term(term: &ConflictTerm) -> String {
     match &term.value {
@@ -175,10 +243,24 @@ pub fn extract_file_conflict_as_single_hunk(
     })
 }
 
+/// Writes the merge of `single_hunk` with conflict markers of the length
+/// `choose_conflict_marker_len()` returns.
 pub fn materialize_merge_result(
     single_hunk: &ConflictHunk,
     output: &mut dyn Write,
 ) -> std::io::Result<()> {
+    let marker_len = choose_conflict_marker_len(single_hunk);
+    materialize_merge_result_with_marker_len(single_hunk, marker_len, output)
+}
+
+/// Like `materialize_merge_result()`, but with conflict markers of the given
+/// length.
+pub fn materialize_merge_result_with_marker_len(
+    single_hunk: &ConflictHunk,
+    marker_len: usize,
+    output: &mut dyn Write,
+) -> std::io::Result<()> {
+    let markers = ConflictMarkers::new(marker_len);
     let removed_slices = single_hunk.removes.iter().map(Vec::as_slice).collect_vec();
     let added_slices = single_hunk.adds.iter().map(Vec::as_slice).collect_vec();
     let merge_result = files::merge(&removed_slices, &added_slices);
@@ -193,7 +275,7 @@ pub fn materialize_merge_result(
                         output.write_all(&content)?;
                     }
                     MergeHunk::Conflict(ConflictHunk { removes, adds }) => {
-                        output.write_all(CONFLICT_START_LINE)?;
+                        output.write_all(&markers.start)?;
                         let mut add_index = 0;
                         for left in &removes {
                             let right1 = if let Some(right1) = adds.get(add_index) {
@@ -201,7 +283,7 @@ pub fn materialize_merge_result(
                             } else {
                                 // If we have no more positive terms, emit the remaining negative
                                 // terms as snapshots.
-                                output.write_all(CONFLICT_MINUS_LINE)?;
+                                output.write_all(&markers.minus)?;
                                 output.write_all(left)?;
                                 continue;
                             };
@@ -219,26 +301,26 @@ pub fn materialize_merge_result(
                                     // If the next positive term is a better match, emit
                                     // the current positive term as a snapshot and the next
                                     // positive term as a diff.
-                                    output.write_all(CONFLICT_PLUS_LINE)?;
+                                    output.write_all(&markers.plus)?;
                                     output.write_all(right1)?;
-                                    output.write_all(CONFLICT_DIFF_LINE)?;
+                                    output.write_all(&markers.diff)?;
                                     write_diff_hunks(&diff2, output)?;
                                     add_index += 2;
                                     continue;
                                 }
                             }
 
-                            output.write_all(CONFLICT_DIFF_LINE)?;
+                            output.write_all(&markers.diff)?;
                             write_diff_hunks(&diff1, output)?;
                             add_index += 1;
                         }
 
                         //  Emit the remaining positive terms as snapshots.
                         for slice in &adds[add_index..] {
-                            output.write_all(CONFLICT_PLUS_LINE)?;
+                            output.write_all(&markers.plus)?;
                             output.write_all(slice)?;
                         }
-                        output.write_all(CONFLICT_END_LINE)?;
+                        output.write_all(&markers.end)?;
                     }
                 }
             }
@@ -271,13 +353,230 @@ pub fn conflict_to_materialized_value(
     }
 }
 
+/// Identifies a term of a conflict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictSide {
+    /// The term at this index in the conflict's `removes`.
+    Base(usize),
+    /// The term at this index in the conflict's `adds`.
+    Add(usize),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("The conflict has no term {side:?}")]
+pub struct ConflictSideError {
+    pub side: ConflictSide,
+}
+
+/// Resolves a conflict by picking one of its terms, like "take ours" or "take
+/// theirs".
+pub fn resolve_conflict_side(
+    conflict: &Conflict,
+    side: ConflictSide,
+) -> Result<TreeValue, ConflictSideError> {
+    let term = match side {
+        ConflictSide::Base(index) => conflict.removes.get(index),
+        ConflictSide::Add(index) => conflict.adds.get(index),
+    };
+    term.map(|term| term.value.clone())
+        .ok_or(ConflictSideError { side })
+}
+
+pub(crate) fn simplify_conflict(
+    store: &Store,
+    path: &RepoPath,
+    conflict: Conflict,
+) -> Result<Conflict, BackendError> {
+    // Important cases to simplify:
+    //
+    // D
+    // |
+    // B C
+    // |/
+    // A
+    //
+    // 1. rebase C to B, then back to A => there should be no conflict
+    // 2. rebase C to B, then to D => the conflict should not mention B
+    // 3. rebase B to C and D to B', then resolve the conflict in B' and rebase D'
+    // on top =>    the conflict should be between B'', B, and D; it should not
+    // mention the conflict in B'
+
+    // Case 1 above:
+    // After first rebase, the conflict is {+B-A+C}. After rebasing back,
+    // the unsimplified conflict is {+A-B+{+B-A+C}}. Since the
+    // inner conflict is positive, we can simply move it into the outer conflict. We
+    // thus get {+A-B+B-A+C}, which we can then simplify to just C (because {+C} ==
+    // C).
+    //
+    // Case 2 above:
+    // After first rebase, the conflict is {+B-A+C}. After rebasing to D,
+    // the unsimplified conflict is {+D-C+{+B-A+C}}. As in the
+    // previous case, the inner conflict can be moved into the outer one. We then
+    // get {+D-C+B-A+C}. That can be simplified to
+    // {+D+B-A}, which is the desired conflict.
+    //
+    // Case 3 above:
+    // TODO: describe this case
+
+    // First expand any diffs with nested conflicts.
+    let mut new_removes = vec![];
+    let mut new_adds = vec![];
+    for term in conflict.adds {
+        match term.value {
+            TreeValue::Conflict(_) => {
+                let conflict = tree_value_to_conflict(store, path, term.value)?;
+                new_removes.extend_from_slice(&conflict.removes);
+                new_adds.extend_from_slice(&conflict.adds);
+            }
+            _ => {
+                new_adds.push(term);
+            }
+        }
+    }
+    for term in conflict.removes {
+        match term.value {
+            TreeValue::Conflict(_) => {
+                let conflict = tree_value_to_conflict(store, path, term.value)?;
+                new_removes.extend_from_slice(&conflict.adds);
+                new_adds.extend_from_slice(&conflict.removes);
+            }
+            _ => {
+                new_removes.push(term);
+            }
+        }
+    }
+
+    remove_canceling_terms(&mut new_removes, &mut new_adds);
+
+    // TODO: We should probably remove duplicate entries here too. So if we have
+    // {+A+A}, that would become just {+A}. Similarly {+B-A+B} would be just
+    // {+B-A}.
+
+    Ok(Conflict {
+        adds: new_adds,
+        removes: new_removes,
+    })
+}
+
+fn tree_value_to_conflict(
+    store: &Store,
+    path: &RepoPath,
+    value: TreeValue,
+) -> Result<Conflict, BackendError> {
+    match value {
+        TreeValue::Conflict(id) => {
+            let conflict = store.read_conflict(path, &id)?;
+            Ok(conflict)
+        }
+        other => Ok(Conflict {
+            removes: vec![],
+            adds: vec![ConflictTerm { value: other }],
+        }),
+    }
+}
+
+/// Removes pairs of entries that match in the removes and adds.
+pub(crate) fn remove_canceling_terms(
+    removes: &mut Vec<ConflictTerm>,
+    adds: &mut Vec<ConflictTerm>,
+) {
+    let mut add_index = 0;
+    while add_index < adds.len() {
+        let add = &adds[add_index];
+        add_index += 1;
+        for (remove_index, remove) in removes.iter().enumerate() {
+            if remove.value == add.value {
+                removes.remove(remove_index);
+                add_index -= 1;
+                adds.remove(add_index);
+                break;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConflictBuildError {
+    #[error(
+        "A conflict must have one more add than removes, but this one has {adds} adds and \
+         {removes} removes"
+    )]
+    Malformed { adds: usize, removes: usize },
+    #[error(transparent)]
+    BackendError(#[from] BackendError),
+}
+
+/// Builds a `Conflict` term by term and checks that the result is well-formed
+/// (see `Conflict::is_well_formed()`).
+#[derive(Clone, Debug, Default)]
+pub struct ConflictBuilder {
+    conflict: Conflict,
+}
+
+impl ConflictBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(mut self, value: TreeValue) -> Self {
+        self.conflict.adds.push(ConflictTerm { value });
+        self
+    }
+
+    pub fn remove(mut self, value: TreeValue) -> Self {
+        self.conflict.removes.push(ConflictTerm { value });
+        self
+    }
+
+    /// Returns the conflict, or `ConflictBuildError::Malformed` if it isn't
+    /// well-formed.
+    pub fn build(self) -> Result<Conflict, ConflictBuildError> {
+        if self.conflict.is_well_formed() {
+            Ok(self.conflict)
+        } else {
+            Err(ConflictBuildError::Malformed {
+                adds: self.conflict.adds.len(),
+                removes: self.conflict.removes.len(),
+            })
+        }
+    }
+
+    /// Like `build()`, but also expands nested conflicts and removes terms
+    /// that cancel out, the same way merging does. The result may have a
+    /// single add and no removes, meaning that the conflict is resolved.
+    pub fn build_simplified(
+        self,
+        store: &Store,
+        path: &RepoPath,
+    ) -> Result<Conflict, ConflictBuildError> {
+        let conflict = self.build()?;
+        Ok(simplify_conflict(store, path, conflict)?)
+    }
+}
+
 /// Parses conflict markers from a slice. Returns None if there were no valid
 /// conflict markers. The caller has to provide the expected number of removed
 /// and added inputs to the conflicts. Conflict markers that are otherwise valid
 /// will be considered invalid if they don't have the expected arity.
+///
+/// Only markers of length `MIN_CONFLICT_MARKER_LEN` are recognized. Use
+/// `parse_conflict_with_marker_len()` for content that was materialized with
+/// longer markers.
 // TODO: "parse" is not usually the opposite of "materialize", so maybe we
 // should rename them to "serialize" and "deserialize"?
 pub fn parse_conflict(input: &[u8], num_removes: usize, num_adds: usize) -> Option<Vec<MergeHunk>> {
+    parse_conflict_with_marker_len(input, num_removes, num_adds, MIN_CONFLICT_MARKER_LEN)
+}
+
+/// Like `parse_conflict()`, but recognizes only conflict markers of the given
+/// length. Lines that would be markers of any other length are treated as
+/// content.
+pub fn parse_conflict_with_marker_len(
+    input: &[u8],
+    num_removes: usize,
+    num_adds: usize,
+    marker_len: usize,
+) -> Option<Vec<MergeHunk>> {
     if input.is_empty() {
         return None;
     }
@@ -285,12 +584,13 @@ pub fn parse_conflict(input: &[u8], num_removes: usize, num_adds: usize) -> Opti
     let mut pos = 0;
     let mut resolved_start = 0;
     let mut conflict_start = None;
+    let markers = ConflictMarkers::new(marker_len);
     for line in input.split_inclusive(|b| *b == b'\n') {
-        if line == CONFLICT_START_LINE {
+        if line == markers.start {
             conflict_start = Some(pos);
-        } else if conflict_start.is_some() && line == CONFLICT_END_LINE {
-            let conflict_body = &input[conflict_start.unwrap() + CONFLICT_START_LINE.len()..pos];
-            let hunk = parse_conflict_hunk(conflict_body);
+        } else if conflict_start.is_some() && line == markers.end {
+            let conflict_body = &input[conflict_start.unwrap() + markers.start.len()..pos];
+            let hunk = parse_conflict_hunk(conflict_body, &markers);
             match &hunk {
                 MergeHunk::Conflict(ConflictHunk { removes, adds })
                     if removes.len() == num_removes && adds.len() == num_adds =>
@@ -319,7 +619,7 @@ pub fn parse_conflict(input: &[u8], num_removes: usize, num_adds: usize) -> Opti
     }
 }
 
-fn parse_conflict_hunk(input: &[u8]) -> MergeHunk {
+fn parse_conflict_hunk(input: &[u8], markers: &ConflictMarkers) -> MergeHunk {
     enum State {
         Diff,
         Minus,
@@ -330,25 +630,20 @@ fn parse_conflict_hunk(input: &[u8]) -> MergeHunk {
     let mut removes = vec![];
     let mut adds = vec![];
     for line in input.split_inclusive(|b| *b == b'\n') {
-        match line {
-            CONFLICT_DIFF_LINE => {
-                state = State::Diff;
-                removes.push(vec![]);
-                adds.push(vec![]);
-                continue;
-            }
-            CONFLICT_MINUS_LINE => {
-                state = State::Minus;
-                removes.push(vec![]);
-                continue;
-            }
-            CONFLICT_PLUS_LINE => {
-                state = State::Plus;
-                adds.push(vec![]);
-                continue;
-            }
-            _ => {}
-        };
+        if line == markers.diff {
+            state = State::Diff;
+            removes.push(vec![]);
+            adds.push(vec![]);
+            continue;
+        } else if line == markers.minus {
+            state = State::Minus;
+            removes.push(vec![]);
+            continue;
+        } else if line == markers.plus {
+            state = State::Plus;
+            adds.push(vec![]);
+            continue;
+        }
         match state {
             State::Diff => {
                 if let Some(rest) = line.strip_prefix(b"-") {
@@ -399,9 +694,20 @@ pub fn update_conflict_from_content(
         return Ok(Some(conflict_id.clone()));
     }
 
+    // The content was materialized with markers as long as the conflict's
+    // terms required, so parse it with markers of the same length.
+    let marker_len = extract_file_conflict_as_single_hunk(store, path, &conflict)
+        .map_or(MIN_CONFLICT_MARKER_LEN, |single_hunk| {
+            choose_conflict_marker_len(&single_hunk)
+        });
     let mut removed_content = vec![vec![]; conflict.removes.len()];
     let mut added_content = vec![vec![]; conflict.adds.len()];
-    if let Some(hunks) = parse_conflict(content, conflict.removes.len(), conflict.adds.len()) {
+    if let Some(hunks) = parse_conflict_with_marker_len(
+        content,
+        conflict.removes.len(),
+        conflict.adds.len(),
+        marker_len,
+    ) {
         for hunk in hunks {
             match hunk {
                 MergeHunk::Resolved(slice) => {
@@ -97,13 +97,19 @@ impl<'index> Revset<'index> for RevsetImpl<'index> {
         Box::new(RevsetGraphIterator::new(self.inner.iter()))
     }
 
+    fn iter_with_change_ids(&self) -> Option<Box<dyn Iterator<Item = (CommitId, ChangeId)> + '_>> {
+        Some(Box::new(self.inner.iter().map(|index_entry| {
+            (index_entry.commit_id(), index_entry.change_id())
+        })))
+    }
+
     fn change_id_index(&self) -> Box<dyn ChangeIdIndex + 'index> {
         // TODO: Create a persistent lookup from change id to commit ids.
-        let mut pos_by_change = vec![];
-        for entry in self.inner.iter() {
-            pos_by_change.push((entry.change_id(), entry.position()));
-        }
-        let pos_by_change = IdIndex::from_vec(pos_by_change);
+        let pos_by_change = self
+            .inner
+            .iter()
+            .map(|entry| (entry.change_id(), entry.position()))
+            .collect();
         Box::new(ChangeIdIndexImpl {
             index: self.index.clone(),
             pos_by_change,
@@ -846,7 +852,7 @@ fn build_predicate_fn<'index>(
         }
         RevsetFilterPredicate::HasConflict => pure_predicate_fn(move |entry| {
             let commit = store.get_commit(&entry.commit_id()).unwrap();
-            commit.tree().has_conflict()
+            commit.tree().has_conflict().unwrap()
         }),
     }
 }
@@ -870,7 +876,7 @@ fn has_diff_from_parent(
     }
     let from_tree = rewrite::merge_commit_trees_without_repo(store, index, &parents);
     let to_tree = commit.tree();
-    from_tree.diff(&to_tree, matcher).next().is_some()
+    from_tree.any_diff(&to_tree, matcher).unwrap()
 }
 
 #[cfg(test)]
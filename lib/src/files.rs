@@ -207,11 +207,26 @@ struct SyncRegion {
     right: Range<usize>,
 }
 
+/// Counts of the regions where the inputs to `merge()` differ.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct MergeStats {
+    /// The number of differing regions that were merged without a conflict.
+    pub clean_hunks: usize,
+    /// The number of differing regions, whether they conflict or not.
+    pub total_hunks: usize,
+}
+
 // TODO: Should we require `add.len() == removes.len() + 1`? If that condition
 // is false, it effectively means that we should pretend that there are empty
 // strings in `removes` or `adds` to make it true. Maybe we should have to
 // caller make it explicitly that way.
 pub fn merge(removes: &[&[u8]], adds: &[&[u8]]) -> MergeResult {
+    merge_with_stats(removes, adds).0
+}
+
+/// Like `merge()`, but also counts how many of the regions where the inputs
+/// differ were merged cleanly.
+pub fn merge_with_stats(removes: &[&[u8]], adds: &[&[u8]]) -> (MergeResult, MergeStats) {
     let num_removes = removes.len();
     // TODO: Using the first remove as base (first in the inputs) is how it's
     // usually done for 3-way conflicts. Are there better heuristics when there are
@@ -222,6 +237,8 @@ pub fn merge(removes: &[&[u8]], adds: &[&[u8]]) -> MergeResult {
     let diff = Diff::for_tokenizer(&diff_inputs, &diff::find_line_ranges);
     let mut resolved_hunk: Vec<u8> = vec![];
     let mut merge_hunks: Vec<MergeHunk> = vec![];
+    let mut total_hunks = 0;
+    let mut conflict_hunks = 0;
     for diff_hunk in diff.hunks() {
         match diff_hunk {
             DiffHunk::Matching(content) => {
@@ -230,6 +247,7 @@ pub fn merge(removes: &[&[u8]], adds: &[&[u8]]) -> MergeResult {
                 }
             }
             DiffHunk::Different(parts) => {
+                total_hunks += 1;
                 let mut removed_parts = parts[..num_removes].to_vec();
                 let mut added_parts = parts[num_removes..].to_vec();
                 // Remove pairs of parts that match in the removes and adds.
@@ -264,6 +282,7 @@ pub fn merge(removes: &[&[u8]], adds: &[&[u8]]) -> MergeResult {
                     // to
                     resolved_hunk.extend(added_parts[0]);
                 } else {
+                    conflict_hunks += 1;
                     if !resolved_hunk.is_empty() {
                         merge_hunks.push(MergeHunk::Resolved(resolved_hunk));
                         resolved_hunk = vec![];
@@ -285,18 +304,25 @@ pub fn merge(removes: &[&[u8]], adds: &[&[u8]]) -> MergeResult {
         }
     }
 
-    if merge_hunks.is_empty() {
+    let stats = MergeStats {
+        clean_hunks: total_hunks - conflict_hunks,
+        total_hunks,
+    };
+    let result = if merge_hunks.is_empty() {
         MergeResult::Resolved(resolved_hunk)
     } else {
         if !resolved_hunk.is_empty() {
             merge_hunks.push(MergeHunk::Resolved(resolved_hunk));
         }
         MergeResult::Conflict(merge_hunks)
-    }
+    };
+    (result, stats)
 }
 
 #[cfg(test)]
 mod tests {
+    use assert_matches::assert_matches;
+
     use super::*;
 
     #[test]
@@ -444,4 +470,46 @@ mod tests {
             })])
         );
     }
+
+    #[test]
+    fn test_merge_with_stats() {
+        // Nothing differs
+        assert_eq!(
+            merge_with_stats(&[b"a\n"], &[b"a\n", b"a\n"]),
+            (
+                MergeResult::Resolved(b"a\n".to_vec()),
+                MergeStats {
+                    clean_hunks: 0,
+                    total_hunks: 0
+                }
+            )
+        );
+        // The sides changed different lines
+        assert_eq!(
+            merge_with_stats(
+                &[b"a\nb\nc\nd\ne\n"],
+                &[b"A\nb\nc\nd\ne\n", b"a\nb\nc\nd\nE\n"]
+            ),
+            (
+                MergeResult::Resolved(b"A\nb\nc\nd\nE\n".to_vec()),
+                MergeStats {
+                    clean_hunks: 2,
+                    total_hunks: 2
+                }
+            )
+        );
+        // One of the changed lines conflicts
+        let (result, stats) = merge_with_stats(
+            &[b"a\nb\nc\nd\ne\n"],
+            &[b"A\nb\nc\nd\nX\n", b"a\nb\nc\nd\nY\n"],
+        );
+        assert_matches!(result, MergeResult::Conflict(_));
+        assert_eq!(
+            stats,
+            MergeStats {
+                clean_hunks: 1,
+                total_hunks: 2
+            }
+        );
+    }
 }
@@ -223,6 +223,19 @@ impl Backend for GitBackend {
         Ok(Box::new(Cursor::new(content)))
     }
 
+    fn file_size(&self, _path: &RepoPath, id: &FileId) -> BackendResult<u64> {
+        let git_blob_id = validate_git_object_id(id)?;
+        let locked_repo = self.repo.lock().unwrap();
+        let odb = locked_repo
+            .odb()
+            .map_err(|err| map_not_found_err(err, id))?;
+        // Only reads the object header, not the contents
+        let (size, _) = odb
+            .read_header(git_blob_id)
+            .map_err(|err| map_not_found_err(err, id))?;
+        Ok(size as u64)
+    }
+
     fn write_file(&self, _path: &RepoPath, contents: &mut dyn Read) -> BackendResult<FileId> {
         let mut bytes = Vec::new();
         contents.read_to_end(&mut bytes).unwrap();
@@ -12,15 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp::max;
 use std::rc::Rc;
+use std::sync::Arc;
 
+use itertools::Itertools;
 use once_cell::unsync::OnceCell;
 
-use crate::backend::{self, ChangeId, CommitId, ObjectId};
+use crate::backend::{self, BackendResult, ChangeId, CommitId, ObjectId};
 use crate::index::{HexPrefix, PrefixResolution};
 use crate::op_store::WorkspaceId;
 use crate::repo::Repo;
-use crate::revset::{DefaultSymbolResolver, RevsetExpression, RevsetIteratorExt};
+use crate::revset::{DefaultSymbolResolver, RevsetExpression};
+use crate::store::Store;
+use crate::thread_util::map_chunks_parallel;
 
 struct PrefixDisambiguationError;
 
@@ -49,14 +54,19 @@ impl DisambiguationData {
                 .evaluate(repo)
                 .map_err(|_| PrefixDisambiguationError)?;
 
-            // TODO: We should be able to get the change IDs from the revset, without having
-            // to read the whole commit objects
+            let ids = if let Some(ids) = revset.iter_with_change_ids() {
+                ids.collect_vec()
+            } else {
+                // The revset engine can't tell the change IDs, so we have to
+                // read the whole commit objects
+                read_change_ids_parallel(repo.store(), &revset.iter().collect_vec())
+                    .map_err(|_| PrefixDisambiguationError)?
+            };
             let mut commit_id_vec = vec![];
             let mut change_id_vec = vec![];
-            for commit in revset.iter().commits(repo.store()) {
-                let commit = commit.map_err(|_| PrefixDisambiguationError)?;
-                commit_id_vec.push((commit.id().clone(), commit.id().clone()));
-                change_id_vec.push((commit.change_id().clone(), commit.id().clone()));
+            for (commit_id, change_id) in ids {
+                commit_id_vec.push((commit_id.clone(), commit_id.clone()));
+                change_id_vec.push((change_id, commit_id));
             }
             Ok(Indexes {
                 commit_index: IdIndex::from_vec(commit_id_vec),
@@ -66,12 +76,53 @@ impl DisambiguationData {
     }
 }
 
+/// Reads the given commits using multiple threads and returns their commit IDs
+/// and change IDs, in the same order as `commit_ids`.
+pub fn read_change_ids_parallel(
+    store: &Arc<Store>,
+    commit_ids: &[CommitId],
+) -> BackendResult<Vec<(CommitId, ChangeId)>> {
+    let chunk_results = map_chunks_parallel(commit_ids, |chunk| {
+        chunk
+            .iter()
+            .map(|commit_id| {
+                let commit = store.get_commit(commit_id)?;
+                Ok((commit_id.clone(), commit.change_id().clone()))
+            })
+            .collect::<BackendResult<Vec<_>>>()
+    });
+    let mut ids = Vec::with_capacity(commit_ids.len());
+    for chunk_result in chunk_results {
+        ids.extend(chunk_result?);
+    }
+    Ok(ids)
+}
+
+/// Where a prefix resolved by `IdPrefixContext` is unambiguous.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrefixScope {
+    /// The prefix only resolves within the disambiguation revset. It's
+    /// ambiguous in the whole repo.
+    DisambiguationSet,
+    /// The prefix resolves the same way in the whole repo.
+    Repo,
+}
+
 #[derive(Default)]
 pub struct IdPrefixContext {
     disambiguation: Option<DisambiguationData>,
+    min_prefix_len: usize,
 }
 
 impl IdPrefixContext {
+    /// Makes `shortest_commit_prefix_len()` and `shortest_change_prefix_len()`
+    /// return at least `min_prefix_len`, so IDs are displayed at a readable
+    /// length. Resolution still accepts any unambiguous prefix.
+    pub fn with_min_prefix_len(mut self, min_prefix_len: usize) -> Self {
+        self.min_prefix_len = min_prefix_len;
+        self
+    }
+
     pub fn disambiguate_within(
         mut self,
         expression: Rc<RevsetExpression>,
@@ -90,6 +141,8 @@ impl IdPrefixContext {
         self.disambiguation
             .as_ref()
             .and_then(|disambiguation| disambiguation.indexes(repo).ok())
+            // An empty revset can't help disambiguating anything
+            .filter(|indexes| !indexes.commit_index.is_empty())
     }
 
     /// Resolve an unambiguous commit ID prefix.
@@ -98,19 +151,52 @@ impl IdPrefixContext {
         repo: &dyn Repo,
         prefix: &HexPrefix,
     ) -> PrefixResolution<CommitId> {
-        if let Some(indexes) = self.disambiguation_indexes(repo) {
-            let resolution = indexes.commit_index.resolve_prefix(prefix);
-            if let PrefixResolution::SingleMatch(mut ids) = resolution {
+        // An ambiguous match in the disambiguation revset is also ambiguous in
+        // the whole repo, so only fall back if there was no match.
+        let resolution = match self.disambiguation_indexes(repo) {
+            Some(indexes) => indexes.commit_index.resolve_prefix(prefix).map(|mut ids| {
                 assert_eq!(ids.len(), 1);
-                return PrefixResolution::SingleMatch(ids.pop().unwrap());
+                ids.pop().unwrap()
+            }),
+            None => PrefixResolution::NoMatch,
+        };
+        resolution.or_else(|| repo.index().resolve_prefix(prefix))
+    }
+
+    /// Like `resolve_commit_prefix()`, but also tells whether the prefix
+    /// resolves the same way in the whole repo, or only within the
+    /// disambiguation revset.
+    pub fn resolve_commit_prefix_scoped(
+        &self,
+        repo: &dyn Repo,
+        prefix: &HexPrefix,
+    ) -> (PrefixResolution<CommitId>, PrefixScope) {
+        let resolution = self.resolve_commit_prefix(repo, prefix);
+        let scope = match &resolution {
+            PrefixResolution::SingleMatch(_)
+                if !matches!(
+                    repo.index().resolve_prefix(prefix),
+                    PrefixResolution::SingleMatch(_)
+                ) =>
+            {
+                PrefixScope::DisambiguationSet
             }
-        }
-        repo.index().resolve_prefix(prefix)
+            _ => PrefixScope::Repo,
+        };
+        (resolution, scope)
     }
 
     /// Returns the shortest length of a prefix of `commit_id` that
-    /// can still be resolved by `resolve_commit_prefix()`.
+    /// can still be resolved by `resolve_commit_prefix()`, but at least the
+    /// minimum prefix length.
     pub fn shortest_commit_prefix_len(&self, repo: &dyn Repo, commit_id: &CommitId) -> usize {
+        max(
+            self.shortest_unique_commit_prefix_len(repo, commit_id),
+            self.min_prefix_len,
+        )
+    }
+
+    fn shortest_unique_commit_prefix_len(&self, repo: &dyn Repo, commit_id: &CommitId) -> usize {
         if let Some(indexes) = self.disambiguation_indexes(repo) {
             // TODO: Avoid the double lookup here (has_key() + shortest_unique_prefix_len())
             if indexes.commit_index.has_key(commit_id) {
@@ -121,23 +207,73 @@ impl IdPrefixContext {
     }
 
     /// Resolve an unambiguous change ID prefix to the commit IDs in the revset.
+    ///
+    /// If the change is divergent, the commit IDs are sorted by their position
+    /// in the index, newest first.
     pub fn resolve_change_prefix(
         &self,
         repo: &dyn Repo,
         prefix: &HexPrefix,
     ) -> PrefixResolution<Vec<CommitId>> {
-        if let Some(indexes) = self.disambiguation_indexes(repo) {
-            let resolution = indexes.change_index.resolve_prefix(prefix);
-            if let PrefixResolution::SingleMatch(ids) = resolution {
-                return PrefixResolution::SingleMatch(ids);
-            }
+        let resolution = match self.disambiguation_indexes(repo) {
+            Some(indexes) => indexes.change_index.resolve_prefix(prefix),
+            None => PrefixResolution::NoMatch,
+        };
+        resolution.or_else(|| repo.resolve_change_id_prefix(prefix))
+    }
+
+    /// Returns the IDs in the disambiguation revset that start with `prefix`,
+    /// in sorted order and at most `limit` of them. Unlike
+    /// `resolve_commit_prefix()`, this returns all candidates instead of
+    /// requiring the prefix to be unambiguous. Returns nothing if there's no
+    /// disambiguation revset.
+    pub fn complete_commit_prefix(
+        &self,
+        repo: &dyn Repo,
+        prefix: &HexPrefix,
+        limit: usize,
+    ) -> Vec<CommitId> {
+        match self.disambiguation_indexes(repo) {
+            Some(indexes) => indexes.commit_index.complete_prefix(prefix, limit),
+            None => vec![],
+        }
+    }
+
+    /// Like `complete_commit_prefix()`, but for change IDs.
+    pub fn complete_change_prefix(
+        &self,
+        repo: &dyn Repo,
+        prefix: &HexPrefix,
+        limit: usize,
+    ) -> Vec<ChangeId> {
+        match self.disambiguation_indexes(repo) {
+            Some(indexes) => indexes.change_index.complete_prefix(prefix, limit),
+            None => vec![],
+        }
+    }
+
+    /// Returns the change IDs that have more than one commit in the
+    /// disambiguation revset, with those commits in the same order as
+    /// `resolve_change_prefix()` returns them. Returns nothing if there's no
+    /// disambiguation revset.
+    pub fn divergent_changes(&self, repo: &dyn Repo) -> Vec<(ChangeId, Vec<CommitId>)> {
+        match self.disambiguation_indexes(repo) {
+            Some(indexes) => indexes.change_index.keys_with_multiple_values(),
+            None => vec![],
         }
-        repo.resolve_change_id_prefix(prefix)
     }
 
     /// Returns the shortest length of a prefix of `change_id` that
-    /// can still be resolved by `resolve_change_prefix()`.
+    /// can still be resolved by `resolve_change_prefix()`, but at least the
+    /// minimum prefix length.
     pub fn shortest_change_prefix_len(&self, repo: &dyn Repo, change_id: &ChangeId) -> usize {
+        max(
+            self.shortest_unique_change_prefix_len(repo, change_id),
+            self.min_prefix_len,
+        )
+    }
+
+    fn shortest_unique_change_prefix_len(&self, repo: &dyn Repo, change_id: &ChangeId) -> usize {
         if let Some(indexes) = self.disambiguation_indexes(repo) {
             if indexes.change_index.has_key(change_id) {
                 return indexes.change_index.shortest_unique_prefix_len(change_id);
@@ -155,12 +291,28 @@ where
     K: ObjectId + Ord,
 {
     /// Creates new index from the given entries. Multiple values can be
-    /// associated with a single key.
+    /// associated with a single key. Such values are kept in the order they
+    /// appear in `vec`.
     pub fn from_vec(mut vec: Vec<(K, V)>) -> Self {
-        vec.sort_unstable_by(|(k0, _), (k1, _)| k0.cmp(k1));
+        vec.sort_by(|(k0, _), (k1, _)| k0.cmp(k1));
         IdIndex(vec)
     }
 
+    /// Returns the number of entries in the index, including entries with
+    /// duplicate keys.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the number of unique keys in the index.
+    pub fn distinct_key_count(&self) -> usize {
+        self.0.iter().dedup_by(|(k0, _), (k1, _)| k0 == k1).count()
+    }
+
     /// Looks up entries with the given prefix, and collects values if matched
     /// entries have unambiguous keys.
     pub fn resolve_prefix_with<U>(
@@ -174,6 +326,8 @@ where
         }
         let mut range = self.resolve_prefix_range(prefix).peekable();
         if let Some((first_key, _)) = range.peek().copied() {
+            // Collecting into an Option stops at the first key that differs from
+            // first_key, so we don't scan the rest of a large ambiguous range.
             let maybe_entries: Option<Vec<_>> = range
                 .map(|(k, v)| (k == first_key).then(|| value_mapper(v)))
                 .collect();
@@ -209,54 +363,218 @@ where
             .map(|(k, v)| (k, v))
     }
 
+    /// Returns up to `limit` distinct keys with the given prefix, in sorted
+    /// order.
+    pub fn complete_prefix(&self, prefix: &HexPrefix, limit: usize) -> Vec<K>
+    where
+        K: Clone,
+    {
+        self.resolve_prefix_range(prefix)
+            .map(|(k, _)| k)
+            .dedup()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns true if any key has the given prefix. Cheaper than
+    /// `resolve_prefix()` if you only need to know whether there's a match.
+    pub fn has_prefix(&self, prefix: &HexPrefix) -> bool {
+        self.resolve_prefix_range(prefix).next().is_some()
+    }
+
     pub fn has_key(&self, key: &K) -> bool {
         self.0.binary_search_by(|(k, _)| k.cmp(key)).is_ok()
     }
 
+    /// Returns the keys that are associated with more than one value, in
+    /// sorted order, together with their values.
+    pub fn keys_with_multiple_values(&self) -> Vec<(K, Vec<V>)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let mut groups = vec![];
+        for (key, entries) in &self.0.iter().group_by(|(k, _)| k) {
+            let values = entries.map(|(_, v)| v.clone()).collect_vec();
+            if values.len() > 1 {
+                groups.push((key.clone(), values));
+            }
+        }
+        groups
+    }
+
     /// This function returns the shortest length of a prefix of `key` that
     /// disambiguates it from every other key in the index.
     ///
-    /// The length to be returned is a number of hexadecimal digits.
-    ///
-    /// This has some properties that we do not currently make much use of:
-    ///
-    /// - The algorithm works even if `key` itself is not in the index.
-    ///
-    /// - In the special case when there are keys in the trie for which our
-    ///   `key` is an exact prefix, returns `key.len() + 1`. Conceptually, in
-    ///   order to disambiguate, you need every letter of the key *and* the
-    ///   additional fact that it's the entire key). This case is extremely
-    ///   unlikely for hashes with 12+ hexadecimal characters.
+    /// See `shortest_unique_prefix_len_in_sorted()` for details.
     pub fn shortest_unique_prefix_len(&self, key: &K) -> usize {
-        let pos = self.0.partition_point(|(k, _)| k < key);
-        let left = pos.checked_sub(1).map(|p| &self.0[p]);
-        let right = self.0[pos..].iter().find(|(k, _)| k != key);
+        shortest_unique_prefix_len_by(&self.0, key, |(k, _)| k)
+    }
+
+    /// Returns the keys whose `shortest_unique_prefix_len()` would grow if
+    /// `new_key` were inserted into the index. Only the keys next to the
+    /// insertion point can be affected, so only those are examined. Returns
+    /// nothing if `new_key` is already in the index.
+    pub fn prefix_len_affected_by_insert(&self, new_key: &K) -> Vec<K>
+    where
+        K: Clone,
+    {
+        if self.has_key(new_key) {
+            return vec![];
+        }
+        let (left, right) = self.nearest(new_key);
         itertools::chain(left, right)
-            .map(|(neighbor, _value)| {
-                backend::common_hex_len(key.as_bytes(), neighbor.as_bytes()) + 1
+            .filter(|neighbor| {
+                let len_with_new_key =
+                    backend::common_hex_len(neighbor.as_bytes(), new_key.as_bytes()) + 1;
+                len_with_new_key > self.shortest_unique_prefix_len(neighbor)
             })
-            .max()
-            // Even if the key is the only one in the index, we require at least one digit.
-            .unwrap_or(1)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the keys just before and just after `key`, which doesn't have
+    /// to be in the index. This can be used to suggest IDs close to a mistyped
+    /// one.
+    pub fn nearest(&self, key: &K) -> (Option<&K>, Option<&K>) {
+        neighbors_by(&self.0, key, |(k, _)| k)
+    }
+}
+
+/// This function returns the shortest length of a prefix of `key` that
+/// disambiguates it from every other key in `sorted`, which must be sorted.
+///
+/// The length to be returned is a number of hexadecimal digits.
+///
+/// This has some properties that we do not currently make much use of:
+///
+/// - The algorithm works even if `key` itself is not in `sorted`.
+///
+/// - In the special case when there are keys in `sorted` for which our `key` is
+///   an exact prefix, returns `key.len() + 1`. Conceptually, in order to
+///   disambiguate, you need every letter of the key *and* the additional fact
+///   that it's the entire key). This case is extremely unlikely for hashes with
+///   12+ hexadecimal characters.
+pub fn shortest_unique_prefix_len_in_sorted<K: ObjectId + Ord>(sorted: &[K], key: &K) -> usize {
+    shortest_unique_prefix_len_by(sorted, key, |k| k)
+}
+
+/// Returns the shortest length of a prefix of `target` that disambiguates it
+/// from every other ID in `ids`, which don't have to be sorted. Useful for
+/// disambiguating against an ad-hoc set of IDs, such as the ones currently
+/// shown to the user.
+pub fn shortest_prefix_among(ids: &[CommitId], target: &CommitId) -> usize {
+    let index: IdIndex<CommitId, ()> = ids.iter().map(|id| (id.clone(), ())).collect();
+    index.shortest_unique_prefix_len(target)
+}
+
+fn shortest_unique_prefix_len_by<T, K: ObjectId + Ord>(
+    sorted: &[T],
+    key: &K,
+    get_key: impl Fn(&T) -> &K,
+) -> usize {
+    let (left, right) = neighbors_by(sorted, key, get_key);
+    itertools::chain(left, right)
+        .map(|neighbor| backend::common_hex_len(key.as_bytes(), neighbor.as_bytes()) + 1)
+        .max()
+        // Even if the key is the only one in the index, we require at least one digit.
+        .unwrap_or(1)
+}
+
+/// Returns the greatest key less than `key` and the smallest key greater than
+/// `key` in `sorted`, which must be sorted.
+fn neighbors_by<'a, T, K: ObjectId + Ord + 'a>(
+    sorted: &'a [T],
+    key: &K,
+    get_key: impl Fn(&T) -> &K,
+) -> (Option<&'a K>, Option<&'a K>) {
+    let pos = sorted.partition_point(|entry| get_key(entry) < key);
+    let left = pos.checked_sub(1).map(|p| get_key(&sorted[p]));
+    let right = sorted[pos..].iter().map(&get_key).find(|k| *k != key);
+    (left, right)
+}
+
+impl<K, V> FromIterator<(K, V)> for IdIndex<K, V>
+where
+    K: ObjectId + Ord,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        IdIndex::from_vec(iter.into_iter().collect())
+    }
+}
+
+/// Like `IdIndex`, but the values associated with each key are grouped
+/// together, so all values for a key can be looked up without collecting them.
+#[derive(Debug, Clone)]
+pub struct GroupedIdIndex<K, V>(Vec<(K, Vec<V>)>);
+
+impl<K, V> GroupedIdIndex<K, V>
+where
+    K: ObjectId + Ord,
+{
+    /// Creates new index from the given entries. Values associated with the
+    /// same key are kept in the order they appear in `vec`.
+    pub fn from_vec(mut vec: Vec<(K, V)>) -> Self {
+        vec.sort_by(|(k0, _), (k1, _)| k0.cmp(k1));
+        let mut groups: Vec<(K, Vec<V>)> = vec![];
+        for (key, value) in vec {
+            match groups.last_mut() {
+                Some((last_key, values)) if *last_key == key => values.push(value),
+                _ => groups.push((key, vec![value])),
+            }
+        }
+        GroupedIdIndex(groups)
+    }
+
+    /// Returns the number of distinct keys in the index.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the values associated with the given key.
+    pub fn get(&self, key: &K) -> Option<&[V]> {
+        let pos = self.0.binary_search_by(|(k, _)| k.cmp(key)).ok()?;
+        Some(&self.0[pos].1)
+    }
+
+    /// Looks up the key with the given prefix, and returns its values if the
+    /// prefix is unambiguous.
+    pub fn resolve_prefix(&self, prefix: &HexPrefix) -> PrefixResolution<&[V]> {
+        if prefix.min_prefix_bytes().is_empty() {
+            // We consider an empty prefix ambiguous even if the index has a single entry.
+            return PrefixResolution::AmbiguousMatch;
+        }
+        let min_bytes = prefix.min_prefix_bytes();
+        let pos = self.0.partition_point(|(k, _)| k.as_bytes() < min_bytes);
+        let mut range = self.0[pos..].iter().take_while(|(k, _)| prefix.matches(k));
+        match (range.next(), range.next()) {
+            (None, _) => PrefixResolution::NoMatch,
+            (Some((_, values)), None) => PrefixResolution::SingleMatch(values),
+            (Some(_), Some(_)) => PrefixResolution::AmbiguousMatch,
+        }
+    }
+
+    /// This function returns the shortest length of a prefix of `key` that
+    /// disambiguates it from every other key in the index.
+    ///
+    /// See `shortest_unique_prefix_len_in_sorted()` for details.
+    pub fn shortest_unique_prefix_len(&self, key: &K) -> usize {
+        shortest_unique_prefix_len_by(&self.0, key, |(k, _)| k)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::backend::{ChangeId, ObjectId};
+    use crate::backend::{ChangeId, CommitId, ObjectId};
 
     #[test]
     fn test_id_index_resolve_prefix() {
-        fn sorted(resolution: PrefixResolution<Vec<i32>>) -> PrefixResolution<Vec<i32>> {
-            match resolution {
-                PrefixResolution::SingleMatch(mut xs) => {
-                    xs.sort(); // order of values might not be preserved by IdIndex
-                    PrefixResolution::SingleMatch(xs)
-                }
-                _ => resolution,
-            }
-        }
         let id_index = IdIndex::from_vec(vec![
             (ChangeId::from_hex("0000"), 0),
             (ChangeId::from_hex("0099"), 1),
@@ -281,7 +599,7 @@ mod tests {
             PrefixResolution::NoMatch,
         );
         assert_eq!(
-            sorted(id_index.resolve_prefix(&HexPrefix::new("009").unwrap())),
+            id_index.resolve_prefix(&HexPrefix::new("009").unwrap()),
             PrefixResolution::SingleMatch(vec![1, 2]),
         );
         assert_eq!(
@@ -298,6 +616,142 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_id_index_resolve_prefix_stops_at_ambiguity() {
+        let id_index: IdIndex<_, _> = (0..1000)
+            .map(|i| (ChangeId::from_hex(&format!("00{i:04x}")), i))
+            .collect();
+        let mut mapped_count = 0;
+        let resolution = id_index.resolve_prefix_with(&HexPrefix::new("0").unwrap(), |v| {
+            mapped_count += 1;
+            *v
+        });
+        assert_eq!(resolution, PrefixResolution::AmbiguousMatch);
+        // Only the first entry was visited before the second key proved the
+        // prefix ambiguous
+        assert_eq!(mapped_count, 1);
+    }
+
+    #[test]
+    fn test_id_index_preserves_value_order() {
+        let id_index = IdIndex::from_vec(vec![
+            (ChangeId::from_hex("0099"), 3),
+            (ChangeId::from_hex("0aaa"), 0),
+            (ChangeId::from_hex("0099"), 1),
+            (ChangeId::from_hex("0000"), 4),
+            (ChangeId::from_hex("0099"), 2),
+        ]);
+        assert_eq!(
+            id_index.resolve_prefix(&HexPrefix::new("009").unwrap()),
+            PrefixResolution::SingleMatch(vec![3, 1, 2]),
+        );
+    }
+
+    #[test]
+    fn test_id_index_from_iter() {
+        let entries = vec![
+            (ChangeId::from_hex("0aab"), 4),
+            (ChangeId::from_hex("0099"), 1),
+            (ChangeId::from_hex("0000"), 0),
+            (ChangeId::from_hex("0aaa"), 3),
+            (ChangeId::from_hex("0099"), 2),
+        ];
+        let collected: IdIndex<_, _> = entries.iter().cloned().collect();
+        let from_vec = IdIndex::from_vec(entries);
+        assert_eq!(collected.0, from_vec.0);
+    }
+
+    #[test]
+    fn test_id_index_len() {
+        let id_index = IdIndex::from_vec(vec![] as Vec<(ChangeId, ())>);
+        assert!(id_index.is_empty());
+        assert_eq!(id_index.len(), 0);
+        assert_eq!(id_index.distinct_key_count(), 0);
+
+        let id_index = IdIndex::from_vec(vec![
+            (ChangeId::from_hex("ab"), ()),
+            (ChangeId::from_hex("acd0"), ()),
+            (ChangeId::from_hex("acd0"), ()), // duplicated key is allowed
+        ]);
+        assert!(!id_index.is_empty());
+        assert_eq!(id_index.len(), 3);
+        assert_eq!(id_index.distinct_key_count(), 2);
+    }
+
+    #[test]
+    fn test_id_index_complete_prefix() {
+        let id_index = IdIndex::from_vec(vec![
+            (ChangeId::from_hex("0aab"), ()),
+            (ChangeId::from_hex("0099"), ()),
+            (ChangeId::from_hex("0000"), ()),
+            (ChangeId::from_hex("0aaa"), ()),
+            (ChangeId::from_hex("0099"), ()),
+            (ChangeId::from_hex("1000"), ()),
+        ]);
+        let complete =
+            |prefix: &str, limit| id_index.complete_prefix(&HexPrefix::new(prefix).unwrap(), limit);
+        assert_eq!(
+            complete("0", 10),
+            vec![
+                ChangeId::from_hex("0000"),
+                ChangeId::from_hex("0099"),
+                ChangeId::from_hex("0aaa"),
+                ChangeId::from_hex("0aab"),
+            ]
+        );
+        assert_eq!(
+            complete("0", 2),
+            vec![ChangeId::from_hex("0000"), ChangeId::from_hex("0099")]
+        );
+        assert_eq!(complete("0a", 1), vec![ChangeId::from_hex("0aaa")]);
+        assert_eq!(complete("0aab", 10), vec![ChangeId::from_hex("0aab")]);
+        assert_eq!(complete("2", 10), vec![]);
+        assert_eq!(complete("0", 0), vec![]);
+    }
+
+    #[test]
+    fn test_has_prefix() {
+        // No crash if empty
+        let id_index = IdIndex::from_vec(vec![] as Vec<(ChangeId, ())>);
+        assert!(!id_index.has_prefix(&HexPrefix::new("").unwrap()));
+        assert!(!id_index.has_prefix(&HexPrefix::new("0").unwrap()));
+
+        let id_index = IdIndex::from_vec(vec![
+            (ChangeId::from_hex("ab"), ()),
+            (ChangeId::from_hex("acd0"), ()),
+        ]);
+        assert!(id_index.has_prefix(&HexPrefix::new("a").unwrap()));
+        assert!(id_index.has_prefix(&HexPrefix::new("acd").unwrap()));
+        assert!(id_index.has_prefix(&HexPrefix::new("acd0").unwrap()));
+        assert!(!id_index.has_prefix(&HexPrefix::new("aa").unwrap()));
+        assert!(!id_index.has_prefix(&HexPrefix::new("acd00").unwrap()));
+        assert!(!id_index.has_prefix(&HexPrefix::new("b").unwrap()));
+        // The empty prefix matches any key, unlike in resolve_prefix()
+        assert!(id_index.has_prefix(&HexPrefix::new("").unwrap()));
+    }
+
+    #[test]
+    fn test_id_index_keys_with_multiple_values() {
+        let id_index = IdIndex::from_vec(vec![] as Vec<(ChangeId, ())>);
+        assert_eq!(id_index.keys_with_multiple_values(), vec![]);
+
+        let id_index = IdIndex::from_vec(vec![
+            (ChangeId::from_hex("0aaa"), 0),
+            (ChangeId::from_hex("0099"), 1),
+            (ChangeId::from_hex("0000"), 2),
+            (ChangeId::from_hex("0aaa"), 3),
+            (ChangeId::from_hex("0099"), 4),
+            (ChangeId::from_hex("0099"), 5),
+        ]);
+        assert_eq!(
+            id_index.keys_with_multiple_values(),
+            vec![
+                (ChangeId::from_hex("0099"), vec![1, 4, 5]),
+                (ChangeId::from_hex("0aaa"), vec![0, 3]),
+            ]
+        );
+    }
+
     #[test]
     fn test_has_key() {
         // No crash if empty
@@ -363,4 +817,207 @@ mod tests {
             1
         );
     }
+
+    #[test]
+    fn test_id_index_nearest() {
+        let id = |hex| ChangeId::from_hex(hex);
+        // No crash if empty
+        let id_index = IdIndex::from_vec(vec![] as Vec<(ChangeId, ())>);
+        assert_eq!(id_index.nearest(&id("00")), (None, None));
+
+        let id_index = IdIndex::from_vec(vec![
+            (id("20"), ()),
+            (id("40"), ()),
+            (id("40"), ()), // duplicated key is allowed
+            (id("60"), ()),
+        ]);
+        // Between two entries
+        assert_eq!(
+            id_index.nearest(&id("30")),
+            (Some(&id("20")), Some(&id("40")))
+        );
+        assert_eq!(
+            id_index.nearest(&id("50")),
+            (Some(&id("40")), Some(&id("60")))
+        );
+        // A key that exists isn't its own neighbor
+        assert_eq!(
+            id_index.nearest(&id("40")),
+            (Some(&id("20")), Some(&id("60")))
+        );
+        // At the ends
+        assert_eq!(id_index.nearest(&id("10")), (None, Some(&id("20"))));
+        assert_eq!(id_index.nearest(&id("20")), (None, Some(&id("40"))));
+        assert_eq!(id_index.nearest(&id("70")), (Some(&id("60")), None));
+    }
+
+    #[test]
+    fn test_id_index_prefix_len_affected_by_insert() {
+        let id = |hex| ChangeId::from_hex(hex);
+        let affected = |id_index: &IdIndex<ChangeId, ()>, hex| {
+            let new_key = id(hex);
+            let affected = id_index.prefix_len_affected_by_insert(&new_key);
+            // Check against recomputing the whole index
+            let mut entries = id_index.0.clone();
+            entries.push((new_key.clone(), ()));
+            let new_index = IdIndex::from_vec(entries);
+            let mut expected = id_index
+                .0
+                .iter()
+                .map(|(k, _)| k)
+                .dedup()
+                .filter(|k| {
+                    **k != new_key
+                        && new_index.shortest_unique_prefix_len(k)
+                            != id_index.shortest_unique_prefix_len(k)
+                })
+                .cloned()
+                .collect_vec();
+            expected.sort();
+            assert_eq!(affected, expected, "{hex}");
+            affected
+        };
+
+        // No crash if empty
+        let id_index = IdIndex::from_vec(vec![] as Vec<(ChangeId, ())>);
+        assert_eq!(affected(&id_index, "00"), vec![]);
+
+        let id_index = IdIndex::from_vec(vec![
+            (id("1234"), ()),
+            (id("5678"), ()),
+            (id("5678"), ()), // duplicated key is allowed
+            (id("9abc"), ()),
+        ]);
+        // Shares more digits with both neighbors than they need today
+        assert_eq!(affected(&id_index, "5600"), vec![id("5678")]);
+        assert_eq!(affected(&id_index, "1299"), vec![id("1234")]);
+        assert_eq!(affected(&id_index, "9000"), vec![id("9abc")]);
+        // Shares no more digits than the neighbors already need
+        assert_eq!(affected(&id_index, "3000"), vec![]);
+        assert_eq!(affected(&id_index, "f000"), vec![]);
+        // Already in the index
+        assert_eq!(affected(&id_index, "5678"), vec![]);
+
+        // At most one neighbor is affected, since the neighbors already need
+        // more digits than they have in common with each other
+        let id_index = IdIndex::from_vec(vec![(id("1200"), ()), (id("1300"), ())]);
+        assert_eq!(affected(&id_index, "1250"), vec![id("1200")]);
+        let id_index = IdIndex::from_vec(vec![(id("1200"), ()), (id("1220"), ())]);
+        assert_eq!(affected(&id_index, "1210"), vec![]);
+        let id_index = IdIndex::from_vec(vec![(id("1200"), ()), (id("1240"), ())]);
+        assert_eq!(affected(&id_index, "1201"), vec![id("1200")]);
+        let id_index = IdIndex::from_vec(vec![(id("1000"), ()), (id("1fff"), ())]);
+        assert_eq!(affected(&id_index, "10f0"), vec![id("1000")]);
+        let id_index = IdIndex::from_vec(vec![(id("1000"), ()), (id("2000"), ())]);
+        assert_eq!(affected(&id_index, "1500"), vec![id("1000")]);
+        let id_index = IdIndex::from_vec(vec![(id("1a00"), ()), (id("1c00"), ())]);
+        assert_eq!(affected(&id_index, "1b00"), vec![]);
+        let id_index = IdIndex::from_vec(vec![(id("1000"), ()), (id("3000"), ())]);
+        assert_eq!(affected(&id_index, "2000"), vec![]);
+    }
+
+    #[test]
+    fn test_grouped_id_index() {
+        // No crash if empty
+        let id_index = GroupedIdIndex::from_vec(vec![] as Vec<(ChangeId, CommitId)>);
+        assert!(id_index.is_empty());
+        assert_eq!(
+            id_index.resolve_prefix(&HexPrefix::new("0").unwrap()),
+            PrefixResolution::NoMatch,
+        );
+
+        // Change 0099 is divergent, so it maps to several commits
+        let commit_id = |hex: &str| CommitId::from_hex(hex);
+        let id_index = GroupedIdIndex::from_vec(vec![
+            (ChangeId::from_hex("0099"), commit_id("03")),
+            (ChangeId::from_hex("0aaa"), commit_id("00")),
+            (ChangeId::from_hex("0099"), commit_id("01")),
+            (ChangeId::from_hex("0000"), commit_id("04")),
+            (ChangeId::from_hex("0099"), commit_id("02")),
+        ]);
+        assert!(!id_index.is_empty());
+        assert_eq!(id_index.len(), 3);
+        let divergent_commit_ids = [commit_id("03"), commit_id("01"), commit_id("02")];
+        assert_eq!(
+            id_index.get(&ChangeId::from_hex("0099")),
+            Some(divergent_commit_ids.as_slice())
+        );
+        assert_eq!(
+            id_index.get(&ChangeId::from_hex("0aaa")),
+            Some([commit_id("00")].as_slice())
+        );
+        assert_eq!(id_index.get(&ChangeId::from_hex("0aab")), None);
+
+        // Prefixes resolve the same way as in IdIndex
+        let resolve = |prefix: &str| id_index.resolve_prefix(&HexPrefix::new(prefix).unwrap());
+        assert_eq!(resolve(""), PrefixResolution::AmbiguousMatch);
+        assert_eq!(resolve("0"), PrefixResolution::AmbiguousMatch);
+        assert_eq!(
+            resolve("009"),
+            PrefixResolution::SingleMatch(divergent_commit_ids.as_slice()),
+        );
+        assert_eq!(
+            resolve("0a"),
+            PrefixResolution::SingleMatch([commit_id("00")].as_slice()),
+        );
+        assert_eq!(resolve("0001"), PrefixResolution::NoMatch);
+        assert_eq!(resolve("f"), PrefixResolution::NoMatch);
+
+        // Duplicated keys don't count as neighbors of each other
+        assert_eq!(
+            id_index.shortest_unique_prefix_len(&ChangeId::from_hex("0099")),
+            3
+        );
+        assert_eq!(
+            id_index.shortest_unique_prefix_len(&ChangeId::from_hex("0aaa")),
+            2
+        );
+    }
+
+    #[test]
+    fn test_shortest_unique_prefix_len_in_sorted() {
+        let shortest_len = |sorted: &[ChangeId], hex: &str| {
+            shortest_unique_prefix_len_in_sorted(sorted, &ChangeId::from_hex(hex))
+        };
+
+        // No crash if empty
+        assert_eq!(shortest_len(&[], "00"), 1);
+
+        let sorted = [
+            ChangeId::from_hex("ab"),
+            ChangeId::from_hex("acd0"),
+            ChangeId::from_hex("acd0"), // duplicated key is allowed
+        ];
+        assert_eq!(shortest_len(&sorted, "acd0"), 2);
+        assert_eq!(shortest_len(&sorted, "ac"), 3);
+
+        let sorted = [
+            ChangeId::from_hex("a0"),
+            ChangeId::from_hex("ab"),
+            ChangeId::from_hex("acd0"),
+            ChangeId::from_hex("acf0"),
+            ChangeId::from_hex("ba"),
+        ];
+        assert_eq!(shortest_len(&sorted, "a0"), 2);
+        assert_eq!(shortest_len(&sorted, "ba"), 1);
+        assert_eq!(shortest_len(&sorted, "ab"), 2);
+        assert_eq!(shortest_len(&sorted, "acd0"), 3);
+        // If it were there, the length would be 1.
+        assert_eq!(shortest_len(&sorted, "c0"), 1);
+    }
+
+    #[test]
+    fn test_shortest_prefix_among() {
+        let id = |hex| CommitId::from_hex(hex);
+        // No crash if empty
+        assert_eq!(shortest_prefix_among(&[], &id("00")), 1);
+
+        // Unsorted, with the target itself among the candidates
+        let ids = [id("acd0"), id("ab"), id("ba"), id("acf0")];
+        assert_eq!(shortest_prefix_among(&ids, &id("acd0")), 3);
+        assert_eq!(shortest_prefix_among(&ids, &id("ab")), 2);
+        assert_eq!(shortest_prefix_among(&ids, &id("ba")), 1);
+        // IDs outside the candidate set don't matter
+        assert_eq!(shortest_prefix_among(&[id("ab"), id("ba")], &id("acd0")), 2);
+    }
 }
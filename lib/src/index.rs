@@ -99,6 +99,9 @@ pub struct HexPrefix {
 }
 
 impl HexPrefix {
+    /// Returns a new `HexPrefix` or `None` if `prefix` cannot be decoded from
+    /// hex. Both lowercase and uppercase digits are accepted, so a prefix
+    /// matches the same IDs regardless of its case.
     pub fn new(prefix: &str) -> Option<HexPrefix> {
         let has_odd_byte = prefix.len() & 1 != 0;
         let min_prefix_bytes = if has_odd_byte {
@@ -165,6 +168,26 @@ pub enum PrefixResolution<T> {
     AmbiguousMatch,
 }
 
+impl<T> PrefixResolution<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> PrefixResolution<U> {
+        match self {
+            PrefixResolution::NoMatch => PrefixResolution::NoMatch,
+            PrefixResolution::SingleMatch(x) => PrefixResolution::SingleMatch(f(x)),
+            PrefixResolution::AmbiguousMatch => PrefixResolution::AmbiguousMatch,
+        }
+    }
+
+    /// Returns this resolution if it matched anything, even ambiguously, and
+    /// otherwise falls back to the resolution returned by `f`. This is useful
+    /// for trying a narrower index before a wider one.
+    pub fn or_else(self, f: impl FnOnce() -> PrefixResolution<T>) -> PrefixResolution<T> {
+        match self {
+            PrefixResolution::NoMatch => f(),
+            resolution => resolution,
+        }
+    }
+}
+
 impl<T: Clone> PrefixResolution<T> {
     pub fn plus(&self, other: &PrefixResolution<T>) -> PrefixResolution<T> {
         match (self, other) {
@@ -214,4 +237,57 @@ mod tests {
         assert!(!HexPrefix::new("12a").unwrap().matches(&id));
         assert!(!HexPrefix::new("123a").unwrap().matches(&id));
     }
+
+    #[test]
+    fn test_hex_prefix_case_insensitive() {
+        let id = CommitId::from_hex("abcd");
+
+        assert_eq!(HexPrefix::new("ABCD"), HexPrefix::new("abcd"));
+        assert_eq!(HexPrefix::new("aBc"), HexPrefix::new("abc"));
+        assert!(HexPrefix::new("ABCD").unwrap().matches(&id));
+        assert!(HexPrefix::new("aBc").unwrap().matches(&id));
+        // The prefix is displayed in the canonical form
+        assert_eq!(HexPrefix::new("ABC").unwrap().hex(), "abc");
+        assert_eq!(HexPrefix::new("G"), None);
+    }
+
+    #[test]
+    fn test_prefix_resolution_map() {
+        let double = |x: i32| x * 2;
+        assert_eq!(
+            PrefixResolution::NoMatch.map(double),
+            PrefixResolution::NoMatch
+        );
+        assert_eq!(
+            PrefixResolution::SingleMatch(1).map(double),
+            PrefixResolution::SingleMatch(2)
+        );
+        assert_eq!(
+            PrefixResolution::AmbiguousMatch.map(double),
+            PrefixResolution::AmbiguousMatch
+        );
+    }
+
+    #[test]
+    fn test_prefix_resolution_or_else() {
+        use PrefixResolution::*;
+        let resolutions = [NoMatch, SingleMatch(1), AmbiguousMatch];
+        for first in &resolutions {
+            for second in &resolutions {
+                let mut called = false;
+                let resolution = first.clone().or_else(|| {
+                    called = true;
+                    second.clone()
+                });
+                // Only NoMatch falls through to the second resolution
+                if *first == NoMatch {
+                    assert!(called);
+                    assert_eq!(resolution, *second);
+                } else {
+                    assert!(!called);
+                    assert_eq!(resolution, *first);
+                }
+            }
+        }
+    }
 }
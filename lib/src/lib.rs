@@ -52,6 +52,7 @@ pub mod simple_op_heads_store;
 pub mod simple_op_store;
 pub mod stacked_table;
 pub mod store;
+pub mod thread_util;
 pub mod transaction;
 pub mod tree;
 pub mod tree_builder;
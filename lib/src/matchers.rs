@@ -712,4 +712,55 @@ mod tests {
             Visit::AllRecursively
         );
     }
+
+    #[test]
+    fn test_intersectionmatcher_everything_and_nothing() {
+        let prefix = PrefixMatcher::new(&[RepoPath::from_internal_string("foo")]);
+
+        // Intersecting with everything is the same as the other matcher
+        let m = IntersectionMatcher::new(&EverythingMatcher, &prefix);
+        for path in ["foo", "foo/bar", "bar"] {
+            let path = RepoPath::from_internal_string(path);
+            assert_eq!(m.matches(&path), prefix.matches(&path));
+            assert_eq!(m.visit(&path), prefix.visit(&path));
+        }
+        assert_eq!(m.visit(&RepoPath::root()), prefix.visit(&RepoPath::root()));
+
+        // Intersecting with nothing is nothing
+        let m = IntersectionMatcher::new(&prefix, &NothingMatcher);
+        assert!(!m.matches(&RepoPath::from_internal_string("foo")));
+        assert!(!m.matches(&RepoPath::from_internal_string("foo/bar")));
+        assert_eq!(m.visit(&RepoPath::root()), Visit::Nothing);
+        assert_eq!(
+            m.visit(&RepoPath::from_internal_string("foo")),
+            Visit::Nothing
+        );
+    }
+
+    #[test]
+    fn test_intersectionmatcher_files_and_prefix() {
+        let files = FilesMatcher::new(&[
+            RepoPath::from_internal_string("foo/a"),
+            RepoPath::from_internal_string("bar/b"),
+        ]);
+        let prefix = PrefixMatcher::new(&[RepoPath::from_internal_string("foo")]);
+        let m = IntersectionMatcher::new(&files, &prefix);
+
+        assert!(m.matches(&RepoPath::from_internal_string("foo/a")));
+        assert!(!m.matches(&RepoPath::from_internal_string("foo/c")));
+        assert!(!m.matches(&RepoPath::from_internal_string("bar/b")));
+
+        assert_eq!(
+            m.visit(&RepoPath::root()),
+            Visit::sets(hashset! {RepoPathComponent::from("foo")}, hashset! {})
+        );
+        assert_eq!(
+            m.visit(&RepoPath::from_internal_string("foo")),
+            Visit::sets(hashset! {}, hashset! {RepoPathComponent::from("a")})
+        );
+        assert_eq!(
+            m.visit(&RepoPath::from_internal_string("bar")),
+            Visit::Nothing
+        );
+    }
 }
@@ -2031,6 +2031,13 @@ pub trait Revset<'index>: fmt::Debug {
 
     fn iter_graph(&self) -> Box<dyn Iterator<Item = (CommitId, Vec<RevsetGraphEdge>)> + '_>;
 
+    /// Iterate in the same order as `iter()`, also yielding each commit's
+    /// change ID. Returns `None` if the change IDs can't be looked up without
+    /// reading the commit objects.
+    fn iter_with_change_ids(&self) -> Option<Box<dyn Iterator<Item = (CommitId, ChangeId)> + '_>> {
+        None
+    }
+
     fn change_id_index(&self) -> Box<dyn ChangeIdIndex + 'index>;
 
     fn is_empty(&self) -> bool;
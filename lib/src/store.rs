@@ -109,6 +109,10 @@ impl Store {
     }
 
     fn get_backend_tree(&self, dir: &RepoPath, id: &TreeId) -> BackendResult<Arc<backend::Tree>> {
+        if id == self.empty_tree_id() {
+            // Common when diffing against an empty tree. No need to ask the backend.
+            return Ok(Arc::new(backend::Tree::default()));
+        }
         let key = (dir.clone(), id.clone());
         {
             let read_locked_cache = self.tree_cache.read().unwrap();
@@ -122,6 +126,39 @@ impl Store {
         Ok(data)
     }
 
+    /// Like `get_tree()`, but reads the tree with `Backend::read_tree_async()`.
+    #[cfg(feature = "async")]
+    pub async fn get_tree_async(
+        self: &Arc<Self>,
+        dir: &RepoPath,
+        id: &TreeId,
+    ) -> BackendResult<Tree> {
+        let data = self.get_backend_tree_async(dir, id).await?;
+        Ok(Tree::new(self.clone(), dir.clone(), id.clone(), data))
+    }
+
+    #[cfg(feature = "async")]
+    async fn get_backend_tree_async(
+        &self,
+        dir: &RepoPath,
+        id: &TreeId,
+    ) -> BackendResult<Arc<backend::Tree>> {
+        if id == self.empty_tree_id() {
+            return Ok(Arc::new(backend::Tree::default()));
+        }
+        let key = (dir.clone(), id.clone());
+        {
+            let read_locked_cache = self.tree_cache.read().unwrap();
+            if let Some(data) = read_locked_cache.get(&key).cloned() {
+                return Ok(data);
+            }
+        }
+        let data = Arc::new(self.backend.read_tree_async(dir, id).await?);
+        let mut write_locked_cache = self.tree_cache.write().unwrap();
+        write_locked_cache.insert(key, data.clone());
+        Ok(data)
+    }
+
     pub fn write_tree(&self, path: &RepoPath, contents: &backend::Tree) -> BackendResult<TreeId> {
         // TODO: This should also do caching like write_commit does.
         self.backend.write_tree(path, contents)
@@ -131,6 +168,10 @@ impl Store {
         self.backend.read_file(path, id)
     }
 
+    pub fn file_size(&self, path: &RepoPath, id: &FileId) -> BackendResult<u64> {
+        self.backend.file_size(path, id)
+    }
+
     pub fn write_file(&self, path: &RepoPath, contents: &mut dyn Read) -> BackendResult<FileId> {
         self.backend.write_file(path, contents)
     }
@@ -0,0 +1,69 @@
+// Copyright 2023 The Jujutsu Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp::max;
+use std::{panic, thread};
+
+use itertools::Itertools;
+
+/// Splits `items` into one chunk per available thread and calls `f` on the
+/// chunks in parallel. The results are returned in the order of the chunks.
+/// If `f` panics on any chunk, the panic is resumed on the calling thread.
+pub fn map_chunks_parallel<T, R>(items: &[T], f: impl Fn(&[T]) -> R + Sync) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+{
+    let num_threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = max(1, (items.len() + num_threads - 1) / num_threads);
+    let f = &f;
+    thread::scope(|scope| {
+        let handles = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || f(chunk)))
+            .collect_vec();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|payload| panic::resume_unwind(payload))
+            })
+            .collect_vec()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_chunks_parallel() {
+        let items = (0..100).collect_vec();
+        let sums = map_chunks_parallel(&items, |chunk| chunk.iter().sum::<i32>());
+        assert_eq!(sums.iter().sum::<i32>(), 4950);
+        assert!(map_chunks_parallel(&[] as &[i32], |chunk| chunk.len()).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "bad item")]
+    fn test_map_chunks_parallel_panic() {
+        let items = (0..100).collect_vec();
+        map_chunks_parallel(&items, |chunk| {
+            if chunk.contains(&42) {
+                panic!("bad item");
+            }
+        });
+    }
+}
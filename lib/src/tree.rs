@@ -12,35 +12,88 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::cmp::Ordering;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::fmt::{Debug, Error, Formatter};
+#[cfg(feature = "async")]
+use std::future::Future;
 use std::io::Read;
 use std::iter::Peekable;
+use std::ops::ControlFlow;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "async")]
+use std::task::{ready, Context, Poll};
 
+#[cfg(feature = "async")]
+use futures_core::Stream;
 use itertools::Itertools;
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
 use thiserror::Error;
 
 use crate::backend::{
-    BackendError, Conflict, ConflictId, ConflictTerm, FileId, ObjectId,
+    BackendError, CommitId, Conflict, ConflictId, ConflictTerm, FileId, ObjectId,
     TreeEntriesNonRecursiveIterator, TreeEntry, TreeId, TreeValue,
 };
-use crate::files::MergeResult;
-use crate::matchers::{EverythingMatcher, Matcher};
+use crate::commit::Commit;
+use crate::conflicts::{
+    remove_canceling_terms, resolve_conflict_side, simplify_conflict, ConflictSide,
+};
+use crate::diff::DiffHunk;
+use crate::files::{MergeResult, MergeStats};
+use crate::matchers::{EverythingMatcher, Matcher, PrefixMatcher, Visit};
 use crate::repo_path::{RepoPath, RepoPathComponent, RepoPathJoin};
 use crate::store::Store;
-use crate::{backend, files};
+use crate::thread_util::map_chunks_parallel;
+use crate::{backend, diff, files};
 
 #[derive(Debug, Error)]
 pub enum TreeMergeError {
-    #[error("Failed to read file with ID {} ", .file_id.hex())]
+    #[error("Failed to read file {} with ID {}", .path.to_internal_file_string(), .file_id.hex())]
     ReadError {
         source: std::io::Error,
+        path: RepoPath,
         file_id: FileId,
     },
     #[error("Backend error: {0}")]
     BackendError(#[from] BackendError),
+    #[error("External merge driver failed: {0}")]
+    ExternalMergeDriverError(#[from] ExternalMergeDriverError),
+}
+
+/// A failure to run an `ExternalMergeDriver`. A driver that runs but can't
+/// merge the file isn't an error.
+#[derive(Debug, Error)]
+pub enum ExternalMergeDriverError {
+    #[error("Failed to set up the files for the merge driver")]
+    SetUpError(#[source] std::io::Error),
+    #[error("Failed to run the merge driver {program}")]
+    FailedToExecute {
+        program: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Failed to read the output of the merge driver")]
+    ReadOutputError(#[source] std::io::Error),
+}
+
+/// A problem found by `Tree::verify()`.
+#[derive(Debug, Error)]
+pub enum TreeVerifyError {
+    #[error("Failed to read tree {} at {}", .id.hex(), .path.to_internal_dir_string())]
+    UnreadableTree {
+        path: RepoPath,
+        id: TreeId,
+        source: Box<BackendError>,
+    },
+    #[error("Failed to read conflict {} at {}", .id.hex(), .path.to_internal_file_string())]
+    UnreadableConflict {
+        path: RepoPath,
+        id: ConflictId,
+        source: Box<BackendError>,
+    },
 }
 
 #[derive(Clone)]
@@ -60,7 +113,7 @@ impl Debug for Tree {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
 pub struct DiffSummary {
     pub modified: Vec<RepoPath>,
     pub added: Vec<RepoPath>,
@@ -71,6 +124,86 @@ impl DiffSummary {
     pub fn is_empty(&self) -> bool {
         self.modified.is_empty() && self.added.is_empty() && self.removed.is_empty()
     }
+
+    fn push<T>(&mut self, path: RepoPath, diff: &Diff<T>) {
+        match diff {
+            Diff::Modified(_, _) => self.modified.push(path),
+            Diff::Added(_) => self.added.push(path),
+            Diff::Removed(_) => self.removed.push(path),
+        }
+    }
+
+    fn append(&mut self, other: &mut DiffSummary) {
+        self.modified.append(&mut other.modified);
+        self.added.append(&mut other.added);
+        self.removed.append(&mut other.removed);
+    }
+
+    fn sort(&mut self) {
+        self.modified.sort();
+        self.added.sort();
+        self.removed.sort();
+    }
+}
+
+/// The differences between two trees, grouped by kind, as returned by
+/// `Tree::diff_buckets()`. Unlike `DiffSummary`, the values are kept. Each
+/// group is in the order `Tree::diff()` yields the entries.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct DiffBuckets {
+    modified: Vec<(RepoPath, Diff<TreeValue>)>,
+    added: Vec<(RepoPath, Diff<TreeValue>)>,
+    removed: Vec<(RepoPath, Diff<TreeValue>)>,
+}
+
+impl DiffBuckets {
+    pub fn modified(&self) -> &[(RepoPath, Diff<TreeValue>)] {
+        &self.modified
+    }
+
+    pub fn added(&self) -> &[(RepoPath, Diff<TreeValue>)] {
+        &self.added
+    }
+
+    pub fn removed(&self) -> &[(RepoPath, Diff<TreeValue>)] {
+        &self.removed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modified.is_empty() && self.added.is_empty() && self.removed.is_empty()
+    }
+
+    fn push(&mut self, path: RepoPath, diff: Diff<TreeValue>) {
+        let bucket = match diff {
+            Diff::Modified(_, _) => &mut self.modified,
+            Diff::Added(_) => &mut self.added,
+            Diff::Removed(_) => &mut self.removed,
+        };
+        bucket.push((path, diff));
+    }
+}
+
+/// The number of changed paths of each kind below a directory, as returned by
+/// `Tree::dir_change_counts()`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct DiffCounts {
+    pub modified: usize,
+    pub added: usize,
+    pub removed: usize,
+}
+
+impl DiffCounts {
+    pub fn total(&self) -> usize {
+        self.modified + self.added + self.removed
+    }
+
+    fn count(&mut self, diff: &Diff<TreeValue>) {
+        match diff {
+            Diff::Modified(_, _) => self.modified += 1,
+            Diff::Added(_) => self.added += 1,
+            Diff::Removed(_) => self.removed += 1,
+        }
+    }
 }
 
 impl Tree {
@@ -108,19 +241,251 @@ impl Tree {
         &self.data
     }
 
+    /// Returns true if the trees have the same content. Since the tree ID is a
+    /// hash of the content, this only compares the IDs. Panics if the trees
+    /// don't belong to the same store and directory, since their IDs aren't
+    /// comparable then.
+    pub fn content_eq(&self, other: &Tree) -> bool {
+        assert!(Arc::ptr_eq(&self.store, &other.store));
+        assert_eq!(self.dir, other.dir);
+        self.id == other.id
+    }
+
     pub fn entries_non_recursive(&self) -> TreeEntriesNonRecursiveIterator {
         self.data.entries()
     }
 
     pub fn entries(&self) -> TreeEntriesIterator<'static> {
-        TreeEntriesIterator::new(self.clone(), &EverythingMatcher)
+        TreeEntriesIterator::new(self.clone(), &EverythingMatcher, false)
     }
 
     pub fn entries_matching<'matcher>(
         &self,
         matcher: &'matcher dyn Matcher,
     ) -> TreeEntriesIterator<'matcher> {
-        TreeEntriesIterator::new(self.clone(), matcher)
+        TreeEntriesIterator::new(self.clone(), matcher, false)
+    }
+
+    /// Like `entries_matching()`, but also yields each visited subtree as a
+    /// `TreeValue::Tree` entry before the entries in it.
+    pub fn entries_matching_with_dirs<'matcher>(
+        &self,
+        matcher: &'matcher dyn Matcher,
+    ) -> TreeEntriesIterator<'matcher> {
+        TreeEntriesIterator::new(self.clone(), matcher, true)
+    }
+
+    /// Like `entries_matching()`, but returns a stream that reads the subtrees
+    /// with `Store::get_tree_async()` instead of blocking on them.
+    #[cfg(feature = "async")]
+    pub fn entries_matching_async<'matcher>(
+        &self,
+        matcher: &'matcher dyn Matcher,
+    ) -> TreeEntriesStream<'matcher> {
+        TreeEntriesStream::new(self, matcher)
+    }
+
+    /// Returns the number of entries `entries_matching()` would yield, without
+    /// cloning the values.
+    pub fn count_entries_matching(&self, matcher: &dyn Matcher) -> Result<usize, BackendError> {
+        let mut count = 0;
+        self.visit_entries_matching(matcher, &mut |_path, _value| count += 1)?;
+        Ok(count)
+    }
+
+    /// Returns the path, ID, and executable bit of each file
+    /// `entries_matching()` would yield. Symlinks, conflicts, and
+    /// submodules are skipped without cloning them. The matching subtrees
+    /// are all read before returning.
+    pub fn file_entries_matching(
+        &self,
+        matcher: &dyn Matcher,
+    ) -> Result<Vec<(RepoPath, FileId, bool)>, BackendError> {
+        let mut files = vec![];
+        self.visit_entries_matching(matcher, &mut |path, value| {
+            if let TreeValue::File { id, executable } = value {
+                files.push((path, id.clone(), *executable));
+            }
+        })?;
+        Ok(files)
+    }
+
+    /// Returns the size in bytes of each file `file_entries_matching()` would
+    /// yield, in the same order. The sizes are looked up without reading the
+    /// files if the backend supports that.
+    pub fn file_sizes(&self, matcher: &dyn Matcher) -> Result<Vec<(RepoPath, u64)>, BackendError> {
+        self.file_entries_matching(matcher)?
+            .into_iter()
+            .map(|(path, id, _executable)| {
+                let size = self.store.file_size(&path, &id)?;
+                Ok((path, size))
+            })
+            .collect()
+    }
+
+    /// Calls `f` with each entry `entries_matching()` would yield, in the same
+    /// order. Unlike that iterator, returns an error instead of panicking if a
+    /// subtree can't be read.
+    fn visit_entries_matching(
+        &self,
+        matcher: &dyn Matcher,
+        f: &mut impl FnMut(RepoPath, &TreeValue),
+    ) -> Result<(), BackendError> {
+        for entry in self.entries_non_recursive() {
+            let path = self.dir.join(entry.name());
+            match entry.value() {
+                TreeValue::Tree(id) => {
+                    // Don't read the subtree if the matcher can't match anything in it
+                    if matcher.visit(&path).is_nothing() {
+                        continue;
+                    }
+                    let subtree = self.known_sub_tree(entry.name(), id)?;
+                    subtree.visit_entries_matching(matcher, f)?;
+                }
+                value => {
+                    if matcher.matches(&path) {
+                        f(path, value);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a copy of this tree where each file is replaced by the result of
+    /// `f`, which is called with the file's path, ID, and executable bit. If
+    /// `f` returns `None`, the file is removed, and so are any directories
+    /// that become empty. Other values are kept as is. Returns the ID of the
+    /// new tree.
+    pub fn map_files(
+        &self,
+        mut f: impl FnMut(&RepoPath, &FileId, bool) -> Option<(FileId, bool)>,
+    ) -> Result<TreeId, BackendError> {
+        self.map_files_impl(&mut f)
+    }
+
+    fn map_files_impl(
+        &self,
+        f: &mut impl FnMut(&RepoPath, &FileId, bool) -> Option<(FileId, bool)>,
+    ) -> Result<TreeId, BackendError> {
+        let mut new_tree = backend::Tree::default();
+        for entry in self.entries_non_recursive() {
+            let path = self.dir.join(entry.name());
+            match entry.value() {
+                TreeValue::File { id, executable } => {
+                    if let Some((id, executable)) = f(&path, id, *executable) {
+                        new_tree.set(entry.name().clone(), TreeValue::File { id, executable });
+                    }
+                }
+                TreeValue::Tree(id) => {
+                    let subtree = self.store.get_tree(&path, id)?;
+                    let new_subtree_id = subtree.map_files_impl(f)?;
+                    if new_subtree_id != *self.store.empty_tree_id() {
+                        new_tree.set(entry.name().clone(), TreeValue::Tree(new_subtree_id));
+                    }
+                }
+                other => new_tree.set(entry.name().clone(), other.clone()),
+            }
+        }
+        self.store.write_tree(&self.dir, &new_tree)
+    }
+
+    /// Writes a copy of this tree with only the entries the matcher matches,
+    /// and returns its ID. Directories that become empty are removed.
+    /// Subtrees that the matcher matches entirely are kept as is without
+    /// being read.
+    pub fn filtered_tree_id(&self, matcher: &dyn Matcher) -> Result<TreeId, BackendError> {
+        match matcher.visit(&self.dir) {
+            Visit::AllRecursively => Ok(self.id.clone()),
+            Visit::Nothing => Ok(self.store.empty_tree_id().clone()),
+            Visit::Specific { .. } => self.filtered_tree_id_impl(matcher),
+        }
+    }
+
+    fn filtered_tree_id_impl(&self, matcher: &dyn Matcher) -> Result<TreeId, BackendError> {
+        let mut new_tree = backend::Tree::default();
+        for entry in self.entries_non_recursive() {
+            let path = self.dir.join(entry.name());
+            match entry.value() {
+                TreeValue::Tree(id) => {
+                    let new_subtree_id = match matcher.visit(&path) {
+                        Visit::AllRecursively => id.clone(),
+                        Visit::Nothing => continue,
+                        Visit::Specific { .. } => {
+                            let subtree = self.store.get_tree(&path, id)?;
+                            subtree.filtered_tree_id_impl(matcher)?
+                        }
+                    };
+                    if new_subtree_id != *self.store.empty_tree_id() {
+                        new_tree.set(entry.name().clone(), TreeValue::Tree(new_subtree_id));
+                    }
+                }
+                value => {
+                    if matcher.matches(&path) {
+                        new_tree.set(entry.name().clone(), value.clone());
+                    }
+                }
+            }
+        }
+        self.store.write_tree(&self.dir, &new_tree)
+    }
+
+    /// Returns the IDs of this tree and all subtrees that the matcher may match
+    /// anything in. File contents are not read.
+    pub fn reachable_tree_ids(
+        &self,
+        matcher: &dyn Matcher,
+    ) -> Result<BTreeSet<TreeId>, BackendError> {
+        let mut tree_ids = BTreeSet::new();
+        let _ = self.visit_reachable_trees(matcher, |id, _depth| {
+            tree_ids.insert(id.clone());
+            ControlFlow::Continue(())
+        })?;
+        Ok(tree_ids)
+    }
+
+    /// Calls `f` with the ID and depth of this tree and of each subtree that
+    /// the matcher may match anything in, the first time each ID is found. This
+    /// tree has depth 0. Stops early if `f` returns `ControlFlow::Break`, in
+    /// which case that's also what's returned. File contents are not read.
+    pub fn visit_reachable_trees(
+        &self,
+        matcher: &dyn Matcher,
+        mut f: impl FnMut(&TreeId, usize) -> ControlFlow<()>,
+    ) -> Result<ControlFlow<()>, BackendError> {
+        let mut seen = BTreeSet::new();
+        self.visit_reachable_trees_impl(matcher, 0, &mut seen, &mut f)
+    }
+
+    fn visit_reachable_trees_impl(
+        &self,
+        matcher: &dyn Matcher,
+        depth: usize,
+        seen: &mut BTreeSet<TreeId>,
+        f: &mut impl FnMut(&TreeId, usize) -> ControlFlow<()>,
+    ) -> Result<ControlFlow<()>, BackendError> {
+        if seen.insert(self.id.clone()) && f(&self.id, depth).is_break() {
+            return Ok(ControlFlow::Break(()));
+        }
+        for entry in self.entries_non_recursive() {
+            if let TreeValue::Tree(id) = entry.value() {
+                let subdir = self.dir.join(entry.name());
+                // Don't read the subtree if the matcher can't match anything in it
+                if matcher.visit(&subdir).is_nothing() {
+                    continue;
+                }
+                // A subtree that was already seen at another path is still
+                // visited, since the matcher may match different paths in it.
+                let subtree = self.known_sub_tree(entry.name(), id)?;
+                if subtree
+                    .visit_reachable_trees_impl(matcher, depth + 1, seen, f)?
+                    .is_break()
+                {
+                    return Ok(ControlFlow::Break(()));
+                }
+            }
+        }
+        Ok(ControlFlow::Continue(()))
     }
 
     pub fn entry(&self, basename: &RepoPathComponent) -> Option<TreeEntry> {
@@ -131,51 +496,72 @@ impl Tree {
         self.data.value(basename)
     }
 
-    pub fn path_value(&self, path: &RepoPath) -> Option<TreeValue> {
+    /// Returns the value at `path`, or an error if a tree on the way to it
+    /// can't be read.
+    pub fn path_value(&self, path: &RepoPath) -> Result<Option<TreeValue>, BackendError> {
+        assert_eq!(self.dir(), &RepoPath::root());
+        match path.split() {
+            Some((dir, basename)) => Ok(self
+                .sub_tree_recursive(dir.components())?
+                .and_then(|tree| tree.data.value(basename).cloned())),
+            None => Ok(Some(TreeValue::Tree(self.id.clone()))),
+        }
+    }
+
+    /// Returns true if there's an entry at `path`, without cloning its value.
+    /// The root path always exists.
+    pub fn path_exists(&self, path: &RepoPath) -> Result<bool, BackendError> {
         assert_eq!(self.dir(), &RepoPath::root());
         match path.split() {
-            Some((dir, basename)) => self
-                .sub_tree_recursive(dir.components())
-                .and_then(|tree| tree.data.value(basename).cloned()),
-            None => Some(TreeValue::Tree(self.id.clone())),
+            Some((dir, basename)) => Ok(self
+                .sub_tree_recursive(dir.components())?
+                .map_or(false, |tree| tree.data.value(basename).is_some())),
+            None => Ok(true),
         }
     }
 
-    pub fn sub_tree(&self, name: &RepoPathComponent) -> Option<Tree> {
-        self.data.value(name).and_then(|sub_tree| match sub_tree {
-            TreeValue::Tree(sub_tree_id) => {
+    pub fn sub_tree(&self, name: &RepoPathComponent) -> Result<Option<Tree>, BackendError> {
+        match self.data.value(name) {
+            Some(TreeValue::Tree(sub_tree_id)) => {
                 let subdir = self.dir.join(name);
-                Some(self.store.get_tree(&subdir, sub_tree_id).unwrap())
+                Ok(Some(self.store.get_tree(&subdir, sub_tree_id)?))
             }
-            _ => None,
-        })
+            _ => Ok(None),
+        }
     }
 
-    pub fn known_sub_tree(&self, name: &RepoPathComponent, id: &TreeId) -> Tree {
+    pub fn known_sub_tree(
+        &self,
+        name: &RepoPathComponent,
+        id: &TreeId,
+    ) -> Result<Tree, BackendError> {
         let subdir = self.dir.join(name);
-        self.store.get_tree(&subdir, id).unwrap()
+        self.store.get_tree(&subdir, id)
     }
 
-    fn sub_tree_recursive(&self, components: &[RepoPathComponent]) -> Option<Tree> {
+    fn sub_tree_recursive(
+        &self,
+        components: &[RepoPathComponent],
+    ) -> Result<Option<Tree>, BackendError> {
         if components.is_empty() {
             // TODO: It would be nice to be able to return a reference here, but
             // then we would have to figure out how to share Tree instances
             // across threads.
-            Some(Tree {
+            Ok(Some(Tree {
                 store: self.store.clone(),
                 dir: self.dir.clone(),
                 id: self.id.clone(),
                 data: self.data.clone(),
-            })
+            }))
         } else {
             match self.data.entry(&components[0]) {
-                None => None,
+                None => Ok(None),
                 Some(entry) => match entry.value() {
                     TreeValue::Tree(sub_tree_id) => {
-                        let sub_tree = self.known_sub_tree(entry.name(), sub_tree_id);
+                        let sub_tree = self.known_sub_tree(entry.name(), sub_tree_id)?;
                         sub_tree.sub_tree_recursive(&components[1..])
                     }
-                    _ => None,
+                    _ => Ok(None),
                 },
             }
         }
@@ -189,138 +575,1168 @@ impl Tree {
         recursive_tree_diff(self.clone(), other.clone(), matcher)
     }
 
-    pub fn diff_summary(&self, other: &Tree, matcher: &dyn Matcher) -> DiffSummary {
-        let mut modified = vec![];
-        let mut added = vec![];
-        let mut removed = vec![];
-        for (file, diff) in self.diff(other, matcher) {
-            match diff {
-                Diff::Modified(_, _) => modified.push(file.clone()),
-                Diff::Added(_) => added.push(file.clone()),
-                Diff::Removed(_) => removed.push(file.clone()),
-            }
-        }
-        modified.sort();
-        added.sort();
-        removed.sort();
-        DiffSummary {
-            modified,
-            added,
-            removed,
-        }
+    /// Like `diff()`, but checks that both trees are rooted at the same
+    /// directory, which the diff assumes when it joins paths. The walk starts
+    /// at that directory, so the paths include it.
+    pub fn diff_rebased<'matcher>(
+        &self,
+        other: &Tree,
+        matcher: &'matcher dyn Matcher,
+    ) -> TreeDiffIterator<'matcher> {
+        assert_eq!(
+            self.dir, other.dir,
+            "diff_rebased() requires trees rooted at the same directory; use diff_cross_dir() to \
+             diff trees rooted at different directories"
+        );
+        TreeDiffIterator::new(self.dir.clone(), self.clone(), other.clone(), matcher, None)
     }
 
-    pub fn conflicts_matching(&self, matcher: &dyn Matcher) -> Vec<(RepoPath, ConflictId)> {
-        let mut conflicts = vec![];
-        for (name, value) in self.entries_matching(matcher) {
-            if let TreeValue::Conflict(id) = value {
-                conflicts.push((name.clone(), id.clone()));
-            }
-        }
-        conflicts
+    /// Diffs against `other` as if it were rooted at this tree's directory.
+    /// Use this to deliberately compare a subtree with a counterpart from a
+    /// different directory. The paths in the result are relative to the
+    /// repository root as seen from this tree.
+    pub fn diff_cross_dir<'matcher>(
+        &self,
+        other: &Tree,
+        matcher: &'matcher dyn Matcher,
+    ) -> TreeDiffIterator<'matcher> {
+        let rerooted = Tree::new(
+            other.store.clone(),
+            self.dir.clone(),
+            other.id.clone(),
+            other.data.clone(),
+        );
+        self.diff_rebased(&rerooted, matcher)
     }
 
-    pub fn conflicts(&self) -> Vec<(RepoPath, ConflictId)> {
-        self.conflicts_matching(&EverythingMatcher)
+    /// Calls `f` with each difference that `diff()` would yield, in the same
+    /// order. Stops early if `f` returns `ControlFlow::Break`, in which case
+    /// that's also what's returned.
+    pub fn for_each_diff(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+        mut f: impl FnMut(RepoPath, Diff<TreeValue>) -> ControlFlow<()>,
+    ) -> Result<ControlFlow<()>, BackendError> {
+        self.for_each_diff_ref(other, matcher, |path, diff| {
+            f(path.clone(), diff.map(Clone::clone))
+        })
     }
 
-    pub fn has_conflict(&self) -> bool {
-        !self.conflicts().is_empty()
+    /// Like `for_each_diff()`, but passes the path and values by reference
+    /// instead of cloning them for each difference. The references are only
+    /// valid for the duration of the call to `f`.
+    pub fn for_each_diff_ref(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+        mut f: impl FnMut(&RepoPath, Diff<&TreeValue>) -> ControlFlow<()>,
+    ) -> Result<ControlFlow<()>, BackendError> {
+        let options = DiffWalkOptions::default();
+        walk_diff(
+            self,
+            other,
+            matcher,
+            options,
+            &mut |path, entry| match entry {
+                WalkedEntry::Changed(diff) => f(path, diff),
+                WalkedEntry::Unchanged(_) => ControlFlow::Continue(()),
+            },
+        )
     }
-}
 
-pub struct TreeEntriesIterator<'matcher> {
-    entry_iterator: TreeEntriesNonRecursiveIterator<'static>,
-    // On drop, tree must outlive entry_iterator
-    tree: Pin<Box<Tree>>,
-    subdir_iterator: Option<Box<TreeEntriesIterator<'matcher>>>,
-    matcher: &'matcher dyn Matcher,
-}
+    /// Like `diff()`, but also yields each matching entry that is the same in
+    /// both trees, as `EntryComparison::Unchanged`. Subtrees that are the same
+    /// in both trees are read so their entries can be yielded. Every entry in
+    /// either tree is yielded exactly once, except that a path is yielded
+    /// twice if a file was replaced by a directory or vice versa, just like
+    /// `diff()` does.
+    pub fn diff_with_unchanged(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+    ) -> Result<Vec<(RepoPath, EntryComparison)>, BackendError> {
+        let mut entries = vec![];
+        let options = DiffWalkOptions {
+            include_unchanged: true,
+            ..Default::default()
+        };
+        let _ = walk_diff(self, other, matcher, options, &mut |path, entry| {
+            let entry = match entry {
+                WalkedEntry::Changed(diff) => EntryComparison::Changed(diff.map(Clone::clone)),
+                WalkedEntry::Unchanged(value) => EntryComparison::Unchanged(value.clone()),
+            };
+            entries.push((path.clone(), entry));
+            ControlFlow::Continue(())
+        })?;
+        Ok(entries)
+    }
 
-impl<'matcher> TreeEntriesIterator<'matcher> {
-    fn new(tree: Tree, matcher: &'matcher dyn Matcher) -> Self {
-        let tree = Box::pin(tree);
-        // TODO: Restrict walk according to Matcher::visit()
-        let entry_iterator = tree.entries_non_recursive();
-        let entry_iterator: TreeEntriesNonRecursiveIterator<'static> =
-            unsafe { std::mem::transmute(entry_iterator) };
-        Self {
-            entry_iterator,
-            tree,
-            subdir_iterator: None,
-            matcher,
-        }
+    /// Returns the paths `diff()` would yield, in the same order, without
+    /// cloning the values. A path is included twice if a file was replaced by
+    /// a directory or vice versa, just like `diff()` does.
+    pub fn changed_paths(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+    ) -> Result<Vec<RepoPath>, BackendError> {
+        let mut paths = vec![];
+        let _ = self.for_each_diff_ref(other, matcher, |path, _diff| {
+            paths.push(path.clone());
+            ControlFlow::Continue(())
+        })?;
+        Ok(paths)
     }
-}
 
-impl Iterator for TreeEntriesIterator<'_> {
-    type Item = (RepoPath, TreeValue);
+    /// Returns the `Diff::Removed` entries that `diff()` would yield, in the
+    /// same order. Subtrees that only exist in `other` can't contain removals,
+    /// so unlike filtering `diff()`, they aren't read.
+    pub fn diff_removals(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+    ) -> Result<Vec<(RepoPath, Diff<TreeValue>)>, BackendError> {
+        let options = DiffWalkOptions {
+            skip_added_subtrees: true,
+            ..Default::default()
+        };
+        self.collect_diffs(other, matcher, options, |diff| {
+            matches!(diff, Diff::Removed(_))
+        })
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            // First return results from any subdirectory we're currently visiting.
-            if let Some(subdir_iter) = &mut self.subdir_iterator {
-                if let Some(item) = subdir_iter.next() {
-                    return Some(item);
+    /// Returns the `Diff::Added` entries that `diff()` would yield, in the
+    /// same order. Subtrees that only exist in this tree can't contain
+    /// additions, so unlike filtering `diff()`, they aren't read.
+    pub fn diff_additions(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+    ) -> Result<Vec<(RepoPath, Diff<TreeValue>)>, BackendError> {
+        let options = DiffWalkOptions {
+            skip_removed_subtrees: true,
+            ..Default::default()
+        };
+        self.collect_diffs(other, matcher, options, |diff| {
+            matches!(diff, Diff::Added(_))
+        })
+    }
+
+    fn collect_diffs(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+        options: DiffWalkOptions,
+        filter: impl Fn(&Diff<&TreeValue>) -> bool,
+    ) -> Result<Vec<(RepoPath, Diff<TreeValue>)>, BackendError> {
+        let mut diffs = vec![];
+        let _ = walk_diff(self, other, matcher, options, &mut |path, entry| {
+            if let WalkedEntry::Changed(diff) = entry {
+                if filter(&diff) {
+                    diffs.push((path.clone(), diff.map(Clone::clone)));
                 }
-                self.subdir_iterator = None;
             }
-            let entry = self.entry_iterator.next()?;
-            match entry.value() {
-                TreeValue::Tree(id) => {
-                    let subtree = self.tree.known_sub_tree(entry.name(), id);
-                    self.subdir_iterator =
-                        Some(Box::new(TreeEntriesIterator::new(subtree, self.matcher)));
-                }
-                other => {
-                    let path = self.tree.dir().join(entry.name());
-                    if !self.matcher.matches(&path) {
-                        continue;
-                    }
-                    return Some((path, other.clone()));
-                }
-            };
-        }
+            ControlFlow::Continue(())
+        })?;
+        Ok(diffs)
     }
-}
-
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub enum Diff<T> {
-    Modified(T, T),
-    Added(T),
-    Removed(T),
-}
 
-impl<T> Diff<T> {
-    pub fn as_options(&self) -> (Option<&T>, Option<&T>) {
-        match self {
-            Diff::Modified(left, right) => (Some(left), Some(right)),
-            Diff::Added(right) => (None, Some(right)),
-            Diff::Removed(left) => (Some(left), None),
-        }
+    /// Returns the paths that have a value in this tree but not in `other`,
+    /// regardless of what the values are, in sorted order. Only files,
+    /// symlinks, conflicts, and submodules count as paths, not directories.
+    /// So a file that `other` replaced by a directory of the same name is
+    /// included, and so are the files in a directory that `other` replaced
+    /// by a file. These are the paths of `diff_removals()`.
+    pub fn paths_only_in(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+    ) -> Result<Vec<RepoPath>, BackendError> {
+        Ok(self
+            .diff_removals(other, matcher)?
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect())
     }
 
-    pub fn into_options(self) -> (Option<T>, Option<T>) {
-        match self {
-            Diff::Modified(left, right) => (Some(left), Some(right)),
-            Diff::Added(right) => (None, Some(right)),
-            Diff::Removed(left) => (Some(left), None),
-        }
+    /// Returns the first `limit` differences that `diff()` would yield, and
+    /// whether there are more. The diff is only computed until one difference
+    /// past the limit is found.
+    pub fn diff_bounded(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+        limit: usize,
+    ) -> Result<BoundedDiff, BackendError> {
+        let mut diff = self.diff(other, matcher);
+        let diffs: Vec<_> = diff.by_ref().take(limit).try_collect()?;
+        let truncated = diff.next().transpose()?.is_some();
+        Ok((diffs, truncated))
     }
-}
 
-struct TreeEntryDiffIterator<'trees> {
-    it1: Peekable<TreeEntriesNonRecursiveIterator<'trees>>,
-    it2: Peekable<TreeEntriesNonRecursiveIterator<'trees>>,
-}
-
-impl<'trees> TreeEntryDiffIterator<'trees> {
-    fn new(tree1: &'trees Tree, tree2: &'trees Tree) -> Self {
-        let it1 = tree1.entries_non_recursive().peekable();
-        let it2 = tree2.entries_non_recursive().peekable();
-        TreeEntryDiffIterator { it1, it2 }
+    /// Like `diff()`, but also tells whether either side of each difference is
+    /// a file larger than `max_bytes`, so the caller can skip reading its
+    /// content. The sizes are looked up without reading the files if the
+    /// backend supports that.
+    pub fn diff_with_size_limit<'matcher>(
+        &self,
+        other: &Tree,
+        matcher: &'matcher dyn Matcher,
+        max_bytes: u64,
+    ) -> impl Iterator<Item = Result<SizeLimitedDiff, BackendError>> + 'matcher {
+        let store = self.store.clone();
+        self.diff(other, matcher).map(move |item| {
+            let (path, diff) = item?;
+            let (before, after) = diff.as_options();
+            let mut too_large = false;
+            for value in itertools::chain(before, after) {
+                if let TreeValue::File { id, .. } = value {
+                    if store.file_size(&path, id)? > max_bytes {
+                        too_large = true;
+                        break;
+                    }
+                }
+            }
+            Ok(SizeLimitedDiff {
+                path,
+                diff,
+                too_large,
+            })
+        })
     }
-}
+
+    /// Pairs files removed in the diff to `other` with added files that have
+    /// the same ID, i.e. the same content. No file contents are read. Each
+    /// removed file is paired at most once, with the first added file in path
+    /// order. The pairs are `(from, to, id)`, sorted by their new path.
+    pub fn content_moves(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+    ) -> Result<Vec<(RepoPath, RepoPath, FileId)>, BackendError> {
+        let (removed, added) = self.removed_and_added_files(other, matcher)?;
+        let (moves, _, _) = pair_by_file_id(removed, added);
+        Ok(moves)
+    }
+
+    /// Returns the files removed and the files added in the diff to `other`.
+    #[allow(clippy::type_complexity)]
+    fn removed_and_added_files(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+    ) -> Result<(Vec<(RepoPath, FileId)>, Vec<(RepoPath, FileId)>), BackendError> {
+        let mut removed = vec![];
+        let mut added = vec![];
+        let _ = self.for_each_diff_ref(other, matcher, |path, diff| {
+            match diff {
+                Diff::Removed(TreeValue::File { id, .. }) => {
+                    removed.push((path.clone(), id.clone()))
+                }
+                Diff::Added(TreeValue::File { id, .. }) => added.push((path.clone(), id.clone())),
+                _ => {}
+            }
+            ControlFlow::Continue(())
+        })?;
+        Ok((removed, added))
+    }
+
+    /// Pairs files removed in the diff to `other` with files added in it, so
+    /// that a moved file can be shown as a single rename. Files with the same
+    /// ID are paired first, without reading their contents. Each remaining
+    /// added file is then paired with the most similar remaining removed file,
+    /// if that one is at least `min_similarity` percent similar. The renames
+    /// are sorted by their new path.
+    pub fn detect_renames(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+        min_similarity: u32,
+    ) -> Result<Vec<Rename>, BackendError> {
+        let (removed, added) = self.removed_and_added_files(other, matcher)?;
+        let (moves, removed, unpaired_added) = pair_by_file_id(removed, added);
+        let mut renames = moves
+            .into_iter()
+            .map(|(from, to, _)| Rename {
+                from,
+                to,
+                modified: false,
+                similarity: 100,
+            })
+            .collect_vec();
+
+        if !unpaired_added.is_empty() && !removed.is_empty() {
+            let mut candidates = vec![];
+            for (from, id) in removed {
+                let contents = read_file_contents(&self.store, &from, &id)?;
+                candidates.push((from, contents));
+            }
+            for (to, id) in unpaired_added {
+                let contents = read_file_contents(&self.store, &to, &id)?;
+                // On ties, the first candidate in path order wins
+                let best = candidates
+                    .iter()
+                    .map(|(_, from_contents)| content_similarity(from_contents, &contents))
+                    .enumerate()
+                    .filter(|(_, similarity)| *similarity >= min_similarity)
+                    .min_by_key(|(_, similarity)| Reverse(*similarity));
+                if let Some((index, similarity)) = best {
+                    let (from, _) = candidates.remove(index);
+                    renames.push(Rename {
+                        from,
+                        to,
+                        modified: true,
+                        similarity,
+                    });
+                }
+            }
+        }
+        renames.sort_by(|a, b| a.to.cmp(&b.to));
+        Ok(renames)
+    }
+
+    /// Like `diff()`, but a conflict on either side is expanded into one diff
+    /// against each of the conflict's adds. The conflicts are read as the
+    /// diffs are yielded.
+    pub fn diff_expanding_conflicts<'matcher>(
+        &self,
+        other: &Tree,
+        matcher: &'matcher dyn Matcher,
+    ) -> impl Iterator<Item = Result<ExpandedDiff, BackendError>> + 'matcher {
+        let store = self.store.clone();
+        self.diff(other, matcher).flat_map(move |item| {
+            match item.and_then(|(path, diff)| expand_diff_conflicts(&store, path, diff)) {
+                Ok(diffs) => diffs.into_iter().map(Ok).collect_vec(),
+                Err(err) => vec![Err(err)],
+            }
+        })
+    }
+
+    /// Like `diff()`, but a file whose executable bit changed while its
+    /// contents stayed the same is considered unchanged. Useful on platforms
+    /// where the executable bit isn't tracked reliably.
+    pub fn diff_ignoring_exec_bit<'matcher>(
+        &self,
+        other: &Tree,
+        matcher: &'matcher dyn Matcher,
+    ) -> impl Iterator<Item = Result<(RepoPath, Diff<TreeValue>), BackendError>> + 'matcher {
+        self.diff(other, matcher).filter(|item| {
+            !matches!(
+                item,
+                Ok((_, Diff::Modified(
+                    TreeValue::File { id: before, .. },
+                    TreeValue::File { id: after, .. },
+                ))) if before == after
+            )
+        })
+    }
+
+    /// Like `diff()`, but a conflict on either side is first replaced by the
+    /// file it resolves to, if its contents can be merged automatically.
+    /// Entries that are the same after that are skipped, so only logical
+    /// differences are yielded. Conflicts that can't be resolved are compared
+    /// by ID as usual. A resolved file is compared by content, so nothing is
+    /// written to the store.
+    pub fn diff_resolving_conflicts<'matcher>(
+        &self,
+        other: &Tree,
+        matcher: &'matcher dyn Matcher,
+    ) -> impl Iterator<Item = Result<(RepoPath, Diff<ResolvedValue>), TreeMergeError>> + 'matcher
+    {
+        let store = self.store.clone();
+        self.diff(other, matcher).filter_map(move |item| {
+            let (path, diff) = match item {
+                Ok(item) => item,
+                Err(err) => return Some(Err(err.into())),
+            };
+            match resolve_diff_conflicts(&store, &path, diff) {
+                Ok(Some(diff)) => Some(Ok((path, diff))),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            }
+        })
+    }
+
+    /// Returns true if `diff()` would yield anything. Stops reading trees at
+    /// the first difference.
+    pub fn any_diff(&self, other: &Tree, matcher: &dyn Matcher) -> Result<bool, BackendError> {
+        Ok(self.diff(other, matcher).next().transpose()?.is_some())
+    }
+
+    /// Like `diff()`, but doesn't descend into subtrees more than `max_depth`
+    /// levels down. A changed subtree at that depth is yielded as a single
+    /// `TreeValue::Tree` entry instead. With `max_depth` 0, only the entries
+    /// directly in this tree are compared.
+    pub fn diff_to_depth<'matcher>(
+        &self,
+        other: &Tree,
+        matcher: &'matcher dyn Matcher,
+        max_depth: usize,
+    ) -> TreeDiffIterator<'matcher> {
+        TreeDiffIterator::new(
+            RepoPath::root(),
+            self.clone(),
+            other.clone(),
+            matcher,
+            Some(max_depth),
+        )
+    }
+
+    pub fn diff_summary(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+    ) -> Result<DiffSummary, BackendError> {
+        let mut summary = DiffSummary::default();
+        for item in self.diff(other, matcher) {
+            let (file, diff) = item?;
+            summary.push(file, &diff);
+        }
+        summary.sort();
+        Ok(summary)
+    }
+
+    /// Like `diff_summary()`, but keeps the values of each difference.
+    pub fn diff_buckets(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+    ) -> Result<DiffBuckets, BackendError> {
+        let mut buckets = DiffBuckets::default();
+        for item in self.diff(other, matcher) {
+            let (path, diff) = item?;
+            buckets.push(path, diff);
+        }
+        Ok(buckets)
+    }
+
+    /// Like `diff_summary()`, but diffs the changed top-level subtrees on
+    /// separate threads. The result is the same as `diff_summary()`'s.
+    pub fn diff_summary_parallel(
+        &self,
+        other: &Tree,
+        matcher: &(dyn Matcher + Sync),
+    ) -> Result<DiffSummary, BackendError> {
+        let mut summary = DiffSummary::default();
+        if matcher.visit(&self.dir).is_nothing() {
+            return Ok(summary);
+        }
+        let mut subtrees = vec![];
+        for (name, before, after) in diff_entries(self, other) {
+            let file_path = self.dir.join(name);
+            let tree_before = matches!(before, Some(TreeValue::Tree(_)));
+            let tree_after = matches!(after, Some(TreeValue::Tree(_)));
+            if (tree_before || tree_after) && !matcher.visit(&file_path).is_nothing() {
+                let subtree1 = sub_tree_or_null(self, name, before)?;
+                let subtree2 = sub_tree_or_null(other, name, after)?;
+                subtrees.push((subtree1, subtree2));
+            }
+            if matcher.matches(&file_path) {
+                // The subtree sides were handled above, so only files are
+                // reported here.
+                let before = before.filter(|value| !matches!(value, TreeValue::Tree(_)));
+                let after = after.filter(|value| !matches!(value, TreeValue::Tree(_)));
+                match (before, after) {
+                    (Some(_), Some(_)) => summary.modified.push(file_path),
+                    (None, Some(_)) => summary.added.push(file_path),
+                    (Some(_), None) => summary.removed.push(file_path),
+                    (None, None) => {}
+                }
+            }
+        }
+
+        let chunk_summaries = map_chunks_parallel(&subtrees, |chunk| {
+            let mut summary = DiffSummary::default();
+            for (subtree1, subtree2) in chunk {
+                let options = DiffWalkOptions::default();
+                let _ = walk_diff(subtree1, subtree2, matcher, options, &mut |path, entry| {
+                    if let WalkedEntry::Changed(diff) = entry {
+                        summary.push(path.clone(), &diff);
+                    }
+                    ControlFlow::Continue(())
+                })?;
+            }
+            Ok::<_, BackendError>(summary)
+        });
+        for chunk_summary in chunk_summaries {
+            summary.append(&mut chunk_summary?);
+        }
+        summary.sort();
+        Ok(summary)
+    }
+
+    /// Counts the changed paths below each directory that contains any, for
+    /// showing a collapsed view of the diff. Each changed path is counted in
+    /// all of its ancestor directories up to and including this tree's
+    /// directory.
+    pub fn dir_change_counts(
+        &self,
+        other: &Tree,
+        matcher: &dyn Matcher,
+    ) -> Result<BTreeMap<RepoPath, DiffCounts>, BackendError> {
+        let mut counts: BTreeMap<RepoPath, DiffCounts> = BTreeMap::new();
+        let diff =
+            TreeDiffIterator::new(self.dir.clone(), self.clone(), other.clone(), matcher, None);
+        for item in diff {
+            let (path, diff) = item?;
+            let mut dir = path.parent();
+            while let Some(parent) = dir {
+                counts.entry(parent.clone()).or_default().count(&diff);
+                if parent == self.dir {
+                    break;
+                }
+                dir = parent.parent();
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Counts the conflicts that the matcher matches below each directory that
+    /// contains any, for showing where conflicts cluster. Each conflict is
+    /// counted in all of its ancestor directories up to and including this
+    /// tree's directory.
+    pub fn conflict_counts(
+        &self,
+        matcher: &dyn Matcher,
+    ) -> Result<BTreeMap<RepoPath, usize>, BackendError> {
+        let mut counts: BTreeMap<RepoPath, usize> = BTreeMap::new();
+        for (path, _) in self.conflicts_matching(matcher)? {
+            let mut dir = path.parent();
+            while let Some(parent) = dir {
+                *counts.entry(parent.clone()).or_default() += 1;
+                if parent == self.dir {
+                    break;
+                }
+                dir = parent.parent();
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Returns the paths of the directories that are the same subtree in both
+    /// trees, without listing the directories below them. This is the
+    /// opposite of a diff, which skips such subtrees. If the trees themselves
+    /// are the same, only this tree's directory is returned.
+    pub fn shared_subtree_paths(&self, other: &Tree) -> Result<Vec<RepoPath>, BackendError> {
+        let mut paths = vec![];
+        if self.id == other.id {
+            paths.push(self.dir.clone());
+        } else {
+            shared_subtree_paths_impl(self, other, &mut paths)?;
+        }
+        Ok(paths)
+    }
+
+    pub fn conflicts_matching(
+        &self,
+        matcher: &dyn Matcher,
+    ) -> Result<Vec<(RepoPath, ConflictId)>, BackendError> {
+        let mut conflicts = vec![];
+        self.visit_entries_matching(matcher, &mut |path, value| {
+            if let TreeValue::Conflict(id) = value {
+                conflicts.push((path, id.clone()));
+            }
+        })?;
+        Ok(conflicts)
+    }
+
+    /// Writes a copy of this tree where each conflict that the matcher
+    /// matches and that can be resolved by merging file contents is replaced
+    /// by the merged file. Other conflicts are kept as is. Returns the ID of
+    /// the new tree and the number of conflicts that were resolved.
+    pub fn auto_resolve_conflicts(
+        &self,
+        matcher: &dyn Matcher,
+    ) -> Result<(TreeId, usize), TreeMergeError> {
+        let mut num_resolved = 0;
+        let tree_id = self.auto_resolve_conflicts_impl(matcher, &mut num_resolved)?;
+        Ok((tree_id, num_resolved))
+    }
+
+    fn auto_resolve_conflicts_impl(
+        &self,
+        matcher: &dyn Matcher,
+        num_resolved: &mut usize,
+    ) -> Result<TreeId, TreeMergeError> {
+        let mut new_tree = backend::Tree::default();
+        let num_resolved_before = *num_resolved;
+        for entry in self.entries_non_recursive() {
+            let path = self.dir.join(entry.name());
+            let new_value = match entry.value() {
+                TreeValue::Tree(id) if !matcher.visit(&path).is_nothing() => {
+                    let subtree = self.known_sub_tree(entry.name(), id)?;
+                    TreeValue::Tree(subtree.auto_resolve_conflicts_impl(matcher, num_resolved)?)
+                }
+                TreeValue::Conflict(id) if matcher.matches(&path) => {
+                    let conflict = self.store.read_conflict(&path, id)?;
+                    match try_resolve_file_conflict(
+                        &self.store,
+                        &path,
+                        &conflict,
+                        ExecBitPolicy::default(),
+                        DeleteHandling::default(),
+                        None,
+                    )? {
+                        Some(resolved) => {
+                            let id = self
+                                .store
+                                .write_file(&path, &mut resolved.content.as_slice())?;
+                            *num_resolved += 1;
+                            TreeValue::File {
+                                id,
+                                executable: resolved.executable,
+                            }
+                        }
+                        None => TreeValue::Conflict(id.clone()),
+                    }
+                }
+                other => other.clone(),
+            };
+            new_tree.set(entry.name().clone(), new_value);
+        }
+        if *num_resolved == num_resolved_before {
+            // Nothing changed, so there's no need to write the tree
+            return Ok(self.id.clone());
+        }
+        Ok(self.store.write_tree(&self.dir, &new_tree)?)
+    }
+
+    pub fn conflicts(&self) -> Result<Vec<(RepoPath, ConflictId)>, BackendError> {
+        self.conflicts_matching(&EverythingMatcher)
+    }
+
+    pub fn has_conflict(&self) -> Result<bool, BackendError> {
+        Ok(!self.conflicts()?.is_empty())
+    }
+
+    /// Checks that the subtrees and conflicts that this tree refers to can be
+    /// read, visiting only the subtrees and conflicts that the matcher
+    /// matches. Returns the first problem found, in the order of
+    /// `entries()`.
+    ///
+    /// Entry order and duplicate names aren't checked, since the entries are
+    /// kept in a sorted map once a tree has been read. File and symlink
+    /// contents aren't read either.
+    pub fn verify(&self, matcher: &dyn Matcher) -> Result<(), TreeVerifyError> {
+        if matcher.visit(&self.dir).is_nothing() {
+            return Ok(());
+        }
+        for entry in self.data.entries() {
+            let path = self.dir.join(entry.name());
+            match entry.value() {
+                TreeValue::Tree(id) => {
+                    if matcher.visit(&path).is_nothing() {
+                        continue;
+                    }
+                    let subtree = self.store.get_tree(&path, id).map_err(|source| {
+                        TreeVerifyError::UnreadableTree {
+                            path: path.clone(),
+                            id: id.clone(),
+                            source: Box::new(source),
+                        }
+                    })?;
+                    subtree.verify(matcher)?;
+                }
+                TreeValue::Conflict(id) if matcher.matches(&path) => {
+                    self.store.read_conflict(&path, id).map_err(|source| {
+                        TreeVerifyError::UnreadableConflict {
+                            path: path.clone(),
+                            id: id.clone(),
+                            source: Box::new(source),
+                        }
+                    })?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the IDs of the conflicts that the matcher matches, and of any
+    /// conflicts nested in their terms. A term that is a tree contributes the
+    /// conflicts in that tree. Each conflict is read once.
+    pub fn reachable_conflict_ids(
+        &self,
+        matcher: &dyn Matcher,
+    ) -> Result<BTreeSet<ConflictId>, BackendError> {
+        let mut ids = BTreeSet::new();
+        self.visit_reachable_conflicts(matcher, |_path, id, _conflict| {
+            ids.insert(id.clone());
+            Ok(())
+        })?;
+        Ok(ids)
+    }
+
+    /// Returns the IDs of the files that the matcher matches, and of the file
+    /// terms of the conflicts that `reachable_conflict_ids()` would return. A
+    /// conflict term that is a tree contributes the files in that tree. File
+    /// contents are not read.
+    pub fn reachable_file_ids(
+        &self,
+        matcher: &dyn Matcher,
+    ) -> Result<BTreeSet<FileId>, BackendError> {
+        let mut ids = BTreeSet::new();
+        insert_file_ids(self, matcher, &mut ids)?;
+        self.insert_conflict_file_ids(self.conflicts_matching(matcher)?, matcher, &mut ids)?;
+        Ok(ids)
+    }
+
+    /// Like `reachable_file_ids()`, but walks the top-level subtrees on
+    /// separate threads. The result is the same as `reachable_file_ids()`'s.
+    pub fn reachable_file_ids_parallel(
+        &self,
+        matcher: &(dyn Matcher + Sync),
+    ) -> Result<BTreeSet<FileId>, BackendError> {
+        let mut ids = BTreeSet::new();
+        if matcher.visit(&self.dir).is_nothing() {
+            return Ok(ids);
+        }
+        let mut subtrees = vec![];
+        let mut conflicts = vec![];
+        for entry in self.entries_non_recursive() {
+            let path = self.dir.join(entry.name());
+            match entry.value() {
+                TreeValue::Tree(id) if !matcher.visit(&path).is_nothing() => {
+                    subtrees.push(self.known_sub_tree(entry.name(), id)?);
+                }
+                TreeValue::File { id, .. } if matcher.matches(&path) => {
+                    ids.insert(id.clone());
+                }
+                TreeValue::Conflict(id) if matcher.matches(&path) => {
+                    conflicts.push((path, id.clone()));
+                }
+                _ => {}
+            }
+        }
+        self.insert_conflict_file_ids(conflicts, matcher, &mut ids)?;
+
+        let chunk_ids = map_chunks_parallel(&subtrees, |chunk| {
+            let mut ids = BTreeSet::new();
+            for subtree in chunk {
+                ids.append(&mut subtree.reachable_file_ids(matcher)?);
+            }
+            Ok::<_, BackendError>(ids)
+        });
+        for chunk_ids in chunk_ids {
+            ids.append(&mut chunk_ids?);
+        }
+        Ok(ids)
+    }
+
+    /// Inserts the IDs of the file terms of the given conflicts and of the
+    /// conflicts reachable from them, like `reachable_file_ids()` does.
+    fn insert_conflict_file_ids(
+        &self,
+        conflicts: Vec<(RepoPath, ConflictId)>,
+        matcher: &dyn Matcher,
+        ids: &mut BTreeSet<FileId>,
+    ) -> Result<(), BackendError> {
+        self.visit_conflicts_from(conflicts, matcher, |path, _id, conflict| {
+            for term in conflict.removes.iter().chain(&conflict.adds) {
+                match &term.value {
+                    TreeValue::File { id, .. } => {
+                        ids.insert(id.clone());
+                    }
+                    TreeValue::Tree(tree_id) => {
+                        let tree = self.store.get_tree(path, tree_id)?;
+                        insert_file_ids(&tree, matcher, ids)?;
+                    }
+                    _ => {}
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Calls `f` once for each conflict that `reachable_conflict_ids()` would
+    /// return, with the path it was found at.
+    fn visit_reachable_conflicts(
+        &self,
+        matcher: &dyn Matcher,
+        f: impl FnMut(&RepoPath, &ConflictId, &Conflict) -> Result<(), BackendError>,
+    ) -> Result<(), BackendError> {
+        self.visit_conflicts_from(self.conflicts_matching(matcher)?, matcher, f)
+    }
+
+    /// Calls `f` once for each of the given conflicts and each conflict
+    /// reachable from them through nested conflicts and tree terms.
+    fn visit_conflicts_from(
+        &self,
+        mut pending: Vec<(RepoPath, ConflictId)>,
+        matcher: &dyn Matcher,
+        mut f: impl FnMut(&RepoPath, &ConflictId, &Conflict) -> Result<(), BackendError>,
+    ) -> Result<(), BackendError> {
+        let mut seen = BTreeSet::new();
+        while let Some((path, id)) = pending.pop() {
+            if !seen.insert(id.clone()) {
+                continue;
+            }
+            let conflict = self.store.read_conflict(&path, &id)?;
+            f(&path, &id, &conflict)?;
+            for term in conflict.removes.iter().chain(&conflict.adds) {
+                match &term.value {
+                    TreeValue::Conflict(nested_id) => {
+                        pending.push((path.clone(), nested_id.clone()));
+                    }
+                    TreeValue::Tree(tree_id) => {
+                        let tree = self.store.get_tree(&path, tree_id)?;
+                        pending.extend(tree.conflicts_matching(matcher)?);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn insert_file_ids(
+    tree: &Tree,
+    matcher: &dyn Matcher,
+    ids: &mut BTreeSet<FileId>,
+) -> Result<(), BackendError> {
+    for (_path, id, _executable) in tree.file_entries_matching(matcher)? {
+        ids.insert(id);
+    }
+    Ok(())
+}
+
+/// Yields an error for each subtree that can't be read, and skips the
+/// entries below it.
+pub struct TreeEntriesIterator<'matcher> {
+    entry_iterator: TreeEntriesNonRecursiveIterator<'static>,
+    // On drop, tree must outlive entry_iterator
+    tree: Pin<Box<Tree>>,
+    subdir_iterator: Option<Box<TreeEntriesIterator<'matcher>>>,
+    matcher: &'matcher dyn Matcher,
+    include_dirs: bool,
+}
+
+impl<'matcher> TreeEntriesIterator<'matcher> {
+    fn new(tree: Tree, matcher: &'matcher dyn Matcher, include_dirs: bool) -> Self {
+        let tree = Box::pin(tree);
+        let entry_iterator = tree.entries_non_recursive();
+        let entry_iterator: TreeEntriesNonRecursiveIterator<'static> =
+            unsafe { std::mem::transmute(entry_iterator) };
+        Self {
+            entry_iterator,
+            tree,
+            subdir_iterator: None,
+            matcher,
+            include_dirs,
+        }
+    }
+}
+
+impl Iterator for TreeEntriesIterator<'_> {
+    type Item = Result<(RepoPath, TreeValue), BackendError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // First return results from any subdirectory we're currently visiting.
+            if let Some(subdir_iter) = &mut self.subdir_iterator {
+                if let Some(item) = subdir_iter.next() {
+                    return Some(item);
+                }
+                self.subdir_iterator = None;
+            }
+            let entry = self.entry_iterator.next()?;
+            match entry.value() {
+                TreeValue::Tree(id) => {
+                    // Don't read the subtree if the matcher can't match anything in it
+                    let subdir = self.tree.dir().join(entry.name());
+                    if self.matcher.visit(&subdir).is_nothing() {
+                        continue;
+                    }
+                    // The entries below an unreadable subtree are skipped
+                    let subtree = match self.tree.known_sub_tree(entry.name(), id) {
+                        Ok(subtree) => subtree,
+                        Err(err) => return Some(Err(err)),
+                    };
+                    self.subdir_iterator = Some(Box::new(TreeEntriesIterator::new(
+                        subtree,
+                        self.matcher,
+                        self.include_dirs,
+                    )));
+                    if self.include_dirs {
+                        return Some(Ok((subdir, TreeValue::Tree(id.clone()))));
+                    }
+                }
+                other => {
+                    let path = self.tree.dir().join(entry.name());
+                    if !self.matcher.matches(&path) {
+                        continue;
+                    }
+                    return Some(Ok((path, other.clone())));
+                }
+            };
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+type SubtreeFuture = Pin<Box<dyn Future<Output = Result<Tree, BackendError>> + Send>>;
+
+/// Async version of `TreeEntriesIterator`. Yields the entries in the same
+/// order, and likewise yields an error for each subtree that can't be read
+/// and skips the entries below it.
+#[cfg(feature = "async")]
+pub struct TreeEntriesStream<'matcher> {
+    store: Arc<Store>,
+    // The unvisited entries of each directory from the root to the current one
+    stack: Vec<(RepoPath, std::vec::IntoIter<(RepoPathComponent, TreeValue)>)>,
+    pending_subtree: Option<SubtreeFuture>,
+    matcher: &'matcher dyn Matcher,
+}
+
+#[cfg(feature = "async")]
+impl<'matcher> TreeEntriesStream<'matcher> {
+    fn new(tree: &Tree, matcher: &'matcher dyn Matcher) -> Self {
+        let mut stream = TreeEntriesStream {
+            store: tree.store().clone(),
+            stack: vec![],
+            pending_subtree: None,
+            matcher,
+        };
+        stream.push_tree(tree);
+        stream
+    }
+
+    fn push_tree(&mut self, tree: &Tree) {
+        let entries = tree
+            .entries_non_recursive()
+            .map(|entry| (entry.name().clone(), entry.value().clone()))
+            .collect_vec();
+        self.stack.push((tree.dir().clone(), entries.into_iter()));
+    }
+}
+
+#[cfg(feature = "async")]
+impl Stream for TreeEntriesStream<'_> {
+    type Item = Result<(RepoPath, TreeValue), BackendError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(future) = &mut this.pending_subtree {
+                let result = ready!(future.as_mut().poll(cx));
+                this.pending_subtree = None;
+                match result {
+                    Ok(subtree) => this.push_tree(&subtree),
+                    // The entries below an unreadable subtree are skipped
+                    Err(err) => return Poll::Ready(Some(Err(err))),
+                }
+            }
+            let (dir, entries) = match this.stack.last_mut() {
+                Some(frame) => frame,
+                None => return Poll::Ready(None),
+            };
+            let (name, value) = match entries.next() {
+                Some(entry) => entry,
+                None => {
+                    this.stack.pop();
+                    continue;
+                }
+            };
+            let path = dir.join(&name);
+            match value {
+                TreeValue::Tree(id) => {
+                    // Don't read the subtree if the matcher can't match anything in it
+                    if this.matcher.visit(&path).is_nothing() {
+                        continue;
+                    }
+                    let store = this.store.clone();
+                    this.pending_subtree =
+                        Some(Box::pin(
+                            async move { store.get_tree_async(&path, &id).await },
+                        ));
+                }
+                other => {
+                    if this.matcher.matches(&path) {
+                        return Poll::Ready(Some(Ok((path, other))));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An item yielded by `Tree::diff_expanding_conflicts()`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ExpandedDiff {
+    pub path: RepoPath,
+    pub diff: Diff<TreeValue>,
+    /// If the value before was a conflict, the index of the add in it that
+    /// `diff` starts from.
+    pub before_term: Option<usize>,
+    /// If the value after is a conflict, the index of the add in it that
+    /// `diff` ends at.
+    pub after_term: Option<usize>,
+}
+
+/// An entry returned by `Tree::diff_with_unchanged()`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum EntryComparison {
+    Changed(Diff<TreeValue>),
+    Unchanged(TreeValue),
+}
+
+/// A side of a diff yielded by `Tree::diff_resolving_conflicts()`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ResolvedValue {
+    /// The value as stored. A conflict is kept as is if it can't be resolved
+    /// automatically.
+    Value(TreeValue),
+    /// A conflict that resolves to a file with this content. The file isn't
+    /// written to the store.
+    ResolvedConflict { content: Vec<u8>, executable: bool },
+}
+
+/// A file that `Tree::detect_renames()` found removed at one path and added at
+/// another.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Rename {
+    pub from: RepoPath,
+    pub to: RepoPath,
+    /// Whether the contents at the two paths differ.
+    pub modified: bool,
+    /// How similar the contents at the two paths are, as a percentage.
+    pub similarity: u32,
+}
+
+/// An item yielded by `Tree::diff_with_size_limit()`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SizeLimitedDiff {
+    pub path: RepoPath,
+    pub diff: Diff<TreeValue>,
+    /// Whether either side is a file above the size limit.
+    pub too_large: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Diff<T> {
+    Modified(T, T),
+    Added(T),
+    Removed(T),
+}
+
+impl<T> Diff<T> {
+    pub fn as_options(&self) -> (Option<&T>, Option<&T>) {
+        match self {
+            Diff::Modified(left, right) => (Some(left), Some(right)),
+            Diff::Added(right) => (None, Some(right)),
+            Diff::Removed(left) => (Some(left), None),
+        }
+    }
+
+    pub fn into_options(self) -> (Option<T>, Option<T>) {
+        match self {
+            Diff::Modified(left, right) => (Some(left), Some(right)),
+            Diff::Added(right) => (None, Some(right)),
+            Diff::Removed(left) => (Some(left), None),
+        }
+    }
+
+    /// Applies `f` to each side of the diff.
+    pub fn map<U>(self, mut f: impl FnMut(T) -> U) -> Diff<U> {
+        match self {
+            Diff::Modified(left, right) => Diff::Modified(f(left), f(right)),
+            Diff::Added(right) => Diff::Added(f(right)),
+            Diff::Removed(left) => Diff::Removed(f(left)),
+        }
+    }
+
+    /// Like `map()`, but returns `None` if `f` returns `None` for either
+    /// side.
+    pub fn try_map<U>(self, mut f: impl FnMut(T) -> Option<U>) -> Option<Diff<U>> {
+        Some(match self {
+            Diff::Modified(left, right) => Diff::Modified(f(left)?, f(right)?),
+            Diff::Added(right) => Diff::Added(f(right)?),
+            Diff::Removed(left) => Diff::Removed(f(left)?),
+        })
+    }
+}
+
+impl Diff<TreeValue> {
+    /// Returns true if this is a modification that changes the kind of value,
+    /// such as a file replaced by a symlink. Changing only the executable bit
+    /// is not a type change. Note that `Tree::diff()` reports a directory
+    /// replaced by a file (or the reverse) as a removal and an addition, not
+    /// as a modification.
+    pub fn is_type_change(&self) -> bool {
+        match self {
+            Diff::Modified(before, after) => {
+                std::mem::discriminant(before) != std::mem::discriminant(after)
+            }
+            Diff::Added(_) | Diff::Removed(_) => false,
+        }
+    }
+
+    /// If either side is a Git submodule, returns the commits it points to
+    /// before and after. A side is `None` if it's missing or not a submodule.
+    pub fn as_submodule_change(&self) -> Option<(Option<&CommitId>, Option<&CommitId>)> {
+        fn submodule_id(value: Option<&TreeValue>) -> Option<&CommitId> {
+            match value {
+                Some(TreeValue::GitSubmodule(id)) => Some(id),
+                _ => None,
+            }
+        }
+        let (before, after) = self.as_options();
+        let (before, after) = (submodule_id(before), submodule_id(after));
+        if before.is_none() && after.is_none() {
+            None
+        } else {
+            Some((before, after))
+        }
+    }
+}
+
+/// The kind of change that a `Diff<TreeValue>` makes to a path, without the
+/// values themselves.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum PathChange {
+    Add,
+    Delete,
+    /// The value changed but kept its kind, such as a file whose content or
+    /// executable bit changed.
+    Modify,
+    /// The value changed to a different kind, such as a file replaced by a
+    /// symlink.
+    TypeChange,
+}
+
+impl From<&Diff<TreeValue>> for PathChange {
+    fn from(diff: &Diff<TreeValue>) -> Self {
+        match diff {
+            Diff::Added(_) => PathChange::Add,
+            Diff::Removed(_) => PathChange::Delete,
+            Diff::Modified(..) if diff.is_type_change() => PathChange::TypeChange,
+            Diff::Modified(..) => PathChange::Modify,
+        }
+    }
+}
+
+/// Iterator over the entries that differ between two trees, without
+/// descending into subtrees. Yields the entry name and the values on each
+/// side, in name order. Created by `diff_entries()`.
+pub struct TreeEntryDiffIterator<'trees> {
+    it1: Peekable<TreeEntriesNonRecursiveIterator<'trees>>,
+    it2: Peekable<TreeEntriesNonRecursiveIterator<'trees>>,
+    // Whether to also yield entries that are equal in both trees
+    include_unchanged: bool,
+}
+
+impl<'trees> TreeEntryDiffIterator<'trees> {
+    fn new(tree1: &'trees Tree, tree2: &'trees Tree, include_unchanged: bool) -> Self {
+        let it1 = tree1.entries_non_recursive().peekable();
+        let it2 = tree2.entries_non_recursive().peekable();
+        TreeEntryDiffIterator {
+            it1,
+            it2,
+            include_unchanged,
+        }
+    }
+}
 
 impl<'trees> Iterator for TreeEntryDiffIterator<'trees> {
     type Item = (
@@ -350,7 +1766,7 @@ impl<'trees> Iterator for TreeEntryDiffIterator<'trees> {
                             // entry modified or clean
                             let before = self.it1.next().unwrap();
                             let after = self.it2.next().unwrap();
-                            if before.value() != after.value() {
+                            if self.include_unchanged || before.value() != after.value() {
                                 return Some((
                                     before.name(),
                                     Some(before.value()),
@@ -379,43 +1795,645 @@ impl<'trees> Iterator for TreeEntryDiffIterator<'trees> {
     }
 }
 
-fn diff_entries<'trees>(tree1: &'trees Tree, tree2: &'trees Tree) -> TreeEntryDiffIterator<'trees> {
-    TreeEntryDiffIterator::new(tree1, tree2)
+/// Pairs each added file with the first unpaired removed file that has the
+/// same ID. Returns the pairs as `(from, to, id)` in the order of `added`,
+/// followed by the unpaired removed and added files in their original order.
+#[allow(clippy::type_complexity)]
+fn pair_by_file_id(
+    removed: Vec<(RepoPath, FileId)>,
+    added: Vec<(RepoPath, FileId)>,
+) -> (
+    Vec<(RepoPath, RepoPath, FileId)>,
+    Vec<(RepoPath, FileId)>,
+    Vec<(RepoPath, FileId)>,
+) {
+    let mut removed_by_id: HashMap<&FileId, VecDeque<usize>> = HashMap::new();
+    for (index, (_, id)) in removed.iter().enumerate() {
+        removed_by_id.entry(id).or_default().push_back(index);
+    }
+    let mut paired_removed = vec![None; removed.len()];
+    let mut unpaired_added = vec![];
+    for (added_index, (_, id)) in added.iter().enumerate() {
+        match removed_by_id.get_mut(id).and_then(VecDeque::pop_front) {
+            Some(removed_index) => paired_removed[removed_index] = Some(added_index),
+            None => unpaired_added.push(added_index),
+        }
+    }
+    let mut moves = vec![];
+    let mut unpaired_removed = vec![];
+    let mut added = added.into_iter().map(Some).collect_vec();
+    for ((from, id), paired) in removed.into_iter().zip(paired_removed) {
+        match paired {
+            Some(added_index) => {
+                let (to, _) = added[added_index].take().unwrap();
+                moves.push((added_index, (from, to, id)));
+            }
+            None => unpaired_removed.push((from, id)),
+        }
+    }
+    moves.sort_by_key(|(added_index, _)| *added_index);
+    let moves = moves.into_iter().map(|(_, paired)| paired).collect();
+    let unpaired_added = unpaired_added
+        .into_iter()
+        .map(|index| added[index].take().unwrap())
+        .collect();
+    (moves, unpaired_removed, unpaired_added)
+}
+
+/// Diffs the entries directly in `tree1` and `tree2`. This is a single-level
+/// diff: a changed subtree is yielded as one entry with `TreeValue::Tree`
+/// values, and isn't descended into. Entries that are equal in both trees are
+/// skipped.
+pub fn diff_entries<'trees>(
+    tree1: &'trees Tree,
+    tree2: &'trees Tree,
+) -> TreeEntryDiffIterator<'trees> {
+    TreeEntryDiffIterator::new(tree1, tree2, false)
+}
+
+/// Options for `walk_diff()`.
+#[derive(Clone, Copy, Debug, Default)]
+struct DiffWalkOptions {
+    /// Also report the entries that are the same in both trees, including the
+    /// entries in subtrees that are the same in both trees.
+    include_unchanged: bool,
+    /// Don't descend into subtrees that only exist in the first tree, which
+    /// can only contain removals.
+    skip_removed_subtrees: bool,
+    /// Don't descend into subtrees that only exist in the second tree, which
+    /// can only contain additions.
+    skip_added_subtrees: bool,
+}
+
+/// An entry reported by `walk_diff()`.
+enum WalkedEntry<'a> {
+    Changed(Diff<&'a TreeValue>),
+    Unchanged(&'a TreeValue),
+}
+
+/// Calls `f` with each difference between `tree1` and `tree2`, in the order
+/// `Tree::diff()` yields them. This is the recursive counterpart of
+/// `TreeDiffIterator` that the callback-based diff functions are built on.
+/// Stops early if `f` returns `ControlFlow::Break`.
+fn walk_diff(
+    tree1: &Tree,
+    tree2: &Tree,
+    matcher: &dyn Matcher,
+    options: DiffWalkOptions,
+    f: &mut impl FnMut(&RepoPath, WalkedEntry) -> ControlFlow<()>,
+) -> Result<ControlFlow<()>, BackendError> {
+    if matcher.visit(tree1.dir()).is_nothing() {
+        return Ok(ControlFlow::Continue(()));
+    }
+    let entries = TreeEntryDiffIterator::new(tree1, tree2, options.include_unchanged);
+    for (name, before, after) in entries {
+        // Note: whenever we say "file" below, it may also be a symlink or a conflict.
+        let file_path = tree1.dir().join(name);
+        let tree_before = matches!(before, Some(TreeValue::Tree(_)));
+        let tree_after = matches!(after, Some(TreeValue::Tree(_)));
+        let (pre_subtree, post_subtree) = if before == after {
+            let unchanged = before.filter(|_| !tree_before).map(WalkedEntry::Unchanged);
+            (unchanged, None)
+        } else {
+            let (pre_subtree, post_subtree) = split_entry_diff(&file_path, before, after);
+            (
+                pre_subtree.map(WalkedEntry::Changed),
+                post_subtree.map(WalkedEntry::Changed),
+            )
+        };
+        let descend = match (tree_before, tree_after) {
+            (false, false) => false,
+            (true, false) => !options.skip_removed_subtrees,
+            (false, true) => !options.skip_added_subtrees,
+            (true, true) => true,
+        } && !matcher.visit(&file_path).is_nothing();
+        let file_matches = matcher.matches(&file_path);
+
+        if let (true, Some(entry)) = (file_matches, pre_subtree) {
+            if f(&file_path, entry).is_break() {
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+        if descend {
+            let subtree1 = sub_tree_or_null(tree1, name, before)?;
+            let subtree2 = sub_tree_or_null(tree2, name, after)?;
+            if walk_diff(&subtree1, &subtree2, matcher, options, f)?.is_break() {
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+        if let (true, Some(entry)) = (file_matches, post_subtree) {
+            if f(&file_path, entry).is_break() {
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+    }
+    Ok(ControlFlow::Continue(()))
+}
+
+/// Splits the difference between two entries with the same name into the
+/// file diffs to report before and after the differences in the subtree on
+/// either side. A file replaced by a directory is removed before the files in
+/// the directory are added, and a directory replaced by a file is added after
+/// the files in the directory are removed. Subtrees themselves are never
+/// reported.
+#[allow(clippy::type_complexity)]
+fn split_entry_diff<'a>(
+    path: &RepoPath,
+    before: Option<&'a TreeValue>,
+    after: Option<&'a TreeValue>,
+) -> (Option<Diff<&'a TreeValue>>, Option<Diff<&'a TreeValue>>) {
+    let before_file = before.filter(|value| !matches!(value, TreeValue::Tree(_)));
+    let after_file = after.filter(|value| !matches!(value, TreeValue::Tree(_)));
+    match (before_file, after_file) {
+        (Some(before), Some(after)) => (Some(Diff::Modified(before, after)), None),
+        (Some(before), None) => (Some(Diff::Removed(before)), None),
+        (None, Some(after)) if before.is_some() => (None, Some(Diff::Added(after))),
+        (None, Some(after)) => (Some(Diff::Added(after)), None),
+        (None, None) => {
+            // TreeEntryDiffIterator never yields an entry that's missing on
+            // both sides. Skip it if a broken backend somehow made it do that.
+            if cfg!(debug_assertions) && before.is_none() && after.is_none() {
+                panic!("unexpected diff at {path:?}");
+            }
+            (None, None)
+        }
+    }
+}
+
+/// Returns the subtree `name` of `tree` if `value` is a tree, or an empty tree
+/// at that path otherwise, to diff against the subtree on the other side.
+fn sub_tree_or_null(
+    tree: &Tree,
+    name: &RepoPathComponent,
+    value: Option<&TreeValue>,
+) -> Result<Tree, BackendError> {
+    match value {
+        Some(TreeValue::Tree(id)) => tree.known_sub_tree(name, id),
+        _ => Ok(Tree::null(tree.store().clone(), tree.dir().join(name))),
+    }
+}
+
+fn shared_subtree_paths_impl(
+    tree1: &Tree,
+    tree2: &Tree,
+    paths: &mut Vec<RepoPath>,
+) -> Result<(), BackendError> {
+    for (name, before, after) in TreeEntryDiffIterator::new(tree1, tree2, true) {
+        if let (Some(TreeValue::Tree(id1)), Some(TreeValue::Tree(id2))) = (before, after) {
+            if id1 == id2 {
+                paths.push(tree1.dir().join(name));
+            } else {
+                let subtree1 = tree1.known_sub_tree(name, id1)?;
+                let subtree2 = tree2.known_sub_tree(name, id2)?;
+                shared_subtree_paths_impl(&subtree1, &subtree2, paths)?;
+            }
+        }
+    }
+    Ok(())
 }
 
 pub fn recursive_tree_diff(root1: Tree, root2: Tree, matcher: &dyn Matcher) -> TreeDiffIterator {
-    TreeDiffIterator::new(RepoPath::root(), root1, root2, matcher)
+    TreeDiffIterator::new(RepoPath::root(), root1, root2, matcher, None)
+}
+
+/// Diffs the root trees of `commit1` and `commit2`, which are read from
+/// `store`.
+pub fn diff_commit_trees<'matcher>(
+    store: &Arc<Store>,
+    commit1: &Commit,
+    commit2: &Commit,
+    matcher: &'matcher dyn Matcher,
+) -> Result<TreeDiffIterator<'matcher>, BackendError> {
+    let root1 = store.get_tree(&RepoPath::root(), commit1.tree_id())?;
+    let root2 = store.get_tree(&RepoPath::root(), commit2.tree_id())?;
+    Ok(recursive_tree_diff(root1, root2, matcher))
+}
+
+/// Groups the diffs by the directory containing the changed path. Within each
+/// directory, the diffs are kept in the order they were yielded.
+pub fn group_diff_by_dir(
+    diff: impl IntoIterator<Item = (RepoPath, Diff<TreeValue>)>,
+) -> BTreeMap<RepoPath, Vec<(RepoPathComponent, Diff<TreeValue>)>> {
+    let mut grouped: BTreeMap<RepoPath, Vec<_>> = BTreeMap::new();
+    for (path, diff) in diff {
+        // Diffs are never reported for the root directory itself
+        let (dir, basename) = path.split().unwrap();
+        grouped
+            .entry(dir)
+            .or_default()
+            .push((basename.clone(), diff));
+    }
+    grouped
+}
+
+/// An item returned by `multi_diff()`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MultiDiff {
+    pub path: RepoPath,
+    /// The diff from each base to the target, in the order of the bases.
+    /// `None` if the path is the same in that base and in the target.
+    pub diffs: Vec<Option<Diff<TreeValue>>>,
+}
+
+impl MultiDiff {
+    /// Returns true if the path is missing from all bases.
+    pub fn is_added_to_all(&self) -> bool {
+        self.diffs
+            .iter()
+            .all(|diff| matches!(diff, Some(Diff::Added(_))))
+    }
+
+    /// Returns true if the path differs from all bases, such as a conflict
+    /// resolution in a merge commit.
+    pub fn is_changed_from_all(&self) -> bool {
+        self.diffs.iter().all(Option::is_some)
+    }
+}
+
+/// Diffs `target` against each of `bases`, such as a merge commit's tree
+/// against its parents' trees. Returns the paths that differ from any base,
+/// in sorted order, with the diff against each base. As in `Tree::diff()`, a
+/// directory replaced by a file is reported as the files in the directory and
+/// the file itself. Subtrees that are the same in all the trees are not read.
+pub fn multi_diff(
+    bases: &[Tree],
+    target: &Tree,
+    matcher: &dyn Matcher,
+) -> Result<Vec<MultiDiff>, BackendError> {
+    let mut diffs: BTreeMap<RepoPath, Vec<Option<Diff<TreeValue>>>> = BTreeMap::new();
+    for (index, base) in bases.iter().enumerate() {
+        let _ = base.for_each_diff_ref(target, matcher, |path, diff| {
+            let path_diffs = diffs
+                .entry(path.clone())
+                .or_insert_with(|| vec![None; bases.len()]);
+            path_diffs[index] = Some(diff.map(Clone::clone));
+            ControlFlow::Continue(())
+        })?;
+    }
+    Ok(diffs
+        .into_iter()
+        .map(|(path, diffs)| MultiDiff { path, diffs })
+        .collect())
+}
+
+/// The matchers that `DiffCache` can tell apart. Arbitrary matchers can't be
+/// compared, so only these are supported.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DiffCacheMatcher {
+    /// Matches everything, like `EverythingMatcher`.
+    Everything,
+    /// Matches the given paths and everything under them, like
+    /// `PrefixMatcher`.
+    Prefixes(Vec<RepoPath>),
+}
+
+impl DiffCacheMatcher {
+    pub fn prefixes(prefixes: &[RepoPath]) -> Self {
+        let mut prefixes = prefixes.to_vec();
+        prefixes.sort();
+        prefixes.dedup();
+        DiffCacheMatcher::Prefixes(prefixes)
+    }
+
+    fn to_matcher(&self) -> Box<dyn Matcher> {
+        match self {
+            DiffCacheMatcher::Everything => Box::new(EverythingMatcher),
+            DiffCacheMatcher::Prefixes(prefixes) => Box::new(PrefixMatcher::new(prefixes)),
+        }
+    }
+}
+
+type DiffCacheKey = (RepoPath, TreeId, TreeId, DiffCacheMatcher);
+
+/// The differences between two trees, as returned by `DiffCache::diff()`.
+pub type CachedDiff = Arc<Vec<(RepoPath, Diff<TreeValue>)>>;
+
+/// Memoizes the results of `Tree::diff()`. Since tree ids are content
+/// addressed, a cached diff never goes stale.
+#[derive(Debug, Default)]
+pub struct DiffCache {
+    diffs: Mutex<HashMap<DiffCacheKey, CachedDiff>>,
+}
+
+impl DiffCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns what `tree1.diff(tree2, matcher)` would yield, computing it
+    /// only if the same diff isn't cached already.
+    pub fn diff(
+        &self,
+        tree1: &Tree,
+        tree2: &Tree,
+        matcher: &DiffCacheMatcher,
+    ) -> Result<CachedDiff, BackendError> {
+        let key = (
+            tree1.dir().clone(),
+            tree1.id().clone(),
+            tree2.id().clone(),
+            matcher.clone(),
+        );
+        if let Some(diffs) = self.diffs.lock().unwrap().get(&key) {
+            return Ok(diffs.clone());
+        }
+        let diffs: Vec<_> = tree1
+            .diff(tree2, matcher.to_matcher().as_ref())
+            .try_collect()?;
+        let diffs = Arc::new(diffs);
+        self.diffs.lock().unwrap().insert(key, diffs.clone());
+        Ok(diffs)
+    }
+
+    pub fn len(&self) -> usize {
+        self.diffs.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.diffs.lock().unwrap().clear();
+    }
 }
 
+/// Yields an error for each pair of subtrees that can't be read, and skips
+/// the differences below them.
 pub struct TreeDiffIterator<'matcher> {
     stack: Vec<TreeDiffItem>,
     matcher: &'matcher dyn Matcher,
+    // Subtrees at paths with this many components are yielded instead of
+    // descended into
+    max_components: Option<usize>,
+}
+
+struct TreeDiffDirItem {
+    path: RepoPath,
+    // Iterator over the diffs between tree1 and tree2
+    entry_iterator: TreeEntryDiffIterator<'static>,
+    // On drop, tree1 and tree2 must outlive entry_iterator
+    tree1: Pin<Box<Tree>>,
+    tree2: Pin<Box<Tree>>,
+}
+
+enum TreeDiffItem {
+    Dir(TreeDiffDirItem),
+    // This is used for making sure that when a directory gets replaced by a file, we
+    // yield the value for the addition of the file after we yield the values
+    // for removing files in the directory.
+    File(RepoPath, Diff<TreeValue>),
+}
+
+impl<'matcher> TreeDiffIterator<'matcher> {
+    fn new(
+        dir: RepoPath,
+        tree1: Tree,
+        tree2: Tree,
+        matcher: &'matcher dyn Matcher,
+        max_depth: Option<usize>,
+    ) -> Self {
+        let max_components = max_depth.map(|max_depth| dir.components().len() + max_depth + 1);
+        let mut stack = Vec::new();
+        if !matcher.visit(&dir).is_nothing() {
+            stack.push(TreeDiffItem::Dir(TreeDiffDirItem::new(dir, tree1, tree2)));
+        };
+        Self {
+            stack,
+            matcher,
+            max_components,
+        }
+    }
+}
+
+/// The differences returned by `Tree::diff_bounded()`, and whether there are
+/// more.
+pub type BoundedDiff = (Vec<(RepoPath, Diff<TreeValue>)>, bool);
+
+/// A diff entry together with readers for the file contents before and after.
+pub type DiffWithContents = (
+    RepoPath,
+    Diff<TreeValue>,
+    Option<Box<dyn Read>>,
+    Option<Box<dyn Read>>,
+);
+
+impl<'matcher> TreeDiffIterator<'matcher> {
+    /// Adapts this iterator to also yield readers for the file contents on
+    /// each side. The readers are `None` for sides that are missing or not
+    /// files. Files are opened as the entries are yielded.
+    pub fn with_contents(
+        self,
+        store: &Arc<Store>,
+    ) -> impl Iterator<Item = Result<DiffWithContents, BackendError>> + 'matcher {
+        let store = store.clone();
+        self.map(move |item| {
+            let (path, diff) = item?;
+            let (before, after) = diff.as_options();
+            let before_reader = open_file_value(&store, &path, before)?;
+            let after_reader = open_file_value(&store, &path, after)?;
+            Ok((path, diff, before_reader, after_reader))
+        })
+    }
+}
+
+fn open_file_value(
+    store: &Store,
+    path: &RepoPath,
+    value: Option<&TreeValue>,
+) -> Result<Option<Box<dyn Read>>, BackendError> {
+    match value {
+        Some(TreeValue::File { id, .. }) => Ok(Some(store.read_file(path, id)?)),
+        _ => Ok(None),
+    }
+}
+
+fn expand_diff_conflicts(
+    store: &Store,
+    path: RepoPath,
+    diff: Diff<TreeValue>,
+) -> Result<Vec<ExpandedDiff>, BackendError> {
+    let (before, after) = diff.into_options();
+    let before_terms = expand_conflict_value(store, &path, before)?;
+    let after_terms = expand_conflict_value(store, &path, after)?;
+    let mut diffs = vec![];
+    for (before_term, before) in &before_terms {
+        for (after_term, after) in &after_terms {
+            let diff = match (before, after) {
+                (Some(before), Some(after)) => Diff::Modified(before.clone(), after.clone()),
+                (None, Some(after)) => Diff::Added(after.clone()),
+                (Some(before), None) => Diff::Removed(before.clone()),
+                (None, None) => continue,
+            };
+            diffs.push(ExpandedDiff {
+                path: path.clone(),
+                diff,
+                before_term: *before_term,
+                after_term: *after_term,
+            });
+        }
+    }
+    Ok(diffs)
+}
+
+/// A value that one side of a diff expands to, along with the index of the
+/// conflict add it came from.
+type ExpandedTerm = (Option<usize>, Option<TreeValue>);
+
+/// Returns the values that one side of a diff expands to.
+fn expand_conflict_value(
+    store: &Store,
+    path: &RepoPath,
+    value: Option<TreeValue>,
+) -> Result<Vec<ExpandedTerm>, BackendError> {
+    match value {
+        Some(TreeValue::Conflict(id)) => {
+            let conflict = store.read_conflict(path, &id)?;
+            Ok(conflict
+                .adds
+                .into_iter()
+                .enumerate()
+                .map(|(index, term)| (Some(index), Some(term.value)))
+                .collect())
+        }
+        value => Ok(vec![(None, value)]),
+    }
+}
+
+/// Resolves the conflicts on each side of `diff`. Returns `None` if the sides
+/// are the same after that.
+fn resolve_diff_conflicts(
+    store: &Store,
+    path: &RepoPath,
+    diff: Diff<TreeValue>,
+) -> Result<Option<Diff<ResolvedValue>>, TreeMergeError> {
+    Ok(match diff {
+        Diff::Modified(before, after) => {
+            let before = resolve_conflict_value(store, path, before)?;
+            let after = resolve_conflict_value(store, path, after)?;
+            if resolved_values_equal(store, path, &before, &after)? {
+                None
+            } else {
+                Some(Diff::Modified(before, after))
+            }
+        }
+        Diff::Added(after) => Some(Diff::Added(resolve_conflict_value(store, path, after)?)),
+        Diff::Removed(before) => Some(Diff::Removed(resolve_conflict_value(store, path, before)?)),
+    })
+}
+
+/// Returns the file a conflict resolves to, or the value itself if it isn't
+/// a conflict or can't be resolved automatically.
+fn resolve_conflict_value(
+    store: &Store,
+    path: &RepoPath,
+    value: TreeValue,
+) -> Result<ResolvedValue, TreeMergeError> {
+    let conflict_id = match &value {
+        TreeValue::Conflict(id) => id,
+        _ => return Ok(ResolvedValue::Value(value)),
+    };
+    let conflict = store.read_conflict(path, conflict_id)?;
+    match try_resolve_file_conflict(
+        store,
+        path,
+        &conflict,
+        ExecBitPolicy::default(),
+        DeleteHandling::default(),
+        None,
+    )? {
+        Some(resolved) => Ok(ResolvedValue::ResolvedConflict {
+            content: resolved.content,
+            executable: resolved.executable,
+        }),
+        None => Ok(ResolvedValue::Value(value)),
+    }
+}
+
+/// Returns true if `left` and `right` are the same. A resolved conflict and a
+/// file are the same if they have the same content and executable bit.
+fn resolved_values_equal(
+    store: &Store,
+    path: &RepoPath,
+    left: &ResolvedValue,
+    right: &ResolvedValue,
+) -> Result<bool, TreeMergeError> {
+    match (left, right) {
+        (ResolvedValue::Value(left), ResolvedValue::Value(right)) => Ok(left == right),
+        (
+            ResolvedValue::ResolvedConflict {
+                content: left_content,
+                executable: left_executable,
+            },
+            ResolvedValue::ResolvedConflict {
+                content: right_content,
+                executable: right_executable,
+            },
+        ) => Ok(left_content == right_content && left_executable == right_executable),
+        (
+            ResolvedValue::ResolvedConflict {
+                content,
+                executable,
+            },
+            ResolvedValue::Value(TreeValue::File {
+                id,
+                executable: file_executable,
+            }),
+        )
+        | (
+            ResolvedValue::Value(TreeValue::File {
+                id,
+                executable: file_executable,
+            }),
+            ResolvedValue::ResolvedConflict {
+                content,
+                executable,
+            },
+        ) => Ok(executable == file_executable && read_file_contents(store, path, id)? == *content),
+        _ => Ok(false),
+    }
 }
 
-struct TreeDiffDirItem {
-    path: RepoPath,
-    // Iterator over the diffs between tree1 and tree2
-    entry_iterator: TreeEntryDiffIterator<'static>,
-    // On drop, tree1 and tree2 must outlive entry_iterator
-    tree1: Pin<Box<Tree>>,
-    tree2: Pin<Box<Tree>>,
+fn read_file_contents(
+    store: &Store,
+    path: &RepoPath,
+    id: &FileId,
+) -> Result<Vec<u8>, BackendError> {
+    let mut contents = vec![];
+    store
+        .read_file(path, id)?
+        .read_to_end(&mut contents)
+        .map_err(|err| BackendError::ReadObject {
+            object_type: id.object_type(),
+            hash: id.hex(),
+            source: Box::new(err),
+        })?;
+    Ok(contents)
 }
 
-enum TreeDiffItem {
-    Dir(TreeDiffDirItem),
-    // This is used for making sure that when a directory gets replaced by a file, we
-    // yield the value for the addition of the file after we yield the values
-    // for removing files in the directory.
-    File(RepoPath, Diff<TreeValue>),
+/// Returns the percentage of the bytes in `left` and `right` that are in
+/// matching hunks of their diff.
+fn content_similarity(left: &[u8], right: &[u8]) -> u32 {
+    let total_len = left.len() + right.len();
+    if total_len == 0 {
+        return 100;
+    }
+    let matching_len: usize = diff::diff(left, right)
+        .iter()
+        .map(|hunk| match hunk {
+            DiffHunk::Matching(content) => content.len(),
+            DiffHunk::Different(_) => 0,
+        })
+        .sum();
+    (matching_len * 2 * 100 / total_len) as u32
 }
 
-impl<'matcher> TreeDiffIterator<'matcher> {
-    fn new(dir: RepoPath, tree1: Tree, tree2: Tree, matcher: &'matcher dyn Matcher) -> Self {
-        let mut stack = Vec::new();
-        if !matcher.visit(&dir).is_nothing() {
-            stack.push(TreeDiffItem::Dir(TreeDiffDirItem::new(dir, tree1, tree2)));
-        };
-        Self { stack, matcher }
+/// Returns true if the matcher matches the value at the given path. A subtree
+/// matches if the matcher may match anything in it.
+fn matches_value(matcher: &dyn Matcher, path: &RepoPath, value: &TreeValue) -> bool {
+    match value {
+        TreeValue::Tree(_) => !matcher.visit(path).is_nothing(),
+        _ => matcher.matches(path),
     }
 }
 
@@ -438,22 +2456,15 @@ impl TreeDiffDirItem {
         name: &RepoPathComponent,
         before: Option<&TreeValue>,
         after: Option<&TreeValue>,
-    ) -> Self {
-        let subdir_path = self.path.join(name);
-        let before_tree = match before {
-            Some(TreeValue::Tree(id_before)) => self.tree1.known_sub_tree(name, id_before),
-            _ => Tree::null(self.tree1.store().clone(), subdir_path.clone()),
-        };
-        let after_tree = match after {
-            Some(TreeValue::Tree(id_after)) => self.tree2.known_sub_tree(name, id_after),
-            _ => Tree::null(self.tree2.store().clone(), subdir_path.clone()),
-        };
-        Self::new(subdir_path, before_tree, after_tree)
+    ) -> Result<Self, BackendError> {
+        let before_tree = sub_tree_or_null(&self.tree1, name, before)?;
+        let after_tree = sub_tree_or_null(&self.tree2, name, after)?;
+        Ok(Self::new(self.path.join(name), before_tree, after_tree))
     }
 }
 
 impl Iterator for TreeDiffIterator<'_> {
-    type Item = (RepoPath, Diff<TreeValue>);
+    type Item = Result<(RepoPath, Diff<TreeValue>), BackendError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         while let Some(top) = self.stack.last_mut() {
@@ -468,7 +2479,7 @@ impl Iterator for TreeDiffIterator<'_> {
                 }
                 TreeDiffItem::File(..) => {
                     if let TreeDiffItem::File(name, diff) = self.stack.pop().unwrap() {
-                        return Some((name, diff));
+                        return Some(Ok((name, diff)));
                     } else {
                         unreachable!();
                     }
@@ -479,44 +2490,58 @@ impl Iterator for TreeDiffIterator<'_> {
             let file_path = dir.path.join(name);
             let tree_before = matches!(before, Some(TreeValue::Tree(_)));
             let tree_after = matches!(after, Some(TreeValue::Tree(_)));
+            let at_max_depth = self.max_components.map_or(false, |max_components| {
+                file_path.components().len() >= max_components
+            });
+            if (tree_before || tree_after) && at_max_depth {
+                // Yield the subtree itself instead of descending into it.
+                let before = before.filter(|value| matches_value(self.matcher, &file_path, value));
+                let after = after.filter(|value| matches_value(self.matcher, &file_path, value));
+                match (before, after) {
+                    (Some(before), Some(after)) if tree_before && tree_after => {
+                        return Some(Ok((
+                            file_path,
+                            Diff::Modified(before.clone(), after.clone()),
+                        )));
+                    }
+                    (Some(before), Some(after)) => {
+                        // Yield the removal before the addition, like we do when descending into
+                        // the subtree.
+                        self.stack.push(TreeDiffItem::File(
+                            file_path.clone(),
+                            Diff::Added(after.clone()),
+                        ));
+                        return Some(Ok((file_path, Diff::Removed(before.clone()))));
+                    }
+                    (Some(before), None) => {
+                        return Some(Ok((file_path, Diff::Removed(before.clone()))));
+                    }
+                    (None, Some(after)) => {
+                        return Some(Ok((file_path, Diff::Added(after.clone()))));
+                    }
+                    (None, None) => continue,
+                }
+            }
             let post_subdir =
                 if (tree_before || tree_after) && !self.matcher.visit(&file_path).is_nothing() {
-                    let subdir = dir.subdir(name, before, after);
+                    // The entries below an unreadable subtree are skipped
+                    let subdir = match dir.subdir(name, before, after) {
+                        Ok(subdir) => subdir,
+                        Err(err) => return Some(Err(err)),
+                    };
                     self.stack.push(TreeDiffItem::Dir(subdir));
                     self.stack.len() - 1
                 } else {
                     self.stack.len()
                 };
             if self.matcher.matches(&file_path) {
-                if !tree_before && tree_after {
-                    if let Some(file_before) = before {
-                        return Some((file_path, Diff::Removed(file_before.clone())));
-                    }
-                } else if tree_before && !tree_after {
-                    if let Some(file_after) = after {
-                        self.stack.insert(
-                            post_subdir,
-                            TreeDiffItem::File(file_path, Diff::Added(file_after.clone())),
-                        );
-                    }
-                } else if !tree_before && !tree_after {
-                    match (before, after) {
-                        (Some(file_before), Some(file_after)) => {
-                            return Some((
-                                file_path,
-                                Diff::Modified(file_before.clone(), file_after.clone()),
-                            ));
-                        }
-                        (None, Some(file_after)) => {
-                            return Some((file_path, Diff::Added(file_after.clone())));
-                        }
-                        (Some(file_before), None) => {
-                            return Some((file_path, Diff::Removed(file_before.clone())));
-                        }
-                        (None, None) => {
-                            panic!("unexpected diff")
-                        }
+                match split_entry_diff(&file_path, before, after) {
+                    (Some(diff), _) => return Some(Ok((file_path, diff.map(Clone::clone)))),
+                    (None, Some(diff)) => {
+                        let item = TreeDiffItem::File(file_path, diff.map(Clone::clone));
+                        self.stack.insert(post_subdir, item);
                     }
+                    (None, None) => {}
                 }
             }
         }
@@ -524,10 +2549,375 @@ impl Iterator for TreeDiffIterator<'_> {
     }
 }
 
+/// How to decide whether the result of a content merge should be executable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExecBitPolicy {
+    /// Use the executable bit from the side that changed it. The conflict is
+    /// left unresolved if that's ambiguous.
+    #[default]
+    Heuristic,
+    /// Make the result executable if any side is executable.
+    PreferExecutable,
+    /// Make the result executable only if all sides are executable.
+    PreferRegular,
+    /// Use the executable bit that most sides have. Falls back to `Heuristic`
+    /// on a tie.
+    Majority,
+}
+
+/// How to merge a file that one side deleted and the other side modified.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeleteHandling {
+    /// Leave a modify/delete conflict.
+    #[default]
+    Conflict,
+    /// Merge the contents as if the deleting side had left an empty file. The
+    /// result is a file even if it ends up empty.
+    TreatAsEmpty,
+}
+
+/// How to merge a path that is a file on one side and a symlink on the other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkFilePolicy {
+    /// Leave a file/symlink conflict.
+    #[default]
+    Conflict,
+    /// Use the side that is a file.
+    PreferFile,
+    /// Use the side that is a symlink.
+    PreferSymlink,
+}
+
+/// A function that decides the merged value of a path instead of the default
+/// conflict resolution. Returns `None` to fall back to the default resolution.
+pub type MergeDriver = dyn Fn(&RepoPath, &Conflict) -> Option<TreeValue>;
+
+/// Merge drivers to consult for conflicting paths, similar to Git's
+/// `merge.<driver>` attributes.
+#[derive(Clone, Default)]
+pub struct MergeDriverRegistry {
+    drivers: Vec<(Arc<dyn Matcher>, Arc<MergeDriver>)>,
+}
+
+impl MergeDriverRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `driver` for the paths matched by `matcher`. Drivers are
+    /// consulted in the order they were registered.
+    pub fn register(
+        &mut self,
+        matcher: Arc<dyn Matcher>,
+        driver: impl Fn(&RepoPath, &Conflict) -> Option<TreeValue> + 'static,
+    ) {
+        self.drivers.push((matcher, Arc::new(driver)));
+    }
+
+    /// Returns true if any driver is registered for `path`.
+    fn has_driver_for(&self, path: &RepoPath) -> bool {
+        self.drivers
+            .iter()
+            .any(|(matcher, _)| matcher.matches(path))
+    }
+
+    /// Returns the value decided by the first matching driver that doesn't
+    /// return `None`.
+    pub fn resolve(&self, path: &RepoPath, conflict: &Conflict) -> Option<TreeValue> {
+        self.drivers
+            .iter()
+            .filter(|(matcher, _)| matcher.matches(path))
+            .find_map(|(_, driver)| driver(path, conflict))
+    }
+}
+
+impl Debug for MergeDriverRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        f.debug_struct("MergeDriverRegistry")
+            .field("len", &self.drivers.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// An external command that merges the contents of a conflicted file, similar
+/// to Git's `merge.<driver>.driver`. The command is run with the contents on
+/// each side written to temporary files. It should write the merged contents
+/// to the output file and exit successfully, or exit with an error if it
+/// can't merge the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExternalMergeDriver {
+    pub program: String,
+    /// `$base`, `$left`, `$right`, and `$output` are replaced with the paths
+    /// to the temporary files. The output file starts out empty.
+    pub args: Vec<String>,
+}
+
+/// The result of running an `ExternalMergeDriver`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExternalMergeResult {
+    /// The driver merged the contents.
+    Resolved(Vec<u8>),
+    /// The driver couldn't merge the contents.
+    Conflict,
+}
+
+impl ExternalMergeDriver {
+    pub fn new(program: impl Into<String>, args: Vec<String>) -> Self {
+        ExternalMergeDriver {
+            program: program.into(),
+            args,
+        }
+    }
+
+    /// Runs the driver to merge `left` and `right` with `base` as the common
+    /// ancestor. `path` is only used for naming the temporary files.
+    pub fn merge(
+        &self,
+        path: &RepoPath,
+        base: &[u8],
+        left: &[u8],
+        right: &[u8],
+    ) -> Result<ExternalMergeResult, ExternalMergeDriverError> {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("jj-merge-driver-")
+            .tempdir()
+            .map_err(ExternalMergeDriverError::SetUpError)?;
+        let suffix = path
+            .components()
+            .last()
+            .map(|name| format!("_{}", name.as_str()))
+            .unwrap_or_default();
+        let mut paths = HashMap::new();
+        for (role, contents) in [
+            ("base", base),
+            ("left", left),
+            ("right", right),
+            ("output", b"".as_slice()),
+        ] {
+            let file_path = temp_dir.path().join(format!("{role}{suffix}"));
+            std::fs::write(&file_path, contents).map_err(ExternalMergeDriverError::SetUpError)?;
+            let file_path = file_path.into_os_string().into_string().map_err(|path| {
+                ExternalMergeDriverError::SetUpError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Temporary file path is not valid UTF-8: {path:?}"),
+                ))
+            })?;
+            paths.insert(role, file_path);
+        }
+
+        let mut cmd = Command::new(&self.program);
+        cmd.args(interpolate_merge_driver_args(&self.args, &paths))
+            .stdin(Stdio::null());
+        tracing::info!(?cmd, "Invoking the external merge driver:");
+        let exit_status =
+            cmd.status()
+                .map_err(|err| ExternalMergeDriverError::FailedToExecute {
+                    program: self.program.clone(),
+                    source: err,
+                })?;
+        if !exit_status.success() {
+            return Ok(ExternalMergeResult::Conflict);
+        }
+        let content =
+            std::fs::read(&paths["output"]).map_err(ExternalMergeDriverError::ReadOutputError)?;
+        Ok(ExternalMergeResult::Resolved(content))
+    }
+}
+
+fn interpolate_merge_driver_args(args: &[String], paths: &HashMap<&str, String>) -> Vec<String> {
+    static VARIABLE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\$([a-z]+)\b").unwrap());
+    args.iter()
+        .map(|arg| {
+            VARIABLE_REGEX
+                .replace_all(arg, |caps: &Captures| match paths.get(&caps[1]) {
+                    Some(path) => path.clone(),
+                    None => caps[0].to_owned(),
+                })
+                .into_owned()
+        })
+        .collect()
+}
+
+/// Options for `merge_trees_with_options()`.
+#[derive(Clone, Debug, Default)]
+pub struct MergeOptions {
+    pub exec_bit_policy: ExecBitPolicy,
+    pub merge_drivers: MergeDriverRegistry,
+    pub delete_handling: DeleteHandling,
+    pub symlink_file_policy: SymlinkFilePolicy,
+    /// If set, a cleanly merged file takes its executable bit from this term
+    /// instead of following `exec_bit_policy`. For a merge of plain files,
+    /// `Add(0)` is side 1, `Add(1)` is side 2, and `Base(0)` is the base.
+    /// The policy still applies if the term is missing or isn't a file.
+    pub exec_authority: Option<ConflictSide>,
+    /// If set, this command is run to merge the contents of a conflicting
+    /// file that couldn't be merged automatically. Never set by default,
+    /// since it runs an arbitrary command.
+    pub external_merge_driver: Option<ExternalMergeDriver>,
+}
+
+/// How a merge decided the value at a path that the two sides changed in
+/// different ways.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EntryMergeKind {
+    /// The changes canceled out, leaving a single value (or no value).
+    Trivial,
+    /// The file contents were merged automatically.
+    ContentMerge,
+    /// A registered merge driver decided the value.
+    MergeDriver,
+    /// The `ExternalMergeDriver` merged the file contents.
+    ExternalMergeDriver,
+    /// One side's value was picked according to the `SymlinkFilePolicy`.
+    SymlinkFilePolicy,
+    /// The resolver passed to `merge_trees_with_options()` decided the value.
+    Resolver,
+    /// The subtrees were merged recursively. The entries in the subtree are
+    /// reported separately.
+    SubtreeMerge,
+    /// The changes could not be merged, so a conflict was written.
+    Conflict,
+}
+
+/// What the resolver passed to `merge_trees_with_options()` decided for a
+/// conflict.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ConflictResolution {
+    /// Leave the conflict unresolved, as if there were no resolver.
+    Keep,
+    /// Use this value instead of the conflict.
+    Resolve(TreeValue),
+    /// Remove the path.
+    Delete,
+}
+
+/// Decides the value of a conflict that `merge_trees_with_options()`
+/// couldn't resolve automatically.
+pub type ConflictResolver<'a> = dyn FnMut(&RepoPath, &Conflict) -> ConflictResolution + 'a;
+
+/// State that is threaded through the recursive tree merge.
+struct MergeState<'options> {
+    options: &'options MergeOptions,
+    resolver: Option<&'options mut ConflictResolver<'options>>,
+    outcomes: Vec<(RepoPath, EntryMergeKind)>,
+    /// Set while merging the terms of a tree conflict, whose result is only
+    /// used if it's clean. The merge drivers and the resolver aren't run then,
+    /// since their decisions would be thrown away with the result.
+    tentative: bool,
+    /// Set when a tentative merge reaches a path that it can't decide without
+    /// the merge drivers or the resolver, or that conflicts. The rest of the
+    /// tentative merge is skipped.
+    abandoned: bool,
+}
+
+impl<'options> MergeState<'options> {
+    fn new(options: &'options MergeOptions) -> Self {
+        MergeState {
+            options,
+            resolver: None,
+            outcomes: vec![],
+            tentative: false,
+            abandoned: false,
+        }
+    }
+
+    /// Abandons the current tentative merge. Returns a placeholder value to
+    /// unwind with, which is never used.
+    fn abandon(&mut self) -> Option<TreeValue> {
+        assert!(self.tentative);
+        self.abandoned = true;
+        None
+    }
+}
+
 pub fn merge_trees(
     side1_tree: &Tree,
     base_tree: &Tree,
     side2_tree: &Tree,
+) -> Result<TreeId, TreeMergeError> {
+    let (tree_id, _) = merge_trees_with_options(
+        side1_tree,
+        base_tree,
+        side2_tree,
+        &MergeOptions::default(),
+        None,
+    )?;
+    Ok(tree_id)
+}
+
+/// Like `merge_trees()`, but takes the IDs of the trees at `dir`. The trees
+/// are only read if the result can't be decided from the IDs alone.
+pub fn merge_tree_ids(
+    store: &Arc<Store>,
+    dir: &RepoPath,
+    base_id: &TreeId,
+    side1_id: &TreeId,
+    side2_id: &TreeId,
+) -> Result<TreeId, TreeMergeError> {
+    if base_id == side1_id {
+        return Ok(side2_id.clone());
+    }
+    if base_id == side2_id || side1_id == side2_id {
+        return Ok(side1_id.clone());
+    }
+    let base_tree = store.get_tree(dir, base_id)?;
+    let side1_tree = store.get_tree(dir, side1_id)?;
+    let side2_tree = store.get_tree(dir, side2_id)?;
+    merge_trees(&side1_tree, &base_tree, &side2_tree)
+}
+
+/// Like `merge_trees()`, but takes the raw data of the trees at `dir`. The
+/// trees are written to the store to find their IDs, which is a no-op for
+/// trees that are already there.
+pub fn merge_backend_trees(
+    store: &Arc<Store>,
+    dir: &RepoPath,
+    base: &backend::Tree,
+    side1: &backend::Tree,
+    side2: &backend::Tree,
+) -> Result<TreeId, TreeMergeError> {
+    let to_tree = |data: &backend::Tree| -> Result<Tree, TreeMergeError> {
+        let id = store.write_tree(dir, data)?;
+        Ok(Tree::new(
+            store.clone(),
+            dir.clone(),
+            id,
+            Arc::new(data.clone()),
+        ))
+    };
+    let base_tree = to_tree(base)?;
+    let side1_tree = to_tree(side1)?;
+    let side2_tree = to_tree(side2)?;
+    merge_trees(&side1_tree, &base_tree, &side2_tree)
+}
+
+/// Merges the trees according to `options`. If `resolver` is set, it's called
+/// for each conflict that couldn't be resolved automatically, so the caller can
+/// decide its value. Conflicts for which it returns `ConflictResolution::Keep`
+/// are written as usual.
+///
+/// Also returns how the merge decided each path that the two sides changed in
+/// different ways. The paths are in sorted order. Conflicts that are carried
+/// over unchanged from one of the input trees are not included.
+pub fn merge_trees_with_options<'a>(
+    side1_tree: &Tree,
+    base_tree: &Tree,
+    side2_tree: &Tree,
+    options: &'a MergeOptions,
+    resolver: Option<&'a mut ConflictResolver<'a>>,
+) -> Result<(TreeId, Vec<(RepoPath, EntryMergeKind)>), TreeMergeError> {
+    let mut state = MergeState::new(options);
+    state.resolver = resolver;
+    let tree_id = merge_trees_impl(&mut state, side1_tree, base_tree, side2_tree)?;
+    // The entries are visited in order, so the paths are already sorted.
+    Ok((tree_id, state.outcomes))
+}
+
+fn merge_trees_impl(
+    state: &mut MergeState,
+    side1_tree: &Tree,
+    base_tree: &Tree,
+    side2_tree: &Tree,
 ) -> Result<TreeId, TreeMergeError> {
     let store = base_tree.store();
     let dir = base_tree.dir();
@@ -557,8 +2947,19 @@ pub fn merge_trees(
             // value
         } else {
             // The two sides changed in different ways
-            let new_value =
-                merge_tree_value(store, dir, basename, maybe_base, maybe_side1, maybe_side2)?;
+            let new_value = merge_tree_value(
+                state,
+                store,
+                dir,
+                basename,
+                maybe_base,
+                maybe_side1,
+                maybe_side2,
+            )?;
+            if state.abandoned {
+                // The result is discarded, so don't bother finishing the merge
+                return Ok(side1_tree.id().clone());
+            }
             match new_value {
                 None => new_tree.remove(basename),
                 Some(value) => new_tree.set(basename.clone(), value),
@@ -568,6 +2969,46 @@ pub fn merge_trees(
     Ok(store.write_tree(dir, &new_tree)?)
 }
 
+/// Replaces the subtree at `dir` in the root tree `parent` by `new_subtree_id`
+/// and returns the ID of the new root tree. Missing directories on the way to
+/// `dir` are created, and non-directory entries in their place are replaced.
+/// If the new subtree is empty, the entry is removed instead, as are any
+/// directories that become empty as a result.
+pub fn splice_subtree(
+    parent: &Tree,
+    dir: &RepoPath,
+    new_subtree_id: TreeId,
+) -> Result<TreeId, BackendError> {
+    assert_eq!(parent.dir(), &RepoPath::root());
+    splice_subtree_at(parent, dir.components(), new_subtree_id)
+}
+
+fn splice_subtree_at(
+    tree: &Tree,
+    components: &[RepoPathComponent],
+    new_subtree_id: TreeId,
+) -> Result<TreeId, BackendError> {
+    let (name, rest) = match components.split_first() {
+        Some(split) => split,
+        None => return Ok(new_subtree_id),
+    };
+    let store = tree.store();
+    let subdir = tree.dir().join(name);
+    let sub_tree_id = match tree.value(name) {
+        Some(TreeValue::Tree(id)) => id,
+        _ => store.empty_tree_id(),
+    };
+    let sub_tree = store.get_tree(&subdir, sub_tree_id)?;
+    let new_sub_tree_id = splice_subtree_at(&sub_tree, rest, new_subtree_id)?;
+    let mut new_tree = tree.data().clone();
+    if new_sub_tree_id == *store.empty_tree_id() {
+        new_tree.remove(name);
+    } else {
+        new_tree.set(name.clone(), TreeValue::Tree(new_sub_tree_id));
+    }
+    store.write_tree(tree.dir(), &new_tree)
+}
+
 /// Returns `Some(TreeId)` if this is a directory or missing. If it's missing,
 /// we treat it as an empty tree.
 fn maybe_tree_id<'id>(
@@ -582,6 +3023,7 @@ fn maybe_tree_id<'id>(
 }
 
 fn merge_tree_value(
+    state: &mut MergeState,
     store: &Arc<Store>,
     dir: &RepoPath,
     basename: &RepoPathComponent,
@@ -602,10 +3044,13 @@ fn merge_tree_value(
     Ok(match (base_tree_id, side1_tree_id, side2_tree_id) {
         (Some(base_id), Some(side1_id), Some(side2_id)) => {
             let subdir = dir.join(basename);
+            state
+                .outcomes
+                .push((subdir.clone(), EntryMergeKind::SubtreeMerge));
             let base_tree = store.get_tree(&subdir, base_id)?;
             let side1_tree = store.get_tree(&subdir, side1_id)?;
             let side2_tree = store.get_tree(&subdir, side2_id)?;
-            let merged_tree_id = merge_trees(&side1_tree, &base_tree, &side2_tree)?;
+            let merged_tree_id = merge_trees_impl(state, &side1_tree, &base_tree, &side2_tree)?;
             if merged_tree_id == *empty_tree_id {
                 None
             } else {
@@ -633,35 +3078,383 @@ fn merge_tree_value(
             }
             let filename = dir.join(basename);
             let conflict = simplify_conflict(store, &filename, conflict)?;
-            if conflict.adds.is_empty() {
+            if conflict.is_absent() {
                 // If there are no values to add, then the path doesn't exist
+                state.outcomes.push((filename, EntryMergeKind::Trivial));
                 return Ok(None);
             }
-            if conflict.removes.is_empty() && conflict.adds.len() == 1 {
+            if conflict.is_resolved() {
                 // A single add means that the current state is that state.
+                state.outcomes.push((filename, EntryMergeKind::Trivial));
                 return Ok(Some(conflict.adds[0].value.clone()));
             }
-            if let Some((merged_content, executable)) =
-                try_resolve_file_conflict(store, &filename, &conflict)?
+            if let Some(tree_id) = try_resolve_tree_conflict(state, store, &filename, &conflict)? {
+                return Ok(if tree_id == *empty_tree_id {
+                    None
+                } else {
+                    Some(TreeValue::Tree(tree_id))
+                });
+            }
+            if state.tentative && state.options.merge_drivers.has_driver_for(&filename) {
+                return Ok(state.abandon());
+            }
+            if let Some(value) = state.options.merge_drivers.resolve(&filename, &conflict) {
+                state.outcomes.push((filename, EntryMergeKind::MergeDriver));
+                return Ok(Some(value));
+            }
+            if let Some(value) =
+                try_resolve_symlink_file_conflict(&conflict, state.options.symlink_file_policy)
             {
-                let id = store.write_file(&filename, &mut merged_content.as_slice())?;
-                Some(TreeValue::File { id, executable })
+                state
+                    .outcomes
+                    .push((filename, EntryMergeKind::SymlinkFilePolicy));
+                return Ok(Some(value));
+            }
+            if let Some(resolved) = try_resolve_file_conflict(
+                store,
+                &filename,
+                &conflict,
+                state.options.exec_bit_policy,
+                state.options.delete_handling,
+                state.options.exec_authority,
+            )? {
+                let id = store.write_file(&filename, &mut resolved.content.as_slice())?;
+                state
+                    .outcomes
+                    .push((filename, EntryMergeKind::ContentMerge));
+                Some(TreeValue::File {
+                    id,
+                    executable: resolved.executable,
+                })
             } else {
+                if state.tentative {
+                    return Ok(state.abandon());
+                }
+                if let Some(driver) = &state.options.external_merge_driver {
+                    if let Some(value) =
+                        try_resolve_with_external_driver(store, &filename, &conflict, driver)?
+                    {
+                        state
+                            .outcomes
+                            .push((filename, EntryMergeKind::ExternalMergeDriver));
+                        return Ok(Some(value));
+                    }
+                }
+                if let Some(resolver) = &mut state.resolver {
+                    match resolver(&filename, &conflict) {
+                        ConflictResolution::Keep => {}
+                        ConflictResolution::Resolve(value) => {
+                            state.outcomes.push((filename, EntryMergeKind::Resolver));
+                            return Ok(Some(value));
+                        }
+                        ConflictResolution::Delete => {
+                            state.outcomes.push((filename, EntryMergeKind::Resolver));
+                            return Ok(None);
+                        }
+                    }
+                }
                 let conflict_id = store.write_conflict(&filename, &conflict)?;
+                state.outcomes.push((filename, EntryMergeKind::Conflict));
                 Some(TreeValue::Conflict(conflict_id))
             }
         }
     })
 }
 
+/// Tries to resolve a conflict between files and symlinks by picking the single
+/// add of the kind preferred by `policy`. Returns `None` if the policy is
+/// `Conflict`, if some term is neither a file nor a symlink, if the adds are
+/// all of the same kind, or if more than one add has the preferred kind.
+fn try_resolve_symlink_file_conflict(
+    conflict: &Conflict,
+    policy: SymlinkFilePolicy,
+) -> Option<TreeValue> {
+    let is_preferred: fn(&TreeValue) -> bool = match policy {
+        SymlinkFilePolicy::Conflict => return None,
+        SymlinkFilePolicy::PreferFile => |value| matches!(value, TreeValue::File { .. }),
+        SymlinkFilePolicy::PreferSymlink => |value| matches!(value, TreeValue::Symlink(_)),
+    };
+    let all_terms_file_or_symlink = conflict
+        .removes
+        .iter()
+        .chain(&conflict.adds)
+        .all(|term| matches!(term.value, TreeValue::File { .. } | TreeValue::Symlink(_)));
+    if !all_terms_file_or_symlink {
+        return None;
+    }
+    let (preferred, other): (Vec<_>, Vec<_>) = conflict
+        .adds
+        .iter()
+        .partition(|term| is_preferred(&term.value));
+    match (preferred.as_slice(), other.is_empty()) {
+        ([term], false) => Some(term.value.clone()),
+        _ => None,
+    }
+}
+
+/// Tries to resolve a conflict where all terms are trees, as can happen after
+/// nested conflicts have been expanded, by merging the trees recursively.
+/// Returns `None` if some term isn't a tree, if the trees conflict, or if the
+/// merge needs the merge drivers or the resolver, in which case the conflict
+/// should be kept as is. The outcomes of the merge are only recorded if it
+/// succeeds.
+fn try_resolve_tree_conflict(
+    state: &mut MergeState,
+    store: &Arc<Store>,
+    path: &RepoPath,
+    conflict: &Conflict,
+) -> Result<Option<TreeId>, TreeMergeError> {
+    if !conflict.is_well_formed() {
+        return Ok(None);
+    }
+    fn tree_ids(terms: &[ConflictTerm]) -> Option<Vec<&TreeId>> {
+        terms
+            .iter()
+            .map(|term| match &term.value {
+                TreeValue::Tree(id) => Some(id),
+                _ => None,
+            })
+            .collect()
+    }
+    let (removes, adds) = match (tree_ids(&conflict.removes), tree_ids(&conflict.adds)) {
+        (Some(removes), Some(adds)) => (removes, adds),
+        _ => return Ok(None),
+    };
+
+    let parent_outcomes = std::mem::take(&mut state.outcomes);
+    let parent_tentative = std::mem::replace(&mut state.tentative, true);
+    let merge_terms = |state: &mut MergeState| -> Result<TreeId, TreeMergeError> {
+        let mut merged_tree_id = adds[0].clone();
+        for (base_id, side2_id) in removes.iter().zip(adds.iter().skip(1)) {
+            let side1_tree = store.get_tree(path, &merged_tree_id)?;
+            let base_tree = store.get_tree(path, base_id)?;
+            let side2_tree = store.get_tree(path, side2_id)?;
+            merged_tree_id = merge_trees_impl(state, &side1_tree, &base_tree, &side2_tree)?;
+            if state.abandoned {
+                break;
+            }
+        }
+        Ok(merged_tree_id)
+    };
+    let merge_result = merge_terms(state);
+    let outcomes = std::mem::replace(&mut state.outcomes, parent_outcomes);
+    state.tentative = parent_tentative;
+    let abandoned = std::mem::replace(&mut state.abandoned, false);
+    let merged_tree_id = merge_result?;
+    if abandoned {
+        return Ok(None);
+    }
+    // The path is reported before the paths in it, like for a 3-way tree
+    // merge. A merge of more than two sides decides some paths in more than
+    // one step, in which case the last step's outcome is kept.
+    state
+        .outcomes
+        .push((path.clone(), EntryMergeKind::SubtreeMerge));
+    let outcomes: BTreeMap<RepoPath, EntryMergeKind> = outcomes.into_iter().collect();
+    state.outcomes.extend(outcomes);
+    Ok(Some(merged_tree_id))
+}
+
+/// The kind of value in one term of a conflict.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ConflictTermKind {
+    File { executable: bool },
+    Symlink,
+    Tree,
+    GitSubmodule,
+    Conflict,
+}
+
+impl ConflictTermKind {
+    pub fn of(value: &TreeValue) -> Self {
+        match value {
+            TreeValue::File { executable, .. } => ConflictTermKind::File {
+                executable: *executable,
+            },
+            TreeValue::Symlink(_) => ConflictTermKind::Symlink,
+            TreeValue::Tree(_) => ConflictTermKind::Tree,
+            TreeValue::GitSubmodule(_) => ConflictTermKind::GitSubmodule,
+            TreeValue::Conflict(_) => ConflictTermKind::Conflict,
+        }
+    }
+}
+
+/// A common shape of conflict.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ConflictShape {
+    /// All terms are files, which may be possible to merge by content.
+    FileContent,
+    /// The path was removed on some side and modified on another.
+    ModifyDelete,
+    /// The path is a directory on some side and not on another.
+    FileDir,
+    /// All terms are Git submodules pointing to different commits.
+    Submodule,
+    Other,
+}
+
+/// The result of `describe_conflict()`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ConflictDescription {
+    pub removes: Vec<ConflictTermKind>,
+    pub adds: Vec<ConflictTermKind>,
+    pub shape: ConflictShape,
+}
+
+impl ConflictDescription {
+    pub fn new(conflict: &Conflict) -> Self {
+        let kinds = |terms: &[ConflictTerm]| {
+            terms
+                .iter()
+                .map(|term| ConflictTermKind::of(&term.value))
+                .collect_vec()
+        };
+        let removes = kinds(&conflict.removes);
+        let adds = kinds(&conflict.adds);
+        let all_kinds = || removes.iter().chain(&adds);
+        let shape = if adds.len() <= removes.len() {
+            // Some side doesn't have the path
+            ConflictShape::ModifyDelete
+        } else if all_kinds().all(|kind| matches!(kind, ConflictTermKind::File { .. })) {
+            ConflictShape::FileContent
+        } else if all_kinds().all(|kind| *kind == ConflictTermKind::GitSubmodule) {
+            ConflictShape::Submodule
+        } else if all_kinds().any(|kind| *kind == ConflictTermKind::Tree)
+            && all_kinds().any(|kind| *kind != ConflictTermKind::Tree)
+        {
+            ConflictShape::FileDir
+        } else {
+            ConflictShape::Other
+        };
+        ConflictDescription {
+            removes,
+            adds,
+            shape,
+        }
+    }
+}
+
+/// Reads a conflict from the store and classifies its terms.
+pub fn describe_conflict(
+    store: &Store,
+    path: &RepoPath,
+    conflict_id: &ConflictId,
+) -> Result<ConflictDescription, BackendError> {
+    let conflict = store.read_conflict(path, conflict_id)?;
+    Ok(ConflictDescription::new(&conflict))
+}
+
+/// The file terms that were merged to resolve a file conflict. Terms that
+/// cancel out are not included, and neither is the empty file that stands in
+/// for a deleted side with `DeleteHandling::TreatAsEmpty`.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct MergeProvenance {
+    pub removed_file_ids: Vec<FileId>,
+    pub added_file_ids: Vec<FileId>,
+}
+
+/// A file conflict that was resolved by merging the contents of its terms.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ResolvedFileConflict {
+    pub content: Vec<u8>,
+    pub executable: bool,
+    pub provenance: MergeProvenance,
+    pub stats: MergeStats,
+}
+
+/// The result of `preview_file_conflict_resolution()`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ConflictPreview {
+    /// The conflict would be resolved to this file.
+    Resolved(ResolvedFileConflict),
+    /// All terms are files, but they can't be merged automatically.
+    Unresolvable,
+    /// Some terms are not files.
+    NonFile,
+}
+
+/// Tries to resolve a file conflict the same way a tree merge would, but
+/// returns the result instead of writing it to the store. The result includes
+/// the file terms that were merged and how many of the differing regions
+/// merged cleanly.
+pub fn preview_file_conflict_resolution(
+    store: &Store,
+    path: &RepoPath,
+    conflict: &Conflict,
+) -> Result<ConflictPreview, TreeMergeError> {
+    let all_files = conflict
+        .removes
+        .iter()
+        .chain(&conflict.adds)
+        .all(|term| matches!(term.value, TreeValue::File { .. }));
+    if !all_files {
+        return Ok(ConflictPreview::NonFile);
+    }
+    match try_resolve_file_conflict(
+        store,
+        path,
+        conflict,
+        ExecBitPolicy::default(),
+        DeleteHandling::default(),
+        None,
+    )? {
+        Some(resolved) => Ok(ConflictPreview::Resolved(resolved)),
+        None => Ok(ConflictPreview::Unresolvable),
+    }
+}
+
 fn try_resolve_file_conflict(
     store: &Store,
     filename: &RepoPath,
     conflict: &Conflict,
-) -> Result<Option<(Vec<u8>, bool)>, TreeMergeError> {
+    exec_bit_policy: ExecBitPolicy,
+    delete_handling: DeleteHandling,
+    exec_authority: Option<ConflictSide>,
+) -> Result<Option<ResolvedFileConflict>, TreeMergeError> {
+    // The authoritative side refers to the terms as passed in, so look it up
+    // before any terms are removed.
+    let authority_executable =
+        exec_authority.and_then(|side| match resolve_conflict_side(conflict, side) {
+            Ok(TreeValue::File { executable, .. }) => Some(executable),
+            _ => None,
+        });
+
+    // The conflict is normally already simplified, but the executable bit
+    // policies count the terms, so make sure a redundant pair of terms can't
+    // skew them.
+    let mut removes = conflict.removes.clone();
+    let mut adds = conflict.adds.clone();
+    remove_canceling_terms(&mut removes, &mut adds);
+    let conflict = &Conflict { removes, adds };
+
+    // With `DeleteHandling::TreatAsEmpty`, a side that deleted the file is
+    // merged as an empty file. That side didn't change the executable bit, so
+    // the empty file gets the bases'. If the bases disagree, it's unclear what
+    // the executable bit would have been, so the conflict is kept.
+    let deleted_side_executable = if delete_handling == DeleteHandling::TreatAsEmpty
+        && conflict.num_sides() == conflict.num_bases()
+    {
+        let base_executable = conflict
+            .removes
+            .iter()
+            .map(|term| match &term.value {
+                TreeValue::File { executable, .. } => Some(*executable),
+                _ => None,
+            })
+            .dedup()
+            .exactly_one();
+        match base_executable {
+            Ok(executable) => executable,
+            Err(_) => return Ok(None),
+        }
+    } else {
+        None
+    };
+    let num_adds = conflict.num_sides() + usize::from(deleted_side_executable.is_some());
+
     // If the file was missing from any side (typically a modify/delete conflict),
     // we can't automatically merge it.
-    if conflict.adds.len() != conflict.removes.len() + 1 {
+    if num_adds != conflict.num_bases() + 1 {
         return Ok(None);
     }
 
@@ -671,6 +3464,7 @@ fn try_resolve_file_conflict(
     // be executable.
     let mut exec_delta = 0;
     let mut regular_delta = 0;
+    let mut exec_adds = 0;
     let mut removed_file_ids = vec![];
     let mut added_file_ids = vec![];
     for term in &conflict.removes {
@@ -693,6 +3487,7 @@ fn try_resolve_file_conflict(
             TreeValue::File { id, executable } => {
                 if *executable {
                     exec_delta += 1;
+                    exec_adds += 1;
                 } else {
                     regular_delta += 1;
                 }
@@ -703,150 +3498,189 @@ fn try_resolve_file_conflict(
             }
         }
     }
-    let executable = if exec_delta > 0 && regular_delta <= 0 {
-        true
+    if let Some(executable) = deleted_side_executable {
+        if executable {
+            exec_delta += 1;
+            exec_adds += 1;
+        } else {
+            regular_delta += 1;
+        }
+    }
+    let regular_adds = num_adds - exec_adds;
+    let heuristic_executable = if exec_delta > 0 && regular_delta <= 0 {
+        Some(true)
     } else if regular_delta > 0 && exec_delta <= 0 {
-        false
+        Some(false)
     } else {
+        None
+    };
+    let executable = match (authority_executable, exec_bit_policy) {
+        (Some(executable), _) => Some(executable),
+        (None, ExecBitPolicy::Heuristic) => heuristic_executable,
+        (None, ExecBitPolicy::PreferExecutable) => Some(exec_adds > 0),
+        (None, ExecBitPolicy::PreferRegular) => Some(regular_adds == 0),
+        (None, ExecBitPolicy::Majority) => match exec_adds.cmp(&regular_adds) {
+            Ordering::Greater => Some(true),
+            Ordering::Less => Some(false),
+            Ordering::Equal => heuristic_executable,
+        },
+    };
+    let executable = match executable {
+        Some(executable) => executable,
         // We're unable to determine whether the result should be executable
-        return Ok(None);
+        None => return Ok(None),
     };
     let mut removed_contents = vec![];
     let mut added_contents = vec![];
-    for file_id in removed_file_ids {
+    for file_id in &removed_file_ids {
         let mut content = vec![];
         store
-            .read_file(filename, &file_id)?
+            .read_file(filename, file_id)?
             .read_to_end(&mut content)
             .map_err(|err| TreeMergeError::ReadError {
                 source: err,
-                file_id,
+                path: filename.clone(),
+                file_id: file_id.clone(),
             })?;
         removed_contents.push(content);
     }
-    for file_id in added_file_ids {
+    for file_id in &added_file_ids {
         let mut content = vec![];
         store
-            .read_file(filename, &file_id)?
+            .read_file(filename, file_id)?
             .read_to_end(&mut content)
             .map_err(|err| TreeMergeError::ReadError {
                 source: err,
-                file_id,
+                path: filename.clone(),
+                file_id: file_id.clone(),
             })?;
         added_contents.push(content);
     }
-    let merge_result = files::merge(
+    if deleted_side_executable.is_some() {
+        added_contents.push(vec![]);
+    }
+    let (merge_result, stats) = files::merge_with_stats(
         &removed_contents.iter().map(Vec::as_slice).collect_vec(),
         &added_contents.iter().map(Vec::as_slice).collect_vec(),
     );
     match merge_result {
-        MergeResult::Resolved(merged_content) => Ok(Some((merged_content, executable))),
+        MergeResult::Resolved(content) => Ok(Some(ResolvedFileConflict {
+            content,
+            executable,
+            provenance: MergeProvenance {
+                removed_file_ids,
+                added_file_ids,
+            },
+            stats,
+        })),
         MergeResult::Conflict(_) => Ok(None),
     }
 }
 
-fn tree_value_to_conflict(
+/// Runs `driver` on a conflict between two files, with at most one base. The
+/// result is executable if the executable bit is the same on both sides, or
+/// if one side made it executable.
+fn try_resolve_with_external_driver(
     store: &Store,
-    path: &RepoPath,
-    value: TreeValue,
-) -> Result<Conflict, BackendError> {
-    match value {
-        TreeValue::Conflict(id) => {
-            let conflict = store.read_conflict(path, &id)?;
-            Ok(conflict)
+    filename: &RepoPath,
+    conflict: &Conflict,
+    driver: &ExternalMergeDriver,
+) -> Result<Option<TreeValue>, TreeMergeError> {
+    let file_term = |term: &ConflictTerm| match &term.value {
+        TreeValue::File { id, executable } => Some((id.clone(), *executable)),
+        _ => None,
+    };
+    if conflict.num_sides() != 2 || conflict.num_bases() > 1 {
+        return Ok(None);
+    }
+    let base = match conflict.removes.first().map(file_term) {
+        None => None,
+        Some(Some(base)) => Some(base),
+        Some(None) => return Ok(None),
+    };
+    let (left_id, left_executable, right_id, right_executable) =
+        match (file_term(&conflict.adds[0]), file_term(&conflict.adds[1])) {
+            (Some((left_id, left_executable)), Some((right_id, right_executable))) => {
+                (left_id, left_executable, right_id, right_executable)
+            }
+            _ => return Ok(None),
+        };
+    let executable = match &base {
+        Some((_, base_executable)) if left_executable == *base_executable => right_executable,
+        Some(_) => left_executable,
+        None if left_executable == right_executable => left_executable,
+        None => return Ok(None),
+    };
+    let base_content = match &base {
+        Some((base_id, _)) => read_file_contents(store, filename, base_id)?,
+        None => vec![],
+    };
+    let left_content = read_file_contents(store, filename, &left_id)?;
+    let right_content = read_file_contents(store, filename, &right_id)?;
+    match driver.merge(filename, &base_content, &left_content, &right_content)? {
+        ExternalMergeResult::Resolved(content) => {
+            let id = store.write_file(filename, &mut content.as_slice())?;
+            Ok(Some(TreeValue::File { id, executable }))
         }
-        other => Ok(Conflict {
-            removes: vec![],
-            adds: vec![ConflictTerm { value: other }],
-        }),
+        ExternalMergeResult::Conflict => Ok(None),
     }
 }
 
-fn simplify_conflict(
+/// Simplifies `conflict` and writes the result to the store. Use this instead
+/// of `Store::write_conflict()` for conflicts built outside of a merge, which
+/// may contain nested conflicts or canceling terms, so the same logical
+/// conflict is always stored with the same id.
+pub fn write_simplified_conflict(
     store: &Store,
     path: &RepoPath,
     conflict: Conflict,
-) -> Result<Conflict, BackendError> {
-    // Important cases to simplify:
-    //
-    // D
-    // |
-    // B C
-    // |/
-    // A
-    //
-    // 1. rebase C to B, then back to A => there should be no conflict
-    // 2. rebase C to B, then to D => the conflict should not mention B
-    // 3. rebase B to C and D to B', then resolve the conflict in B' and rebase D'
-    // on top =>    the conflict should be between B'', B, and D; it should not
-    // mention the conflict in B'
-
-    // Case 1 above:
-    // After first rebase, the conflict is {+B-A+C}. After rebasing back,
-    // the unsimplified conflict is {+A-B+{+B-A+C}}. Since the
-    // inner conflict is positive, we can simply move it into the outer conflict. We
-    // thus get {+A-B+B-A+C}, which we can then simplify to just C (because {+C} ==
-    // C).
-    //
-    // Case 2 above:
-    // After first rebase, the conflict is {+B-A+C}. After rebasing to D,
-    // the unsimplified conflict is {+D-C+{+B-A+C}}. As in the
-    // previous case, the inner conflict can be moved into the outer one. We then
-    // get {+D-C+B-A+C}. That can be simplified to
-    // {+D+B-A}, which is the desired conflict.
-    //
-    // Case 3 above:
-    // TODO: describe this case
-
-    // First expand any diffs with nested conflicts.
-    let mut new_removes = vec![];
-    let mut new_adds = vec![];
-    for term in conflict.adds {
-        match term.value {
-            TreeValue::Conflict(_) => {
-                let conflict = tree_value_to_conflict(store, path, term.value)?;
-                new_removes.extend_from_slice(&conflict.removes);
-                new_adds.extend_from_slice(&conflict.adds);
-            }
-            _ => {
-                new_adds.push(term);
-            }
-        }
-    }
-    for term in conflict.removes {
-        match term.value {
-            TreeValue::Conflict(_) => {
-                let conflict = tree_value_to_conflict(store, path, term.value)?;
-                new_removes.extend_from_slice(&conflict.adds);
-                new_adds.extend_from_slice(&conflict.removes);
-            }
-            _ => {
-                new_removes.push(term);
-            }
-        }
-    }
+) -> Result<ConflictId, BackendError> {
+    let conflict = simplify_conflict(store, path, conflict)?;
+    store.write_conflict(path, &conflict)
+}
 
-    // Remove pairs of entries that match in the removes and adds.
-    let mut add_index = 0;
-    while add_index < new_adds.len() {
-        let add = &new_adds[add_index];
-        add_index += 1;
-        for (remove_index, remove) in new_removes.iter().enumerate() {
-            if remove.value == add.value {
-                new_removes.remove(remove_index);
-                add_index -= 1;
-                new_adds.remove(add_index);
-                break;
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SymlinkId;
+
+    #[test]
+    fn test_split_entry_diff() {
+        let path = RepoPath::from_internal_string("file");
+        let file = TreeValue::Symlink(SymlinkId::new(vec![1]));
+        let tree = TreeValue::Tree(TreeId::new(vec![2]));
+        assert_eq!(
+            split_entry_diff(&path, Some(&file), None),
+            (Some(Diff::Removed(&file)), None)
+        );
+        // A file replaced by a directory is removed before the directory's
+        // contents are added, and the other way around for the opposite
+        assert_eq!(
+            split_entry_diff(&path, Some(&file), Some(&tree)),
+            (Some(Diff::Removed(&file)), None)
+        );
+        assert_eq!(
+            split_entry_diff(&path, Some(&tree), Some(&file)),
+            (None, Some(Diff::Added(&file)))
+        );
+        assert_eq!(split_entry_diff(&path, Some(&tree), None), (None, None));
     }
 
-    // TODO: We should probably remove duplicate entries here too. So if we have
-    // {+A+A}, that would become just {+A}. Similarly {+B-A+B} would be just
-    // {+B-A}.
+    // TreeEntryDiffIterator never yields an entry that's missing on both sides,
+    // so this can only be tested on the helper directly
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "unexpected diff")]
+    fn test_split_entry_diff_missing_on_both_sides() {
+        split_entry_diff(&RepoPath::from_internal_string("file"), None, None);
+    }
 
-    Ok(Conflict {
-        adds: new_adds,
-        removes: new_removes,
-    })
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn test_split_entry_diff_missing_on_both_sides() {
+        assert_eq!(
+            split_entry_diff(&RepoPath::from_internal_string("file"), None, None),
+            (None, None)
+        );
+    }
 }
@@ -16,7 +16,7 @@ use std::collections::{BTreeMap, HashSet};
 use std::sync::Arc;
 
 use crate::backend;
-use crate::backend::{TreeId, TreeValue};
+use crate::backend::{BackendError, TreeId, TreeValue};
 use crate::repo_path::{RepoPath, RepoPathJoin};
 use crate::store::Store;
 use crate::tree::Tree;
@@ -60,10 +60,14 @@ impl TreeBuilder {
         self.overrides.insert(path, Override::Tombstone);
     }
 
-    pub fn write_tree(mut self) -> TreeId {
-        let mut trees_to_write = self.get_base_trees();
+    /// Writes the trees affected by the overrides and returns the ID of the new
+    /// root tree. Missing parent directories are created (replacing any
+    /// non-directory entry in their place), and directories left empty are
+    /// removed.
+    pub fn write_tree(mut self) -> Result<TreeId, BackendError> {
+        let mut trees_to_write = self.get_base_trees()?;
         if trees_to_write.is_empty() {
-            return self.base_tree_id;
+            return Ok(self.base_tree_id);
         }
 
         // Update entries in parent trees for file overrides
@@ -100,27 +104,27 @@ impl TreeBuilder {
                     if tree.is_empty() {
                         parent_tree.remove(basename);
                     } else {
-                        let tree_id = store.write_tree(&dir, &tree).unwrap();
+                        let tree_id = store.write_tree(&dir, &tree)?;
                         parent_tree.set(basename.clone(), TreeValue::Tree(tree_id));
                     }
                 } else {
                     // We're writing the root tree. Write it even if empty. Return its id.
-                    return store.write_tree(&dir, &tree).unwrap();
+                    return store.write_tree(&dir, &tree);
                 }
             }
         }
     }
 
-    fn get_base_trees(&mut self) -> BTreeMap<RepoPath, backend::Tree> {
+    fn get_base_trees(&mut self) -> Result<BTreeMap<RepoPath, backend::Tree>, BackendError> {
         let mut tree_cache = BTreeMap::new();
         let mut base_trees = BTreeMap::new();
         let store = self.store.clone();
 
-        let mut populate_trees = |dir: &RepoPath| {
+        let mut populate_trees = |dir: &RepoPath| -> Result<(), BackendError> {
             let mut current_dir = RepoPath::root();
 
             if !tree_cache.contains_key(&current_dir) {
-                let tree = store.get_tree(&current_dir, &self.base_tree_id).unwrap();
+                let tree = store.get_tree(&current_dir, &self.base_tree_id)?;
                 let store_tree = tree.data().clone();
                 tree_cache.insert(current_dir.clone(), tree);
                 base_trees.insert(current_dir.clone(), store_tree);
@@ -131,7 +135,7 @@ impl TreeBuilder {
                 let current_tree = tree_cache.get(&current_dir).unwrap();
                 if !tree_cache.contains_key(&next_dir) {
                     let tree = current_tree
-                        .sub_tree(component)
+                        .sub_tree(component)?
                         .unwrap_or_else(|| Tree::null(self.store.clone(), next_dir.clone()));
                     let store_tree = tree.data().clone();
                     tree_cache.insert(next_dir.clone(), tree);
@@ -139,13 +143,14 @@ impl TreeBuilder {
                 }
                 current_dir = next_dir;
             }
+            Ok(())
         };
         for path in self.overrides.keys() {
             if let Some(parent) = path.parent() {
-                populate_trees(&parent);
+                populate_trees(&parent)?;
             }
         }
 
-        base_trees
+        Ok(base_trees)
     }
 }
@@ -558,7 +558,7 @@ impl TreeState {
             tree_builder.remove(file.clone());
         }
         let changed = tree_builder.has_overrides();
-        self.tree_id = tree_builder.write_tree();
+        self.tree_id = tree_builder.write_tree()?;
         Ok(changed)
     }
 
@@ -956,7 +956,8 @@ impl TreeState {
             Ok(())
         };
 
-        for (path, diff) in old_tree.diff(new_tree, matcher) {
+        for item in old_tree.diff(new_tree, matcher) {
+            let (path, diff) = item?;
             apply_diff(path, diff).or_else(&mut handle_error)?;
         }
         Ok(stats)
@@ -973,7 +974,8 @@ impl TreeState {
                 other => ResetError::InternalBackendError(other),
             })?;
 
-        for (path, diff) in old_tree.diff(new_tree, self.sparse_matcher().as_ref()) {
+        for item in old_tree.diff(new_tree, self.sparse_matcher().as_ref()) {
+            let (path, diff) = item?;
             match diff {
                 Diff::Removed(_before) => {
                     self.file_states.remove(&path);
@@ -86,7 +86,8 @@ fn test_initial(use_git: bool) {
         store
             .root_commit()
             .tree()
-            .diff_summary(&commit.tree(), &EverythingMatcher),
+            .diff_summary(&commit.tree(), &EverythingMatcher)
+            .unwrap(),
         DiffSummary {
             modified: vec![],
             added: vec![dir_file_path, root_file_path],
@@ -169,7 +170,8 @@ fn test_rewrite(use_git: bool) {
         store
             .root_commit()
             .tree()
-            .diff_summary(&rewritten_commit.tree(), &EverythingMatcher),
+            .diff_summary(&rewritten_commit.tree(), &EverythingMatcher)
+            .unwrap(),
         DiffSummary {
             modified: vec![],
             added: vec![dir_file_path.clone(), root_file_path],
@@ -179,7 +181,8 @@ fn test_rewrite(use_git: bool) {
     assert_eq!(
         initial_commit
             .tree()
-            .diff_summary(&rewritten_commit.tree(), &EverythingMatcher),
+            .diff_summary(&rewritten_commit.tree(), &EverythingMatcher)
+            .unwrap(),
         DiffSummary {
             modified: vec![dir_file_path],
             added: vec![],
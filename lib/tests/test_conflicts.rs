@@ -12,8 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use jujutsu_lib::backend::{Conflict, ConflictTerm, FileId, TreeValue};
-use jujutsu_lib::conflicts::{materialize_conflict, parse_conflict, update_conflict_from_content};
+use assert_matches::assert_matches;
+use jujutsu_lib::backend::{Conflict, ConflictTerm, FileId, ObjectId, TreeValue};
+use jujutsu_lib::conflicts::{
+    choose_conflict_marker_len, materialize_conflict, materialize_merge_result_with_marker_len,
+    parse_conflict, parse_conflict_with_marker_len, resolve_conflict_side,
+    update_conflict_from_content, ConflictBuildError, ConflictBuilder, ConflictSide,
+    ConflictSideError, MIN_CONFLICT_MARKER_LEN,
+};
+use jujutsu_lib::files::{ConflictHunk, MergeHunk};
 use jujutsu_lib::repo::Repo;
 use jujutsu_lib::repo_path::RepoPath;
 use jujutsu_lib::store::Store;
@@ -687,8 +694,339 @@ fn test_update_conflict_from_content() {
     )
 }
 
+#[test]
+fn test_choose_conflict_marker_len() {
+    let marker_len = |adds: &[&str]| {
+        choose_conflict_marker_len(&ConflictHunk {
+            removes: vec![b"base\n".to_vec()],
+            adds: adds.iter().map(|add| add.as_bytes().to_vec()).collect(),
+        })
+    };
+    assert_eq!(marker_len(&["a\n", "b\n"]), MIN_CONFLICT_MARKER_LEN);
+    // Lines that only partly look like markers don't matter
+    assert_eq!(marker_len(&["<<<<<<< a\n", "b\n"]), MIN_CONFLICT_MARKER_LEN);
+    assert_eq!(marker_len(&["<<<<<\n", "b\n"]), MIN_CONFLICT_MARKER_LEN);
+    // Markers are longer than marker-like lines
+    assert_eq!(marker_len(&["<<<<<<<\n", "b\n"]), 8);
+    assert_eq!(marker_len(&["a\n", ">>>>>>>>>>"]), 11);
+    // A "-" or "+" line becomes one longer when prefixed in a diff
+    assert_eq!(marker_len(&["------\n", "b\n"]), 8);
+    assert_eq!(marker_len(&["a\n", "+++++++\n"]), 9);
+}
+
+#[test]
+fn test_materialize_conflict_with_marker_like_lines() {
+    let test_repo = TestRepo::init(false);
+    let store = test_repo.repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let base_id = testutils::write_file(store, &path, "line 1\nline 2\n<<<<<<<\n");
+    let left_id = testutils::write_file(store, &path, "<<<<<<<\nleft\n<<<<<<<\n");
+    let right_id = testutils::write_file(store, &path, "right\n>>>>>>>\n<<<<<<<\n");
+    let conflict = Conflict {
+        removes: vec![file_conflict_term(&base_id)],
+        adds: vec![file_conflict_term(&left_id), file_conflict_term(&right_id)],
+    };
+    let materialized = materialize_conflict_string(store, &path, &conflict);
+    insta::assert_snapshot!(materialized, @r###"
+    <<<<<<<<
+    %%%%%%%%
+    -line 1
+    -line 2
+    ++++++++
+    right
+    >>>>>>>
+    >>>>>>>>
+    <<<<<<<
+    left
+    <<<<<<<
+    "###);
+
+    // The content round-trips with markers of the same length
+    assert_eq!(
+        parse_conflict_with_marker_len(materialized.as_bytes(), 1, 2, 8),
+        Some(vec![
+            MergeHunk::Conflict(ConflictHunk {
+                removes: vec![b"line 1\nline 2\n".to_vec()],
+                adds: vec![b"".to_vec(), b"right\n>>>>>>>\n".to_vec()],
+            }),
+            MergeHunk::Resolved(b"<<<<<<<\nleft\n<<<<<<<\n".to_vec()),
+        ])
+    );
+    // Markers of the default length are treated as content
+    assert_eq!(parse_conflict(materialized.as_bytes(), 1, 2), None);
+
+    // update_conflict_from_content() parses with the longer markers too
+    let conflict_id = store.write_conflict(&path, &conflict).unwrap();
+    let result =
+        update_conflict_from_content(store, &path, &conflict_id, materialized.as_bytes()).unwrap();
+    assert_eq!(result, Some(conflict_id.clone()));
+    let edited = materialized.replace("right", "edited");
+    let result =
+        update_conflict_from_content(store, &path, &conflict_id, edited.as_bytes()).unwrap();
+    let new_conflict = store.read_conflict(&path, &result.unwrap()).unwrap();
+    // The resolved hunk is added to each side, so the base and right side
+    // gain the left side's addition
+    let new_base_id =
+        testutils::write_file(store, &path, "line 1\nline 2\n<<<<<<<\nleft\n<<<<<<<\n");
+    let edited_right_id =
+        testutils::write_file(store, &path, "edited\n>>>>>>>\n<<<<<<<\nleft\n<<<<<<<\n");
+    assert_eq!(
+        new_conflict,
+        Conflict {
+            removes: vec![file_conflict_term(&new_base_id)],
+            adds: vec![
+                file_conflict_term(&left_id),
+                file_conflict_term(&edited_right_id)
+            ],
+        }
+    );
+
+    // An explicit marker length can also be requested
+    let single_hunk = ConflictHunk {
+        removes: vec![b"a\n".to_vec()],
+        adds: vec![b"b\n".to_vec(), b"c\n".to_vec()],
+    };
+    let mut output = vec![];
+    materialize_merge_result_with_marker_len(&single_hunk, 10, &mut output).unwrap();
+    insta::assert_snapshot!(String::from_utf8(output.clone()).unwrap(), @r###"
+    <<<<<<<<<<
+    %%%%%%%%%%
+    -a
+    +b
+    ++++++++++
+    c
+    >>>>>>>>>>
+    "###);
+    assert_eq!(
+        parse_conflict_with_marker_len(&output, 1, 2, 10),
+        Some(vec![MergeHunk::Conflict(single_hunk)])
+    );
+}
+
 fn materialize_conflict_string(store: &Store, path: &RepoPath, conflict: &Conflict) -> String {
     let mut result: Vec<u8> = vec![];
     materialize_conflict(store, path, conflict, &mut result).unwrap();
     String::from_utf8(result).unwrap()
 }
+
+#[test]
+fn test_resolve_conflict_side() {
+    let test_repo = TestRepo::init(false);
+    let store = test_repo.repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let base_id = testutils::write_file(store, &path, "base\n");
+    let left_id = testutils::write_file(store, &path, "left\n");
+    let right_id = testutils::write_file(store, &path, "right\n");
+    let conflict = Conflict {
+        removes: vec![file_conflict_term(&base_id)],
+        adds: vec![file_conflict_term(&left_id), file_conflict_term(&right_id)],
+    };
+    let file_value = |id: &FileId| TreeValue::File {
+        id: id.clone(),
+        executable: false,
+    };
+
+    assert_eq!(
+        resolve_conflict_side(&conflict, ConflictSide::Base(0)),
+        Ok(file_value(&base_id))
+    );
+    assert_eq!(
+        resolve_conflict_side(&conflict, ConflictSide::Add(0)),
+        Ok(file_value(&left_id))
+    );
+    assert_eq!(
+        resolve_conflict_side(&conflict, ConflictSide::Add(1)),
+        Ok(file_value(&right_id))
+    );
+    assert_eq!(
+        resolve_conflict_side(&conflict, ConflictSide::Base(1)),
+        Err(ConflictSideError {
+            side: ConflictSide::Base(1)
+        })
+    );
+    assert_eq!(
+        resolve_conflict_side(&conflict, ConflictSide::Add(2)),
+        Err(ConflictSideError {
+            side: ConflictSide::Add(2)
+        })
+    );
+}
+
+#[test]
+fn test_conflict_canonicalize() {
+    let base1_id = FileId::from_hex("01");
+    let base2_id = FileId::from_hex("02");
+    let left_id = FileId::from_hex("03");
+    let middle_id = FileId::from_hex("04");
+    let right_id = FileId::from_hex("05");
+    let conflict1 = Conflict {
+        removes: vec![file_conflict_term(&base1_id), file_conflict_term(&base2_id)],
+        adds: vec![
+            file_conflict_term(&left_id),
+            file_conflict_term(&middle_id),
+            file_conflict_term(&right_id),
+        ],
+    };
+    let conflict2 = Conflict {
+        removes: vec![file_conflict_term(&base2_id), file_conflict_term(&base1_id)],
+        adds: vec![
+            file_conflict_term(&right_id),
+            file_conflict_term(&left_id),
+            file_conflict_term(&middle_id),
+        ],
+    };
+    assert_ne!(conflict1, conflict2);
+    assert_eq!(conflict1.canonicalize(), conflict2.canonicalize());
+    assert_eq!(conflict1.canonicalize(), conflict1);
+    assert!(conflict1.content_eq(&conflict2));
+
+    // A term moved from the adds to the removes is a different conflict
+    let conflict3 = Conflict {
+        removes: vec![file_conflict_term(&base1_id), file_conflict_term(&right_id)],
+        adds: vec![
+            file_conflict_term(&left_id),
+            file_conflict_term(&middle_id),
+            file_conflict_term(&base2_id),
+        ],
+    };
+    assert!(!conflict1.content_eq(&conflict3));
+}
+
+#[test]
+fn test_conflict_builder() {
+    let test_repo = TestRepo::init(false);
+    let store = test_repo.repo.store();
+    let path = RepoPath::from_internal_string("file");
+
+    let file_value = |file_id: &FileId| file_conflict_term(file_id).value;
+    let base_id = FileId::from_hex("01");
+    let left_id = FileId::from_hex("02");
+    let right_id = FileId::from_hex("03");
+
+    // Well-formed
+    let conflict = ConflictBuilder::new()
+        .remove(file_value(&base_id))
+        .add(file_value(&left_id))
+        .add(file_value(&right_id))
+        .build()
+        .unwrap();
+    assert_eq!(
+        conflict,
+        Conflict {
+            removes: vec![file_conflict_term(&base_id)],
+            adds: vec![file_conflict_term(&left_id), file_conflict_term(&right_id)],
+        }
+    );
+    assert!(conflict.is_well_formed());
+    let conflict = ConflictBuilder::new()
+        .add(file_value(&left_id))
+        .build()
+        .unwrap();
+    assert!(conflict.is_well_formed());
+
+    // Malformed
+    assert_matches!(
+        ConflictBuilder::new().build(),
+        Err(ConflictBuildError::Malformed {
+            adds: 0,
+            removes: 0
+        })
+    );
+    assert_matches!(
+        ConflictBuilder::new()
+            .add(file_value(&left_id))
+            .add(file_value(&right_id))
+            .build(),
+        Err(ConflictBuildError::Malformed {
+            adds: 2,
+            removes: 0
+        })
+    );
+    assert_matches!(
+        ConflictBuilder::new()
+            .remove(file_value(&base_id))
+            .add(file_value(&left_id))
+            .build(),
+        Err(ConflictBuildError::Malformed {
+            adds: 1,
+            removes: 1
+        })
+    );
+    assert!(!Conflict {
+        removes: vec![file_conflict_term(&base_id)],
+        adds: vec![],
+    }
+    .is_well_formed());
+
+    // Simplified
+    let conflict = ConflictBuilder::new()
+        .remove(file_value(&base_id))
+        .add(file_value(&left_id))
+        .add(file_value(&base_id))
+        .build_simplified(store, &path)
+        .unwrap();
+    assert_eq!(
+        conflict,
+        Conflict {
+            removes: vec![],
+            adds: vec![file_conflict_term(&left_id)],
+        }
+    );
+    assert_matches!(
+        ConflictBuilder::new()
+            .remove(file_value(&base_id))
+            .build_simplified(store, &path),
+        Err(ConflictBuildError::Malformed {
+            adds: 0,
+            removes: 1
+        })
+    );
+}
+
+#[test]
+fn test_conflict_shape_queries() {
+    let base_id = FileId::from_hex("01");
+    let left_id = FileId::from_hex("02");
+    let right_id = FileId::from_hex("03");
+
+    // Three-way conflict
+    let conflict = Conflict {
+        removes: vec![file_conflict_term(&base_id)],
+        adds: vec![file_conflict_term(&left_id), file_conflict_term(&right_id)],
+    };
+    assert_eq!(conflict.num_sides(), 2);
+    assert_eq!(conflict.num_bases(), 1);
+    assert!(!conflict.is_resolved());
+    assert!(!conflict.is_absent());
+
+    // A single value
+    let conflict = Conflict {
+        removes: vec![],
+        adds: vec![file_conflict_term(&left_id)],
+    };
+    assert_eq!(conflict.num_sides(), 1);
+    assert_eq!(conflict.num_bases(), 0);
+    assert!(conflict.is_resolved());
+    assert!(!conflict.is_absent());
+
+    // Modify/delete conflict
+    let conflict = Conflict {
+        removes: vec![file_conflict_term(&base_id)],
+        adds: vec![file_conflict_term(&left_id)],
+    };
+    assert!(!conflict.is_resolved());
+    assert!(!conflict.is_absent());
+
+    // Nothing added
+    let conflict = Conflict {
+        removes: vec![file_conflict_term(&base_id)],
+        adds: vec![],
+    };
+    assert_eq!(conflict.num_sides(), 0);
+    assert!(!conflict.is_resolved());
+    assert!(conflict.is_absent());
+    assert!(Conflict::default().is_absent());
+    assert!(!Conflict::default().is_resolved());
+}
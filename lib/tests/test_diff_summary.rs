@@ -14,7 +14,7 @@
 
 use jujutsu_lib::matchers::{EverythingMatcher, FilesMatcher};
 use jujutsu_lib::repo_path::RepoPath;
-use jujutsu_lib::tree::DiffSummary;
+use jujutsu_lib::tree::{Diff, DiffSummary};
 use test_case::test_case;
 use testutils::TestRepo;
 
@@ -48,7 +48,7 @@ fn test_types(use_git: bool) {
     );
 
     assert_eq!(
-        tree1.diff_summary(&tree2, &EverythingMatcher),
+        tree1.diff_summary(&tree2, &EverythingMatcher).unwrap(),
         DiffSummary {
             modified: vec![modified_path],
             added: vec![added_path],
@@ -70,7 +70,7 @@ fn test_tree_file_transition(use_git: bool) {
     let tree2 = testutils::create_tree(repo, &[(&dir_path, "contents")]);
 
     assert_eq!(
-        tree1.diff_summary(&tree2, &EverythingMatcher),
+        tree1.diff_summary(&tree2, &EverythingMatcher).unwrap(),
         DiffSummary {
             modified: vec![],
             added: vec![dir_path.clone()],
@@ -78,7 +78,7 @@ fn test_tree_file_transition(use_git: bool) {
         }
     );
     assert_eq!(
-        tree2.diff_summary(&tree1, &EverythingMatcher),
+        tree2.diff_summary(&tree1, &EverythingMatcher).unwrap(),
         DiffSummary {
             modified: vec![],
             added: vec![dir_file_path],
@@ -128,7 +128,7 @@ fn test_sorting(use_git: bool) {
     );
 
     assert_eq!(
-        tree1.diff_summary(&tree2, &EverythingMatcher),
+        tree1.diff_summary(&tree2, &EverythingMatcher).unwrap(),
         DiffSummary {
             modified: vec![a_path.clone(), f_a_path.clone(), f_f_a_path.clone()],
             added: vec![
@@ -143,7 +143,7 @@ fn test_sorting(use_git: bool) {
         }
     );
     assert_eq!(
-        tree2.diff_summary(&tree1, &EverythingMatcher),
+        tree2.diff_summary(&tree1, &EverythingMatcher).unwrap(),
         DiffSummary {
             modified: vec![a_path, f_a_path, f_f_a_path],
             added: vec![],
@@ -166,7 +166,7 @@ fn test_matcher_dir_file_transition(use_git: bool) {
 
     let matcher = FilesMatcher::new(&[a_path.clone()]);
     assert_eq!(
-        tree1.diff_summary(&tree2, &matcher),
+        tree1.diff_summary(&tree2, &matcher).unwrap(),
         DiffSummary {
             modified: vec![],
             added: vec![],
@@ -174,7 +174,7 @@ fn test_matcher_dir_file_transition(use_git: bool) {
         }
     );
     assert_eq!(
-        tree2.diff_summary(&tree1, &matcher),
+        tree2.diff_summary(&tree1, &matcher).unwrap(),
         DiffSummary {
             modified: vec![],
             added: vec![a_path.clone()],
@@ -184,7 +184,7 @@ fn test_matcher_dir_file_transition(use_git: bool) {
 
     let matcher = FilesMatcher::new(&[a_a_path.clone()]);
     assert_eq!(
-        tree1.diff_summary(&tree2, &matcher),
+        tree1.diff_summary(&tree2, &matcher).unwrap(),
         DiffSummary {
             modified: vec![],
             added: vec![a_a_path.clone()],
@@ -192,7 +192,7 @@ fn test_matcher_dir_file_transition(use_git: bool) {
         }
     );
     assert_eq!(
-        tree2.diff_summary(&tree1, &matcher),
+        tree2.diff_summary(&tree1, &matcher).unwrap(),
         DiffSummary {
             modified: vec![],
             added: vec![],
@@ -202,7 +202,7 @@ fn test_matcher_dir_file_transition(use_git: bool) {
 
     let matcher = FilesMatcher::new(&[a_path.clone(), a_a_path.clone()]);
     assert_eq!(
-        tree1.diff_summary(&tree2, &matcher),
+        tree1.diff_summary(&tree2, &matcher).unwrap(),
         DiffSummary {
             modified: vec![],
             added: vec![a_a_path.clone()],
@@ -210,7 +210,7 @@ fn test_matcher_dir_file_transition(use_git: bool) {
         }
     );
     assert_eq!(
-        tree2.diff_summary(&tree1, &matcher),
+        tree2.diff_summary(&tree1, &matcher).unwrap(),
         DiffSummary {
             modified: vec![],
             added: vec![a_path],
@@ -247,7 +247,7 @@ fn test_matcher_normal_cases(use_git: bool) {
 
     let matcher = FilesMatcher::new(&[a_path.clone(), z_path.clone()]);
     assert_eq!(
-        tree1.diff_summary(&tree2, &matcher),
+        tree1.diff_summary(&tree2, &matcher).unwrap(),
         DiffSummary {
             modified: vec![a_path.clone()],
             added: vec![z_path.clone()],
@@ -255,7 +255,7 @@ fn test_matcher_normal_cases(use_git: bool) {
         }
     );
     assert_eq!(
-        tree2.diff_summary(&tree1, &matcher),
+        tree2.diff_summary(&tree1, &matcher).unwrap(),
         DiffSummary {
             modified: vec![a_path],
             added: vec![],
@@ -265,7 +265,7 @@ fn test_matcher_normal_cases(use_git: bool) {
 
     let matcher = FilesMatcher::new(&[dir1_a_path.clone(), dir2_b_path.clone()]);
     assert_eq!(
-        tree1.diff_summary(&tree2, &matcher),
+        tree1.diff_summary(&tree2, &matcher).unwrap(),
         DiffSummary {
             modified: vec![dir1_a_path.clone()],
             added: vec![dir2_b_path.clone()],
@@ -273,7 +273,7 @@ fn test_matcher_normal_cases(use_git: bool) {
         }
     );
     assert_eq!(
-        tree2.diff_summary(&tree1, &matcher),
+        tree2.diff_summary(&tree1, &matcher).unwrap(),
         DiffSummary {
             modified: vec![dir1_a_path],
             added: vec![],
@@ -281,3 +281,97 @@ fn test_matcher_normal_cases(use_git: bool) {
         }
     );
 }
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_buckets(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let clean_path = RepoPath::from_internal_string("clean");
+    let modified_path = RepoPath::from_internal_string("modified");
+    let added_path = RepoPath::from_internal_string("added");
+    let removed_path = RepoPath::from_internal_string("removed");
+    let dir_file_path = RepoPath::from_internal_string("dir/file");
+    let dir_path = RepoPath::from_internal_string("dir");
+
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&clean_path, "clean"),
+            (&modified_path, "contents before"),
+            (&removed_path, "removed contents"),
+            (&dir_file_path, "contents"),
+        ],
+    );
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&clean_path, "clean"),
+            (&modified_path, "contents after"),
+            (&added_path, "added contents"),
+            (&dir_path, "contents"),
+        ],
+    );
+
+    // The values are kept
+    let buckets = tree1.diff_buckets(&tree2, &EverythingMatcher).unwrap();
+    assert_eq!(
+        buckets.modified(),
+        [(
+            modified_path.clone(),
+            Diff::Modified(
+                tree1.path_value(&modified_path).unwrap().unwrap(),
+                tree2.path_value(&modified_path).unwrap().unwrap()
+            )
+        )]
+    );
+    assert_eq!(
+        buckets.added(),
+        [
+            (
+                added_path.clone(),
+                Diff::Added(tree2.path_value(&added_path).unwrap().unwrap())
+            ),
+            (
+                dir_path.clone(),
+                Diff::Added(tree2.path_value(&dir_path).unwrap().unwrap())
+            ),
+        ]
+    );
+    assert_eq!(
+        buckets.removed(),
+        [
+            (
+                dir_file_path.clone(),
+                Diff::Removed(tree1.path_value(&dir_file_path).unwrap().unwrap())
+            ),
+            (
+                removed_path.clone(),
+                Diff::Removed(tree1.path_value(&removed_path).unwrap().unwrap())
+            ),
+        ]
+    );
+
+    // Same paths as the summary
+    let summary = tree1.diff_summary(&tree2, &EverythingMatcher).unwrap();
+    let paths = |bucket: &[(RepoPath, Diff<_>)]| {
+        bucket
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(paths(buckets.modified()), summary.modified);
+    assert_eq!(paths(buckets.added()), summary.added);
+    assert_eq!(paths(buckets.removed()), summary.removed);
+
+    assert!(tree1
+        .diff_buckets(&tree1, &EverythingMatcher)
+        .unwrap()
+        .is_empty());
+    let matcher = FilesMatcher::new(&[added_path.clone()]);
+    let buckets = tree1.diff_buckets(&tree2, &matcher).unwrap();
+    assert_eq!(paths(buckets.added()), vec![added_path]);
+    assert!(buckets.modified().is_empty());
+    assert!(buckets.removed().is_empty());
+}
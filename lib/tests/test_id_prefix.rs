@@ -14,12 +14,13 @@
 
 use itertools::Itertools;
 use jujutsu_lib::backend::{CommitId, MillisSinceEpoch, ObjectId, Signature, Timestamp};
-use jujutsu_lib::id_prefix::IdPrefixContext;
+use jujutsu_lib::id_prefix::{read_change_ids_parallel, IdIndex, IdPrefixContext, PrefixScope};
 use jujutsu_lib::index::HexPrefix;
 use jujutsu_lib::index::PrefixResolution::{AmbiguousMatch, NoMatch, SingleMatch};
-use jujutsu_lib::repo::Repo;
+use jujutsu_lib::local_backend::LocalBackend;
+use jujutsu_lib::repo::{Repo, RepoLoader, StoreFactories};
 use jujutsu_lib::revset::RevsetExpression;
-use testutils::TestRepo;
+use testutils::{CountingBackend, TestRepo};
 
 #[test]
 fn test_id_prefix() {
@@ -180,6 +181,50 @@ fn test_id_prefix() {
         NoMatch
     );
 
+    // With a minimum prefix length, which only affects the reported lengths
+    // ---------------------------------------------------------------------------------------------
+    let c3 = IdPrefixContext::default().with_min_prefix_len(3);
+    assert_eq!(
+        c3.shortest_commit_prefix_len(repo.as_ref(), commits[2].id()),
+        3
+    );
+    assert_eq!(
+        c3.shortest_commit_prefix_len(repo.as_ref(), commits[5].id()),
+        3
+    );
+    assert_eq!(
+        c3.resolve_commit_prefix(repo.as_ref(), &prefix("2a")),
+        SingleMatch(commits[2].id().clone())
+    );
+    assert_eq!(
+        c3.resolve_commit_prefix(repo.as_ref(), &prefix("1")),
+        SingleMatch(commits[5].id().clone())
+    );
+    assert_eq!(
+        c3.shortest_change_prefix_len(repo.as_ref(), commits[0].change_id()),
+        3
+    );
+    assert_eq!(
+        c3.resolve_change_prefix(repo.as_ref(), &prefix("78")),
+        SingleMatch(vec![commits[0].id().clone()])
+    );
+    // The true length wins if it's longer
+    let c1 = IdPrefixContext::default().with_min_prefix_len(1);
+    assert_eq!(
+        c1.shortest_commit_prefix_len(repo.as_ref(), commits[2].id()),
+        2
+    );
+    // The minimum is kept when disambiguating within a revset
+    let c3 = c3.disambiguate_within(RevsetExpression::commit(commits[2].id().clone()), None);
+    assert_eq!(
+        c3.shortest_commit_prefix_len(repo.as_ref(), commits[2].id()),
+        3
+    );
+    assert_eq!(
+        c3.resolve_commit_prefix(repo.as_ref(), &prefix("2")),
+        SingleMatch(commits[2].id().clone())
+    );
+
     // Disambiguate within a revset
     // ---------------------------------------------------------------------------------------------
     let expression =
@@ -208,6 +253,30 @@ fn test_id_prefix() {
         c.resolve_change_prefix(repo.as_ref(), &prefix("7")),
         SingleMatch(vec![commits[0].id().clone()])
     );
+    // The scope tells whether the prefix is unique in the whole repo
+    assert_eq!(
+        c.resolve_commit_prefix_scoped(repo.as_ref(), &prefix("2")),
+        (
+            SingleMatch(commits[2].id().clone()),
+            PrefixScope::DisambiguationSet
+        )
+    );
+    assert_eq!(
+        c.resolve_commit_prefix_scoped(repo.as_ref(), &prefix("2a")),
+        (SingleMatch(commits[2].id().clone()), PrefixScope::Repo)
+    );
+    assert_eq!(
+        c.resolve_commit_prefix_scoped(repo.as_ref(), &prefix("21")),
+        (SingleMatch(commits[24].id().clone()), PrefixScope::Repo)
+    );
+    assert_eq!(
+        c.resolve_commit_prefix_scoped(repo.as_ref(), &prefix("20")),
+        (NoMatch, PrefixScope::Repo)
+    );
+    assert_eq!(
+        IdPrefixContext::default().resolve_commit_prefix_scoped(repo.as_ref(), &prefix("2")),
+        (AmbiguousMatch, PrefixScope::Repo)
+    );
 
     // Single commit in revset. Length 0 is unambiguous, but we pretend 1 digit is
     // needed.
@@ -253,3 +322,248 @@ fn test_id_prefix() {
         AmbiguousMatch
     );
 }
+
+#[test]
+fn test_id_prefix_divergent_change() {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+    let root_commit_id = repo.store().root_commit_id();
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let commit1 = tx
+        .mut_repo()
+        .new_commit(
+            &settings,
+            vec![root_commit_id.clone()],
+            repo.store().empty_tree_id().clone(),
+        )
+        .write()
+        .unwrap();
+    let change_id = commit1.change_id().clone();
+    let mut create_divergent_commit = |parent_id: &CommitId, description: &str| {
+        tx.mut_repo()
+            .new_commit(
+                &settings,
+                vec![parent_id.clone()],
+                repo.store().empty_tree_id().clone(),
+            )
+            .set_change_id(change_id.clone())
+            .set_description(description)
+            .write()
+            .unwrap()
+    };
+    let commit2 = create_divergent_commit(commit1.id(), "2");
+    let commit3 = create_divergent_commit(commit2.id(), "3");
+    let repo = tx.commit();
+
+    // Commits are sorted by index position, newest first
+    let expected = SingleMatch(vec![
+        commit3.id().clone(),
+        commit2.id().clone(),
+        commit1.id().clone(),
+    ]);
+    let prefix = HexPrefix::new(&change_id.hex()[..4]).unwrap();
+    let c = IdPrefixContext::default();
+    assert_eq!(c.resolve_change_prefix(repo.as_ref(), &prefix), expected);
+
+    // Same order when resolving within a revset
+    let expression = RevsetExpression::commits(vec![
+        commit1.id().clone(),
+        commit3.id().clone(),
+        commit2.id().clone(),
+    ]);
+    let c = c.disambiguate_within(expression, None);
+    assert_eq!(c.resolve_change_prefix(repo.as_ref(), &prefix), expected);
+}
+
+#[test]
+fn test_id_prefix_divergent_changes() {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+    let root_commit_id = repo.store().root_commit_id();
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let commit1 = testutils::write_random_commit(tx.mut_repo(), &settings);
+    let commit2 = testutils::write_random_commit(tx.mut_repo(), &settings);
+    let divergent_commit = tx
+        .mut_repo()
+        .new_commit(
+            &settings,
+            vec![root_commit_id.clone()],
+            repo.store().empty_tree_id().clone(),
+        )
+        .set_change_id(commit1.change_id().clone())
+        .set_description("divergent")
+        .write()
+        .unwrap();
+    let repo = tx.commit();
+
+    // Nothing is divergent without a disambiguation revset
+    let c = IdPrefixContext::default();
+    assert_eq!(c.divergent_changes(repo.as_ref()), vec![]);
+
+    let expression = RevsetExpression::commits(vec![
+        commit1.id().clone(),
+        commit2.id().clone(),
+        divergent_commit.id().clone(),
+    ]);
+    let c = c.disambiguate_within(expression, None);
+    assert_eq!(
+        c.divergent_changes(repo.as_ref()),
+        vec![(
+            commit1.change_id().clone(),
+            vec![divergent_commit.id().clone(), commit1.id().clone()]
+        )]
+    );
+
+    // Only commits in the revset count
+    let expression = RevsetExpression::commits(vec![commit1.id().clone(), commit2.id().clone()]);
+    let c = IdPrefixContext::default().disambiguate_within(expression, None);
+    assert_eq!(c.divergent_changes(repo.as_ref()), vec![]);
+}
+
+#[test]
+fn test_id_prefix_completion() {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let commits = (0..20)
+        .map(|_| testutils::write_random_commit(tx.mut_repo(), &settings))
+        .collect_vec();
+    let repo = tx.commit();
+
+    let commit_ids = commits
+        .iter()
+        .map(|commit| commit.id().clone())
+        .collect_vec();
+    let c = IdPrefixContext::default();
+    let hex_prefix = &commits[0].id().hex()[..1];
+    let prefix = HexPrefix::new(hex_prefix).unwrap();
+    // Nothing to complete from without a disambiguation revset
+    assert_eq!(c.complete_commit_prefix(repo.as_ref(), &prefix, 10), vec![]);
+
+    let c = c.disambiguate_within(RevsetExpression::commits(commit_ids.clone()), None);
+    let expected = commit_ids
+        .iter()
+        .filter(|id| id.hex().starts_with(hex_prefix))
+        .sorted()
+        .cloned()
+        .collect_vec();
+    assert_eq!(
+        c.complete_commit_prefix(repo.as_ref(), &prefix, 100),
+        expected
+    );
+    assert_eq!(
+        c.complete_commit_prefix(repo.as_ref(), &prefix, 1),
+        expected[..1]
+    );
+
+    let hex_prefix = &commits[0].change_id().hex()[..1];
+    let prefix = HexPrefix::new(hex_prefix).unwrap();
+    let expected = commits
+        .iter()
+        .map(|commit| commit.change_id().clone())
+        .filter(|id| id.hex().starts_with(hex_prefix))
+        .sorted()
+        .collect_vec();
+    assert_eq!(
+        c.complete_change_prefix(repo.as_ref(), &prefix, 100),
+        expected
+    );
+}
+
+#[test]
+fn test_read_change_ids_parallel() {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let commits = (0..50)
+        .map(|_| testutils::write_random_commit(tx.mut_repo(), &settings))
+        .collect_vec();
+    let repo = tx.commit();
+
+    let commit_ids = commits
+        .iter()
+        .map(|commit| commit.id().clone())
+        .collect_vec();
+    let sequential = commits
+        .iter()
+        .map(|commit| (commit.id().clone(), commit.change_id().clone()))
+        .collect_vec();
+    let parallel = read_change_ids_parallel(repo.store(), &commit_ids).unwrap();
+    assert_eq!(parallel, sequential);
+    assert_eq!(read_change_ids_parallel(repo.store(), &[]).unwrap(), vec![]);
+
+    // The indexes built from the parallel reads resolve the same way
+    let sequential_index: IdIndex<_, _> = sequential.into_iter().collect();
+    let parallel_index: IdIndex<_, _> = parallel.into_iter().collect();
+    for commit in &commits {
+        let hex = commit.change_id().hex();
+        for len in 1..=3 {
+            let prefix = HexPrefix::new(&hex[..len]).unwrap();
+            assert_eq!(
+                parallel_index.resolve_prefix(&prefix),
+                sequential_index.resolve_prefix(&prefix)
+            );
+        }
+    }
+}
+
+// Local-backend only; the counting wrapper is registered for "local" stores.
+#[test]
+fn test_id_prefix_commit_reads() {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(false);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let commits = (0..20)
+        .map(|_| testutils::write_random_commit(tx.mut_repo(), &settings))
+        .collect_vec();
+    tx.commit();
+    let commit_ids = commits
+        .iter()
+        .map(|commit| commit.id().clone())
+        .collect_vec();
+
+    // Reload the repo so its store counts the objects read from the backend
+    let mut store_factories = StoreFactories::default();
+    store_factories.add_backend(
+        "local",
+        Box::new(|store_path| {
+            Box::new(CountingBackend::new(Box::new(LocalBackend::load(
+                store_path,
+            ))))
+        }),
+    );
+    let repo = RepoLoader::init(&settings, repo.repo_path(), &store_factories)
+        .unwrap()
+        .load_at_head(&settings)
+        .unwrap();
+    let counting_backend = CountingBackend::of(repo.store());
+    let initial_reads = counting_backend.commit_reads();
+
+    // The default revset engine gets the change ids from the index, so
+    // building the disambiguation index doesn't read any commits
+    let c = IdPrefixContext::default()
+        .disambiguate_within(RevsetExpression::commits(commit_ids.clone()), None);
+    let prefix = HexPrefix::new(&commits[0].change_id().hex()).unwrap();
+    assert_eq!(
+        c.resolve_change_prefix(repo.as_ref(), &prefix),
+        SingleMatch(vec![commits[0].id().clone()])
+    );
+    assert_eq!(counting_backend.commit_reads(), initial_reads);
+
+    // The fallback reads every commit in the revset
+    read_change_ids_parallel(repo.store(), &commit_ids).unwrap();
+    assert_eq!(
+        counting_backend.commit_reads(),
+        initial_reads + commit_ids.len()
+    );
+}
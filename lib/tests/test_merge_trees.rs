@@ -12,16 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::Arc;
+
 use assert_matches::assert_matches;
 use itertools::Itertools;
-use jujutsu_lib::backend::{ConflictTerm, TreeValue};
+use jujutsu_lib::backend::{CommitId, Conflict, ConflictTerm, FileId, ObjectId, TreeValue};
+use jujutsu_lib::conflicts::ConflictSide;
+use jujutsu_lib::files::MergeStats;
+use jujutsu_lib::matchers::{EverythingMatcher, FilesMatcher, PrefixMatcher};
 use jujutsu_lib::repo::Repo;
 use jujutsu_lib::repo_path::{RepoPath, RepoPathComponent};
 use jujutsu_lib::rewrite::rebase_commit;
 use jujutsu_lib::tree;
-use jujutsu_lib::tree::Tree;
+use jujutsu_lib::tree::{
+    ConflictDescription, ConflictPreview, ConflictResolution, ConflictShape, ConflictTermKind,
+    DeleteHandling, EntryMergeKind, ExecBitPolicy, ExternalMergeDriver, ExternalMergeDriverError,
+    MergeDriverRegistry, MergeOptions, MergeProvenance, SymlinkFilePolicy, Tree, TreeMergeError,
+};
 use test_case::test_case;
-use testutils::TestRepo;
+use testutils::{CountingBackend, TestRepo};
 
 #[test_case(false ; "local backend")]
 #[test_case(true ; "git backend")]
@@ -64,7 +73,7 @@ fn test_same_type(use_git: bool) {
                 );
             }
         }
-        let tree_id = tree_builder.write_tree();
+        let tree_id = tree_builder.write_tree().unwrap();
         store.get_tree(&RepoPath::root(), &tree_id).unwrap()
     };
 
@@ -226,7 +235,7 @@ fn test_subtrees(use_git: bool) {
                 &format!("contents of {path:?}"),
             );
         }
-        let tree_id = tree_builder.write_tree();
+        let tree_id = tree_builder.write_tree().unwrap();
         store.get_tree(&RepoPath::root(), &tree_id).unwrap()
     };
 
@@ -249,7 +258,7 @@ fn test_subtrees(use_git: bool) {
 
     let merged_tree_id = tree::merge_trees(&side1_tree, &base_tree, &side2_tree).unwrap();
     let merged_tree = store.get_tree(&RepoPath::root(), &merged_tree_id).unwrap();
-    let entries = merged_tree.entries().collect_vec();
+    let entries = merged_tree.entries().map(Result::unwrap).collect_vec();
 
     let expected_tree = write_tree(vec![
         "f1",
@@ -260,7 +269,7 @@ fn test_subtrees(use_git: bool) {
         "d1/d1/d1/f1",
         "d1/d1/d1/f2",
     ]);
-    let expected_entries = expected_tree.entries().collect_vec();
+    let expected_entries = expected_tree.entries().map(Result::unwrap).collect_vec();
     assert_eq!(entries, expected_entries);
 }
 
@@ -282,7 +291,7 @@ fn test_subtree_becomes_empty(use_git: bool) {
                 &format!("contents of {path:?}"),
             );
         }
-        let tree_id = tree_builder.write_tree();
+        let tree_id = tree_builder.write_tree().unwrap();
         store.get_tree(&RepoPath::root(), &tree_id).unwrap()
     };
 
@@ -313,7 +322,7 @@ fn test_subtree_one_missing(use_git: bool) {
                 &format!("contents of {path:?}"),
             );
         }
-        let tree_id = tree_builder.write_tree();
+        let tree_id = tree_builder.write_tree().unwrap();
         store.get_tree(&RepoPath::root(), &tree_id).unwrap()
     };
 
@@ -324,8 +333,14 @@ fn test_subtree_one_missing(use_git: bool) {
     // The two sides add different trees
     let merged_tree_id = tree::merge_trees(&tree2, &tree1, &tree3).unwrap();
     let merged_tree = store.get_tree(&RepoPath::root(), &merged_tree_id).unwrap();
-    let expected_entries = write_tree(vec!["d1/f1", "d1/f2"]).entries().collect_vec();
-    assert_eq!(merged_tree.entries().collect_vec(), expected_entries);
+    let expected_entries = write_tree(vec!["d1/f1", "d1/f2"])
+        .entries()
+        .map(Result::unwrap)
+        .collect_vec();
+    assert_eq!(
+        merged_tree.entries().map(Result::unwrap).collect_vec(),
+        expected_entries
+    );
     // Same tree other way
     let merged_tree_id = tree::merge_trees(&tree3, &tree1, &tree2).unwrap();
     assert_eq!(merged_tree_id, *merged_tree.id());
@@ -333,8 +348,14 @@ fn test_subtree_one_missing(use_git: bool) {
     // One side removes, the other side modifies
     let merged_tree_id = tree::merge_trees(&tree1, &tree2, &tree3).unwrap();
     let merged_tree = store.get_tree(&RepoPath::root(), &merged_tree_id).unwrap();
-    let expected_entries = write_tree(vec!["d1/f2"]).entries().collect_vec();
-    assert_eq!(merged_tree.entries().collect_vec(), expected_entries);
+    let expected_entries = write_tree(vec!["d1/f2"])
+        .entries()
+        .map(Result::unwrap)
+        .collect_vec();
+    assert_eq!(
+        merged_tree.entries().map(Result::unwrap).collect_vec(),
+        expected_entries
+    );
     // Same tree other way
     let merged_tree_id = tree::merge_trees(&tree3, &tree2, &tree1).unwrap();
     assert_eq!(merged_tree_id, *merged_tree.id());
@@ -383,11 +404,11 @@ fn test_types(use_git: bool) {
         &RepoPath::from_internal_string("tree_normal_symlink"),
         "contents",
     );
-    let base_tree_id = base_tree_builder.write_tree();
+    let base_tree_id = base_tree_builder.write_tree().unwrap();
     let base_tree = store.get_tree(&RepoPath::root(), &base_tree_id).unwrap();
-    let side1_tree_id = side1_tree_builder.write_tree();
+    let side1_tree_id = side1_tree_builder.write_tree().unwrap();
     let side1_tree = store.get_tree(&RepoPath::root(), &side1_tree_id).unwrap();
-    let side2_tree_id = side2_tree_builder.write_tree();
+    let side2_tree_id = side2_tree_builder.write_tree().unwrap();
     let side2_tree = store.get_tree(&RepoPath::root(), &side2_tree_id).unwrap();
 
     // Created the merged tree
@@ -607,11 +628,11 @@ fn test_simplify_conflict_after_resolving_parent(use_git: bool) {
 
     // Test the setup: Both B and C should have conflicts.
     assert_matches!(
-        commit_b2.tree().path_value(&path),
+        commit_b2.tree().path_value(&path).unwrap(),
         Some(TreeValue::Conflict(_))
     );
     assert_matches!(
-        commit_c2.tree().path_value(&path),
+        commit_c2.tree().path_value(&path).unwrap(),
         Some(TreeValue::Conflict(_))
     );
 
@@ -628,7 +649,7 @@ fn test_simplify_conflict_after_resolving_parent(use_git: bool) {
     let repo = tx.commit();
 
     // The conflict should now be resolved.
-    let resolved_value = commit_c3.tree().path_value(&path);
+    let resolved_value = commit_c3.tree().path_value(&path).unwrap();
     match resolved_value {
         Some(TreeValue::File {
             id,
@@ -645,5 +666,1754 @@ fn test_simplify_conflict_after_resolving_parent(use_git: bool) {
     }
 }
 
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_write_simplified_conflict(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let store = test_repo.repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let file_term = |contents: &str| ConflictTerm {
+        value: TreeValue::File {
+            id: testutils::write_file(store, &path, contents),
+            executable: false,
+        },
+    };
+    let base_term = file_term("base");
+    let left_term = file_term("left");
+    let right_term = file_term("right");
+    let upstream_term = file_term("upstream");
+    let simplified = Conflict {
+        removes: vec![base_term.clone()],
+        adds: vec![upstream_term.clone(), left_term.clone()],
+    };
+    let simplified_id = store.write_conflict(&path, &simplified).unwrap();
+
+    // An already simplified conflict is stored as is
+    assert_eq!(
+        tree::write_simplified_conflict(store, &path, simplified.clone()).unwrap(),
+        simplified_id
+    );
+
+    // {+upstream-right+{+left-base+right}} simplifies to {+upstream+left-base}
+    let nested_id = store
+        .write_conflict(
+            &path,
+            &Conflict {
+                removes: vec![base_term.clone()],
+                adds: vec![left_term.clone(), right_term.clone()],
+            },
+        )
+        .unwrap();
+    let nested = Conflict {
+        removes: vec![right_term.clone()],
+        adds: vec![
+            upstream_term.clone(),
+            ConflictTerm {
+                value: TreeValue::Conflict(nested_id),
+            },
+        ],
+    };
+    let nested_written_id = store.write_conflict(&path, &nested).unwrap();
+    assert_ne!(nested_written_id, simplified_id);
+    assert_eq!(
+        tree::write_simplified_conflict(store, &path, nested).unwrap(),
+        simplified_id
+    );
+
+    // {+upstream+right+left-right-base} simplifies the same way
+    let canceling = Conflict {
+        removes: vec![right_term.clone(), base_term],
+        adds: vec![upstream_term, right_term, left_term],
+    };
+    assert_eq!(
+        tree::write_simplified_conflict(store, &path, canceling).unwrap(),
+        simplified_id
+    );
+}
+
 // TODO: Add tests for simplification of multi-way conflicts. Both the content
 // and the executable bit need testing.
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_merge_trees_conflict_outcomes(use_git: bool) {
+    // Tests that the conflicts produced by the merge are reported, including ones
+    // in subdirectories, and that auto-resolved paths are not.
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let clean_path = RepoPath::from_internal_string("clean");
+    let merged_path = RepoPath::from_internal_string("dir/merged");
+    let conflict_path1 = RepoPath::from_internal_string("conflict");
+    let conflict_path2 = RepoPath::from_internal_string("dir/sub/conflict");
+
+    let base_tree = testutils::create_tree(
+        repo,
+        &[
+            (&clean_path, "base"),
+            (&merged_path, "a\nb\nc\n"),
+            (&conflict_path1, "base"),
+            (&conflict_path2, "base"),
+        ],
+    );
+    let side1_tree = testutils::create_tree(
+        repo,
+        &[
+            (&clean_path, "side 1"),
+            (&merged_path, "A\nb\nc\n"),
+            (&conflict_path1, "side 1"),
+            (&conflict_path2, "side 1"),
+        ],
+    );
+    let side2_tree = testutils::create_tree(
+        repo,
+        &[
+            (&clean_path, "base"),
+            (&merged_path, "a\nb\nC\n"),
+            (&conflict_path1, "side 2"),
+            (&conflict_path2, "side 2"),
+        ],
+    );
+
+    let (merged_tree_id, outcomes) = tree::merge_trees_with_options(
+        &side1_tree,
+        &base_tree,
+        &side2_tree,
+        &MergeOptions::default(),
+        None,
+    )
+    .unwrap();
+    let conflict_paths = outcomes
+        .into_iter()
+        .filter(|(_, kind)| *kind == EntryMergeKind::Conflict)
+        .map(|(path, _)| path)
+        .collect_vec();
+    assert_eq!(
+        merged_tree_id,
+        tree::merge_trees(&side1_tree, &base_tree, &side2_tree).unwrap()
+    );
+    assert_eq!(conflict_paths, vec![conflict_path1, conflict_path2]);
+    let merged_tree = repo
+        .store()
+        .get_tree(&RepoPath::root(), &merged_tree_id)
+        .unwrap();
+    assert_eq!(
+        merged_tree
+            .conflicts()
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect_vec(),
+        conflict_paths
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_exec_bit_policy(use_git: bool) {
+    // Tests that the executable bit of a cleanly merged file is decided according
+    // to the `ExecBitPolicy`.
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let file_value = |contents: &str, executable: bool| -> TreeValue {
+        let id = testutils::write_file(store, &path, contents);
+        TreeValue::File { id, executable }
+    };
+    let write_tree = |value: TreeValue| -> Tree {
+        let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+        tree_builder.set(path.clone(), value);
+        let tree_id = tree_builder.write_tree().unwrap();
+        store.get_tree(&RepoPath::root(), &tree_id).unwrap()
+    };
+    let merge = |side1: &Tree,
+                 base: &Tree,
+                 side2: &Tree,
+                 exec_bit_policy: ExecBitPolicy|
+     -> Option<(Vec<u8>, bool)> {
+        let options = MergeOptions {
+            exec_bit_policy,
+            ..Default::default()
+        };
+        let (tree_id, _) =
+            tree::merge_trees_with_options(side1, base, side2, &options, None).unwrap();
+        let merged_tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+        match merged_tree.path_value(&path).unwrap().unwrap() {
+            TreeValue::File { id, executable } => {
+                Some((testutils::read_file(store, &path, &id), executable))
+            }
+            TreeValue::Conflict(_) => None,
+            other => panic!("unexpected value: {other:#?}"),
+        }
+    };
+
+    // Side 1 makes the file executable, side 2 leaves it regular
+    let base_tree = write_tree(file_value("1\n2\n3\n", false));
+    let side1_tree = write_tree(file_value("one\n2\n3\n", true));
+    let side2_tree = write_tree(file_value("1\n2\nthree\n", false));
+    let expected_content = b"one\n2\nthree\n".to_vec();
+    for (exec_bit_policy, expected_executable) in [
+        (ExecBitPolicy::Heuristic, true),
+        (ExecBitPolicy::PreferExecutable, true),
+        (ExecBitPolicy::PreferRegular, false),
+        // The sides are tied, so this falls back to the heuristic
+        (ExecBitPolicy::Majority, true),
+    ] {
+        assert_eq!(
+            merge(&side1_tree, &base_tree, &side2_tree, exec_bit_policy),
+            Some((expected_content.clone(), expected_executable)),
+            "{exec_bit_policy:?}"
+        );
+    }
+
+    // Side 1 is a conflict between two regular files, side 2 makes the file
+    // executable. The heuristic says the result should be executable since
+    // that's the only change to the executable bit, but most of the sides are
+    // regular files.
+    let base_value = file_value("1\n2\n3\n4\n5\n", false);
+    let conflict = Conflict {
+        removes: vec![ConflictTerm {
+            value: base_value.clone(),
+        }],
+        adds: vec![
+            ConflictTerm {
+                value: file_value("one\n2\n3\n4\n5\n", false),
+            },
+            ConflictTerm {
+                value: file_value("1\n2\nthree\n4\n5\n", false),
+            },
+        ],
+    };
+    let conflict_id = store.write_conflict(&path, &conflict).unwrap();
+    let base_tree = write_tree(base_value);
+    let side1_tree = write_tree(TreeValue::Conflict(conflict_id));
+    let side2_tree = write_tree(file_value("1\n2\n3\n4\nfive\n", true));
+    let expected_content = b"one\n2\nthree\n4\nfive\n".to_vec();
+    for (exec_bit_policy, expected_executable) in [
+        (ExecBitPolicy::Heuristic, true),
+        (ExecBitPolicy::PreferExecutable, true),
+        (ExecBitPolicy::PreferRegular, false),
+        (ExecBitPolicy::Majority, false),
+    ] {
+        assert_eq!(
+            merge(&side1_tree, &base_tree, &side2_tree, exec_bit_policy),
+            Some((expected_content.clone(), expected_executable)),
+            "{exec_bit_policy:?}"
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_external_merge_driver(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let file_value = |contents: &str, executable: bool| -> TreeValue {
+        let id = testutils::write_file(store, &path, contents);
+        TreeValue::File { id, executable }
+    };
+    let write_tree = |value: TreeValue| -> Tree {
+        let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+        tree_builder.set(path.clone(), value);
+        let tree_id = tree_builder.write_tree().unwrap();
+        store.get_tree(&RepoPath::root(), &tree_id).unwrap()
+    };
+    let base_tree = write_tree(file_value("base\n", false));
+    let side1_tree = write_tree(file_value("side1\n", false));
+    let side2_tree = write_tree(file_value("side2\n", true));
+    let merge = |script: &str| -> TreeValue {
+        let driver = ExternalMergeDriver::new("sh", vec!["-c".to_string(), script.to_string()]);
+        let options = MergeOptions {
+            external_merge_driver: Some(driver),
+            ..Default::default()
+        };
+        let (tree_id, _) =
+            tree::merge_trees_with_options(&side1_tree, &base_tree, &side2_tree, &options, None)
+                .unwrap();
+        let merged_tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+        merged_tree.path_value(&path).unwrap().unwrap()
+    };
+
+    // The driver's output becomes the merged file. Side 2 made it executable.
+    assert_eq!(merge("echo merged > $output"), file_value("merged\n", true));
+
+    // The driver gets the contents of each side
+    assert_eq!(
+        merge("cat $base $left $right > $output"),
+        file_value("base\nside1\nside2\n", true)
+    );
+
+    // A driver that fails leaves the conflict
+    assert_matches!(
+        merge("echo ignored > $output; exit 1"),
+        TreeValue::Conflict(_)
+    );
+
+    // A driver that can't be run is an error
+    let driver = ExternalMergeDriver::new("/nonexistent/merge-driver", vec![]);
+    let options = MergeOptions {
+        external_merge_driver: Some(driver),
+        ..Default::default()
+    };
+    assert_matches!(
+        tree::merge_trees_with_options(&side1_tree, &base_tree, &side2_tree, &options, None),
+        Err(TreeMergeError::ExternalMergeDriverError(
+            ExternalMergeDriverError::FailedToExecute { .. }
+        ))
+    );
+
+    // The driver isn't run for files that merge cleanly, so the merge succeeds
+    // even though the driver can't be run
+    let side2_tree = write_tree(file_value("base\n", true));
+    let (tree_id, _) =
+        tree::merge_trees_with_options(&side1_tree, &base_tree, &side2_tree, &options, None)
+            .unwrap();
+    let merged_tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+    assert_eq!(
+        merged_tree.path_value(&path).unwrap().unwrap(),
+        file_value("side1\n", true)
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_exec_authority(use_git: bool) {
+    // Tests that the executable bit of a cleanly merged file follows the
+    // authoritative side when there is one.
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let file_value = |contents: &str, executable: bool| -> TreeValue {
+        let id = testutils::write_file(store, &path, contents);
+        TreeValue::File { id, executable }
+    };
+    let write_tree = |value: TreeValue| -> Tree {
+        let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+        tree_builder.set(path.clone(), value);
+        let tree_id = tree_builder.write_tree().unwrap();
+        store.get_tree(&RepoPath::root(), &tree_id).unwrap()
+    };
+    let merge = |side1: &Tree,
+                 base: &Tree,
+                 side2: &Tree,
+                 exec_authority: Option<ConflictSide>|
+     -> Option<(Vec<u8>, bool)> {
+        let options = MergeOptions {
+            exec_authority,
+            ..Default::default()
+        };
+        let (tree_id, _) =
+            tree::merge_trees_with_options(side1, base, side2, &options, None).unwrap();
+        let merged_tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+        match merged_tree.path_value(&path).unwrap().unwrap() {
+            TreeValue::File { id, executable } => {
+                Some((testutils::read_file(store, &path, &id), executable))
+            }
+            TreeValue::Conflict(_) => None,
+            other => panic!("unexpected value: {other:#?}"),
+        }
+    };
+
+    // Side 2 makes the file executable, side 1 leaves it regular
+    let base_tree = write_tree(file_value("1\n2\n3\n", false));
+    let side1_tree = write_tree(file_value("one\n2\n3\n", false));
+    let side2_tree = write_tree(file_value("1\n2\nthree\n", true));
+    let expected_content = b"one\n2\nthree\n".to_vec();
+    for (exec_authority, expected_executable) in [
+        (None, true),
+        (Some(ConflictSide::Add(0)), false),
+        (Some(ConflictSide::Add(1)), true),
+        (Some(ConflictSide::Base(0)), false),
+        // A missing term falls back to the policy
+        (Some(ConflictSide::Add(2)), true),
+    ] {
+        assert_eq!(
+            merge(&side1_tree, &base_tree, &side2_tree, exec_authority),
+            Some((expected_content.clone(), expected_executable)),
+            "{exec_authority:?}"
+        );
+    }
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_preview_file_conflict_resolution(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let file_term = |contents: &str| -> ConflictTerm {
+        let id = testutils::write_file(store, &path, contents);
+        ConflictTerm {
+            value: TreeValue::File {
+                id,
+                executable: false,
+            },
+        }
+    };
+
+    // The contents can be merged
+    let conflict = Conflict {
+        removes: vec![file_term("a\nb\nc\n")],
+        adds: vec![file_term("A\nb\nc\n"), file_term("a\nb\nC\n")],
+    };
+    let resolved = assert_matches!(
+        tree::preview_file_conflict_resolution(store, &path, &conflict).unwrap(),
+        ConflictPreview::Resolved(resolved) => resolved
+    );
+    assert_eq!(resolved.content, b"A\nb\nC\n".to_vec());
+    assert!(!resolved.executable);
+
+    // The contents conflict
+    let conflict = Conflict {
+        removes: vec![file_term("a\n")],
+        adds: vec![file_term("b\n"), file_term("c\n")],
+    };
+    assert_eq!(
+        tree::preview_file_conflict_resolution(store, &path, &conflict).unwrap(),
+        ConflictPreview::Unresolvable
+    );
+
+    // Modify/delete conflict
+    let conflict = Conflict {
+        removes: vec![file_term("a\n")],
+        adds: vec![file_term("b\n")],
+    };
+    assert_eq!(
+        tree::preview_file_conflict_resolution(store, &path, &conflict).unwrap(),
+        ConflictPreview::Unresolvable
+    );
+
+    // A redundant pair of terms that cancel out doesn't prevent the resolution
+    let redundant_term = ConflictTerm {
+        value: TreeValue::File {
+            id: testutils::write_file(store, &path, "unrelated\n"),
+            executable: true,
+        },
+    };
+    let conflict = Conflict {
+        removes: vec![file_term("a\nb\nc\n"), redundant_term.clone()],
+        adds: vec![
+            file_term("A\nb\nc\n"),
+            redundant_term,
+            file_term("a\nb\nC\n"),
+        ],
+    };
+    let resolved = assert_matches!(
+        tree::preview_file_conflict_resolution(store, &path, &conflict).unwrap(),
+        ConflictPreview::Resolved(resolved) => resolved
+    );
+    assert_eq!(resolved.content, b"A\nb\nC\n".to_vec());
+    assert!(!resolved.executable);
+
+    // One side is a symlink
+    let symlink_id = store.write_symlink(&path, "target").unwrap();
+    let conflict = Conflict {
+        removes: vec![file_term("a\n")],
+        adds: vec![
+            file_term("b\n"),
+            ConflictTerm {
+                value: TreeValue::Symlink(symlink_id),
+            },
+        ],
+    };
+    assert_eq!(
+        tree::preview_file_conflict_resolution(store, &path, &conflict).unwrap(),
+        ConflictPreview::NonFile
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_merge_trees_outcomes(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let conflict_path = RepoPath::from_internal_string("conflict");
+    let dir_path = RepoPath::from_internal_string("dir");
+    let dir_file1_path = RepoPath::from_internal_string("dir/file1");
+    let dir_file2_path = RepoPath::from_internal_string("dir/file2");
+    let merged_path = RepoPath::from_internal_string("merged");
+
+    let base_tree = testutils::create_tree(
+        repo,
+        &[
+            (&conflict_path, "base"),
+            (&dir_file1_path, "base"),
+            (&dir_file2_path, "base"),
+            (&merged_path, "a\nb\nc\n"),
+        ],
+    );
+    let side1_tree = testutils::create_tree(
+        repo,
+        &[
+            (&conflict_path, "side 1"),
+            (&dir_file1_path, "side 1"),
+            (&dir_file2_path, "base"),
+            (&merged_path, "A\nb\nc\n"),
+        ],
+    );
+    let side2_tree = testutils::create_tree(
+        repo,
+        &[
+            (&conflict_path, "side 2"),
+            (&dir_file1_path, "base"),
+            (&dir_file2_path, "side 2"),
+            (&merged_path, "a\nb\nC\n"),
+        ],
+    );
+
+    let (merged_tree_id, outcomes) = tree::merge_trees_with_options(
+        &side1_tree,
+        &base_tree,
+        &side2_tree,
+        &MergeOptions::default(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(
+        outcomes,
+        vec![
+            (conflict_path.clone(), EntryMergeKind::Conflict),
+            (dir_path.clone(), EntryMergeKind::SubtreeMerge),
+            (merged_path.clone(), EntryMergeKind::ContentMerge),
+        ]
+    );
+
+    // Undo the changes from side 2. The conflict is then resolved trivially
+    // since the conflicting change cancels out.
+    let merged_tree = store.get_tree(&RepoPath::root(), &merged_tree_id).unwrap();
+    let (undone_tree_id, outcomes) = tree::merge_trees_with_options(
+        &merged_tree,
+        &side2_tree,
+        &base_tree,
+        &MergeOptions::default(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(
+        outcomes,
+        vec![
+            (conflict_path, EntryMergeKind::Trivial),
+            (dir_path, EntryMergeKind::SubtreeMerge),
+            (merged_path, EntryMergeKind::ContentMerge),
+        ]
+    );
+    assert_eq!(undone_tree_id, *side1_tree.id());
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_merge_driver(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let lock_path = RepoPath::from_internal_string("Cargo.lock");
+    let other_path = RepoPath::from_internal_string("other");
+    let write_tree = |contents: &str| -> Tree {
+        testutils::create_tree(repo, &[(&lock_path, contents), (&other_path, contents)])
+    };
+    let base_tree = write_tree("base");
+    let side1_tree = write_tree("side 1");
+    let side2_tree = write_tree("side 2");
+
+    // Take side 2 for the lock file
+    let mut merge_drivers = MergeDriverRegistry::new();
+    merge_drivers.register(
+        Arc::new(FilesMatcher::new(&[lock_path.clone()])),
+        |_path, conflict| conflict.adds.get(1).map(|term| term.value.clone()),
+    );
+    let options = MergeOptions {
+        merge_drivers,
+        ..Default::default()
+    };
+    let (merged_tree_id, _) =
+        tree::merge_trees_with_options(&side1_tree, &base_tree, &side2_tree, &options, None)
+            .unwrap();
+    let merged_tree = store.get_tree(&RepoPath::root(), &merged_tree_id).unwrap();
+    assert_eq!(
+        merged_tree.path_value(&lock_path).unwrap(),
+        side2_tree.path_value(&lock_path).unwrap()
+    );
+    // Paths not matched by any driver are resolved as usual
+    assert_matches!(
+        merged_tree.path_value(&other_path).unwrap(),
+        Some(TreeValue::Conflict(_))
+    );
+
+    // A driver returning None falls back to the default resolution
+    let mut merge_drivers = MergeDriverRegistry::new();
+    merge_drivers.register(
+        Arc::new(FilesMatcher::new(&[lock_path.clone()])),
+        |_path, _conflict| None,
+    );
+    let options = MergeOptions {
+        merge_drivers,
+        ..Default::default()
+    };
+    let (merged_tree_id, _) =
+        tree::merge_trees_with_options(&side1_tree, &base_tree, &side2_tree, &options, None)
+            .unwrap();
+    let merged_tree = store.get_tree(&RepoPath::root(), &merged_tree_id).unwrap();
+    assert_matches!(
+        merged_tree.path_value(&lock_path).unwrap(),
+        Some(TreeValue::Conflict(_))
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_merge_trees_with_resolver(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let conflict_path = RepoPath::from_internal_string("conflict");
+    let merged_path = RepoPath::from_internal_string("merged");
+    let base_tree = testutils::create_tree(
+        repo,
+        &[(&conflict_path, "base"), (&merged_path, "a\nb\nc\nd\ne\n")],
+    );
+    let side1_tree = testutils::create_tree(
+        repo,
+        &[
+            (&conflict_path, "side 1"),
+            (&merged_path, "a\nB\nc\nd\ne\n"),
+        ],
+    );
+    let side2_tree = testutils::create_tree(
+        repo,
+        &[
+            (&conflict_path, "side 2"),
+            (&merged_path, "a\nb\nc\nd\nE\n"),
+        ],
+    );
+    let default_tree_id = tree::merge_trees(&side1_tree, &base_tree, &side2_tree).unwrap();
+    let merge = |resolution: ConflictResolution| -> (Tree, Vec<RepoPath>) {
+        let mut resolved_paths = vec![];
+        let mut resolver = |path: &RepoPath, conflict: &Conflict| {
+            assert_eq!(conflict.adds.len(), 2);
+            resolved_paths.push(path.clone());
+            resolution.clone()
+        };
+        let (tree_id, _) = tree::merge_trees_with_options(
+            &side1_tree,
+            &base_tree,
+            &side2_tree,
+            &MergeOptions::default(),
+            Some(&mut resolver),
+        )
+        .unwrap();
+        let tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+        (tree, resolved_paths)
+    };
+
+    // Keeping the conflict gives the same result as without a resolver. The
+    // resolver isn't called for the conflict that could be merged.
+    let (merged_tree, resolved_paths) = merge(ConflictResolution::Keep);
+    assert_eq!(merged_tree.id(), &default_tree_id);
+    assert_eq!(resolved_paths, vec![conflict_path.clone()]);
+
+    let value = side2_tree.path_value(&conflict_path).unwrap().unwrap();
+    let (merged_tree, resolved_paths) = merge(ConflictResolution::Resolve(value.clone()));
+    assert_eq!(merged_tree.path_value(&conflict_path).unwrap(), Some(value));
+    assert_eq!(resolved_paths, vec![conflict_path.clone()]);
+
+    let (merged_tree, resolved_paths) = merge(ConflictResolution::Delete);
+    assert_eq!(merged_tree.path_value(&conflict_path).unwrap(), None);
+    assert_eq!(resolved_paths, vec![conflict_path]);
+
+    // The other path is merged as usual in all cases
+    assert_eq!(
+        merged_tree.path_value(&merged_path).unwrap(),
+        store
+            .get_tree(&RepoPath::root(), &default_tree_id)
+            .unwrap()
+            .path_value(&merged_path)
+            .unwrap()
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_preview_file_conflict_resolution_provenance(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let file_id = |contents: &str| testutils::write_file(store, &path, contents);
+    let file_term = |id: &FileId| ConflictTerm {
+        value: TreeValue::File {
+            id: id.clone(),
+            executable: false,
+        },
+    };
+    let base_id = file_id("a\nb\nc\nd\ne\n");
+    let side1_id = file_id("A\nb\nc\nd\ne\n");
+    let side2_id = file_id("a\nb\nC\nd\ne\n");
+    let side3_id = file_id("a\nb\nc\nd\nE\n");
+
+    // The provenance lists the file terms of the conflict
+    let conflict = Conflict {
+        removes: vec![file_term(&base_id), file_term(&base_id)],
+        adds: vec![
+            file_term(&side1_id),
+            file_term(&side2_id),
+            file_term(&side3_id),
+        ],
+    };
+    let resolved = assert_matches!(
+        tree::preview_file_conflict_resolution(store, &path, &conflict).unwrap(),
+        ConflictPreview::Resolved(resolved) => resolved
+    );
+    assert_eq!(resolved.content, b"A\nb\nC\nd\nE\n".to_vec());
+    assert!(!resolved.executable);
+    assert_eq!(
+        resolved.provenance,
+        MergeProvenance {
+            removed_file_ids: vec![base_id.clone(), base_id.clone()],
+            added_file_ids: vec![side1_id.clone(), side2_id, side3_id],
+        }
+    );
+
+    // The contents conflict
+    let conflict = Conflict {
+        removes: vec![file_term(&base_id)],
+        adds: vec![file_term(&side1_id), file_term(&file_id("B\nb\nc\nd\ne\n"))],
+    };
+    assert_eq!(
+        tree::preview_file_conflict_resolution(store, &path, &conflict).unwrap(),
+        ConflictPreview::Unresolvable
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_preview_file_conflict_resolution_stats(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let file_term = |contents: &str| ConflictTerm {
+        value: TreeValue::File {
+            id: testutils::write_file(store, &path, contents),
+            executable: false,
+        },
+    };
+    let conflict = Conflict {
+        removes: vec![file_term("a\nb\nc\nd\ne\n")],
+        adds: vec![file_term("A\nb\nc\nd\ne\n"), file_term("a\nb\nc\nd\nE\n")],
+    };
+    let resolved = assert_matches!(
+        tree::preview_file_conflict_resolution(store, &path, &conflict).unwrap(),
+        ConflictPreview::Resolved(resolved) => resolved
+    );
+    assert_eq!(resolved.content, b"A\nb\nc\nd\nE\n".to_vec());
+    assert!(!resolved.executable);
+    assert_eq!(
+        resolved.stats,
+        MergeStats {
+            clean_hunks: 2,
+            total_hunks: 2
+        }
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_auto_resolve_conflicts(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let resolvable_path = RepoPath::from_internal_string("dir/resolvable");
+    let unresolvable_path = RepoPath::from_internal_string("dir/unresolvable");
+    let unmatched_path = RepoPath::from_internal_string("unmatched");
+    let file_path = RepoPath::from_internal_string("file");
+    let write_conflict = |path: &RepoPath, base: &str, side1: &str, side2: &str| {
+        let file_term = |contents: &str| ConflictTerm {
+            value: TreeValue::File {
+                id: testutils::write_file(store, path, contents),
+                executable: false,
+            },
+        };
+        let conflict = Conflict {
+            removes: vec![file_term(base)],
+            adds: vec![file_term(side1), file_term(side2)],
+        };
+        TreeValue::Conflict(store.write_conflict(path, &conflict).unwrap())
+    };
+    let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+    tree_builder.set(
+        resolvable_path.clone(),
+        write_conflict(&resolvable_path, "a\nb\nc\n", "A\nb\nc\n", "a\nb\nC\n"),
+    );
+    let unresolvable_value = write_conflict(&unresolvable_path, "a\n", "b\n", "c\n");
+    tree_builder.set(unresolvable_path.clone(), unresolvable_value.clone());
+    let unmatched_value = write_conflict(&unmatched_path, "a\nb\nc\n", "A\nb\nc\n", "a\nb\nC\n");
+    tree_builder.set(unmatched_path.clone(), unmatched_value.clone());
+    let file_value = TreeValue::File {
+        id: testutils::write_file(store, &file_path, "contents"),
+        executable: false,
+    };
+    tree_builder.set(file_path.clone(), file_value.clone());
+    let tree_id = tree_builder.write_tree().unwrap();
+    let tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+
+    let matcher = PrefixMatcher::new(&[RepoPath::from_internal_string("dir")]);
+    let (resolved_tree_id, num_resolved) = tree.auto_resolve_conflicts(&matcher).unwrap();
+    assert_eq!(num_resolved, 1);
+    let resolved_tree = store
+        .get_tree(&RepoPath::root(), &resolved_tree_id)
+        .unwrap();
+    let resolved_id = assert_matches!(
+        resolved_tree.path_value(&resolvable_path).unwrap(),
+        Some(TreeValue::File { id, executable: false }) => id
+    );
+    assert_eq!(
+        testutils::read_file(store, &resolvable_path, &resolved_id),
+        b"A\nb\nC\n"
+    );
+    // The other values are kept as they were
+    assert_eq!(
+        resolved_tree.path_value(&unresolvable_path).unwrap(),
+        Some(unresolvable_value)
+    );
+    assert_eq!(
+        resolved_tree.path_value(&unmatched_path).unwrap(),
+        Some(unmatched_value)
+    );
+    assert_eq!(
+        resolved_tree.path_value(&file_path).unwrap(),
+        Some(file_value)
+    );
+
+    // The tree is unchanged if nothing can be resolved
+    assert_eq!(
+        resolved_tree.auto_resolve_conflicts(&matcher).unwrap(),
+        (resolved_tree_id, 0)
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_describe_conflict(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let modify_delete_path = RepoPath::from_internal_string("modify_delete");
+    let file_dir_path = RepoPath::from_internal_string("file_dir");
+    let file_dir_child_path = RepoPath::from_internal_string("file_dir/file");
+    let base_tree = testutils::create_tree(
+        repo,
+        &[(&modify_delete_path, "base"), (&file_dir_path, "base")],
+    );
+    let side1_tree = testutils::create_tree(
+        repo,
+        &[(&modify_delete_path, "side 1"), (&file_dir_path, "side 1")],
+    );
+    let side2_tree = testutils::create_tree(repo, &[(&file_dir_child_path, "side 2")]);
+    let merged_tree_id = tree::merge_trees(&side1_tree, &base_tree, &side2_tree).unwrap();
+    let merged_tree = store.get_tree(&RepoPath::root(), &merged_tree_id).unwrap();
+    let describe = |path: &RepoPath| {
+        let conflict_id = assert_matches!(
+            merged_tree.path_value(path).unwrap(),
+            Some(TreeValue::Conflict(id)) => id
+        );
+        tree::describe_conflict(store, path, &conflict_id).unwrap()
+    };
+
+    let regular_file = ConflictTermKind::File { executable: false };
+    assert_eq!(
+        describe(&modify_delete_path),
+        ConflictDescription {
+            removes: vec![regular_file],
+            adds: vec![regular_file],
+            shape: ConflictShape::ModifyDelete,
+        }
+    );
+    assert_eq!(
+        describe(&file_dir_path),
+        ConflictDescription {
+            removes: vec![regular_file],
+            adds: vec![regular_file, ConflictTermKind::Tree],
+            shape: ConflictShape::FileDir,
+        }
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_file_dir_conflict(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let added_path = RepoPath::from_internal_string("added");
+    let added_child_path = RepoPath::from_internal_string("added/file");
+    let modified_path = RepoPath::from_internal_string("modified");
+    let modified_child_path = RepoPath::from_internal_string("modified/file");
+    let content_path = RepoPath::from_internal_string("content");
+    let base_tree =
+        testutils::create_tree(repo, &[(&modified_path, "base"), (&content_path, "base")]);
+    let side1_tree = testutils::create_tree(
+        repo,
+        &[
+            (&added_path, "side 1"),
+            (&modified_path, "side 1"),
+            (&content_path, "side 1"),
+        ],
+    );
+    let side2_tree = testutils::create_tree(
+        repo,
+        &[
+            (&added_child_path, "side 2"),
+            (&modified_child_path, "side 2"),
+            (&content_path, "side 2"),
+        ],
+    );
+    let merged_tree_id = tree::merge_trees(&side1_tree, &base_tree, &side2_tree).unwrap();
+    let merged_tree = store.get_tree(&RepoPath::root(), &merged_tree_id).unwrap();
+    let read_conflict = |path: &RepoPath| {
+        let conflict_id = assert_matches!(
+            merged_tree.path_value(path).unwrap(),
+            Some(TreeValue::Conflict(id)) => id
+        );
+        store.read_conflict(path, &conflict_id).unwrap()
+    };
+
+    // A file added on one side and a directory on the other
+    let conflict = read_conflict(&added_path);
+    assert!(conflict.removes.is_empty());
+    assert!(conflict.is_file_dir_conflict());
+    // A file modified on one side and replaced by a directory on the other
+    assert!(read_conflict(&modified_path).is_file_dir_conflict());
+    // A plain content conflict
+    assert!(!read_conflict(&content_path).is_file_dir_conflict());
+}
+
+#[test]
+fn test_merge_in_memory_store() {
+    let store = testutils::in_memory_store();
+
+    let base_tree = testutils::build_tree(
+        &store,
+        &[("file", b"a\nb\nc\n"), ("dir/unchanged", b"unchanged")],
+    );
+    let side1_tree = testutils::build_tree(
+        &store,
+        &[("file", b"A\nb\nc\n"), ("dir/unchanged", b"unchanged")],
+    );
+    let side2_tree = testutils::build_tree(
+        &store,
+        &[
+            ("file", b"a\nb\nC\n"),
+            ("dir/unchanged", b"unchanged"),
+            ("dir/added", b"added"),
+        ],
+    );
+    let expected_tree = testutils::build_tree(
+        &store,
+        &[
+            ("file", b"A\nb\nC\n"),
+            ("dir/unchanged", b"unchanged"),
+            ("dir/added", b"added"),
+        ],
+    );
+    // Equal trees get equal IDs
+    assert_eq!(
+        testutils::build_tree(&store, &[("dir/unchanged", b"unchanged")])
+            .sub_tree(&RepoPathComponent::from("dir"))
+            .unwrap()
+            .unwrap()
+            .id(),
+        base_tree
+            .sub_tree(&RepoPathComponent::from("dir"))
+            .unwrap()
+            .unwrap()
+            .id()
+    );
+
+    let merged_tree_id = tree::merge_trees(&side1_tree, &base_tree, &side2_tree).unwrap();
+    assert_eq!(merged_tree_id, *expected_tree.id());
+    let diff = base_tree
+        .diff(&expected_tree, &EverythingMatcher)
+        .map(Result::unwrap)
+        .collect_vec();
+    assert_eq!(
+        diff.iter()
+            .map(|(path, _)| path.to_internal_file_string())
+            .collect_vec(),
+        vec!["dir/added", "file"]
+    );
+}
+
+#[test]
+fn test_read_error_names_path() {
+    let err = tree::TreeMergeError::ReadError {
+        source: std::io::Error::new(std::io::ErrorKind::Other, "truncated"),
+        path: RepoPath::from_internal_string("dir/file"),
+        file_id: FileId::from_hex("abcd"),
+    };
+    assert_eq!(err.to_string(), "Failed to read file dir/file with ID abcd");
+}
+
+#[test]
+fn test_submodule_conflict() {
+    // Only the Git backend can store submodules
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("submodule");
+    let submodule_value =
+        |hex_byte: &str| TreeValue::GitSubmodule(CommitId::from_hex(&hex_byte.repeat(20)));
+    let write_tree = |value: TreeValue| -> Tree {
+        let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+        tree_builder.set(path.clone(), value);
+        let tree_id = tree_builder.write_tree().unwrap();
+        store.get_tree(&RepoPath::root(), &tree_id).unwrap()
+    };
+    let base_tree = write_tree(submodule_value("01"));
+    let side1_tree = write_tree(submodule_value("02"));
+    let side2_tree = write_tree(submodule_value("03"));
+
+    let merged_tree_id = tree::merge_trees(&side1_tree, &base_tree, &side2_tree).unwrap();
+    let merged_tree = store.get_tree(&RepoPath::root(), &merged_tree_id).unwrap();
+    let conflict_id = assert_matches!(
+        merged_tree.path_value(&path).unwrap(),
+        Some(TreeValue::Conflict(id)) => id
+    );
+    // The conflict keeps the commits the submodule points to on each side
+    assert_eq!(
+        store.read_conflict(&path, &conflict_id).unwrap(),
+        Conflict {
+            removes: vec![ConflictTerm {
+                value: submodule_value("01")
+            }],
+            adds: vec![
+                ConflictTerm {
+                    value: submodule_value("02")
+                },
+                ConflictTerm {
+                    value: submodule_value("03")
+                },
+            ],
+        }
+    );
+    assert_eq!(
+        tree::describe_conflict(store, &path, &conflict_id)
+            .unwrap()
+            .shape,
+        ConflictShape::Submodule
+    );
+
+    // If only one side moves the submodule, that side is taken
+    let merged_tree_id = tree::merge_trees(&side1_tree, &base_tree, &base_tree).unwrap();
+    assert_eq!(merged_tree_id, *side1_tree.id());
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_merge_reuses_cached_subtrees(use_git: bool) {
+    // The Store caches trees by (RepoPath, TreeId), so merging trees whose
+    // subtrees were already read, as happens when rebasing a stack of commits,
+    // doesn't read them from the backend again.
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let a_x_path = RepoPath::from_internal_string("a/x");
+    let a_y_path = RepoPath::from_internal_string("a/y");
+    let b_x_path = RepoPath::from_internal_string("b/x");
+    let b_y_path = RepoPath::from_internal_string("b/y");
+    let base_tree = testutils::create_tree(
+        repo,
+        &[
+            (&a_x_path, "base"),
+            (&a_y_path, "base"),
+            (&b_x_path, "base"),
+            (&b_y_path, "base"),
+        ],
+    );
+    let side1_tree = testutils::create_tree(
+        repo,
+        &[
+            (&a_x_path, "side1"),
+            (&a_y_path, "base"),
+            (&b_x_path, "side1"),
+            (&b_y_path, "base"),
+        ],
+    );
+    let side2_tree = testutils::create_tree(
+        repo,
+        &[
+            (&a_x_path, "base"),
+            (&a_y_path, "side2"),
+            (&b_x_path, "base"),
+            (&b_y_path, "side2"),
+        ],
+    );
+    // Like side 2, but with a different "b" subtree
+    let side3_tree = testutils::create_tree(
+        repo,
+        &[
+            (&a_x_path, "base"),
+            (&a_y_path, "side2"),
+            (&b_x_path, "base"),
+            (&b_y_path, "side3"),
+        ],
+    );
+
+    let counting_store = test_repo.counting_store();
+    let get_tree = |tree: &Tree| {
+        counting_store
+            .get_tree(&RepoPath::root(), tree.id())
+            .unwrap()
+    };
+    let base_tree = get_tree(&base_tree);
+    let side1_tree = get_tree(&side1_tree);
+    let side2_tree = get_tree(&side2_tree);
+    let side3_tree = get_tree(&side3_tree);
+    let tree_reads_before = CountingBackend::of(&counting_store).tree_reads();
+
+    // All three "a" and "b" subtrees are read
+    tree::merge_trees(&side1_tree, &base_tree, &side2_tree).unwrap();
+    let tree_reads_after_first = CountingBackend::of(&counting_store).tree_reads();
+    assert_eq!(tree_reads_after_first - tree_reads_before, 6);
+
+    // Only side 3's "b" subtree is new
+    tree::merge_trees(&side1_tree, &base_tree, &side3_tree).unwrap();
+    let tree_reads_after_second = CountingBackend::of(&counting_store).tree_reads();
+    assert_eq!(tree_reads_after_second - tree_reads_after_first, 1);
+
+    // Repeating a merge reads nothing
+    tree::merge_trees(&side1_tree, &base_tree, &side2_tree).unwrap();
+    assert_eq!(
+        CountingBackend::of(&counting_store).tree_reads(),
+        tree_reads_after_second
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_merge_empty_base_reads_only_shared_subtrees(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let path = RepoPath::from_internal_string;
+    let side1_tree = testutils::create_tree(
+        repo,
+        &[
+            (&path("dir/a"), "a"),
+            (&path("dir/both"), "side1"),
+            (&path("side1_dir/file"), "side1"),
+            (&path("top"), "same"),
+        ],
+    );
+    let side2_tree = testutils::create_tree(
+        repo,
+        &[
+            (&path("dir/b"), "b"),
+            (&path("dir/both"), "side2"),
+            (&path("side2_dir/file"), "side2"),
+            (&path("top"), "same"),
+        ],
+    );
+
+    let counting_store = test_repo.counting_store();
+    let get_tree = |tree: &Tree| {
+        counting_store
+            .get_tree(&RepoPath::root(), tree.id())
+            .unwrap()
+    };
+    let base_tree = get_tree(&testutils::create_tree(repo, &[]));
+    let side1_tree = get_tree(&side1_tree);
+    let side2_tree = get_tree(&side2_tree);
+    assert_eq!(CountingBackend::of(&counting_store).tree_reads(), 2);
+
+    let (merged_tree_id, outcomes) = tree::merge_trees_with_options(
+        &side1_tree,
+        &base_tree,
+        &side2_tree,
+        &MergeOptions::default(),
+        None,
+    )
+    .unwrap();
+    // Only the "dir" subtrees, which both sides added, are read. The empty base
+    // is never read.
+    assert_eq!(CountingBackend::of(&counting_store).tree_reads(), 4);
+    assert_eq!(
+        outcomes,
+        vec![
+            (path("dir"), EntryMergeKind::SubtreeMerge),
+            (path("dir/both"), EntryMergeKind::Conflict),
+        ]
+    );
+    let merged_tree = counting_store
+        .get_tree(&RepoPath::root(), &merged_tree_id)
+        .unwrap();
+    assert_eq!(
+        merged_tree
+            .entries()
+            .map(Result::unwrap)
+            .map(|(path, value)| {
+                (
+                    path.to_internal_file_string(),
+                    matches!(value, TreeValue::Conflict(_)),
+                )
+            })
+            .collect_vec(),
+        vec![
+            ("dir/a".to_string(), false),
+            ("dir/b".to_string(), false),
+            ("dir/both".to_string(), true),
+            ("side1_dir/file".to_string(), false),
+            ("side2_dir/file".to_string(), false),
+            ("top".to_string(), false),
+        ]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_simplify_tree_conflict(use_git: bool) {
+    // Side 1 has a file/dir conflict {-A+B+C} at "d", where C is a file and A
+    // and B are trees. Side 2 changes the file C in the base to the tree D. The
+    // merged conflict {-A-C+B+C+D} simplifies to {-A+B+D}, which only has
+    // trees, so it can be resolved by merging the trees.
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("d");
+    let x_path = RepoPath::from_internal_string("x");
+    let y_path = RepoPath::from_internal_string("y");
+    let tree_a = testutils::create_tree(repo, &[(&x_path, "a"), (&y_path, "a")]);
+    let tree_b = testutils::create_tree(repo, &[(&x_path, "b"), (&y_path, "a")]);
+    let file_c = TreeValue::File {
+        id: testutils::write_file(store, &path, "c"),
+        executable: false,
+    };
+    let side1_conflict = Conflict {
+        removes: vec![ConflictTerm {
+            value: TreeValue::Tree(tree_a.id().clone()),
+        }],
+        adds: vec![
+            ConflictTerm {
+                value: TreeValue::Tree(tree_b.id().clone()),
+            },
+            ConflictTerm {
+                value: file_c.clone(),
+            },
+        ],
+    };
+    let side1_conflict_id = store.write_conflict(&path, &side1_conflict).unwrap();
+    let write_tree = |value: TreeValue| -> Tree {
+        let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+        tree_builder.set(path.clone(), value);
+        let tree_id = tree_builder.write_tree().unwrap();
+        store.get_tree(&RepoPath::root(), &tree_id).unwrap()
+    };
+    let base_tree = write_tree(file_c);
+    let side1_tree = write_tree(TreeValue::Conflict(side1_conflict_id));
+
+    // D changes a different file than B, so the trees merge cleanly
+    let tree_d = testutils::create_tree(repo, &[(&x_path, "a"), (&y_path, "d")]);
+    let side2_tree = write_tree(TreeValue::Tree(tree_d.id().clone()));
+    let merged_tree_id = tree::merge_trees(&side1_tree, &base_tree, &side2_tree).unwrap();
+    let merged_tree = store.get_tree(&RepoPath::root(), &merged_tree_id).unwrap();
+    let expected_tree = testutils::create_tree(repo, &[(&x_path, "b"), (&y_path, "d")]);
+    assert_eq!(
+        merged_tree.path_value(&path).unwrap(),
+        Some(TreeValue::Tree(expected_tree.id().clone()))
+    );
+
+    // D changes the same file as B, so the tree conflict is kept
+    let tree_d = testutils::create_tree(repo, &[(&x_path, "d"), (&y_path, "a")]);
+    let side2_tree = write_tree(TreeValue::Tree(tree_d.id().clone()));
+    let (merged_tree_id, outcomes) = tree::merge_trees_with_options(
+        &side1_tree,
+        &base_tree,
+        &side2_tree,
+        &MergeOptions::default(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(outcomes, vec![(path.clone(), EntryMergeKind::Conflict)]);
+    let merged_tree = store.get_tree(&RepoPath::root(), &merged_tree_id).unwrap();
+    let conflict_id = assert_matches!(
+        merged_tree.path_value(&path).unwrap(),
+        Some(TreeValue::Conflict(id)) => id
+    );
+    assert_eq!(
+        store.read_conflict(&path, &conflict_id).unwrap(),
+        Conflict {
+            removes: vec![ConflictTerm {
+                value: TreeValue::Tree(tree_a.id().clone()),
+            }],
+            adds: vec![
+                ConflictTerm {
+                    value: TreeValue::Tree(tree_b.id().clone()),
+                },
+                ConflictTerm {
+                    value: TreeValue::Tree(tree_d.id().clone()),
+                },
+            ],
+        }
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_simplify_tree_conflict_outcomes(use_git: bool) {
+    // Same setup as test_simplify_tree_conflict(), but with changes to the
+    // files in the trees that need a content merge or that conflict
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("d");
+    let x_path = RepoPath::from_internal_string("x");
+    let tree_a = testutils::create_tree(repo, &[(&x_path, "a\nb\nc\n")]);
+    let tree_b = testutils::create_tree(repo, &[(&x_path, "A\nb\nc\n")]);
+    let file_c = TreeValue::File {
+        id: testutils::write_file(store, &path, "c"),
+        executable: false,
+    };
+    let side1_conflict = Conflict {
+        removes: vec![ConflictTerm {
+            value: TreeValue::Tree(tree_a.id().clone()),
+        }],
+        adds: vec![
+            ConflictTerm {
+                value: TreeValue::Tree(tree_b.id().clone()),
+            },
+            ConflictTerm {
+                value: file_c.clone(),
+            },
+        ],
+    };
+    let side1_conflict_id = store.write_conflict(&path, &side1_conflict).unwrap();
+    let write_tree = |value: TreeValue| -> Tree {
+        let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+        tree_builder.set(path.clone(), value);
+        let tree_id = tree_builder.write_tree().unwrap();
+        store.get_tree(&RepoPath::root(), &tree_id).unwrap()
+    };
+    let base_tree = write_tree(file_c);
+    let side1_tree = write_tree(TreeValue::Conflict(side1_conflict_id));
+
+    // The file in the trees is merged, and the directory is reported before it
+    let tree_d = testutils::create_tree(repo, &[(&x_path, "a\nb\nC\n")]);
+    let side2_tree = write_tree(TreeValue::Tree(tree_d.id().clone()));
+    let (_, outcomes) = tree::merge_trees_with_options(
+        &side1_tree,
+        &base_tree,
+        &side2_tree,
+        &MergeOptions::default(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(
+        outcomes,
+        vec![
+            (path.clone(), EntryMergeKind::SubtreeMerge),
+            (
+                RepoPath::from_internal_string("d/x"),
+                EntryMergeKind::ContentMerge
+            ),
+        ]
+    );
+
+    // The file in the trees conflicts, so the merge of the trees is discarded.
+    // The resolver is only called for the tree conflict that is kept, not for
+    // the file in the discarded merge.
+    let tree_d = testutils::create_tree(repo, &[(&x_path, "D\nb\nc\n")]);
+    let side2_tree = write_tree(TreeValue::Tree(tree_d.id().clone()));
+    let mut resolver_paths = vec![];
+    let mut resolver = |path: &RepoPath, _conflict: &Conflict| {
+        resolver_paths.push(path.clone());
+        ConflictResolution::Keep
+    };
+    let (_, outcomes) = tree::merge_trees_with_options(
+        &side1_tree,
+        &base_tree,
+        &side2_tree,
+        &MergeOptions::default(),
+        Some(&mut resolver),
+    )
+    .unwrap();
+    assert_eq!(resolver_paths, vec![path.clone()]);
+    assert_eq!(outcomes, vec![(path, EntryMergeKind::Conflict)]);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_delete_handling(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let file_value = |contents: &str, executable: bool| -> TreeValue {
+        let id = testutils::write_file(store, &path, contents);
+        TreeValue::File { id, executable }
+    };
+    let write_tree = |value: Option<TreeValue>| -> Tree {
+        let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+        if let Some(value) = value {
+            tree_builder.set(path.clone(), value);
+        }
+        let tree_id = tree_builder.write_tree().unwrap();
+        store.get_tree(&RepoPath::root(), &tree_id).unwrap()
+    };
+    let merge = |side1: &Tree,
+                 base: &Tree,
+                 side2: &Tree,
+                 delete_handling: DeleteHandling|
+     -> Option<(Vec<u8>, bool)> {
+        let options = MergeOptions {
+            delete_handling,
+            ..Default::default()
+        };
+        let (tree_id, _) =
+            tree::merge_trees_with_options(side1, base, side2, &options, None).unwrap();
+        let merged_tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+        match merged_tree.path_value(&path).unwrap().unwrap() {
+            TreeValue::File { id, executable } => {
+                Some((testutils::read_file(store, &path, &id), executable))
+            }
+            TreeValue::Conflict(_) => None,
+            other => panic!("unexpected value: {other:#?}"),
+        }
+    };
+    let deleted_tree = write_tree(None);
+
+    // An empty file is filled in on one side and deleted on the other
+    let base_tree = write_tree(Some(file_value("", false)));
+    let modified_tree = write_tree(Some(file_value("contents\n", false)));
+    assert_eq!(
+        merge(
+            &modified_tree,
+            &base_tree,
+            &deleted_tree,
+            DeleteHandling::Conflict
+        ),
+        None
+    );
+    let expected = Some((b"contents\n".to_vec(), false));
+    assert_eq!(
+        merge(
+            &modified_tree,
+            &base_tree,
+            &deleted_tree,
+            DeleteHandling::TreatAsEmpty
+        ),
+        expected
+    );
+    assert_eq!(
+        merge(
+            &deleted_tree,
+            &base_tree,
+            &modified_tree,
+            DeleteHandling::TreatAsEmpty
+        ),
+        expected
+    );
+
+    // The deleting side doesn't change the executable bit
+    let base_tree = write_tree(Some(file_value("", true)));
+    let modified_tree = write_tree(Some(file_value("contents\n", true)));
+    assert_eq!(
+        merge(
+            &modified_tree,
+            &base_tree,
+            &deleted_tree,
+            DeleteHandling::TreatAsEmpty
+        ),
+        Some((b"contents\n".to_vec(), true))
+    );
+
+    // Removing all the lines conflicts with changing one of them
+    let base_tree = write_tree(Some(file_value("a\n", false)));
+    let modified_tree = write_tree(Some(file_value("b\n", false)));
+    assert_eq!(
+        merge(
+            &modified_tree,
+            &base_tree,
+            &deleted_tree,
+            DeleteHandling::TreatAsEmpty
+        ),
+        None
+    );
+
+    // Side 1 has a conflict {-B+S1+S2} and side 2 deletes the file from the
+    // base X, so the merged conflict {-B-X+S1+S2} has two bases. The deleting
+    // side's executable bit is only known if the bases agree on it.
+    let conflict_value = {
+        let conflict = Conflict {
+            removes: vec![ConflictTerm {
+                value: file_value("a\n", false),
+            }],
+            adds: vec![
+                ConflictTerm {
+                    value: file_value("a\n", true),
+                },
+                ConflictTerm {
+                    value: file_value("c\n", false),
+                },
+            ],
+        };
+        TreeValue::Conflict(store.write_conflict(&path, &conflict).unwrap())
+    };
+    let conflict_tree = write_tree(Some(conflict_value));
+    let base_tree = write_tree(Some(file_value("", false)));
+    assert_eq!(
+        merge(
+            &conflict_tree,
+            &base_tree,
+            &deleted_tree,
+            DeleteHandling::TreatAsEmpty
+        ),
+        Some((b"c\n".to_vec(), true))
+    );
+    let base_tree = write_tree(Some(file_value("", true)));
+    assert_eq!(
+        merge(
+            &conflict_tree,
+            &base_tree,
+            &deleted_tree,
+            DeleteHandling::TreatAsEmpty
+        ),
+        None
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_symlink_file_policy(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let file_value = |contents: &str| -> TreeValue {
+        let id = testutils::write_file(store, &path, contents);
+        TreeValue::File {
+            id,
+            executable: false,
+        }
+    };
+    let symlink_value = |target: &str| -> TreeValue {
+        let id = store.write_symlink(&path, target).unwrap();
+        TreeValue::Symlink(id)
+    };
+    let write_tree = |value: TreeValue| -> Tree {
+        let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+        tree_builder.set(path.clone(), value);
+        let tree_id = tree_builder.write_tree().unwrap();
+        store.get_tree(&RepoPath::root(), &tree_id).unwrap()
+    };
+    let merge = |side1: &Tree,
+                 base: &Tree,
+                 side2: &Tree,
+                 symlink_file_policy: SymlinkFilePolicy|
+     -> TreeValue {
+        let options = MergeOptions {
+            symlink_file_policy,
+            ..Default::default()
+        };
+        let (tree_id, _) =
+            tree::merge_trees_with_options(side1, base, side2, &options, None).unwrap();
+        let merged_tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+        merged_tree.path_value(&path).unwrap().unwrap()
+    };
+
+    // One side modifies the file, the other side turns it into a symlink
+    let base_tree = write_tree(file_value("base\n"));
+    let file_tree = write_tree(file_value("modified\n"));
+    let symlink_tree = write_tree(symlink_value("target"));
+    assert_matches!(
+        merge(
+            &file_tree,
+            &base_tree,
+            &symlink_tree,
+            SymlinkFilePolicy::Conflict
+        ),
+        TreeValue::Conflict(_)
+    );
+    assert_eq!(
+        merge(
+            &file_tree,
+            &base_tree,
+            &symlink_tree,
+            SymlinkFilePolicy::PreferFile
+        ),
+        file_value("modified\n")
+    );
+    assert_eq!(
+        merge(
+            &symlink_tree,
+            &base_tree,
+            &file_tree,
+            SymlinkFilePolicy::PreferFile
+        ),
+        file_value("modified\n")
+    );
+    assert_eq!(
+        merge(
+            &file_tree,
+            &base_tree,
+            &symlink_tree,
+            SymlinkFilePolicy::PreferSymlink
+        ),
+        symlink_value("target")
+    );
+
+    // Both sides are files, so the policy doesn't apply
+    let other_file_tree = write_tree(file_value("other\n"));
+    assert_matches!(
+        merge(
+            &file_tree,
+            &base_tree,
+            &other_file_tree,
+            SymlinkFilePolicy::PreferSymlink
+        ),
+        TreeValue::Conflict(_)
+    );
+
+    // Both sides are symlinks, so the policy doesn't pick one of them
+    let base_tree = write_tree(symlink_value("base"));
+    let other_symlink_tree = write_tree(symlink_value("other"));
+    assert_matches!(
+        merge(
+            &symlink_tree,
+            &base_tree,
+            &other_symlink_tree,
+            SymlinkFilePolicy::PreferSymlink
+        ),
+        TreeValue::Conflict(_)
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_merge_tree_ids(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let path = RepoPath::from_internal_string("file");
+    let other_path = RepoPath::from_internal_string("other");
+    let base_tree = testutils::create_tree(repo, &[(&path, "base"), (&other_path, "base")]);
+    let side1_tree = testutils::create_tree(repo, &[(&path, "side1"), (&other_path, "base")]);
+    let side2_tree = testutils::create_tree(repo, &[(&path, "base"), (&other_path, "side2")]);
+    let base_id = base_tree.id();
+    let side1_id = side1_tree.id();
+    let side2_id = side2_tree.id();
+
+    // The trivial cases are decided without reading any trees
+    let counting_store = test_repo.counting_store();
+    let merge = |base_id, side1_id, side2_id| {
+        tree::merge_tree_ids(
+            &counting_store,
+            &RepoPath::root(),
+            base_id,
+            side1_id,
+            side2_id,
+        )
+        .unwrap()
+    };
+    assert_eq!(merge(base_id, base_id, side2_id), *side2_id);
+    assert_eq!(merge(base_id, side1_id, base_id), *side1_id);
+    assert_eq!(merge(base_id, side1_id, side1_id), *side1_id);
+    assert_eq!(CountingBackend::of(&counting_store).tree_reads(), 0);
+
+    // Otherwise, the trees are read and merged
+    assert_eq!(
+        merge(base_id, side1_id, side2_id),
+        tree::merge_trees(&side1_tree, &base_tree, &side2_tree).unwrap()
+    );
+    assert_eq!(CountingBackend::of(&counting_store).tree_reads(), 3);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_merge_backend_trees(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let other_path = RepoPath::from_internal_string("other");
+    let conflict_path = RepoPath::from_internal_string("conflict");
+    let base_tree = testutils::create_tree(
+        repo,
+        &[
+            (&path, "base"),
+            (&other_path, "base"),
+            (&conflict_path, "base"),
+        ],
+    );
+    let side1_tree = testutils::create_tree(
+        repo,
+        &[
+            (&path, "side1"),
+            (&other_path, "base"),
+            (&conflict_path, "side1"),
+        ],
+    );
+    let side2_tree = testutils::create_tree(
+        repo,
+        &[
+            (&path, "base"),
+            (&other_path, "side2"),
+            (&conflict_path, "side2"),
+        ],
+    );
+
+    let merged_id = tree::merge_backend_trees(
+        store,
+        &RepoPath::root(),
+        base_tree.data(),
+        side1_tree.data(),
+        side2_tree.data(),
+    )
+    .unwrap();
+    assert_eq!(
+        merged_id,
+        tree::merge_trees(&side1_tree, &base_tree, &side2_tree).unwrap()
+    );
+    let merged_tree = store.get_tree(&RepoPath::root(), &merged_id).unwrap();
+    assert!(matches!(
+        merged_tree.path_value(&conflict_path).unwrap(),
+        Some(TreeValue::Conflict(_))
+    ));
+
+    // Trivial merges work the same way
+    assert_eq!(
+        tree::merge_backend_trees(
+            store,
+            &RepoPath::root(),
+            base_tree.data(),
+            base_tree.data(),
+            side2_tree.data(),
+        )
+        .unwrap(),
+        *side2_tree.id()
+    );
+}
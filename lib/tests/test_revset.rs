@@ -26,8 +26,8 @@ use jujutsu_lib::repo::Repo;
 use jujutsu_lib::repo_path::RepoPath;
 use jujutsu_lib::revset::{
     optimize, parse, DefaultSymbolResolver, ReverseRevsetGraphIterator, Revset, RevsetAliasesMap,
-    RevsetExpression, RevsetFilterPredicate, RevsetGraphEdge, RevsetResolutionError,
-    RevsetWorkspaceContext, SymbolResolver as _,
+    RevsetExpression, RevsetFilterPredicate, RevsetGraphEdge, RevsetIteratorExt,
+    RevsetResolutionError, RevsetWorkspaceContext, SymbolResolver as _,
 };
 use jujutsu_lib::settings::GitSettings;
 use jujutsu_lib::tree::merge_trees;
@@ -176,6 +176,21 @@ fn test_resolve_symbol_commit_id() {
         Err(RevsetResolutionError::NoSuchRevision(s)) if s == "040"
     );
 
+    // Test uppercase commit id and prefix
+    assert_eq!(
+        resolve_symbol(
+            repo.as_ref(),
+            "0454DE3CAE04C46CDA37BA2E8873B4C17FF51DCB",
+            None
+        )
+        .unwrap(),
+        vec![commits[0].id().clone()]
+    );
+    assert_eq!(
+        resolve_symbol(repo.as_ref(), "045F", None).unwrap(),
+        resolve_symbol(repo.as_ref(), "045f", None).unwrap()
+    );
+
     // Test non-hex string
     assert_matches!(
         resolve_symbol(repo.as_ref(), "foo", None),
@@ -2487,3 +2502,31 @@ fn test_change_id_index() {
     );
     assert_eq!(resolve_prefix("a"), PrefixResolution::AmbiguousMatch);
 }
+
+#[test]
+fn test_iter_with_change_ids() {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(true);
+    let repo = &test_repo.repo;
+
+    let mut tx = repo.start_transaction(&settings, "test");
+    let mut graph_builder = CommitGraphBuilder::new(&settings, tx.mut_repo());
+    let commit1 = graph_builder.initial_commit();
+    let commit2 = graph_builder.commit_with_parents(&[&commit1]);
+    let commit3 = graph_builder.initial_commit();
+
+    let revset = revset_for_commits(tx.repo(), &[&commit1, &commit2, &commit3]);
+    let expected = revset
+        .iter()
+        .commits(tx.repo().store())
+        .map(|commit| {
+            let commit = commit.unwrap();
+            (commit.id().clone(), commit.change_id().clone())
+        })
+        .collect_vec();
+    assert_eq!(expected.len(), 3);
+    assert_eq!(
+        revset.iter_with_change_ids().unwrap().collect_vec(),
+        expected
+    );
+}
@@ -904,16 +904,16 @@ fn test_rebase_descendants_contents(use_git: bool) {
         .unwrap();
 
     assert_eq!(
-        new_commit_c.tree().path_value(&path3),
-        commit_c.tree().path_value(&path3)
+        new_commit_c.tree().path_value(&path3).unwrap(),
+        commit_c.tree().path_value(&path3).unwrap()
     );
     assert_eq!(
-        new_commit_c.tree().path_value(&path4),
-        commit_d.tree().path_value(&path4)
+        new_commit_c.tree().path_value(&path4).unwrap(),
+        commit_d.tree().path_value(&path4).unwrap()
     );
     assert_ne!(
-        new_commit_c.tree().path_value(&path2),
-        commit_b.tree().path_value(&path2)
+        new_commit_c.tree().path_value(&path2).unwrap(),
+        commit_b.tree().path_value(&path2).unwrap()
     );
 }
 
@@ -0,0 +1,3093 @@
+// Copyright 2023 The Jujutsu Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::ops::ControlFlow;
+
+use assert_matches::assert_matches;
+use itertools::Itertools;
+use jujutsu_lib::backend::{
+    CommitId, Conflict, ConflictId, ConflictTerm, FileId, ObjectId, SymlinkId, TreeValue,
+};
+use jujutsu_lib::matchers::{
+    EverythingMatcher, FilesMatcher, Matcher, NothingMatcher, PrefixMatcher,
+};
+use jujutsu_lib::repo::Repo;
+use jujutsu_lib::repo_path::{RepoPath, RepoPathComponent};
+use jujutsu_lib::tree;
+use jujutsu_lib::tree::{
+    Diff, DiffCache, DiffCacheMatcher, DiffCounts, EntryComparison, ExpandedDiff, PathChange,
+    Rename, ResolvedValue, Tree, TreeVerifyError,
+};
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use test_case::test_case;
+use testutils::{create_random_commit, CountingBackend, TestRepo};
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_to_depth(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let a_path = RepoPath::from_internal_string("a");
+    let d_path = RepoPath::from_internal_string("d");
+    let d_e_path = RepoPath::from_internal_string("d/e");
+    let d_e_f_path = RepoPath::from_internal_string("d/e/f");
+    let d_f_path = RepoPath::from_internal_string("d/f");
+    let x_path = RepoPath::from_internal_string("x");
+    let x_f_path = RepoPath::from_internal_string("x/f");
+
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&a_path, "before"),
+            (&d_e_f_path, "before"),
+            (&d_f_path, "before"),
+            (&x_f_path, "before"),
+        ],
+    );
+    // Directory "x" gets replaced by a file
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&a_path, "after"),
+            (&d_e_f_path, "after"),
+            (&d_f_path, "after"),
+            (&x_path, "after"),
+        ],
+    );
+    let modified = |path: &RepoPath| {
+        (
+            path.clone(),
+            Diff::Modified(
+                tree1.path_value(path).unwrap().unwrap(),
+                tree2.path_value(path).unwrap().unwrap(),
+            ),
+        )
+    };
+    let removed = |path: &RepoPath| {
+        (
+            path.clone(),
+            Diff::Removed(tree1.path_value(path).unwrap().unwrap()),
+        )
+    };
+    let added = |path: &RepoPath| {
+        (
+            path.clone(),
+            Diff::Added(tree2.path_value(path).unwrap().unwrap()),
+        )
+    };
+
+    assert_eq!(
+        tree1
+            .diff_to_depth(&tree2, &EverythingMatcher, 0)
+            .map(Result::unwrap)
+            .collect_vec(),
+        vec![
+            modified(&a_path),
+            modified(&d_path),
+            removed(&x_path),
+            added(&x_path),
+        ]
+    );
+    assert_eq!(
+        tree1
+            .diff_to_depth(&tree2, &EverythingMatcher, 1)
+            .map(Result::unwrap)
+            .collect_vec(),
+        vec![
+            modified(&a_path),
+            modified(&d_e_path),
+            modified(&d_f_path),
+            removed(&x_f_path),
+            added(&x_path),
+        ]
+    );
+    let expected = vec![
+        modified(&a_path),
+        modified(&d_e_f_path),
+        modified(&d_f_path),
+        removed(&x_f_path),
+        added(&x_path),
+    ];
+    assert_eq!(
+        tree1
+            .diff_to_depth(&tree2, &EverythingMatcher, 2)
+            .map(Result::unwrap)
+            .collect_vec(),
+        expected
+    );
+    assert_eq!(
+        tree1
+            .diff(&tree2, &EverythingMatcher)
+            .map(Result::unwrap)
+            .collect_vec(),
+        expected
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_conflicts_matching_reads_only_visited_subtrees(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let src_path = RepoPath::from_internal_string("src");
+    let src_file_path = RepoPath::from_internal_string("src/file");
+    let src_sub_file_path = RepoPath::from_internal_string("src/sub/file");
+    let docs_file_path = RepoPath::from_internal_string("docs/file");
+    let docs_sub_file_path = RepoPath::from_internal_string("docs/sub/file");
+
+    let base_tree = testutils::create_tree(
+        repo,
+        &[
+            (&src_file_path, "base"),
+            (&src_sub_file_path, "base"),
+            (&docs_file_path, "base"),
+            (&docs_sub_file_path, "base"),
+        ],
+    );
+    let side1_tree = testutils::create_tree(
+        repo,
+        &[
+            (&src_file_path, "side 1"),
+            (&src_sub_file_path, "side 1"),
+            (&docs_file_path, "side 1"),
+            (&docs_sub_file_path, "side 1"),
+        ],
+    );
+    let side2_tree = testutils::create_tree(
+        repo,
+        &[
+            (&src_file_path, "side 2"),
+            (&src_sub_file_path, "side 2"),
+            (&docs_file_path, "side 2"),
+            (&docs_sub_file_path, "side 2"),
+        ],
+    );
+    let merged_tree_id = tree::merge_trees(&side1_tree, &base_tree, &side2_tree).unwrap();
+
+    let counting_store = test_repo.counting_store();
+    let merged_tree = counting_store
+        .get_tree(&RepoPath::root(), &merged_tree_id)
+        .unwrap();
+    let matcher = PrefixMatcher::new(&[src_path]);
+    let conflicts = merged_tree.conflicts_matching(&matcher).unwrap();
+    assert_eq!(
+        conflicts.iter().map(|(path, _)| path.clone()).collect_vec(),
+        vec![src_file_path, src_sub_file_path]
+    );
+    // Only the root tree, "src", and "src/sub" were read, not "docs" or "docs/sub"
+    assert_eq!(CountingBackend::of(&counting_store).tree_reads(), 3);
+    assert!(conflicts
+        .iter()
+        .all(|(path, id)| merged_tree.path_value(path).unwrap()
+            == Some(TreeValue::Conflict(id.clone()))));
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_entries_with_dirs(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let a_path = RepoPath::from_internal_string("a");
+    let d_path = RepoPath::from_internal_string("d");
+    let d_a_path = RepoPath::from_internal_string("d/a");
+    let d_e_path = RepoPath::from_internal_string("d/e");
+    let d_e_a_path = RepoPath::from_internal_string("d/e/a");
+    let z_path = RepoPath::from_internal_string("z");
+
+    let tree = testutils::create_tree(
+        repo,
+        &[
+            (&a_path, "contents"),
+            (&d_a_path, "contents"),
+            (&d_e_a_path, "contents"),
+            (&z_path, "contents"),
+        ],
+    );
+    let value = |path: &RepoPath| (path.clone(), tree.path_value(path).unwrap().unwrap());
+
+    assert_eq!(
+        tree.entries_matching_with_dirs(&EverythingMatcher)
+            .map(Result::unwrap)
+            .collect_vec(),
+        vec![
+            value(&a_path),
+            value(&d_path),
+            value(&d_a_path),
+            value(&d_e_path),
+            value(&d_e_a_path),
+            value(&z_path),
+        ]
+    );
+    assert_matches!(
+        tree.path_value(&d_e_path).unwrap(),
+        Some(TreeValue::Tree(_))
+    );
+    // The default is to not include directories
+    assert_eq!(
+        tree.entries().map(Result::unwrap).collect_vec(),
+        vec![
+            value(&a_path),
+            value(&d_a_path),
+            value(&d_e_a_path),
+            value(&z_path),
+        ]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_group_diff_by_dir(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let a_path = RepoPath::from_internal_string("a");
+    let d_b_path = RepoPath::from_internal_string("d/b");
+    let d_c_path = RepoPath::from_internal_string("d/c");
+    let d_e_f_path = RepoPath::from_internal_string("d/e/f");
+    let x_path = RepoPath::from_internal_string("x");
+    let x_f_path = RepoPath::from_internal_string("x/f");
+    let z_path = RepoPath::from_internal_string("z");
+
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&a_path, "before"),
+            (&d_b_path, "before"),
+            (&d_e_f_path, "before"),
+            (&x_f_path, "before"),
+        ],
+    );
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&a_path, "after"),
+            (&d_c_path, "after"),
+            (&d_e_f_path, "after"),
+            (&x_path, "after"),
+            (&z_path, "after"),
+        ],
+    );
+    let diff = tree1
+        .diff(&tree2, &EverythingMatcher)
+        .map(Result::unwrap)
+        .collect_vec();
+    let diff_at = |path: &RepoPath| {
+        diff.iter()
+            .find(|(diff_path, _)| diff_path == path)
+            .map(|(path, diff)| (path.components().last().unwrap().clone(), diff.clone()))
+            .unwrap()
+    };
+
+    let grouped = tree::group_diff_by_dir(diff.clone());
+    assert_eq!(
+        grouped.keys().collect_vec(),
+        vec![
+            &RepoPath::root(),
+            &RepoPath::from_internal_string("d"),
+            &RepoPath::from_internal_string("d/e"),
+            &RepoPath::from_internal_string("x"),
+        ]
+    );
+    assert_eq!(
+        grouped[&RepoPath::root()],
+        vec![diff_at(&a_path), diff_at(&x_path), diff_at(&z_path)]
+    );
+    assert_eq!(
+        grouped[&RepoPath::from_internal_string("d")],
+        vec![diff_at(&d_b_path), diff_at(&d_c_path)]
+    );
+    assert_eq!(
+        grouped[&RepoPath::from_internal_string("d/e")],
+        vec![diff_at(&d_e_f_path)]
+    );
+    assert_eq!(
+        grouped[&RepoPath::from_internal_string("x")],
+        vec![diff_at(&x_f_path)]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_count_entries_matching(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let tree = testutils::create_tree(
+        repo,
+        &[
+            (&RepoPath::from_internal_string("a"), "contents"),
+            (&RepoPath::from_internal_string("d/a"), "contents"),
+            (&RepoPath::from_internal_string("d/e/a"), "contents"),
+            (&RepoPath::from_internal_string("d/e/b"), "contents"),
+            (&RepoPath::from_internal_string("z"), "contents"),
+        ],
+    );
+
+    assert_eq!(tree.count_entries_matching(&EverythingMatcher).unwrap(), 5);
+    assert_eq!(
+        tree.count_entries_matching(&EverythingMatcher).unwrap(),
+        tree.entries().map(Result::unwrap).count()
+    );
+    for prefix in ["a", "d", "d/e", "d/e/b", "missing"] {
+        let matcher = PrefixMatcher::new(&[RepoPath::from_internal_string(prefix)]);
+        assert_eq!(
+            tree.count_entries_matching(&matcher).unwrap(),
+            tree.entries_matching(&matcher).map(Result::unwrap).count(),
+            "prefix {prefix:?}"
+        );
+    }
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_file_entries_matching(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let normal_path = RepoPath::from_internal_string("a");
+    let executable_path = RepoPath::from_internal_string("d/exec");
+    let symlink_path = RepoPath::from_internal_string("d/link");
+    let conflict_path = RepoPath::from_internal_string("d/conflict");
+    let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+    testutils::write_normal_file(&mut tree_builder, &normal_path, "normal");
+    testutils::write_executable_file(&mut tree_builder, &executable_path, "executable");
+    testutils::write_symlink(&mut tree_builder, &symlink_path, "target");
+    let conflict_id = store
+        .write_conflict(&conflict_path, &Conflict::default())
+        .unwrap();
+    tree_builder.set(conflict_path, TreeValue::Conflict(conflict_id));
+    let tree_id = tree_builder.write_tree().unwrap();
+    let tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+
+    let file_id = |path: &RepoPath| match tree.path_value(path).unwrap() {
+        Some(TreeValue::File { id, .. }) => id,
+        value => panic!("unexpected value {value:?}"),
+    };
+    assert_eq!(
+        tree.file_entries_matching(&EverythingMatcher).unwrap(),
+        vec![
+            (normal_path.clone(), file_id(&normal_path), false),
+            (executable_path.clone(), file_id(&executable_path), true),
+        ]
+    );
+    let matcher = PrefixMatcher::new(&[RepoPath::from_internal_string("d")]);
+    assert_eq!(
+        tree.file_entries_matching(&matcher).unwrap(),
+        vec![(executable_path.clone(), file_id(&executable_path), true)]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_splice_subtree(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let file_path = RepoPath::from_internal_string("file");
+    let a_c_path = RepoPath::from_internal_string("a/c");
+    let a_b_dir = RepoPath::from_internal_string("a/b");
+    let a_b_old_path = RepoPath::from_internal_string("a/b/old");
+    let tree = testutils::create_tree(
+        repo,
+        &[
+            (&a_b_old_path, "old"),
+            (&a_c_path, "c"),
+            (&file_path, "file"),
+        ],
+    );
+    let new_subtree =
+        testutils::create_tree(repo, &[(&RepoPath::from_internal_string("new"), "new")]);
+    let get_tree = |id| store.get_tree(&RepoPath::root(), &id).unwrap();
+
+    // Replace an existing subtree
+    let spliced =
+        get_tree(tree::splice_subtree(&tree, &a_b_dir, new_subtree.id().clone()).unwrap());
+    assert_eq!(
+        spliced.path_value(&a_b_dir).unwrap(),
+        Some(TreeValue::Tree(new_subtree.id().clone()))
+    );
+    assert_eq!(spliced.path_value(&a_b_old_path).unwrap(), None);
+    assert_eq!(
+        spliced.path_value(&a_c_path).unwrap(),
+        tree.path_value(&a_c_path).unwrap()
+    );
+    assert_eq!(
+        spliced.path_value(&file_path).unwrap(),
+        tree.path_value(&file_path).unwrap()
+    );
+
+    // Intermediate directories are created
+    let x_y_dir = RepoPath::from_internal_string("x/y");
+    let spliced =
+        get_tree(tree::splice_subtree(&tree, &x_y_dir, new_subtree.id().clone()).unwrap());
+    assert_eq!(
+        spliced.path_value(&x_y_dir).unwrap(),
+        Some(TreeValue::Tree(new_subtree.id().clone()))
+    );
+    assert_eq!(
+        spliced.path_value(&a_b_old_path).unwrap(),
+        tree.path_value(&a_b_old_path).unwrap()
+    );
+
+    // An empty subtree removes the entry
+    let spliced =
+        get_tree(tree::splice_subtree(&tree, &a_b_dir, store.empty_tree_id().clone()).unwrap());
+    assert_eq!(spliced.path_value(&a_b_dir).unwrap(), None);
+    assert_eq!(
+        spliced.path_value(&a_c_path).unwrap(),
+        tree.path_value(&a_c_path).unwrap()
+    );
+    // Splicing back the original subtree gives the original tree
+    let original_a_b = assert_matches!(
+        tree.path_value(&a_b_dir).unwrap(),
+        Some(TreeValue::Tree(id)) => id
+    );
+    assert_eq!(
+        tree::splice_subtree(&spliced, &a_b_dir, original_a_b).unwrap(),
+        *tree.id()
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_any_diff(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let a_path = RepoPath::from_internal_string("a");
+    let b_file_path = RepoPath::from_internal_string("b/file");
+    let c_file_path = RepoPath::from_internal_string("c/file");
+    let d_file_path = RepoPath::from_internal_string("d/file");
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&a_path, "1"),
+            (&b_file_path, "1"),
+            (&c_file_path, "1"),
+            (&d_file_path, "same"),
+        ],
+    );
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&a_path, "2"),
+            (&b_file_path, "2"),
+            (&c_file_path, "2"),
+            (&d_file_path, "same"),
+        ],
+    );
+
+    let counting_store = test_repo.counting_store();
+    let get_tree = |tree: &Tree| {
+        counting_store
+            .get_tree(&RepoPath::root(), tree.id())
+            .unwrap()
+    };
+    let tree1 = get_tree(&tree1);
+    let tree2 = get_tree(&tree2);
+    let tree_reads = || CountingBackend::of(&counting_store).tree_reads();
+    assert_eq!(tree_reads(), 2);
+
+    // The first entry differs, so no subtrees are read
+    assert!(tree1.any_diff(&tree2, &EverythingMatcher).unwrap());
+    assert_eq!(tree_reads(), 2);
+
+    // Only the subtrees the matcher can match are read
+    let matcher = PrefixMatcher::new(&[c_file_path.clone()]);
+    assert!(tree1.any_diff(&tree2, &matcher).unwrap());
+    assert_eq!(tree_reads(), 4);
+    let matcher = PrefixMatcher::new(&[d_file_path.clone()]);
+    assert!(!tree1.any_diff(&tree2, &matcher).unwrap());
+    assert!(!tree1.any_diff(&tree1, &EverythingMatcher).unwrap());
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_content_eq(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let path = RepoPath::from_internal_string("file");
+    let tree1 = testutils::create_tree(repo, &[(&path, "contents")]);
+    let tree2 = testutils::create_tree(repo, &[(&path, "contents")]);
+    let tree3 = testutils::create_tree(repo, &[(&path, "other contents")]);
+    assert!(tree1.content_eq(&tree2));
+    assert!(!tree1.content_eq(&tree3));
+}
+
+#[test]
+#[should_panic]
+fn test_content_eq_different_dir() {
+    let test_repo = TestRepo::init(false);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let tree = testutils::create_tree(repo, &[(&RepoPath::from_internal_string("file"), "")]);
+    let subdir_tree = store
+        .get_tree(&RepoPath::from_internal_string("dir"), tree.id())
+        .unwrap();
+    tree.content_eq(&subdir_tree);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_tree_builder_nested_paths(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let a_path = RepoPath::from_internal_string("a");
+    let a_b_path = RepoPath::from_internal_string("a/b");
+    let d_e_f_path = RepoPath::from_internal_string("d/e/f");
+    let d_e_g_path = RepoPath::from_internal_string("d/e/g");
+    let base_tree = testutils::create_tree(repo, &[(&a_path, "a"), (&d_e_f_path, "f")]);
+    let file_value = |path: &RepoPath, contents: &str| TreeValue::File {
+        id: testutils::write_file(store, path, contents),
+        executable: false,
+    };
+    let write_tree = |overrides: Vec<(&RepoPath, Option<TreeValue>)>| {
+        let mut tree_builder = store.tree_builder(base_tree.id().clone());
+        for (path, value) in overrides {
+            match value {
+                Some(value) => tree_builder.set(path.clone(), value),
+                None => tree_builder.remove(path.clone()),
+            }
+        }
+        let tree_id = tree_builder.write_tree().unwrap();
+        store.get_tree(&RepoPath::root(), &tree_id).unwrap()
+    };
+
+    // No overrides leaves the tree unchanged
+    assert_eq!(write_tree(vec![]).id(), base_tree.id());
+
+    // Intermediate directories are created
+    let value = file_value(&d_e_g_path, "g");
+    let tree = write_tree(vec![(&d_e_g_path, Some(value.clone()))]);
+    assert_eq!(tree.path_value(&d_e_g_path).unwrap(), Some(value));
+    assert_eq!(
+        tree.path_value(&d_e_f_path).unwrap(),
+        base_tree.path_value(&d_e_f_path).unwrap()
+    );
+    assert_eq!(
+        tree.path_value(&a_path).unwrap(),
+        base_tree.path_value(&a_path).unwrap()
+    );
+
+    // Setting a path under a file replaces the file by a directory
+    let value = file_value(&a_b_path, "b");
+    let tree = write_tree(vec![(&a_b_path, Some(value.clone()))]);
+    assert_matches!(tree.path_value(&a_path).unwrap(), Some(TreeValue::Tree(_)));
+    assert_eq!(tree.path_value(&a_b_path).unwrap(), Some(value));
+
+    // Removing the last entry of a directory removes the directory, and any
+    // parent directories that become empty
+    let tree = write_tree(vec![(&d_e_f_path, None)]);
+    assert_eq!(
+        tree.path_value(&RepoPath::from_internal_string("d/e"))
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        tree.path_value(&RepoPath::from_internal_string("d"))
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        tree.path_value(&a_path).unwrap(),
+        base_tree.path_value(&a_path).unwrap()
+    );
+
+    // Removing everything gives the empty tree
+    let tree = write_tree(vec![(&a_path, None), (&d_e_f_path, None)]);
+    assert_eq!(tree.id(), store.empty_tree_id());
+
+    // The result is the same as building the tree from scratch
+    let value = file_value(&d_e_g_path, "g");
+    let tree = write_tree(vec![(&a_path, None), (&d_e_g_path, Some(value))]);
+    let expected = testutils::create_tree(repo, &[(&d_e_f_path, "f"), (&d_e_g_path, "g")]);
+    assert_eq!(tree.id(), expected.id());
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_against_empty_tree(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let a_b_file_path = RepoPath::from_internal_string("a/b/file");
+    let c_path = RepoPath::from_internal_string("c");
+    let tree = testutils::create_tree(repo, &[(&a_b_file_path, "file"), (&c_path, "c")]);
+
+    let counting_store = test_repo.counting_store();
+    let empty_tree = counting_store
+        .get_tree(&RepoPath::root(), counting_store.empty_tree_id())
+        .unwrap();
+    let tree = counting_store
+        .get_tree(&RepoPath::root(), tree.id())
+        .unwrap();
+    let value = |path: &RepoPath| tree.path_value(path).unwrap().unwrap();
+    assert_eq!(
+        empty_tree
+            .diff(&tree, &EverythingMatcher)
+            .map(Result::unwrap)
+            .collect_vec(),
+        vec![
+            (a_b_file_path.clone(), Diff::Added(value(&a_b_file_path))),
+            (c_path.clone(), Diff::Added(value(&c_path))),
+        ]
+    );
+    // Only the root tree, "a", and "a/b" were read. The empty tree is never
+    // read from the backend.
+    assert_eq!(CountingBackend::of(&counting_store).tree_reads(), 3);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_map_files(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let a_path = RepoPath::from_internal_string("a");
+    let a_tmp_path = RepoPath::from_internal_string("a.tmp");
+    let d_b_path = RepoPath::from_internal_string("d/b");
+    let d_b_tmp_path = RepoPath::from_internal_string("d/b.tmp");
+    let e_c_tmp_path = RepoPath::from_internal_string("e/c.tmp");
+    let tree = testutils::create_tree(
+        repo,
+        &[
+            (&a_path, "a"),
+            (&a_tmp_path, "a"),
+            (&d_b_path, "b"),
+            (&d_b_tmp_path, "b"),
+            (&e_c_tmp_path, "c"),
+        ],
+    );
+    let get_tree = |id| store.get_tree(&RepoPath::root(), &id).unwrap();
+
+    // Invert the executable bit
+    let inverted = get_tree(
+        tree.map_files(|_path, id, executable| Some((id.clone(), !executable)))
+            .unwrap(),
+    );
+    assert_eq!(
+        inverted
+            .entries()
+            .map(Result::unwrap)
+            .map(|(path, _)| path)
+            .collect_vec(),
+        tree.entries()
+            .map(Result::unwrap)
+            .map(|(path, _)| path)
+            .collect_vec()
+    );
+    for (path, value) in inverted.entries().map(Result::unwrap) {
+        let id = assert_matches!(
+            tree.path_value(&path).unwrap(),
+            Some(TreeValue::File { id, .. }) => id
+        );
+        assert_eq!(
+            value,
+            TreeValue::File {
+                id,
+                executable: true
+            }
+        );
+    }
+    // Inverting it again gives the original tree
+    assert_eq!(
+        inverted
+            .map_files(|_path, id, executable| Some((id.clone(), !executable)))
+            .unwrap(),
+        *tree.id()
+    );
+
+    // Remove all .tmp files
+    let mut visited = vec![];
+    let without_tmp = get_tree(
+        tree.map_files(|path, id, executable| {
+            visited.push(path.clone());
+            if path.to_internal_file_string().ends_with(".tmp") {
+                None
+            } else {
+                Some((id.clone(), executable))
+            }
+        })
+        .unwrap(),
+    );
+    assert_eq!(
+        visited,
+        tree.entries()
+            .map(Result::unwrap)
+            .map(|(path, _)| path)
+            .collect_vec()
+    );
+    assert_eq!(
+        without_tmp
+            .entries()
+            .map(Result::unwrap)
+            .map(|(path, _)| path)
+            .collect_vec(),
+        vec![a_path, d_b_path]
+    );
+    // The directory that only had .tmp files was removed
+    assert_eq!(
+        without_tmp
+            .path_value(&RepoPath::from_internal_string("e"))
+            .unwrap(),
+        None
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_for_each_diff(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let a_path = RepoPath::from_internal_string("a");
+    let b_path = RepoPath::from_internal_string("b");
+    let b_file_path = RepoPath::from_internal_string("b/file");
+    let d_path = RepoPath::from_internal_string("d");
+    let d_file_path = RepoPath::from_internal_string("d/file");
+    let e_f_path = RepoPath::from_internal_string("e/f");
+    let e_g_path = RepoPath::from_internal_string("e/g");
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&a_path, "a"),
+            (&b_path, "b"),
+            (&d_file_path, "d"),
+            (&e_f_path, "f"),
+        ],
+    );
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&a_path, "modified"),
+            (&b_file_path, "b"),
+            (&d_path, "d"),
+            (&e_g_path, "g"),
+        ],
+    );
+    let collect = |tree1: &Tree, tree2: &Tree, matcher: &dyn Matcher| {
+        let mut diffs = vec![];
+        let result = tree1
+            .for_each_diff(tree2, matcher, |path, diff| {
+                diffs.push((path, diff));
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert_eq!(result, ControlFlow::Continue(()));
+        diffs
+    };
+
+    // Same diffs in the same order as diff()
+    for (tree1, tree2) in [(&tree1, &tree2), (&tree2, &tree1), (&tree1, &tree1)] {
+        assert_eq!(
+            collect(tree1, tree2, &EverythingMatcher),
+            tree1
+                .diff(tree2, &EverythingMatcher)
+                .map(Result::unwrap)
+                .collect_vec()
+        );
+        let matcher = PrefixMatcher::new(&[b_path.clone(), e_g_path.clone()]);
+        assert_eq!(
+            collect(tree1, tree2, &matcher),
+            tree1
+                .diff(tree2, &matcher)
+                .map(Result::unwrap)
+                .collect_vec()
+        );
+    }
+    assert_eq!(collect(&tree1, &tree2, &EverythingMatcher).len(), 7);
+
+    // Stops when the callback says so
+    let mut diffs = vec![];
+    let result = tree1
+        .for_each_diff(&tree2, &EverythingMatcher, |path, diff| {
+            diffs.push((path, diff));
+            if diffs.len() == 2 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .unwrap();
+    assert_eq!(result, ControlFlow::Break(()));
+    assert_eq!(
+        diffs,
+        tree1
+            .diff(&tree2, &EverythingMatcher)
+            .map(Result::unwrap)
+            .take(2)
+            .collect_vec()
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_for_each_diff_ref(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let a_path = RepoPath::from_internal_string("a");
+    let b_path = RepoPath::from_internal_string("b");
+    let c_path = RepoPath::from_internal_string("c");
+    let d_file_path = RepoPath::from_internal_string("d/file");
+    let tree1 =
+        testutils::create_tree(repo, &[(&a_path, "a"), (&b_path, "b"), (&d_file_path, "d")]);
+    let tree2 = testutils::create_tree(repo, &[(&a_path, "modified"), (&c_path, "c")]);
+
+    // Same diffs in the same order as for_each_diff(), just borrowed
+    let mut borrowed = vec![];
+    let result = tree1
+        .for_each_diff_ref(&tree2, &EverythingMatcher, |path, diff| {
+            borrowed.push((path.clone(), diff.map(Clone::clone)));
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+    assert_eq!(result, ControlFlow::Continue(()));
+    assert_eq!(
+        borrowed,
+        tree1
+            .diff(&tree2, &EverythingMatcher)
+            .map(Result::unwrap)
+            .collect_vec()
+    );
+    assert_eq!(borrowed.len(), 4);
+
+    // Stops when the callback says so
+    let mut paths = vec![];
+    let result = tree1
+        .for_each_diff_ref(&tree2, &EverythingMatcher, |path, _diff| {
+            paths.push(path.clone());
+            ControlFlow::Break(())
+        })
+        .unwrap();
+    assert_eq!(result, ControlFlow::Break(()));
+    assert_eq!(paths, vec![a_path]);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_commit_trees(use_git: bool) {
+    let settings = testutils::user_settings();
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let a_path = RepoPath::from_internal_string("a");
+    let b_path = RepoPath::from_internal_string("dir/b");
+    let tree1 = testutils::create_tree(repo, &[(&a_path, "a"), (&b_path, "b")]);
+    let tree2 = testutils::create_tree(repo, &[(&a_path, "modified"), (&b_path, "b")]);
+    let mut tx = repo.start_transaction(&settings, "test");
+    let commit1 = create_random_commit(tx.mut_repo(), &settings)
+        .set_tree(tree1.id().clone())
+        .write()
+        .unwrap();
+    let commit2 = create_random_commit(tx.mut_repo(), &settings)
+        .set_tree(tree2.id().clone())
+        .write()
+        .unwrap();
+
+    let store = repo.store();
+    for matcher in [
+        &EverythingMatcher as &dyn Matcher,
+        &PrefixMatcher::new(&[b_path.clone()]),
+    ] {
+        assert_eq!(
+            tree::diff_commit_trees(store, &commit1, &commit2, matcher)
+                .unwrap()
+                .map(Result::unwrap)
+                .collect_vec(),
+            tree::recursive_tree_diff(tree1.clone(), tree2.clone(), matcher)
+                .map(Result::unwrap)
+                .collect_vec()
+        );
+    }
+    assert_eq!(
+        tree::diff_commit_trees(store, &commit1, &commit2, &EverythingMatcher)
+            .unwrap()
+            .map(|item| item.unwrap().0)
+            .collect_vec(),
+        vec![a_path]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_entries(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let added_path = RepoPath::from_internal_string("added");
+    let modified_path = RepoPath::from_internal_string("modified");
+    let removed_path = RepoPath::from_internal_string("removed");
+    let same_path = RepoPath::from_internal_string("same");
+    let dir_file_path = RepoPath::from_internal_string("dir/file");
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&modified_path, "before"),
+            (&removed_path, "removed"),
+            (&same_path, "same"),
+            (&dir_file_path, "before"),
+        ],
+    );
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&added_path, "added"),
+            (&modified_path, "after"),
+            (&same_path, "same"),
+            (&dir_file_path, "after"),
+        ],
+    );
+
+    let component = |name: &str| RepoPathComponent::from(name);
+    let entries = tree::diff_entries(&tree1, &tree2)
+        .map(|(name, before, after)| (name.clone(), before.cloned(), after.cloned()))
+        .collect_vec();
+    assert_eq!(
+        entries,
+        vec![
+            (
+                component("added"),
+                None,
+                tree2.path_value(&added_path).unwrap()
+            ),
+            (
+                component("dir"),
+                tree1.value(&component("dir")).cloned(),
+                tree2.value(&component("dir")).cloned(),
+            ),
+            (
+                component("modified"),
+                tree1.path_value(&modified_path).unwrap(),
+                tree2.path_value(&modified_path).unwrap(),
+            ),
+            (
+                component("removed"),
+                tree1.path_value(&removed_path).unwrap(),
+                None
+            ),
+        ]
+    );
+    // The changed subtree is yielded as a tree, not descended into
+    assert_matches!(entries[1].1, Some(TreeValue::Tree(_)));
+
+    assert_eq!(tree::diff_entries(&tree1, &tree1).count(), 0);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_removals_and_additions(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let path = RepoPath::from_internal_string;
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&path("file"), "file"),
+            (&path("modified"), "before"),
+            (&path("old/dir/file"), "old"),
+            (&path("replaced"), "file"),
+            (&path("shared/removed"), "removed"),
+            (&path("shared/kept"), "kept"),
+        ],
+    );
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&path("file/new"), "file"),
+            (&path("modified"), "after"),
+            (&path("new/dir/file"), "new"),
+            (&path("replaced/file"), "dir"),
+            (&path("shared/added"), "added"),
+            (&path("shared/kept"), "kept"),
+        ],
+    );
+
+    // Same results as filtering diff()
+    let filtered = |tree1: &Tree, tree2: &Tree, matcher: &dyn Matcher, removed: bool| {
+        tree1
+            .diff(tree2, matcher)
+            .map(Result::unwrap)
+            .filter(|(_, diff)| match diff {
+                Diff::Removed(_) => removed,
+                Diff::Added(_) => !removed,
+                Diff::Modified(..) => false,
+            })
+            .collect_vec()
+    };
+    let matcher = PrefixMatcher::new(&[path("new"), path("old"), path("shared")]);
+    for (tree1, tree2) in [(&tree1, &tree2), (&tree2, &tree1)] {
+        for matcher in [&EverythingMatcher as &dyn Matcher, &matcher] {
+            assert_eq!(
+                tree1.diff_removals(tree2, matcher).unwrap(),
+                filtered(tree1, tree2, matcher, true)
+            );
+            assert_eq!(
+                tree1.diff_additions(tree2, matcher).unwrap(),
+                filtered(tree1, tree2, matcher, false)
+            );
+        }
+    }
+    assert_eq!(
+        tree1
+            .diff_removals(&tree2, &EverythingMatcher)
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect_vec(),
+        vec![
+            path("file"),
+            path("old/dir/file"),
+            path("replaced"),
+            path("shared/removed")
+        ]
+    );
+
+    // Subtrees that only exist on the other side aren't read
+    let counting_store = test_repo.counting_store();
+    let get_tree = |tree: &Tree| {
+        counting_store
+            .get_tree(&RepoPath::root(), tree.id())
+            .unwrap()
+    };
+    let tree_reads = || CountingBackend::of(&counting_store).tree_reads();
+    let tree1 = get_tree(&tree1);
+    let tree2 = get_tree(&tree2);
+    assert_eq!(tree_reads(), 2);
+    // old, old/dir, and both sides of shared
+    assert_eq!(
+        tree1
+            .diff_removals(&tree2, &EverythingMatcher)
+            .unwrap()
+            .len(),
+        4
+    );
+    assert_eq!(tree_reads(), 6);
+    // new, new/dir, file, and replaced were not read above, but diff() reads
+    // them
+    assert_eq!(filtered(&tree1, &tree2, &EverythingMatcher, true).len(), 4);
+    assert_eq!(tree_reads(), 10);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_resolving_conflicts(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let file_value = |contents: &str| -> TreeValue {
+        TreeValue::File {
+            id: testutils::write_file(store, &path, contents),
+            executable: false,
+        }
+    };
+    let conflict_value = |base: &str, left: &str, right: &str| -> TreeValue {
+        let term = |contents| ConflictTerm {
+            value: file_value(contents),
+        };
+        let conflict = Conflict {
+            removes: vec![term(base)],
+            adds: vec![term(left), term(right)],
+        };
+        TreeValue::Conflict(store.write_conflict(&path, &conflict).unwrap())
+    };
+    let write_tree = |value: TreeValue| -> Tree {
+        let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+        tree_builder.set(path.clone(), value);
+        let tree_id = tree_builder.write_tree().unwrap();
+        store.get_tree(&RepoPath::root(), &tree_id).unwrap()
+    };
+    let resolved_value = |contents: &str| ResolvedValue::ResolvedConflict {
+        content: contents.as_bytes().to_vec(),
+        executable: false,
+    };
+    let diff = |tree1: &Tree, tree2: &Tree| {
+        tree1
+            .diff_resolving_conflicts(tree2, &EverythingMatcher)
+            .map(Result::unwrap)
+            .collect_vec()
+    };
+
+    // Two different conflicts that resolve to the same contents, and the
+    // resolved file itself
+    let resolvable1 = write_tree(conflict_value("a\nb\nc\n", "A\nb\nc\n", "a\nb\nC\n"));
+    let resolvable2 = write_tree(conflict_value("a\nb\nc\n", "a\nb\nC\n", "A\nb\nc\n"));
+    let resolved = write_tree(file_value("A\nb\nC\n"));
+    assert_eq!(
+        resolvable1
+            .diff(&resolvable2, &EverythingMatcher)
+            .map(Result::unwrap)
+            .count(),
+        1
+    );
+    assert_eq!(diff(&resolvable1, &resolvable2), vec![]);
+    assert_eq!(diff(&resolvable1, &resolved), vec![]);
+    assert_eq!(diff(&resolved, &resolvable2), vec![]);
+
+    // Conflicts that resolve to different contents are compared as files
+    let other_resolvable = write_tree(conflict_value("a\nb\nc\n", "X\nb\nc\n", "a\nb\nZ\n"));
+    assert_eq!(
+        diff(&resolvable1, &other_resolvable),
+        vec![(
+            path.clone(),
+            Diff::Modified(resolved_value("A\nb\nC\n"), resolved_value("X\nb\nZ\n"))
+        )]
+    );
+
+    // Conflicts that can't be resolved are compared by ID
+    let unresolvable1 = conflict_value("a\n", "b\n", "c\n");
+    let unresolvable2 = conflict_value("a\n", "c\n", "b\n");
+    assert_eq!(
+        diff(
+            &write_tree(unresolvable1.clone()),
+            &write_tree(unresolvable2.clone())
+        ),
+        vec![(
+            path.clone(),
+            Diff::Modified(
+                ResolvedValue::Value(unresolvable1.clone()),
+                ResolvedValue::Value(unresolvable2)
+            )
+        )]
+    );
+    assert_eq!(
+        diff(&resolvable1, &write_tree(unresolvable1.clone())),
+        vec![(
+            path.clone(),
+            Diff::Modified(
+                resolved_value("A\nb\nC\n"),
+                ResolvedValue::Value(unresolvable1)
+            )
+        )]
+    );
+
+    // Added and removed conflicts are resolved too
+    let empty_tree = store
+        .get_tree(&RepoPath::root(), store.empty_tree_id())
+        .unwrap();
+    assert_eq!(
+        diff(&empty_tree, &resolvable1),
+        vec![(path.clone(), Diff::Added(resolved_value("A\nb\nC\n")))]
+    );
+    assert_eq!(
+        diff(&resolvable1, &empty_tree),
+        vec![(path.clone(), Diff::Removed(resolved_value("A\nb\nC\n")))]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_paths_only_in(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string;
+    let tree1 = {
+        let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+        testutils::write_normal_file(&mut tree_builder, &path("became_dir"), "file");
+        testutils::write_normal_file(&mut tree_builder, &path("became_file/a"), "a");
+        testutils::write_normal_file(&mut tree_builder, &path("became_file/b"), "b");
+        testutils::write_normal_file(&mut tree_builder, &path("modified"), "before");
+        testutils::write_normal_file(&mut tree_builder, &path("only_in_1"), "1");
+        testutils::write_normal_file(&mut tree_builder, &path("type_changed"), "file");
+        let tree_id = tree_builder.write_tree().unwrap();
+        store.get_tree(&RepoPath::root(), &tree_id).unwrap()
+    };
+    let tree2 = {
+        let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+        testutils::write_normal_file(&mut tree_builder, &path("became_dir/file"), "file");
+        testutils::write_normal_file(&mut tree_builder, &path("became_file"), "file");
+        testutils::write_normal_file(&mut tree_builder, &path("modified"), "after");
+        testutils::write_normal_file(&mut tree_builder, &path("only_in_2"), "2");
+        testutils::write_symlink(&mut tree_builder, &path("type_changed"), "target");
+        let tree_id = tree_builder.write_tree().unwrap();
+        store.get_tree(&RepoPath::root(), &tree_id).unwrap()
+    };
+
+    // A path with different values on each side is in both trees, but a file
+    // and a directory of the same name are not the same path
+    assert_eq!(
+        tree1.paths_only_in(&tree2, &EverythingMatcher).unwrap(),
+        vec![
+            path("became_dir"),
+            path("became_file/a"),
+            path("became_file/b"),
+            path("only_in_1"),
+        ]
+    );
+    assert_eq!(
+        tree2.paths_only_in(&tree1, &EverythingMatcher).unwrap(),
+        vec![
+            path("became_dir/file"),
+            path("became_file"),
+            path("only_in_2"),
+        ]
+    );
+    assert_eq!(
+        tree1
+            .paths_only_in(&tree2, &PrefixMatcher::new(&[path("became_file")]))
+            .unwrap(),
+        vec![path("became_file/a"), path("became_file/b")]
+    );
+    assert_eq!(
+        tree1.paths_only_in(&tree1, &EverythingMatcher).unwrap(),
+        vec![]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_ignoring_exec_bit(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let store = test_repo.repo.store();
+
+    let flipped_path = RepoPath::from_internal_string("dir/flipped");
+    let modified_path = RepoPath::from_internal_string("dir/modified");
+    let both_path = RepoPath::from_internal_string("both");
+    let write_tree = |flipped_exec: bool, modified_contents: &str, both_exec: bool| {
+        let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+        if flipped_exec {
+            testutils::write_executable_file(&mut tree_builder, &flipped_path, "flipped");
+        } else {
+            testutils::write_normal_file(&mut tree_builder, &flipped_path, "flipped");
+        }
+        testutils::write_normal_file(&mut tree_builder, &modified_path, modified_contents);
+        if both_exec {
+            testutils::write_executable_file(&mut tree_builder, &both_path, "after");
+        } else {
+            testutils::write_normal_file(&mut tree_builder, &both_path, "before");
+        }
+        let tree_id = tree_builder.write_tree().unwrap();
+        store.get_tree(&RepoPath::root(), &tree_id).unwrap()
+    };
+    let tree1 = write_tree(false, "before", false);
+    let tree2 = write_tree(true, "after", true);
+
+    // A plain diff includes the exec-bit flip
+    let all_paths = tree1
+        .diff(&tree2, &EverythingMatcher)
+        .map(Result::unwrap)
+        .map(|(path, _)| path)
+        .collect_vec();
+    assert_eq!(
+        all_paths,
+        vec![
+            both_path.clone(),
+            flipped_path.clone(),
+            modified_path.clone()
+        ]
+    );
+
+    // The flip is ignored, but content changes are still included, even if
+    // the exec bit also changed
+    let paths = tree1
+        .diff_ignoring_exec_bit(&tree2, &EverythingMatcher)
+        .map(Result::unwrap)
+        .map(|(path, _)| path)
+        .collect_vec();
+    assert_eq!(paths, vec![both_path.clone(), modified_path.clone()]);
+
+    // A pure exec-bit flip yields no diff at all
+    let tree3 = write_tree(true, "before", false);
+    assert_eq!(
+        tree1
+            .diff_ignoring_exec_bit(&tree3, &EverythingMatcher)
+            .map(Result::unwrap)
+            .count(),
+        0
+    );
+    assert_eq!(
+        tree1
+            .diff(&tree3, &EverythingMatcher)
+            .map(Result::unwrap)
+            .count(),
+        1
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_with_size_limit(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let small_path = RepoPath::from_internal_string("small");
+    let large_path = RepoPath::from_internal_string("large");
+    let grown_path = RepoPath::from_internal_string("grown");
+    let large_content = "x".repeat(100);
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&small_path, "small"),
+            (&large_path, &large_content),
+            (&grown_path, "small"),
+        ],
+    );
+    let tree2 = testutils::create_tree(
+        repo,
+        &[(&small_path, "still small"), (&grown_path, &large_content)],
+    );
+    assert_eq!(
+        repo.store()
+            .file_size(
+                &large_path,
+                &testutils::write_file(repo.store(), &large_path, &large_content)
+            )
+            .unwrap(),
+        100
+    );
+
+    let counting_store = test_repo.counting_store();
+    let get_tree = |tree: &Tree| {
+        counting_store
+            .get_tree(&RepoPath::root(), tree.id())
+            .unwrap()
+    };
+    let tree1 = get_tree(&tree1);
+    let tree2 = get_tree(&tree2);
+    let too_large = tree1
+        .diff_with_size_limit(&tree2, &EverythingMatcher, 50)
+        .map(|diff| {
+            let diff = diff.unwrap();
+            (diff.path, diff.too_large)
+        })
+        .collect_vec();
+    assert_eq!(
+        too_large,
+        vec![(grown_path, true), (large_path, true), (small_path, false)]
+    );
+    // The file contents were not read
+    assert_eq!(CountingBackend::of(&counting_store).file_reads(), 0);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_file_sizes(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let empty_path = RepoPath::from_internal_string("empty");
+    let small_path = RepoPath::from_internal_string("dir/small");
+    let large_path = RepoPath::from_internal_string("dir/large");
+    let symlink_path = RepoPath::from_internal_string("dir/symlink");
+    let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+    testutils::write_normal_file(&mut tree_builder, &empty_path, "");
+    testutils::write_normal_file(&mut tree_builder, &small_path, "small");
+    testutils::write_executable_file(&mut tree_builder, &large_path, &"x".repeat(1000));
+    testutils::write_symlink(&mut tree_builder, &symlink_path, "target");
+    let tree_id = tree_builder.write_tree().unwrap();
+
+    let counting_store = test_repo.counting_store();
+    let tree = counting_store
+        .get_tree(&RepoPath::root(), &tree_id)
+        .unwrap();
+    assert_eq!(
+        tree.file_sizes(&EverythingMatcher).unwrap(),
+        vec![(large_path, 1000), (small_path.clone(), 5), (empty_path, 0)]
+    );
+    // The file contents were not read through the store
+    assert_eq!(CountingBackend::of(&counting_store).file_reads(), 0);
+
+    let matcher = PrefixMatcher::new(&[small_path.clone()]);
+    assert_eq!(tree.file_sizes(&matcher).unwrap(), vec![(small_path, 5)]);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_filtered_tree_id(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let top_path = RepoPath::from_internal_string("top");
+    let src_a_path = RepoPath::from_internal_string("src/a");
+    let src_sub_b_path = RepoPath::from_internal_string("src/sub/b");
+    let docs_c_path = RepoPath::from_internal_string("docs/c");
+    let tree = testutils::create_tree(
+        repo,
+        &[
+            (&top_path, "top"),
+            (&src_a_path, "a"),
+            (&src_sub_b_path, "b"),
+            (&docs_c_path, "c"),
+        ],
+    );
+
+    // Nothing matches
+    assert_eq!(
+        tree.filtered_tree_id(&NothingMatcher).unwrap(),
+        *repo.store().empty_tree_id()
+    );
+    // Everything matches
+    assert_eq!(
+        tree.filtered_tree_id(&EverythingMatcher).unwrap(),
+        *tree.id()
+    );
+
+    // Some files match, so "src/sub" and "docs" become empty and are removed
+    let matcher = FilesMatcher::new(&[top_path.clone(), src_a_path.clone()]);
+    let expected_tree = testutils::create_tree(repo, &[(&top_path, "top"), (&src_a_path, "a")]);
+    assert_eq!(
+        tree.filtered_tree_id(&matcher).unwrap(),
+        *expected_tree.id()
+    );
+
+    // A whole subtree matches, so it's kept without being read
+    let counting_store = test_repo.counting_store();
+    let tree = counting_store
+        .get_tree(&RepoPath::root(), tree.id())
+        .unwrap();
+    let matcher = PrefixMatcher::new(&[RepoPath::from_internal_string("src")]);
+    let expected_tree = testutils::create_tree(repo, &[(&src_a_path, "a"), (&src_sub_b_path, "b")]);
+    assert_eq!(
+        tree.filtered_tree_id(&matcher).unwrap(),
+        *expected_tree.id()
+    );
+    assert_eq!(CountingBackend::of(&counting_store).tree_reads(), 1);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_reachable_tree_ids(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let a_file_path = RepoPath::from_internal_string("a/file");
+    let b_file_path = RepoPath::from_internal_string("b/file");
+    let c_d_file_path = RepoPath::from_internal_string("c/d/file");
+    // "a" and "b" have the same content, so they're the same tree
+    let tree = testutils::create_tree(
+        repo,
+        &[
+            (&a_file_path, "same"),
+            (&b_file_path, "same"),
+            (&c_d_file_path, "other"),
+        ],
+    );
+    let tree_id = |path: &str| {
+        assert_matches!(
+            tree.path_value(&RepoPath::from_internal_string(path)).unwrap(),
+            Some(TreeValue::Tree(id)) => id
+        )
+    };
+    assert_eq!(tree_id("a"), tree_id("b"));
+
+    assert_eq!(
+        tree.reachable_tree_ids(&EverythingMatcher).unwrap(),
+        BTreeSet::from([
+            tree.id().clone(),
+            tree_id("a"),
+            tree_id("c"),
+            tree_id("c/d")
+        ])
+    );
+    let matcher = PrefixMatcher::new(&[RepoPath::from_internal_string("c")]);
+    assert_eq!(
+        tree.reachable_tree_ids(&matcher).unwrap(),
+        BTreeSet::from([tree.id().clone(), tree_id("c"), tree_id("c/d")])
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_visit_reachable_trees(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    // "a" and "b" have the same content, so they're the same tree
+    let tree = testutils::create_tree(
+        repo,
+        &[
+            (&RepoPath::from_internal_string("a/file"), "same"),
+            (&RepoPath::from_internal_string("b/file"), "same"),
+            (&RepoPath::from_internal_string("c/d/file"), "other"),
+        ],
+    );
+    let tree_id = |path: &str| {
+        assert_matches!(
+            tree.path_value(&RepoPath::from_internal_string(path)).unwrap(),
+            Some(TreeValue::Tree(id)) => id
+        )
+    };
+
+    let mut visited = vec![];
+    let result = tree
+        .visit_reachable_trees(&EverythingMatcher, |id, depth| {
+            visited.push((id.clone(), depth));
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+    assert_eq!(result, ControlFlow::Continue(()));
+    assert_eq!(
+        visited,
+        vec![
+            (tree.id().clone(), 0),
+            (tree_id("a"), 1),
+            (tree_id("c"), 1),
+            (tree_id("c/d"), 2),
+        ]
+    );
+
+    // Breaking out of the callback stops reading trees
+    let counting_store = test_repo.counting_store();
+    let tree = counting_store
+        .get_tree(&RepoPath::root(), tree.id())
+        .unwrap();
+    let mut visited = vec![];
+    let result = tree
+        .visit_reachable_trees(&EverythingMatcher, |id, depth| {
+            visited.push(id.clone());
+            if depth > 0 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .unwrap();
+    assert_eq!(result, ControlFlow::Break(()));
+    assert_eq!(visited, vec![tree.id().clone(), tree_id("a")]);
+    // Only the root tree and "a" were read
+    assert_eq!(CountingBackend::of(&counting_store).tree_reads(), 2);
+}
+
+#[test]
+fn test_diff_is_type_change() {
+    let file = |hex: &str, executable| TreeValue::File {
+        id: FileId::from_hex(hex),
+        executable,
+    };
+    let symlink = TreeValue::Symlink(SymlinkId::from_hex("cc"));
+    let conflict = TreeValue::Conflict(ConflictId::from_hex("dd"));
+
+    // Content and executable bit changes are not type changes
+    assert!(!Diff::Modified(file("aa", false), file("bb", false)).is_type_change());
+    assert!(!Diff::Modified(file("aa", false), file("aa", true)).is_type_change());
+    assert!(Diff::Modified(file("aa", false), symlink.clone()).is_type_change());
+    assert!(Diff::Modified(symlink.clone(), file("aa", false)).is_type_change());
+    assert!(Diff::Modified(file("aa", false), conflict).is_type_change());
+    assert!(!Diff::Added(symlink.clone()).is_type_change());
+    assert!(!Diff::Removed(symlink).is_type_change());
+}
+
+#[test]
+fn test_path_change_from_diff() {
+    let file = |hex: &str, executable| TreeValue::File {
+        id: FileId::from_hex(hex),
+        executable,
+    };
+    let symlink = TreeValue::Symlink(SymlinkId::from_hex("cc"));
+
+    assert_eq!(
+        PathChange::from(&Diff::Added(file("aa", false))),
+        PathChange::Add
+    );
+    assert_eq!(
+        PathChange::from(&Diff::Removed(symlink.clone())),
+        PathChange::Delete
+    );
+    assert_eq!(
+        PathChange::from(&Diff::Modified(file("aa", false), file("bb", false))),
+        PathChange::Modify
+    );
+    assert_eq!(
+        PathChange::from(&Diff::Modified(file("aa", false), file("aa", true))),
+        PathChange::Modify
+    );
+    assert_eq!(
+        PathChange::from(&Diff::Modified(file("aa", false), symlink.clone())),
+        PathChange::TypeChange
+    );
+    assert_eq!(
+        PathChange::from(&Diff::Modified(symlink, file("aa", false))),
+        PathChange::TypeChange
+    );
+}
+
+#[test]
+fn test_diff_map() {
+    assert_eq!(Diff::Modified(1, 2).map(|x| x * 10), Diff::Modified(10, 20));
+    assert_eq!(
+        Diff::Added(1).map(|x| x.to_string()),
+        Diff::Added("1".to_string())
+    );
+    assert_eq!(Diff::Removed(2).map(|x| x + 1), Diff::Removed(3));
+}
+
+#[test]
+fn test_diff_try_map() {
+    let file = |hex: &str| TreeValue::File {
+        id: FileId::from_hex(hex),
+        executable: false,
+    };
+    let symlink = TreeValue::Symlink(SymlinkId::from_hex("cc"));
+    let file_id = |value: TreeValue| match value {
+        TreeValue::File { id, .. } => Some(id),
+        _ => None,
+    };
+
+    assert_eq!(
+        Diff::Modified(file("aa"), file("bb")).try_map(file_id),
+        Some(Diff::Modified(
+            FileId::from_hex("aa"),
+            FileId::from_hex("bb")
+        ))
+    );
+    assert_eq!(
+        Diff::Added(file("aa")).try_map(file_id),
+        Some(Diff::Added(FileId::from_hex("aa")))
+    );
+    assert_eq!(
+        Diff::Removed(file("bb")).try_map(file_id),
+        Some(Diff::Removed(FileId::from_hex("bb")))
+    );
+
+    // A side that isn't a file
+    assert_eq!(
+        Diff::Modified(file("aa"), symlink.clone()).try_map(file_id),
+        None
+    );
+    assert_eq!(
+        Diff::Modified(symlink.clone(), file("aa")).try_map(file_id),
+        None
+    );
+    assert_eq!(Diff::Added(symlink.clone()).try_map(file_id), None);
+    assert_eq!(Diff::Removed(symlink).try_map(file_id), None);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_with_contents(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let modified_path = RepoPath::from_internal_string("modified");
+    let added_path = RepoPath::from_internal_string("added");
+    let symlink_path = RepoPath::from_internal_string("symlink");
+    let tree1 = testutils::create_tree(repo, &[(&modified_path, "before\n")]);
+    let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+    testutils::write_normal_file(&mut tree_builder, &modified_path, "after\n");
+    testutils::write_normal_file(&mut tree_builder, &added_path, "added\n");
+    tree_builder.set(
+        symlink_path.clone(),
+        TreeValue::Symlink(store.write_symlink(&symlink_path, "target").unwrap()),
+    );
+    let tree2 = store
+        .get_tree(&RepoPath::root(), &tree_builder.write_tree().unwrap())
+        .unwrap();
+
+    let read_all = |reader: Option<Box<dyn Read>>| {
+        reader.map(|mut reader| {
+            let mut content = String::new();
+            reader.read_to_string(&mut content).unwrap();
+            content
+        })
+    };
+    let diffs = tree1
+        .diff(&tree2, &EverythingMatcher)
+        .with_contents(store)
+        .map(|entry| entry.unwrap())
+        .collect_vec();
+    // The same diffs as without contents
+    assert_eq!(
+        diffs
+            .iter()
+            .map(|(path, diff, _, _)| (path.clone(), diff.clone()))
+            .collect_vec(),
+        tree1
+            .diff(&tree2, &EverythingMatcher)
+            .map(Result::unwrap)
+            .collect_vec()
+    );
+    assert_eq!(
+        diffs
+            .into_iter()
+            .map(|(path, _, before, after)| (path, read_all(before), read_all(after)))
+            .collect_vec(),
+        vec![
+            (added_path, None, Some("added\n".to_string())),
+            (
+                modified_path,
+                Some("before\n".to_string()),
+                Some("after\n".to_string())
+            ),
+            // Symlinks don't get readers
+            (symlink_path, None, None),
+        ]
+    );
+}
+
+#[test]
+fn test_diff_as_submodule_change() {
+    let submodule = |hex: &str| TreeValue::GitSubmodule(CommitId::from_hex(hex));
+    let file = TreeValue::File {
+        id: FileId::from_hex("aa"),
+        executable: false,
+    };
+
+    assert_eq!(
+        Diff::Modified(submodule("01"), submodule("02")).as_submodule_change(),
+        Some((
+            Some(&CommitId::from_hex("01")),
+            Some(&CommitId::from_hex("02"))
+        ))
+    );
+    assert_eq!(
+        Diff::Added(submodule("01")).as_submodule_change(),
+        Some((None, Some(&CommitId::from_hex("01"))))
+    );
+    assert_eq!(
+        Diff::Modified(submodule("01"), file.clone()).as_submodule_change(),
+        Some((Some(&CommitId::from_hex("01")), None))
+    );
+    assert_eq!(
+        Diff::Modified(file.clone(), file).as_submodule_change(),
+        None
+    );
+}
+
+fn random_tree_paths(rng: &mut impl Rng) -> Vec<(RepoPath, String)> {
+    let components = ["a", "b", "c"];
+    let mut paths = BTreeSet::new();
+    for _ in 0..rng.gen_range(0..20) {
+        let depth = rng.gen_range(1..=3);
+        let path = (0..depth)
+            .map(|_| *components.choose(rng).unwrap())
+            .join("/");
+        paths.insert(path);
+    }
+    // A path can't be both a file and a directory
+    let files = paths
+        .iter()
+        .filter(|path| {
+            !paths
+                .iter()
+                .any(|other| other.starts_with(&format!("{path}/")))
+        })
+        .collect_vec();
+    files
+        .into_iter()
+        .map(|path| {
+            let contents = format!("{}\n", rng.gen_range(0..2));
+            (RepoPath::from_internal_string(path), contents)
+        })
+        .collect()
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_summary_parallel(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let mut rng = ChaCha8Rng::seed_from_u64(0);
+    for _ in 0..50 {
+        let paths1 = random_tree_paths(&mut rng);
+        let paths2 = random_tree_paths(&mut rng);
+        let tree1 = testutils::create_tree(
+            repo,
+            &paths1
+                .iter()
+                .map(|(path, contents)| (path, contents.as_str()))
+                .collect_vec(),
+        );
+        let tree2 = testutils::create_tree(
+            repo,
+            &paths2
+                .iter()
+                .map(|(path, contents)| (path, contents.as_str()))
+                .collect_vec(),
+        );
+        let prefix_matcher = PrefixMatcher::new(&[RepoPath::from_internal_string("a")]);
+        let matchers: [&(dyn Matcher + Sync); 2] = [&EverythingMatcher, &prefix_matcher];
+        for matcher in matchers {
+            assert_eq!(
+                tree1.diff_summary_parallel(&tree2, matcher).unwrap(),
+                tree1.diff_summary(&tree2, matcher).unwrap(),
+                "{paths1:?} vs {paths2:?}"
+            );
+        }
+    }
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_dir_change_counts(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let path = RepoPath::from_internal_string;
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&path("README"), "readme"),
+            (&path("src/main.rs"), "main"),
+            (&path("src/lib/mod.rs"), "mod"),
+            (&path("src/lib/old.rs"), "old"),
+            (&path("docs/index.md"), "index"),
+        ],
+    );
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&path("README"), "readme v2"),
+            (&path("src/main.rs"), "main"),
+            (&path("src/lib/mod.rs"), "mod v2"),
+            (&path("src/lib/new.rs"), "new"),
+            (&path("src/bin/tool.rs"), "tool"),
+            (&path("docs/index.md"), "index"),
+        ],
+    );
+
+    let counts = tree1.dir_change_counts(&tree2, &EverythingMatcher).unwrap();
+    let diff_counts = |modified, added, removed| DiffCounts {
+        modified,
+        added,
+        removed,
+    };
+    // Directories without changes are not included
+    assert_eq!(
+        counts.into_iter().collect_vec(),
+        vec![
+            (RepoPath::root(), diff_counts(2, 2, 1)),
+            (path("src"), diff_counts(1, 2, 1)),
+            (path("src/bin"), diff_counts(0, 1, 0)),
+            (path("src/lib"), diff_counts(1, 1, 1)),
+        ]
+    );
+
+    // The matcher limits the counted paths
+    let matcher = PrefixMatcher::new(&[path("src/lib")]);
+    let counts = tree1.dir_change_counts(&tree2, &matcher).unwrap();
+    assert_eq!(counts[&RepoPath::root()].total(), 3);
+    assert_eq!(counts[&path("src")], diff_counts(1, 1, 1));
+    assert!(!counts.contains_key(&path("src/bin")));
+
+    // For a subtree, ancestors above its directory are not included
+    let src_tree1 = tree1
+        .sub_tree(&RepoPathComponent::from("src"))
+        .unwrap()
+        .unwrap();
+    let src_tree2 = tree2
+        .sub_tree(&RepoPathComponent::from("src"))
+        .unwrap()
+        .unwrap();
+    let counts = src_tree1
+        .dir_change_counts(&src_tree2, &EverythingMatcher)
+        .unwrap();
+    assert_eq!(
+        counts.keys().collect_vec(),
+        vec![&path("src"), &path("src/bin"), &path("src/lib")]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_conflict_counts(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string;
+    let conflict_paths = [
+        path("src/a"),
+        path("src/lib/b"),
+        path("src/lib/c"),
+        path("docs/d"),
+    ];
+    let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+    for conflict_path in &conflict_paths {
+        let conflict_id = store
+            .write_conflict(conflict_path, &Conflict::default())
+            .unwrap();
+        tree_builder.set(conflict_path.clone(), TreeValue::Conflict(conflict_id));
+    }
+    testutils::write_normal_file(&mut tree_builder, &path("src/main.rs"), "main");
+    testutils::write_normal_file(&mut tree_builder, &path("tests/test.rs"), "test");
+    let tree_id = tree_builder.write_tree().unwrap();
+    let tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+
+    // Directories without conflicts are not included
+    assert_eq!(
+        tree.conflict_counts(&EverythingMatcher)
+            .unwrap()
+            .into_iter()
+            .collect_vec(),
+        vec![
+            (RepoPath::root(), 4),
+            (path("docs"), 1),
+            (path("src"), 3),
+            (path("src/lib"), 2),
+        ]
+    );
+
+    // The matcher limits the counted conflicts
+    let matcher = PrefixMatcher::new(&[path("src/lib")]);
+    assert_eq!(
+        tree.conflict_counts(&matcher)
+            .unwrap()
+            .into_iter()
+            .collect_vec(),
+        vec![
+            (RepoPath::root(), 2),
+            (path("src"), 2),
+            (path("src/lib"), 2)
+        ]
+    );
+
+    // For a subtree, ancestors above its directory are not included
+    let src_tree = tree
+        .sub_tree(&RepoPathComponent::from("src"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        src_tree
+            .conflict_counts(&EverythingMatcher)
+            .unwrap()
+            .into_iter()
+            .collect_vec(),
+        vec![(path("src"), 3), (path("src/lib"), 2)]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_multi_diff(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let path = RepoPath::from_internal_string;
+    let parent1_tree = testutils::create_tree(
+        repo,
+        &[
+            (&path("common"), "common"),
+            (&path("from_parent1"), "parent 1"),
+            (&path("dir/file"), "base"),
+        ],
+    );
+    let parent2_tree = testutils::create_tree(
+        repo,
+        &[
+            (&path("common"), "common"),
+            (&path("from_parent2"), "parent 2"),
+            (&path("dir/file"), "base"),
+        ],
+    );
+    let merge_tree = testutils::create_tree(
+        repo,
+        &[
+            (&path("common"), "common"),
+            (&path("from_parent1"), "parent 1"),
+            (&path("from_parent2"), "parent 2"),
+            (&path("dir/file"), "merged"),
+            (&path("dir/new"), "new"),
+        ],
+    );
+    let file_value = |tree: &Tree, path: &RepoPath| tree.path_value(path).unwrap().unwrap();
+
+    let diffs = tree::multi_diff(
+        &[parent1_tree.clone(), parent2_tree.clone()],
+        &merge_tree,
+        &EverythingMatcher,
+    )
+    .unwrap();
+    assert_eq!(
+        diffs.iter().map(|diff| &diff.path).collect_vec(),
+        vec![
+            &path("dir/file"),
+            &path("dir/new"),
+            &path("from_parent1"),
+            &path("from_parent2"),
+        ]
+    );
+    // Changed relative to both parents
+    let modified = Diff::Modified(
+        file_value(&parent1_tree, &path("dir/file")),
+        file_value(&merge_tree, &path("dir/file")),
+    );
+    assert_eq!(diffs[0].diffs, vec![Some(modified.clone()), Some(modified)]);
+    assert!(diffs[0].is_changed_from_all());
+    assert!(!diffs[0].is_added_to_all());
+    // New relative to both parents
+    let added = Diff::Added(file_value(&merge_tree, &path("dir/new")));
+    assert_eq!(diffs[1].diffs, vec![Some(added.clone()), Some(added)]);
+    assert!(diffs[1].is_added_to_all());
+    // New relative to only one parent
+    assert_eq!(
+        diffs[2].diffs,
+        vec![
+            None,
+            Some(Diff::Added(file_value(&merge_tree, &path("from_parent1"))))
+        ]
+    );
+    assert!(!diffs[2].is_changed_from_all());
+    assert!(!diffs[2].is_added_to_all());
+    assert_eq!(
+        diffs[3].diffs,
+        vec![
+            Some(Diff::Added(file_value(&merge_tree, &path("from_parent2")))),
+            None
+        ]
+    );
+
+    // The matcher limits the paths
+    let matcher = PrefixMatcher::new(&[path("dir")]);
+    let diffs = tree::multi_diff(&[parent1_tree, parent2_tree], &merge_tree, &matcher).unwrap();
+    assert_eq!(
+        diffs.iter().map(|diff| &diff.path).collect_vec(),
+        vec![&path("dir/file"), &path("dir/new")]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_shared_subtree_paths(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let path = RepoPath::from_internal_string;
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&path("file"), "a"),
+            (&path("shared/file"), "shared"),
+            (&path("shared/sub/file"), "shared"),
+            (&path("changed/file"), "a"),
+            (&path("changed/shared/file"), "shared"),
+            (&path("removed/file"), "a"),
+        ],
+    );
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&path("file"), "b"),
+            (&path("shared/file"), "shared"),
+            (&path("shared/sub/file"), "shared"),
+            (&path("changed/file"), "b"),
+            (&path("changed/shared/file"), "shared"),
+            (&path("added/file"), "a"),
+        ],
+    );
+
+    // Each shared subtree is reported once, without the directories below it
+    assert_eq!(
+        tree1.shared_subtree_paths(&tree2).unwrap(),
+        vec![path("changed/shared"), path("shared")]
+    );
+    assert_eq!(
+        tree2.shared_subtree_paths(&tree1).unwrap(),
+        vec![path("changed/shared"), path("shared")]
+    );
+    assert_eq!(
+        tree1.shared_subtree_paths(&tree1).unwrap(),
+        vec![RepoPath::root()]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_bounded(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let paths = (0..5)
+        .map(|i| RepoPath::from_internal_string(&format!("dir{i}/file")))
+        .collect_vec();
+    let tree1 = testutils::create_tree(repo, &[]);
+    let tree2 = testutils::create_tree(
+        repo,
+        &paths.iter().map(|path| (path, "contents")).collect_vec(),
+    );
+    let all_diffs = tree1
+        .diff(&tree2, &EverythingMatcher)
+        .map(Result::unwrap)
+        .collect_vec();
+    assert_eq!(all_diffs.len(), 5);
+
+    let (diffs, truncated) = tree1.diff_bounded(&tree2, &EverythingMatcher, 3).unwrap();
+    assert_eq!(diffs, all_diffs[..3]);
+    assert!(truncated);
+    let (diffs, truncated) = tree1.diff_bounded(&tree2, &EverythingMatcher, 5).unwrap();
+    assert_eq!(diffs, all_diffs);
+    assert!(!truncated);
+    let (diffs, truncated) = tree1.diff_bounded(&tree2, &EverythingMatcher, 10).unwrap();
+    assert_eq!(diffs, all_diffs);
+    assert!(!truncated);
+    let (diffs, truncated) = tree1.diff_bounded(&tree2, &EverythingMatcher, 0).unwrap();
+    assert_eq!(diffs, vec![]);
+    assert!(truncated);
+    let (diffs, truncated) = tree1.diff_bounded(&tree1, &EverythingMatcher, 0).unwrap();
+    assert_eq!(diffs, vec![]);
+    assert!(!truncated);
+
+    // Subtrees past the first one beyond the limit are not read
+    let store = test_repo.counting_store();
+    let tree1 = store.get_tree(&RepoPath::root(), tree1.id()).unwrap();
+    let tree2 = store.get_tree(&RepoPath::root(), tree2.id()).unwrap();
+    let (diffs, truncated) = tree1.diff_bounded(&tree2, &EverythingMatcher, 1).unwrap();
+    assert_eq!(diffs.len(), 1);
+    assert!(truncated);
+    // The root of tree2 and the subtrees of dir0 and dir1 (the empty tree is
+    // never read)
+    assert_eq!(CountingBackend::of(&store).tree_reads(), 3);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_rebased_and_cross_dir(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let a_file_path = RepoPath::from_internal_string("a/file");
+    let a_other_path = RepoPath::from_internal_string("a/other");
+    let b_file_path = RepoPath::from_internal_string("b/file");
+    let b_other_path = RepoPath::from_internal_string("b/other");
+    let tree1 = testutils::create_tree(repo, &[(&a_file_path, "1"), (&a_other_path, "x")]);
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&a_file_path, "2"),
+            (&a_other_path, "x"),
+            (&b_file_path, "2"),
+            (&b_other_path, "x"),
+        ],
+    );
+    let a = RepoPathComponent::from("a");
+    let b = RepoPathComponent::from("b");
+    let subtree1_a = tree1.sub_tree(&a).unwrap().unwrap();
+    let subtree2_a = tree2.sub_tree(&a).unwrap().unwrap();
+    let subtree2_b = tree2.sub_tree(&b).unwrap().unwrap();
+
+    // Trees rooted at the same directory
+    let expected = vec![(
+        a_file_path.clone(),
+        Diff::Modified(
+            tree1.path_value(&a_file_path).unwrap().unwrap(),
+            tree2.path_value(&a_file_path).unwrap().unwrap(),
+        ),
+    )];
+    assert_eq!(
+        subtree1_a
+            .diff_rebased(&subtree2_a, &EverythingMatcher)
+            .map(Result::unwrap)
+            .collect_vec(),
+        expected
+    );
+
+    // The "b" subtree is compared as if it were rooted at "a"
+    assert_eq!(
+        subtree1_a
+            .diff_cross_dir(&subtree2_b, &EverythingMatcher)
+            .map(Result::unwrap)
+            .collect_vec(),
+        expected
+    );
+    assert_eq!(
+        subtree2_b
+            .diff_cross_dir(&subtree2_a, &EverythingMatcher)
+            .map(Result::unwrap)
+            .collect_vec(),
+        vec![]
+    );
+}
+
+#[test]
+#[should_panic(expected = "diff_rebased() requires trees rooted at the same directory")]
+fn test_diff_rebased_different_dir() {
+    let test_repo = TestRepo::init(false);
+    let repo = &test_repo.repo;
+
+    let tree = testutils::create_tree(
+        repo,
+        &[
+            (&RepoPath::from_internal_string("a/file"), ""),
+            (&RepoPath::from_internal_string("b/file"), ""),
+        ],
+    );
+    let subtree_a = tree
+        .sub_tree(&RepoPathComponent::from("a"))
+        .unwrap()
+        .unwrap();
+    let subtree_b = tree
+        .sub_tree(&RepoPathComponent::from("b"))
+        .unwrap()
+        .unwrap();
+    subtree_a.diff_rebased(&subtree_b, &EverythingMatcher);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_cache(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let dir1_file_path = RepoPath::from_internal_string("dir1/file");
+    let dir2_file_path = RepoPath::from_internal_string("dir2/file");
+    let tree1 = testutils::create_tree(repo, &[(&dir1_file_path, "1"), (&dir2_file_path, "1")]);
+    let tree2 = testutils::create_tree(repo, &[(&dir1_file_path, "2"), (&dir2_file_path, "2")]);
+
+    let cache = DiffCache::new();
+    let everything = DiffCacheMatcher::Everything;
+    let dir1 = DiffCacheMatcher::prefixes(&[RepoPath::from_internal_string("dir1")]);
+    assert_eq!(
+        *cache.diff(&tree1, &tree2, &everything).unwrap(),
+        tree1
+            .diff(&tree2, &EverythingMatcher)
+            .map(Result::unwrap)
+            .collect_vec()
+    );
+    assert_eq!(
+        cache
+            .diff(&tree1, &tree2, &dir1)
+            .unwrap()
+            .iter()
+            .map(|(path, _)| path)
+            .collect_vec(),
+        vec![&dir1_file_path]
+    );
+    assert_eq!(cache.len(), 2);
+
+    // The cached diffs are returned without reading any subtrees, even from a
+    // store that hasn't cached them
+    let store = test_repo.counting_store();
+    let tree1 = store.get_tree(&RepoPath::root(), tree1.id()).unwrap();
+    let tree2 = store.get_tree(&RepoPath::root(), tree2.id()).unwrap();
+    assert_eq!(CountingBackend::of(&store).tree_reads(), 2);
+    assert_eq!(cache.diff(&tree1, &tree2, &everything).unwrap().len(), 2);
+    assert_eq!(cache.diff(&tree1, &tree2, &dir1).unwrap().len(), 1);
+    assert_eq!(CountingBackend::of(&store).tree_reads(), 2);
+    assert_eq!(cache.len(), 2);
+
+    // The diff in the other direction is a different entry
+    assert_eq!(cache.diff(&tree2, &tree1, &everything).unwrap().len(), 2);
+    assert_eq!(CountingBackend::of(&store).tree_reads(), 6);
+    assert_eq!(cache.len(), 3);
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_reachable_conflict_ids(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let dir_path = RepoPath::from_internal_string("dir");
+    let dir_file_path = RepoPath::from_internal_string("dir/file");
+    let other_path = RepoPath::from_internal_string("other");
+    let file_value = |path: &RepoPath, contents: &str| -> TreeValue {
+        TreeValue::File {
+            id: testutils::write_file(store, path, contents),
+            executable: false,
+        }
+    };
+    // The contents include the path so that the conflicts get different IDs
+    let file_conflict = |path: &RepoPath| -> Conflict {
+        let name = path.to_internal_file_string();
+        Conflict {
+            removes: vec![ConflictTerm {
+                value: file_value(path, &format!("{name} base")),
+            }],
+            adds: vec![
+                ConflictTerm {
+                    value: file_value(path, &format!("{name} left")),
+                },
+                ConflictTerm {
+                    value: file_value(path, &format!("{name} right")),
+                },
+            ],
+        }
+    };
+
+    // A conflict with a term that is itself a conflict
+    let inner_id = store.write_conflict(&path, &file_conflict(&path)).unwrap();
+    let outer_conflict = Conflict {
+        removes: vec![],
+        adds: vec![
+            ConflictTerm {
+                value: TreeValue::Conflict(inner_id.clone()),
+            },
+            ConflictTerm {
+                value: file_value(&path, "other"),
+            },
+        ],
+    };
+    let outer_id = store.write_conflict(&path, &outer_conflict).unwrap();
+
+    // A conflict with a term that is a tree containing a conflict
+    let dir_file_id = store
+        .write_conflict(&dir_file_path, &file_conflict(&dir_file_path))
+        .unwrap();
+    let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+    tree_builder.set(
+        dir_file_path.clone(),
+        TreeValue::Conflict(dir_file_id.clone()),
+    );
+    let subtree_id = tree_builder.write_tree().unwrap();
+    let subtree = store.get_tree(&RepoPath::root(), &subtree_id).unwrap();
+    let dir_conflict = Conflict {
+        removes: vec![],
+        adds: vec![
+            ConflictTerm {
+                value: subtree.path_value(&dir_path).unwrap().unwrap(),
+            },
+            ConflictTerm {
+                value: file_value(&dir_path, "file"),
+            },
+        ],
+    };
+    let dir_id = store.write_conflict(&dir_path, &dir_conflict).unwrap();
+
+    let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+    tree_builder.set(path.clone(), TreeValue::Conflict(outer_id.clone()));
+    tree_builder.set(dir_path.clone(), TreeValue::Conflict(dir_id.clone()));
+    tree_builder.set(other_path.clone(), file_value(&other_path, "contents"));
+    let tree_id = tree_builder.write_tree().unwrap();
+    let tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+
+    assert_eq!(
+        tree.conflicts()
+            .unwrap()
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect_vec(),
+        vec![dir_id.clone(), outer_id.clone()]
+    );
+    assert_eq!(
+        tree.reachable_conflict_ids(&EverythingMatcher).unwrap(),
+        BTreeSet::from([inner_id.clone(), outer_id.clone(), dir_id, dir_file_id])
+    );
+    assert_eq!(
+        tree.reachable_conflict_ids(&FilesMatcher::new(&[path.clone()]))
+            .unwrap(),
+        BTreeSet::from([inner_id, outer_id])
+    );
+    assert_eq!(
+        tree.reachable_conflict_ids(&FilesMatcher::new(&[other_path]))
+            .unwrap(),
+        BTreeSet::new()
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_reachable_file_ids(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let a_path = RepoPath::from_internal_string("a");
+    let b_path = RepoPath::from_internal_string("dir/b");
+    let c_path = RepoPath::from_internal_string("dir/c");
+    let conflict_path = RepoPath::from_internal_string("conflict");
+    let shared_id = testutils::write_file(store, &a_path, "shared");
+    let other_id = testutils::write_file(store, &c_path, "other");
+    let base_id = testutils::write_file(store, &conflict_path, "base");
+    let left_id = testutils::write_file(store, &conflict_path, "left");
+    let file_term = |id: &FileId| ConflictTerm {
+        value: TreeValue::File {
+            id: id.clone(),
+            executable: false,
+        },
+    };
+    // One side of the conflict is the shared file, which is only collected once
+    let conflict = Conflict {
+        removes: vec![file_term(&base_id)],
+        adds: vec![file_term(&left_id), file_term(&shared_id)],
+    };
+    let conflict_id = store.write_conflict(&conflict_path, &conflict).unwrap();
+
+    let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+    tree_builder.set(a_path.clone(), file_term(&shared_id).value);
+    tree_builder.set(b_path.clone(), file_term(&shared_id).value);
+    tree_builder.set(c_path.clone(), file_term(&other_id).value);
+    tree_builder.set(conflict_path.clone(), TreeValue::Conflict(conflict_id));
+    let tree_id = tree_builder.write_tree().unwrap();
+    let tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+
+    assert_eq!(
+        tree.reachable_file_ids(&EverythingMatcher).unwrap(),
+        BTreeSet::from([
+            shared_id.clone(),
+            other_id.clone(),
+            base_id.clone(),
+            left_id.clone()
+        ])
+    );
+    assert_eq!(
+        tree.reachable_file_ids(&PrefixMatcher::new(&[RepoPath::from_internal_string(
+            "dir"
+        )]))
+        .unwrap(),
+        BTreeSet::from([shared_id.clone(), other_id])
+    );
+    assert_eq!(
+        tree.reachable_file_ids(&FilesMatcher::new(&[conflict_path]))
+            .unwrap(),
+        BTreeSet::from([base_id, left_id, shared_id])
+    );
+    assert_eq!(
+        tree.reachable_file_ids(&NothingMatcher).unwrap(),
+        BTreeSet::new()
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_reachable_file_ids_parallel(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string;
+    let file_term = |path: &RepoPath, contents: &str| ConflictTerm {
+        value: TreeValue::File {
+            id: testutils::write_file(store, path, contents),
+            executable: false,
+        },
+    };
+    let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+    for dir in 0..10 {
+        for file in 0..10 {
+            let file_path = path(&format!("dir{dir}/sub/file{file}"));
+            // Some contents are shared between directories
+            testutils::write_normal_file(
+                &mut tree_builder,
+                &file_path,
+                &format!("contents {}", (dir * 10 + file) % 15),
+            );
+        }
+    }
+    testutils::write_normal_file(&mut tree_builder, &path("top"), "top");
+    // Conflicts both at the top level and in a subtree
+    for conflict_path in [path("conflict"), path("dir3/conflict")] {
+        let conflict = Conflict {
+            removes: vec![file_term(&conflict_path, "base")],
+            adds: vec![
+                file_term(&conflict_path, "left"),
+                file_term(
+                    &conflict_path,
+                    &format!("right {}", conflict_path.to_internal_file_string()),
+                ),
+            ],
+        };
+        let conflict_id = store.write_conflict(&conflict_path, &conflict).unwrap();
+        tree_builder.set(conflict_path, TreeValue::Conflict(conflict_id));
+    }
+    let tree_id = tree_builder.write_tree().unwrap();
+    let tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+
+    let everything_ids = tree.reachable_file_ids(&EverythingMatcher).unwrap();
+    assert_eq!(everything_ids.len(), 15 + 1 + 4);
+    assert_eq!(
+        tree.reachable_file_ids_parallel(&EverythingMatcher)
+            .unwrap(),
+        everything_ids
+    );
+    let prefix_matcher = PrefixMatcher::new(&[path("dir3"), path("dir7/sub")]);
+    assert_eq!(
+        tree.reachable_file_ids_parallel(&prefix_matcher).unwrap(),
+        tree.reachable_file_ids(&prefix_matcher).unwrap()
+    );
+    let files_matcher = FilesMatcher::new(&[path("conflict"), path("dir5/sub/file5")]);
+    assert_eq!(
+        tree.reachable_file_ids_parallel(&files_matcher).unwrap(),
+        tree.reachable_file_ids(&files_matcher).unwrap()
+    );
+    assert_eq!(
+        tree.reachable_file_ids_parallel(&NothingMatcher).unwrap(),
+        BTreeSet::new()
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_path_exists(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let path = RepoPath::from_internal_string;
+    let tree = testutils::create_tree(repo, &[(&path("file"), "a"), (&path("dir/sub/file"), "b")]);
+
+    // Files and directories
+    assert!(tree.path_exists(&path("file")).unwrap());
+    assert!(tree.path_exists(&path("dir/sub/file")).unwrap());
+    assert!(tree.path_exists(&path("dir")).unwrap());
+    assert!(tree.path_exists(&path("dir/sub")).unwrap());
+    assert!(tree.path_exists(&RepoPath::root()).unwrap());
+    // Absent paths, including ones below a file
+    assert!(!tree.path_exists(&path("missing")).unwrap());
+    assert!(!tree.path_exists(&path("dir/missing")).unwrap());
+    assert!(!tree.path_exists(&path("missing/file")).unwrap());
+    assert!(!tree.path_exists(&path("file/file")).unwrap());
+
+    for p in ["file", "dir", "dir/sub/file", "missing", "file/file"] {
+        assert_eq!(
+            tree.path_exists(&path(p)).unwrap(),
+            tree.path_value(&path(p)).unwrap().is_some(),
+            "{p}"
+        );
+    }
+}
+
+#[test]
+fn test_sub_tree_read_error() {
+    // The Git backend refuses to write trees that point to missing objects
+    let test_repo = TestRepo::init(false);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+    // Trees written only to this repo are missing from the one above
+    let other_test_repo = TestRepo::init(false);
+
+    let file_path = RepoPath::from_internal_string("file");
+    let dir_file_path = RepoPath::from_internal_string("dir/file");
+    let missing_file_path = RepoPath::from_internal_string("missing/dir/file");
+    let missing = RepoPathComponent::from("missing");
+    let missing_tree =
+        testutils::create_tree(&other_test_repo.repo, &[(&dir_file_path, "contents")]);
+    let tree = testutils::create_tree(repo, &[(&file_path, "contents"), (&dir_file_path, "dir")]);
+    let mut tree_builder = store.tree_builder(tree.id().clone());
+    tree_builder.set(
+        RepoPath::from_internal_string("missing"),
+        TreeValue::Tree(missing_tree.id().clone()),
+    );
+    let tree_id = tree_builder.write_tree().unwrap();
+    let tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+
+    // The missing subtree is reported as an error instead of panicking
+    assert!(tree.sub_tree(&missing).is_err());
+    assert!(tree.known_sub_tree(&missing, missing_tree.id()).is_err());
+    assert!(tree.path_value(&missing_file_path).is_err());
+
+    // Other paths are unaffected
+    assert!(tree
+        .sub_tree(&RepoPathComponent::from("dir"))
+        .unwrap()
+        .is_some());
+    assert!(tree
+        .sub_tree(&RepoPathComponent::from("file"))
+        .unwrap()
+        .is_none());
+    assert!(tree
+        .sub_tree(&RepoPathComponent::from("nonexistent"))
+        .unwrap()
+        .is_none());
+    assert!(tree.path_value(&dir_file_path).unwrap().is_some());
+    // The missing tree's own value is stored in its parent, so it can be read
+    assert_eq!(
+        tree.path_value(&RepoPath::from_internal_string("missing"))
+            .unwrap(),
+        Some(TreeValue::Tree(missing_tree.id().clone()))
+    );
+
+    // Functions that need to read the missing subtree return the error too
+    let empty_tree = store
+        .get_tree(&RepoPath::root(), store.empty_tree_id())
+        .unwrap();
+    assert!(tree.path_exists(&missing_file_path).is_err());
+    assert!(tree.count_entries_matching(&EverythingMatcher).is_err());
+    assert!(tree.file_entries_matching(&EverythingMatcher).is_err());
+    assert!(tree.file_sizes(&EverythingMatcher).is_err());
+    assert!(tree.conflicts_matching(&EverythingMatcher).is_err());
+    assert!(tree.reachable_tree_ids(&EverythingMatcher).is_err());
+    assert!(tree.reachable_file_ids(&EverythingMatcher).is_err());
+    assert!(tree
+        .reachable_file_ids_parallel(&EverythingMatcher)
+        .is_err());
+    assert!(tree.changed_paths(&empty_tree, &EverythingMatcher).is_err());
+    assert!(tree
+        .diff_with_unchanged(&empty_tree, &EverythingMatcher)
+        .is_err());
+    assert!(empty_tree
+        .diff_additions(&tree, &EverythingMatcher)
+        .is_err());
+    assert!(tree
+        .diff_summary_parallel(&empty_tree, &EverythingMatcher)
+        .is_err());
+    assert!(tree::multi_diff(&[empty_tree.clone()], &tree, &EverythingMatcher).is_err());
+    assert!(tree.conflicts().is_err());
+    assert!(tree.diff_summary(&empty_tree, &EverythingMatcher).is_err());
+
+    // The iterators yield the error and skip the entries below the missing
+    // subtree
+    let entries = tree.entries().collect_vec();
+    assert_eq!(entries.len(), 3);
+    assert!(entries[2].is_err());
+    assert_eq!(
+        entries
+            .into_iter()
+            .filter_map(Result::ok)
+            .map(|(path, _)| path)
+            .collect_vec(),
+        vec![dir_file_path.clone(), file_path.clone()]
+    );
+    let diffs = tree.diff(&empty_tree, &EverythingMatcher).collect_vec();
+    assert_eq!(diffs.len(), 3);
+    assert!(diffs[2].is_err());
+    let diffs = empty_tree.diff(&tree, &EverythingMatcher).collect_vec();
+    assert_eq!(diffs.len(), 3);
+    assert!(diffs[2].is_err());
+    let mut tree_builder = store.tree_builder(tree_id);
+    testutils::write_normal_file(&mut tree_builder, &missing_file_path, "contents");
+    assert!(tree_builder.write_tree().is_err());
+
+    // They don't read it if the matcher excludes it
+    let matcher = PrefixMatcher::new(&[RepoPath::from_internal_string("dir")]);
+    assert_eq!(tree.count_entries_matching(&matcher).unwrap(), 1);
+    assert_eq!(tree.reachable_tree_ids(&matcher).unwrap().len(), 2);
+}
+
+#[test]
+fn test_verify() {
+    // The Git backend refuses to write trees that point to missing objects
+    let test_repo = TestRepo::init(false);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+    // Objects written only to this repo are missing from the one above
+    let other_test_repo = TestRepo::init(false);
+    let other_store = other_test_repo.repo.store();
+
+    let file_path = RepoPath::from_internal_string("dir/file");
+    let missing_dir_path = RepoPath::from_internal_string("missing_dir");
+    let missing_conflict_path = RepoPath::from_internal_string("sub/conflict");
+    let tree = testutils::create_tree(repo, &[(&file_path, "contents")]);
+    tree.verify(&EverythingMatcher).unwrap();
+
+    // A dangling tree ID
+    let missing_tree =
+        testutils::create_tree(&other_test_repo.repo, &[(&file_path, "other contents")]);
+    let mut tree_builder = store.tree_builder(tree.id().clone());
+    tree_builder.set(
+        missing_dir_path.clone(),
+        TreeValue::Tree(missing_tree.id().clone()),
+    );
+    let tree_id = tree_builder.write_tree().unwrap();
+    let tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+    assert_matches!(
+        tree.verify(&EverythingMatcher),
+        Err(TreeVerifyError::UnreadableTree { path, id, .. })
+            if path == missing_dir_path && id == *missing_tree.id()
+    );
+    // Not visited by the matcher
+    tree.verify(&PrefixMatcher::new(&[file_path.clone()]))
+        .unwrap();
+
+    // A dangling conflict ID in a subtree
+    let missing_conflict_id = other_store
+        .write_conflict(&missing_conflict_path, &Conflict::default())
+        .unwrap();
+    let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+    tree_builder.set(
+        missing_conflict_path.clone(),
+        TreeValue::Conflict(missing_conflict_id.clone()),
+    );
+    let tree_id = tree_builder.write_tree().unwrap();
+    let tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
+    assert_matches!(
+        tree.verify(&EverythingMatcher),
+        Err(TreeVerifyError::UnreadableConflict { path, id, .. })
+            if path == missing_conflict_path && id == missing_conflict_id
+    );
+    tree.verify(&FilesMatcher::new(&[file_path])).unwrap();
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_content_moves(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let path = RepoPath::from_internal_string;
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&path("a/x"), "moved"),
+            (&path("a/y"), "modified"),
+            (&path("a/z"), "copied"),
+            (&path("kept"), "moved"),
+        ],
+    );
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&path("b/x"), "moved"),
+            (&path("b/y"), "modified and moved"),
+            (&path("b/z1"), "copied"),
+            (&path("b/z2"), "copied"),
+            (&path("kept"), "moved"),
+        ],
+    );
+    let file_id = |path: &RepoPath| match tree1.path_value(path).unwrap().unwrap() {
+        TreeValue::File { id, .. } => id,
+        other => panic!("unexpected value: {other:?}"),
+    };
+
+    // Only files with the same ID are paired, and each removed file only once
+    let counting_store = test_repo.counting_store();
+    let tree1 = counting_store
+        .get_tree(&RepoPath::root(), tree1.id())
+        .unwrap();
+    let tree2 = counting_store
+        .get_tree(&RepoPath::root(), tree2.id())
+        .unwrap();
+    assert_eq!(
+        tree1.content_moves(&tree2, &EverythingMatcher).unwrap(),
+        vec![
+            (path("a/x"), path("b/x"), file_id(&path("a/x"))),
+            (path("a/z"), path("b/z1"), file_id(&path("a/z"))),
+        ]
+    );
+    // No file contents were read
+    assert_eq!(CountingBackend::of(&counting_store).file_reads(), 0);
+
+    // The matcher limits the paths on both sides
+    assert_eq!(
+        tree1
+            .content_moves(&tree2, &PrefixMatcher::new(&[path("a")]))
+            .unwrap(),
+        vec![]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_detect_renames(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let contents = (1..=10).map(|i| format!("line {i}\n")).join("");
+    let edited_contents = contents.replace("line 5\n", "line five\n");
+    let moved_from = RepoPath::from_internal_string("src/moved");
+    let moved_to = RepoPath::from_internal_string("dst/moved");
+    let edited_from = RepoPath::from_internal_string("src/edited");
+    let edited_to = RepoPath::from_internal_string("dst/edited");
+    let removed_path = RepoPath::from_internal_string("removed");
+    let added_path = RepoPath::from_internal_string("added");
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&moved_from, "moved\n"),
+            (&edited_from, &contents),
+            (&removed_path, "something\n"),
+        ],
+    );
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&moved_to, "moved\n"),
+            (&edited_to, &edited_contents),
+            (&added_path, "unrelated\n"),
+        ],
+    );
+
+    let counting_store = test_repo.counting_store();
+    let get_tree = |tree: &Tree| {
+        counting_store
+            .get_tree(&RepoPath::root(), tree.id())
+            .unwrap()
+    };
+    let tree1 = get_tree(&tree1);
+    let tree2 = get_tree(&tree2);
+
+    // A move without edits is detected without reading the file
+    let matcher = PrefixMatcher::new(&[
+        RepoPath::from_internal_string("src/moved"),
+        RepoPath::from_internal_string("dst/moved"),
+    ]);
+    assert_eq!(
+        tree1.detect_renames(&tree2, &matcher, 50).unwrap(),
+        vec![Rename {
+            from: moved_from.clone(),
+            to: moved_to.clone(),
+            modified: false,
+            similarity: 100,
+        }]
+    );
+    assert_eq!(CountingBackend::of(&counting_store).file_reads(), 0);
+
+    // The moved and edited file is paired by similarity, and the unrelated
+    // files are not paired
+    let renames = tree1
+        .detect_renames(&tree2, &EverythingMatcher, 50)
+        .unwrap();
+    assert_eq!(renames.len(), 2);
+    assert_eq!(renames[0].from, edited_from);
+    assert_eq!(renames[0].to, edited_to);
+    assert!(renames[0].modified);
+    assert!((50..100).contains(&renames[0].similarity));
+    assert_eq!(
+        renames[1],
+        Rename {
+            from: moved_from,
+            to: moved_to,
+            modified: false,
+            similarity: 100,
+        }
+    );
+
+    // Nothing is similar enough with a threshold of 100%
+    assert_eq!(
+        tree1
+            .detect_renames(&tree2, &EverythingMatcher, 100)
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_changed_paths(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&RepoPath::from_internal_string("modified"), "before"),
+            (&RepoPath::from_internal_string("removed"), "before"),
+            (&RepoPath::from_internal_string("file_to_dir"), "before"),
+            (&RepoPath::from_internal_string("dir_to_file/a"), "before"),
+            (&RepoPath::from_internal_string("dir/modified"), "before"),
+            (&RepoPath::from_internal_string("dir/unchanged"), "same"),
+        ],
+    );
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&RepoPath::from_internal_string("modified"), "after"),
+            (&RepoPath::from_internal_string("added"), "after"),
+            (&RepoPath::from_internal_string("file_to_dir/a"), "after"),
+            (&RepoPath::from_internal_string("dir_to_file"), "after"),
+            (&RepoPath::from_internal_string("dir/modified"), "after"),
+            (&RepoPath::from_internal_string("dir/unchanged"), "same"),
+        ],
+    );
+
+    let prefix_matcher = PrefixMatcher::new(&[
+        RepoPath::from_internal_string("dir"),
+        RepoPath::from_internal_string("file_to_dir"),
+    ]);
+    let matchers: [&dyn Matcher; 2] = [&EverythingMatcher, &prefix_matcher];
+    for matcher in matchers {
+        for (from, to) in [(&tree1, &tree2), (&tree2, &tree1)] {
+            assert_eq!(
+                from.changed_paths(to, matcher).unwrap(),
+                from.diff(to, matcher)
+                    .map(Result::unwrap)
+                    .map(|(path, _)| path)
+                    .collect_vec()
+            );
+        }
+    }
+    assert_eq!(
+        tree1
+            .changed_paths(&tree1, &EverythingMatcher)
+            .unwrap()
+            .len(),
+        0
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_with_unchanged(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+
+    let unchanged_path = RepoPath::from_internal_string("unchanged");
+    let modified_path = RepoPath::from_internal_string("modified");
+    let added_path = RepoPath::from_internal_string("dir/added");
+    let dir_unchanged_path = RepoPath::from_internal_string("dir/unchanged");
+    let same_dir_a_path = RepoPath::from_internal_string("same_dir/a");
+    let same_dir_b_path = RepoPath::from_internal_string("same_dir/b");
+    let tree1 = testutils::create_tree(
+        repo,
+        &[
+            (&unchanged_path, "same"),
+            (&modified_path, "before"),
+            (&dir_unchanged_path, "same"),
+            (&same_dir_a_path, "a"),
+            (&same_dir_b_path, "b"),
+        ],
+    );
+    let tree2 = testutils::create_tree(
+        repo,
+        &[
+            (&unchanged_path, "same"),
+            (&modified_path, "after"),
+            (&added_path, "added"),
+            (&dir_unchanged_path, "same"),
+            (&same_dir_a_path, "a"),
+            (&same_dir_b_path, "b"),
+        ],
+    );
+
+    let entries = tree1
+        .diff_with_unchanged(&tree2, &EverythingMatcher)
+        .unwrap();
+    assert_eq!(
+        entries.iter().map(|(path, _)| path.clone()).collect_vec(),
+        vec![
+            added_path.clone(),
+            dir_unchanged_path.clone(),
+            modified_path.clone(),
+            same_dir_a_path.clone(),
+            same_dir_b_path.clone(),
+            unchanged_path.clone(),
+        ]
+    );
+    // The changed entries are the same as in the regular diff
+    assert_eq!(
+        entries
+            .iter()
+            .filter_map(|(path, entry)| match entry {
+                EntryComparison::Changed(diff) => Some((path.clone(), diff.clone())),
+                EntryComparison::Unchanged(_) => None,
+            })
+            .collect_vec(),
+        tree1
+            .diff(&tree2, &EverythingMatcher)
+            .map(Result::unwrap)
+            .collect_vec()
+    );
+    // The unchanged entries have the value from both trees
+    for (path, entry) in &entries {
+        if let EntryComparison::Unchanged(value) = entry {
+            assert_eq!(tree1.path_value(path).unwrap().as_ref(), Some(value));
+            assert_eq!(tree2.path_value(path).unwrap().as_ref(), Some(value));
+        }
+    }
+
+    let matcher = PrefixMatcher::new(&[RepoPath::from_internal_string("same_dir")]);
+    assert_eq!(
+        tree1
+            .diff_with_unchanged(&tree2, &matcher)
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect_vec(),
+        vec![same_dir_a_path, same_dir_b_path]
+    );
+}
+
+#[test_case(false ; "local backend")]
+#[test_case(true ; "git backend")]
+fn test_diff_expanding_conflicts(use_git: bool) {
+    let test_repo = TestRepo::init(use_git);
+    let repo = &test_repo.repo;
+    let store = repo.store();
+
+    let path = RepoPath::from_internal_string("file");
+    let file_value = |contents: &str| TreeValue::File {
+        id: testutils::write_file(store, &path, contents),
+        executable: false,
+    };
+    let conflict = Conflict {
+        removes: vec![ConflictTerm {
+            value: file_value("base"),
+        }],
+        adds: vec![
+            ConflictTerm {
+                value: file_value("left"),
+            },
+            ConflictTerm {
+                value: file_value("right"),
+            },
+        ],
+    };
+    let conflict_id = store.write_conflict(&path, &conflict).unwrap();
+    let write_tree = |value: TreeValue| -> Tree {
+        let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+        tree_builder.set(path.clone(), value);
+        let tree_id = tree_builder.write_tree().unwrap();
+        store.get_tree(&RepoPath::root(), &tree_id).unwrap()
+    };
+    let clean_tree = write_tree(file_value("base"));
+    let conflict_tree = write_tree(TreeValue::Conflict(conflict_id.clone()));
+
+    // The regular diff doesn't look into the conflict
+    assert_eq!(
+        clean_tree
+            .diff(&conflict_tree, &EverythingMatcher)
+            .map(Result::unwrap)
+            .collect_vec(),
+        vec![(
+            path.clone(),
+            Diff::Modified(file_value("base"), TreeValue::Conflict(conflict_id))
+        )]
+    );
+
+    let expanded = clean_tree
+        .diff_expanding_conflicts(&conflict_tree, &EverythingMatcher)
+        .map(Result::unwrap)
+        .collect_vec();
+    assert_eq!(
+        expanded,
+        vec![
+            ExpandedDiff {
+                path: path.clone(),
+                diff: Diff::Modified(file_value("base"), file_value("left")),
+                before_term: None,
+                after_term: Some(0),
+            },
+            ExpandedDiff {
+                path: path.clone(),
+                diff: Diff::Modified(file_value("base"), file_value("right")),
+                before_term: None,
+                after_term: Some(1),
+            },
+        ]
+    );
+
+    // Resolving the conflict expands the other way
+    let expanded = conflict_tree
+        .diff_expanding_conflicts(&clean_tree, &EverythingMatcher)
+        .map(Result::unwrap)
+        .collect_vec();
+    assert_eq!(
+        expanded
+            .iter()
+            .map(|expanded| (expanded.diff.clone(), expanded.before_term))
+            .collect_vec(),
+        vec![
+            (
+                Diff::Modified(file_value("left"), file_value("base")),
+                Some(0)
+            ),
+            (
+                Diff::Modified(file_value("right"), file_value("base")),
+                Some(1)
+            ),
+        ]
+    );
+}
@@ -0,0 +1,275 @@
+// Copyright 2023 The Jujutsu Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(feature = "async")]
+
+use std::any::Any;
+use std::future::{poll_fn, Future};
+use std::io::Read;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+use async_trait::async_trait;
+use futures_core::Stream;
+use itertools::Itertools;
+use jujutsu_lib::backend::{
+    self, Backend, BackendResult, ChangeId, CommitId, Conflict, ConflictId, FileId, ObjectId,
+    SymlinkId, TreeId, TreeValue,
+};
+use jujutsu_lib::matchers::{EverythingMatcher, Matcher, PrefixMatcher};
+use jujutsu_lib::repo_path::RepoPath;
+use jujutsu_lib::store::Store;
+use testutils::InMemoryBackend;
+
+/// An in-memory backend whose async tree reads suspend once before
+/// returning, like a network backend would.
+#[derive(Debug)]
+struct AsyncInMemoryBackend {
+    inner: InMemoryBackend,
+    sync_tree_reads: AtomicUsize,
+    async_tree_reads: AtomicUsize,
+}
+
+impl AsyncInMemoryBackend {
+    fn new() -> Self {
+        AsyncInMemoryBackend {
+            inner: InMemoryBackend::new(),
+            sync_tree_reads: AtomicUsize::new(0),
+            async_tree_reads: AtomicUsize::new(0),
+        }
+    }
+
+    fn of(store: &Store) -> &AsyncInMemoryBackend {
+        store
+            .backend_impl()
+            .downcast_ref::<AsyncInMemoryBackend>()
+            .unwrap()
+    }
+}
+
+#[async_trait]
+impl Backend for AsyncInMemoryBackend {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn commit_id_length(&self) -> usize {
+        self.inner.commit_id_length()
+    }
+
+    fn change_id_length(&self) -> usize {
+        self.inner.change_id_length()
+    }
+
+    fn read_file(&self, path: &RepoPath, id: &FileId) -> BackendResult<Box<dyn Read>> {
+        self.inner.read_file(path, id)
+    }
+
+    fn write_file(&self, path: &RepoPath, contents: &mut dyn Read) -> BackendResult<FileId> {
+        self.inner.write_file(path, contents)
+    }
+
+    fn read_symlink(&self, path: &RepoPath, id: &SymlinkId) -> BackendResult<String> {
+        self.inner.read_symlink(path, id)
+    }
+
+    fn write_symlink(&self, path: &RepoPath, target: &str) -> BackendResult<SymlinkId> {
+        self.inner.write_symlink(path, target)
+    }
+
+    fn root_commit_id(&self) -> &CommitId {
+        self.inner.root_commit_id()
+    }
+
+    fn root_change_id(&self) -> &ChangeId {
+        self.inner.root_change_id()
+    }
+
+    fn empty_tree_id(&self) -> &TreeId {
+        self.inner.empty_tree_id()
+    }
+
+    fn read_tree(&self, path: &RepoPath, id: &TreeId) -> BackendResult<backend::Tree> {
+        self.sync_tree_reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.read_tree(path, id)
+    }
+
+    async fn read_tree_async(&self, path: &RepoPath, id: &TreeId) -> BackendResult<backend::Tree> {
+        self.async_tree_reads.fetch_add(1, Ordering::SeqCst);
+        yield_now().await;
+        self.inner.read_tree(path, id)
+    }
+
+    fn write_tree(&self, path: &RepoPath, contents: &backend::Tree) -> BackendResult<TreeId> {
+        self.inner.write_tree(path, contents)
+    }
+
+    fn read_conflict(&self, path: &RepoPath, id: &ConflictId) -> BackendResult<Conflict> {
+        self.inner.read_conflict(path, id)
+    }
+
+    fn write_conflict(&self, path: &RepoPath, contents: &Conflict) -> BackendResult<ConflictId> {
+        self.inner.write_conflict(path, contents)
+    }
+
+    fn read_commit(&self, id: &CommitId) -> BackendResult<backend::Commit> {
+        self.inner.read_commit(id)
+    }
+
+    fn write_commit(
+        &self,
+        contents: backend::Commit,
+    ) -> BackendResult<(CommitId, backend::Commit)> {
+        self.inner.write_commit(contents)
+    }
+}
+
+/// Returns `Poll::Pending` once, after asking to be polled again.
+async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            Poll::Ready(())
+        } else {
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs the future to completion on the current thread.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+fn collect_stream<S: Stream + Unpin>(mut stream: S) -> Vec<S::Item> {
+    block_on(async {
+        let mut items = vec![];
+        while let Some(item) = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await {
+            items.push(item);
+        }
+        items
+    })
+}
+
+#[test]
+fn test_entries_matching_async() {
+    let store = Store::new(Box::new(AsyncInMemoryBackend::new()));
+    let backend = AsyncInMemoryBackend::of(&store);
+    let tree = testutils::build_tree(
+        &store,
+        &[
+            ("a", b"a"),
+            ("dir/b", b"b"),
+            ("dir/sub/c", b"c"),
+            ("dir/sub/d", b"d"),
+            ("other/e", b"e"),
+            ("z", b"z"),
+        ],
+    );
+    let sync_tree_reads = backend.sync_tree_reads.load(Ordering::SeqCst);
+
+    // The subtrees aren't cached yet, so they're all read asynchronously
+    let entries = collect_stream(tree.entries_matching_async(&EverythingMatcher))
+        .into_iter()
+        .map(Result::unwrap)
+        .collect_vec();
+    assert_eq!(backend.async_tree_reads.load(Ordering::SeqCst), 3);
+    assert_eq!(
+        backend.sync_tree_reads.load(Ordering::SeqCst),
+        sync_tree_reads
+    );
+    assert_eq!(
+        entries,
+        tree.entries_matching(&EverythingMatcher)
+            .map(Result::unwrap)
+            .collect_vec()
+    );
+
+    // Subtrees the matcher can't match anything in are skipped
+    let matchers: Vec<Box<dyn Matcher>> = vec![
+        Box::new(PrefixMatcher::new(&[RepoPath::from_internal_string("dir")])),
+        Box::new(PrefixMatcher::new(&[RepoPath::from_internal_string(
+            "dir/sub/c",
+        )])),
+        Box::new(PrefixMatcher::new(&[RepoPath::from_internal_string(
+            "missing",
+        )])),
+    ];
+    for matcher in &matchers {
+        let entries = collect_stream(tree.entries_matching_async(matcher.as_ref()))
+            .into_iter()
+            .map(Result::unwrap)
+            .collect_vec();
+        assert_eq!(
+            entries,
+            tree.entries_matching(matcher.as_ref())
+                .map(Result::unwrap)
+                .collect_vec()
+        );
+    }
+}
+
+#[test]
+fn test_entries_matching_async_missing_subtree() {
+    let store = Store::new(Box::new(AsyncInMemoryBackend::new()));
+    let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+    testutils::write_normal_file(&mut tree_builder, &RepoPath::from_internal_string("a"), "a");
+    tree_builder.set(
+        RepoPath::from_internal_string("missing"),
+        TreeValue::Tree(TreeId::from_hex("00000000000000ff")),
+    );
+    testutils::write_normal_file(&mut tree_builder, &RepoPath::from_internal_string("z"), "z");
+    let tree = store
+        .get_tree(&RepoPath::root(), &tree_builder.write_tree().unwrap())
+        .unwrap();
+
+    // The missing subtree is reported as an error, and the traversal goes on
+    let entries = collect_stream(tree.entries_matching_async(&EverythingMatcher));
+    assert_eq!(entries.len(), 3);
+    assert_eq!(
+        entries[0].as_ref().unwrap().0,
+        RepoPath::from_internal_string("a")
+    );
+    assert!(entries[1].is_err());
+    assert_eq!(
+        entries[2].as_ref().unwrap().0,
+        RepoPath::from_internal_string("z")
+    );
+}
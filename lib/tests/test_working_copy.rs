@@ -159,7 +159,7 @@ fn test_checkout_file_transitions(use_git: bool) {
                     Kind::Normal,
                     &file_path,
                 );
-                let id = sub_tree_builder.write_tree();
+                let id = sub_tree_builder.write_tree().unwrap();
                 TreeValue::Tree(id)
             }
             Kind::GitSubmodule => {
@@ -196,8 +196,8 @@ fn test_checkout_file_transitions(use_git: bool) {
             files.push((*left_kind, *right_kind, path));
         }
     }
-    let left_tree_id = left_tree_builder.write_tree();
-    let right_tree_id = right_tree_builder.write_tree();
+    let left_tree_id = left_tree_builder.write_tree().unwrap();
+    let right_tree_id = right_tree_builder.write_tree().unwrap();
     let left_tree = store.get_tree(&RepoPath::root(), &left_tree_id).unwrap();
     let right_tree = store.get_tree(&RepoPath::root(), &right_tree_id).unwrap();
 
@@ -445,7 +445,10 @@ fn test_snapshot_special_file() {
     let tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
     // Only the regular files should be in the tree
     assert_eq!(
-        tree.entries().map(|(path, _value)| path).collect_vec(),
+        tree.entries()
+            .map(Result::unwrap)
+            .map(|(path, _value)| path)
+            .collect_vec(),
         vec![file1_path.clone(), file2_path.clone()]
     );
     assert_eq!(
@@ -462,7 +465,10 @@ fn test_snapshot_special_file() {
     let tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
     // Only the regular file should be in the tree
     assert_eq!(
-        tree.entries().map(|(path, _value)| path).collect_vec(),
+        tree.entries()
+            .map(Result::unwrap)
+            .map(|(path, _value)| path)
+            .collect_vec(),
         vec![file2_path.clone()]
     );
     assert_eq!(
@@ -503,7 +509,11 @@ fn test_gitignores(use_git: bool) {
         .store()
         .get_tree(&RepoPath::root(), &new_tree_id1)
         .unwrap();
-    let files1 = tree1.entries().map(|(name, _value)| name).collect_vec();
+    let files1 = tree1
+        .entries()
+        .map(Result::unwrap)
+        .map(|(name, _value)| name)
+        .collect_vec();
     assert_eq!(
         files1,
         vec![
@@ -533,7 +543,11 @@ fn test_gitignores(use_git: bool) {
         .store()
         .get_tree(&RepoPath::root(), &new_tree_id2)
         .unwrap();
-    let files2 = tree2.entries().map(|(name, _value)| name).collect_vec();
+    let files2 = tree2
+        .entries()
+        .map(Result::unwrap)
+        .map(|(name, _value)| name)
+        .collect_vec();
     assert_eq!(
         files2,
         vec![
@@ -567,7 +581,7 @@ fn test_gitignores_checkout_never_overwrites_ignored(use_git: bool) {
         .store()
         .tree_builder(repo.store().empty_tree_id().clone());
     testutils::write_normal_file(&mut tree_builder, &modified_path, "contents");
-    let tree_id = tree_builder.write_tree();
+    let tree_id = tree_builder.write_tree().unwrap();
     let tree = repo.store().get_tree(&RepoPath::root(), &tree_id).unwrap();
 
     // Now check out the tree that adds the file "modified" with contents
@@ -606,7 +620,7 @@ fn test_gitignores_ignored_directory_already_tracked(use_git: bool) {
         .store()
         .tree_builder(repo.store().empty_tree_id().clone());
     testutils::write_normal_file(&mut tree_builder, &file_path, "contents");
-    let tree_id = tree_builder.write_tree();
+    let tree_id = tree_builder.write_tree().unwrap();
     let tree = repo.store().get_tree(&RepoPath::root(), &tree_id).unwrap();
 
     // Check out the tree with the file in ignored/
@@ -622,7 +636,7 @@ fn test_gitignores_ignored_directory_already_tracked(use_git: bool) {
         .store()
         .get_tree(&RepoPath::root(), &new_tree_id)
         .unwrap();
-    assert!(new_tree.path_value(&file_path).is_some());
+    assert!(new_tree.path_value(&file_path).unwrap().is_some());
 }
 
 #[test_case(false ; "local backend")]
@@ -696,7 +710,7 @@ fn test_gitsubmodule() {
         TreeValue::GitSubmodule(submodule_id),
     );
 
-    let tree_id = tree_builder.write_tree();
+    let tree_id = tree_builder.write_tree().unwrap();
     let tree = store.get_tree(&RepoPath::root(), &tree_id).unwrap();
     let wc = test_workspace.workspace.working_copy_mut();
     wc.check_out(repo.op_id().clone(), None, &tree).unwrap();
@@ -744,7 +758,7 @@ fn test_existing_directory_symlink(use_git: bool) {
         &RepoPath::from_internal_string("parent/escaped"),
         "contents",
     );
-    let tree_id = tree_builder.write_tree();
+    let tree_id = tree_builder.write_tree().unwrap();
     let tree = repo.store().get_tree(&RepoPath::root(), &tree_id).unwrap();
 
     // Checkout should fail because "parent" already exists and is a symlink.
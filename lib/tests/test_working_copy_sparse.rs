@@ -174,7 +174,10 @@ fn test_sparse_commit() {
         .store()
         .get_tree(&RepoPath::root(), &modified_tree_id)
         .unwrap();
-    let diff = tree.diff(&modified_tree, &EverythingMatcher).collect_vec();
+    let diff = tree
+        .diff(&modified_tree, &EverythingMatcher)
+        .map(Result::unwrap)
+        .collect_vec();
     assert_eq!(diff.len(), 1);
     assert_eq!(diff[0].0, dir1_file1_path);
 
@@ -193,7 +196,10 @@ fn test_sparse_commit() {
         .store()
         .get_tree(&RepoPath::root(), &modified_tree_id)
         .unwrap();
-    let diff = tree.diff(&modified_tree, &EverythingMatcher).collect_vec();
+    let diff = tree
+        .diff(&modified_tree, &EverythingMatcher)
+        .map(Result::unwrap)
+        .collect_vec();
     assert_eq!(diff.len(), 2);
     assert_eq!(diff[0].0, dir1_file1_path);
     assert_eq!(diff[1].0, dir2_file1_path);
@@ -234,7 +240,7 @@ fn test_sparse_commit_gitignore() {
         .store()
         .get_tree(&RepoPath::root(), &modified_tree_id)
         .unwrap();
-    let entries = modified_tree.entries().collect_vec();
+    let entries = modified_tree.entries().map(Result::unwrap).collect_vec();
     assert_eq!(entries.len(), 1);
     assert_eq!(entries[0].0, dir1_file2_path);
 }
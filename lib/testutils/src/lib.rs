@@ -12,14 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::fs;
+use std::any::Any;
 use std::fs::OpenOptions;
-use std::io::{Read, Write};
+use std::io::{Cursor, Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Once};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
+use std::{fs, thread};
 
 use itertools::Itertools;
-use jujutsu_lib::backend::{FileId, TreeId, TreeValue};
+use jujutsu_lib::backend::{
+    self, make_root_commit, Backend, BackendError, BackendResult, ChangeId, CommitId, Conflict,
+    ConflictId, FileId, ObjectId, SymlinkId, TreeId, TreeValue,
+};
 use jujutsu_lib::commit::Commit;
 use jujutsu_lib::commit_builder::CommitBuilder;
 use jujutsu_lib::git_backend::GitBackend;
@@ -118,6 +124,437 @@ impl TestRepo {
             repo,
         }
     }
+
+    /// Returns a new store on top of this repo's backend, which counts the
+    /// objects read through it. The new store starts with an empty cache.
+    pub fn counting_store(&self) -> Arc<Store> {
+        let backend = StoreFactories::default()
+            .load_backend(&self.repo.repo_path().join("store"))
+            .unwrap();
+        Store::new(Box::new(CountingBackend::new(backend)))
+    }
+
+    /// Returns a new store for the repo's backend where each object read takes
+    /// at least `latency`, for benchmarking against a slow backend.
+    pub fn latent_store(&self, latency: Duration) -> Arc<Store> {
+        let backend = StoreFactories::default()
+            .load_backend(&self.repo.repo_path().join("store"))
+            .unwrap();
+        Store::new(Box::new(LatentBackend::new(backend, latency)))
+    }
+}
+
+/// Wraps another backend and counts how many objects are read from it.
+#[derive(Debug)]
+pub struct CountingBackend {
+    inner: Box<dyn Backend>,
+    file_reads: AtomicUsize,
+    tree_reads: AtomicUsize,
+    conflict_reads: AtomicUsize,
+    commit_reads: AtomicUsize,
+}
+
+impl CountingBackend {
+    pub fn new(inner: Box<dyn Backend>) -> Self {
+        CountingBackend {
+            inner,
+            file_reads: AtomicUsize::new(0),
+            tree_reads: AtomicUsize::new(0),
+            conflict_reads: AtomicUsize::new(0),
+            commit_reads: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the `CountingBackend` of a store created by
+    /// `TestRepo::counting_store()`.
+    pub fn of(store: &Store) -> &CountingBackend {
+        store
+            .backend_impl()
+            .downcast_ref::<CountingBackend>()
+            .unwrap()
+    }
+
+    pub fn file_reads(&self) -> usize {
+        self.file_reads.load(Ordering::SeqCst)
+    }
+
+    pub fn tree_reads(&self) -> usize {
+        self.tree_reads.load(Ordering::SeqCst)
+    }
+
+    pub fn conflict_reads(&self) -> usize {
+        self.conflict_reads.load(Ordering::SeqCst)
+    }
+
+    pub fn commit_reads(&self) -> usize {
+        self.commit_reads.load(Ordering::SeqCst)
+    }
+}
+
+impl Backend for CountingBackend {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn commit_id_length(&self) -> usize {
+        self.inner.commit_id_length()
+    }
+
+    fn change_id_length(&self) -> usize {
+        self.inner.change_id_length()
+    }
+
+    fn read_file(&self, path: &RepoPath, id: &FileId) -> BackendResult<Box<dyn Read>> {
+        self.file_reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.read_file(path, id)
+    }
+
+    fn file_size(&self, path: &RepoPath, id: &FileId) -> BackendResult<u64> {
+        self.inner.file_size(path, id)
+    }
+
+    fn write_file(&self, path: &RepoPath, contents: &mut dyn Read) -> BackendResult<FileId> {
+        self.inner.write_file(path, contents)
+    }
+
+    fn read_symlink(&self, path: &RepoPath, id: &SymlinkId) -> BackendResult<String> {
+        self.inner.read_symlink(path, id)
+    }
+
+    fn write_symlink(&self, path: &RepoPath, target: &str) -> BackendResult<SymlinkId> {
+        self.inner.write_symlink(path, target)
+    }
+
+    fn root_commit_id(&self) -> &CommitId {
+        self.inner.root_commit_id()
+    }
+
+    fn root_change_id(&self) -> &ChangeId {
+        self.inner.root_change_id()
+    }
+
+    fn empty_tree_id(&self) -> &TreeId {
+        self.inner.empty_tree_id()
+    }
+
+    fn read_tree(&self, path: &RepoPath, id: &TreeId) -> BackendResult<backend::Tree> {
+        self.tree_reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.read_tree(path, id)
+    }
+
+    fn write_tree(&self, path: &RepoPath, contents: &backend::Tree) -> BackendResult<TreeId> {
+        self.inner.write_tree(path, contents)
+    }
+
+    fn read_conflict(&self, path: &RepoPath, id: &ConflictId) -> BackendResult<Conflict> {
+        self.conflict_reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.read_conflict(path, id)
+    }
+
+    fn write_conflict(&self, path: &RepoPath, contents: &Conflict) -> BackendResult<ConflictId> {
+        self.inner.write_conflict(path, contents)
+    }
+
+    fn read_commit(&self, id: &CommitId) -> BackendResult<backend::Commit> {
+        self.commit_reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.read_commit(id)
+    }
+
+    fn write_commit(
+        &self,
+        contents: backend::Commit,
+    ) -> BackendResult<(CommitId, backend::Commit)> {
+        self.inner.write_commit(contents)
+    }
+}
+
+/// Wraps another backend and sleeps before each object read.
+#[derive(Debug)]
+pub struct LatentBackend {
+    inner: Box<dyn Backend>,
+    latency: Duration,
+}
+
+impl LatentBackend {
+    pub fn new(inner: Box<dyn Backend>, latency: Duration) -> Self {
+        LatentBackend { inner, latency }
+    }
+}
+
+impl Backend for LatentBackend {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn commit_id_length(&self) -> usize {
+        self.inner.commit_id_length()
+    }
+
+    fn change_id_length(&self) -> usize {
+        self.inner.change_id_length()
+    }
+
+    fn read_file(&self, path: &RepoPath, id: &FileId) -> BackendResult<Box<dyn Read>> {
+        thread::sleep(self.latency);
+        self.inner.read_file(path, id)
+    }
+
+    fn file_size(&self, path: &RepoPath, id: &FileId) -> BackendResult<u64> {
+        self.inner.file_size(path, id)
+    }
+
+    fn write_file(&self, path: &RepoPath, contents: &mut dyn Read) -> BackendResult<FileId> {
+        self.inner.write_file(path, contents)
+    }
+
+    fn read_symlink(&self, path: &RepoPath, id: &SymlinkId) -> BackendResult<String> {
+        thread::sleep(self.latency);
+        self.inner.read_symlink(path, id)
+    }
+
+    fn write_symlink(&self, path: &RepoPath, target: &str) -> BackendResult<SymlinkId> {
+        self.inner.write_symlink(path, target)
+    }
+
+    fn root_commit_id(&self) -> &CommitId {
+        self.inner.root_commit_id()
+    }
+
+    fn root_change_id(&self) -> &ChangeId {
+        self.inner.root_change_id()
+    }
+
+    fn empty_tree_id(&self) -> &TreeId {
+        self.inner.empty_tree_id()
+    }
+
+    fn read_tree(&self, path: &RepoPath, id: &TreeId) -> BackendResult<backend::Tree> {
+        thread::sleep(self.latency);
+        self.inner.read_tree(path, id)
+    }
+
+    fn write_tree(&self, path: &RepoPath, contents: &backend::Tree) -> BackendResult<TreeId> {
+        self.inner.write_tree(path, contents)
+    }
+
+    fn read_conflict(&self, path: &RepoPath, id: &ConflictId) -> BackendResult<Conflict> {
+        thread::sleep(self.latency);
+        self.inner.read_conflict(path, id)
+    }
+
+    fn write_conflict(&self, path: &RepoPath, contents: &Conflict) -> BackendResult<ConflictId> {
+        self.inner.write_conflict(path, contents)
+    }
+
+    fn read_commit(&self, id: &CommitId) -> BackendResult<backend::Commit> {
+        thread::sleep(self.latency);
+        self.inner.read_commit(id)
+    }
+
+    fn write_commit(
+        &self,
+        contents: backend::Commit,
+    ) -> BackendResult<(CommitId, backend::Commit)> {
+        self.inner.write_commit(contents)
+    }
+}
+
+/// A backend that keeps all objects in memory, for testing trees without
+/// creating a repo on disk. An object's ID is its index in the order the
+/// objects were first written, so writing an equal object again returns the
+/// same ID.
+#[derive(Debug)]
+pub struct InMemoryBackend {
+    files: Mutex<Vec<Vec<u8>>>,
+    symlinks: Mutex<Vec<String>>,
+    trees: Mutex<Vec<backend::Tree>>,
+    conflicts: Mutex<Vec<Conflict>>,
+    commits: Mutex<Vec<backend::Commit>>,
+    root_commit_id: CommitId,
+    root_change_id: ChangeId,
+    empty_tree_id: TreeId,
+}
+
+impl InMemoryBackend {
+    pub fn new() -> Self {
+        let empty_tree_id = TreeId::new(in_memory_object_id(0));
+        let root_change_id = ChangeId::from_bytes(&[0; 16]);
+        let root_commit = make_root_commit(root_change_id.clone(), empty_tree_id.clone());
+        InMemoryBackend {
+            files: Mutex::new(vec![]),
+            symlinks: Mutex::new(vec![]),
+            trees: Mutex::new(vec![backend::Tree::default()]),
+            conflicts: Mutex::new(vec![]),
+            commits: Mutex::new(vec![root_commit]),
+            root_commit_id: CommitId::new(in_memory_object_id(0)),
+            root_change_id,
+            empty_tree_id,
+        }
+    }
+}
+
+impl Default for InMemoryBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn in_memory_object_id(index: usize) -> Vec<u8> {
+    (index as u64).to_be_bytes().to_vec()
+}
+
+fn write_in_memory_object<T: PartialEq>(objects: &Mutex<Vec<T>>, object: T) -> Vec<u8> {
+    let mut objects = objects.lock().unwrap();
+    let index = match objects.iter().position(|existing| *existing == object) {
+        Some(index) => index,
+        None => {
+            objects.push(object);
+            objects.len() - 1
+        }
+    };
+    in_memory_object_id(index)
+}
+
+fn read_in_memory_object<T: Clone>(
+    objects: &Mutex<Vec<T>>,
+    id: &impl ObjectId,
+) -> BackendResult<T> {
+    let index = <[u8; 8]>::try_from(id.as_bytes())
+        .ok()
+        .map(|bytes| u64::from_be_bytes(bytes) as usize);
+    let objects = objects.lock().unwrap();
+    index
+        .and_then(|index| objects.get(index))
+        .cloned()
+        .ok_or_else(|| BackendError::ObjectNotFound {
+            object_type: id.object_type(),
+            hash: id.hex(),
+            source: "no such object in memory".into(),
+        })
+}
+
+impl Backend for InMemoryBackend {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        "in-memory"
+    }
+
+    fn commit_id_length(&self) -> usize {
+        8
+    }
+
+    fn change_id_length(&self) -> usize {
+        16
+    }
+
+    fn read_file(&self, _path: &RepoPath, id: &FileId) -> BackendResult<Box<dyn Read>> {
+        let contents = read_in_memory_object(&self.files, id)?;
+        Ok(Box::new(Cursor::new(contents)))
+    }
+
+    fn file_size(&self, _path: &RepoPath, id: &FileId) -> BackendResult<u64> {
+        let contents = read_in_memory_object(&self.files, id)?;
+        Ok(contents.len() as u64)
+    }
+
+    fn write_file(&self, _path: &RepoPath, contents: &mut dyn Read) -> BackendResult<FileId> {
+        let mut buf = vec![];
+        contents.read_to_end(&mut buf)?;
+        Ok(FileId::new(write_in_memory_object(&self.files, buf)))
+    }
+
+    fn read_symlink(&self, _path: &RepoPath, id: &SymlinkId) -> BackendResult<String> {
+        read_in_memory_object(&self.symlinks, id)
+    }
+
+    fn write_symlink(&self, _path: &RepoPath, target: &str) -> BackendResult<SymlinkId> {
+        Ok(SymlinkId::new(write_in_memory_object(
+            &self.symlinks,
+            target.to_owned(),
+        )))
+    }
+
+    fn root_commit_id(&self) -> &CommitId {
+        &self.root_commit_id
+    }
+
+    fn root_change_id(&self) -> &ChangeId {
+        &self.root_change_id
+    }
+
+    fn empty_tree_id(&self) -> &TreeId {
+        &self.empty_tree_id
+    }
+
+    fn read_tree(&self, _path: &RepoPath, id: &TreeId) -> BackendResult<backend::Tree> {
+        read_in_memory_object(&self.trees, id)
+    }
+
+    fn write_tree(&self, _path: &RepoPath, contents: &backend::Tree) -> BackendResult<TreeId> {
+        Ok(TreeId::new(write_in_memory_object(
+            &self.trees,
+            contents.clone(),
+        )))
+    }
+
+    fn read_conflict(&self, _path: &RepoPath, id: &ConflictId) -> BackendResult<Conflict> {
+        read_in_memory_object(&self.conflicts, id)
+    }
+
+    fn write_conflict(&self, _path: &RepoPath, contents: &Conflict) -> BackendResult<ConflictId> {
+        Ok(ConflictId::new(write_in_memory_object(
+            &self.conflicts,
+            contents.clone(),
+        )))
+    }
+
+    fn read_commit(&self, id: &CommitId) -> BackendResult<backend::Commit> {
+        read_in_memory_object(&self.commits, id)
+    }
+
+    fn write_commit(
+        &self,
+        contents: backend::Commit,
+    ) -> BackendResult<(CommitId, backend::Commit)> {
+        let id = CommitId::new(write_in_memory_object(&self.commits, contents.clone()));
+        Ok((id, contents))
+    }
+}
+
+/// Returns a new store backed by an `InMemoryBackend`.
+pub fn in_memory_store() -> Arc<Store> {
+    Store::new(Box::new(InMemoryBackend::new()))
+}
+
+/// Writes a tree with the given regular files to the store and returns it.
+/// Unlike `create_tree()`, this doesn't need a repo, so it can be used with
+/// `in_memory_store()`.
+pub fn build_tree(store: &Arc<Store>, entries: &[(&str, &[u8])]) -> Tree {
+    let mut tree_builder = store.tree_builder(store.empty_tree_id().clone());
+    for (path, contents) in entries {
+        let path = RepoPath::from_internal_string(path);
+        let id = store.write_file(&path, &mut &contents[..]).unwrap();
+        tree_builder.set(
+            path,
+            TreeValue::File {
+                id,
+                executable: false,
+            },
+        );
+    }
+    let id = tree_builder.write_tree().unwrap();
+    store.get_tree(&RepoPath::root(), &id).unwrap()
 }
 
 pub struct TestWorkspace {
@@ -204,7 +641,7 @@ pub fn create_tree(repo: &Arc<ReadonlyRepo>, path_contents: &[(&RepoPath, &str)]
     for (path, contents) in path_contents {
         write_normal_file(&mut tree_builder, path, contents);
     }
-    let id = tree_builder.write_tree();
+    let id = tree_builder.write_tree().unwrap();
     store.get_tree(&RepoPath::root(), &id).unwrap()
 }
 
@@ -216,7 +653,7 @@ pub fn create_random_tree(repo: &Arc<ReadonlyRepo>) -> TreeId {
     let number = rand::random::<u32>();
     let path = RepoPath::from_internal_string(format!("file{number}").as_str());
     write_normal_file(&mut tree_builder, &path, "contents");
-    tree_builder.write_tree()
+    tree_builder.write_tree().unwrap()
 }
 
 pub fn create_random_commit<'repo>(
@@ -1282,7 +1282,8 @@ impl WorkspaceCommandTransaction<'_> {
             Ok(right_tree.id().clone())
         } else {
             let mut tree_builder = self.repo().store().tree_builder(left_tree.id().clone());
-            for (repo_path, diff) in left_tree.diff(right_tree, matcher) {
+            for item in left_tree.diff(right_tree, matcher) {
+                let (repo_path, diff) = item?;
                 match diff.into_options().1 {
                     Some(value) => {
                         tree_builder.set(repo_path, value);
@@ -1292,7 +1293,7 @@ impl WorkspaceCommandTransaction<'_> {
                     }
                 }
             }
-            Ok(tree_builder.write_tree())
+            Ok(tree_builder.write_tree()?)
         }
     }
 
@@ -1246,10 +1246,11 @@ fn cmd_untrack(
     let (mut locked_working_copy, wc_commit) = workspace_command.start_working_copy_mutation()?;
     // Create a new tree without the unwanted files
     let mut tree_builder = store.tree_builder(wc_commit.tree_id().clone());
-    for (path, _value) in wc_commit.tree().entries_matching(matcher.as_ref()) {
+    for item in wc_commit.tree().entries_matching(matcher.as_ref()) {
+        let (path, _value) = item?;
         tree_builder.remove(path);
     }
-    let new_tree_id = tree_builder.write_tree();
+    let new_tree_id = tree_builder.write_tree()?;
     let new_tree = store.get_tree(&RepoPath::root(), &new_tree_id)?;
     // Reset the working copy to the new tree
     locked_working_copy.reset(&new_tree)?;
@@ -1258,7 +1259,7 @@ fn cmd_untrack(
     let wc_tree_id = locked_working_copy.snapshot(base_ignores, None)?;
     if wc_tree_id != new_tree_id {
         let wc_tree = store.get_tree(&RepoPath::root(), &wc_tree_id)?;
-        let added_back = wc_tree.entries_matching(matcher.as_ref()).collect_vec();
+        let added_back: Vec<_> = wc_tree.entries_matching(matcher.as_ref()).try_collect()?;
         if !added_back.is_empty() {
             locked_working_copy.discard();
             let path = &added_back[0].0;
@@ -1301,7 +1302,8 @@ fn cmd_files(ui: &mut Ui, command: &CommandHelper, args: &FilesArgs) -> Result<(
     let commit = workspace_command.resolve_single_rev(&args.revision)?;
     let matcher = workspace_command.matcher_from_values(&args.paths)?;
     ui.request_pager();
-    for (name, _value) in commit.tree().entries_matching(matcher.as_ref()) {
+    for item in commit.tree().entries_matching(matcher.as_ref()) {
+        let (name, _value) = item?;
         writeln!(ui, "{}", &workspace_command.format_file_path(&name))?;
     }
     Ok(())
@@ -1312,7 +1314,7 @@ fn cmd_cat(ui: &mut Ui, command: &CommandHelper, args: &CatArgs) -> Result<(), C
     let commit = workspace_command.resolve_single_rev(&args.revision)?;
     let path = workspace_command.parse_file_path(&args.path)?;
     let repo = workspace_command.repo();
-    match commit.tree().path_value(&path) {
+    match commit.tree().path_value(&path)? {
         None => {
             return Err(user_error("No such path"));
         }
@@ -1470,7 +1472,7 @@ fn cmd_status(
             )?;
         }
 
-        let conflicts = tree.conflicts();
+        let conflicts = tree.conflicts()?;
         if !conflicts.is_empty() {
             writeln!(
                 formatter.labeled("conflict"),
@@ -2454,7 +2456,7 @@ fn cmd_resolve(
     let matcher = workspace_command.matcher_from_values(&args.paths)?;
     let commit = workspace_command.resolve_single_rev(&args.revision)?;
     let tree = commit.tree();
-    let conflicts = tree.conflicts_matching(matcher.as_ref());
+    let conflicts = tree.conflicts_matching(matcher.as_ref())?;
     if conflicts.is_empty() {
         return Err(CommandError::CliError(format!(
             "No conflicts found {}",
@@ -2490,7 +2492,7 @@ fn cmd_resolve(
 
     if !args.quiet {
         let new_tree = new_commit.tree();
-        let new_conflicts = new_tree.conflicts_matching(&EverythingMatcher);
+        let new_conflicts = new_tree.conflicts_matching(&EverythingMatcher)?;
         if !new_conflicts.is_empty() {
             ui.write("After this operation, some files at this revision still have conflicts:\n")?;
             print_conflicted_paths(
@@ -2624,7 +2626,8 @@ fn cmd_restore(
             .repo()
             .store()
             .tree_builder(to_commit.tree_id().clone());
-        for (repo_path, diff) in from_tree.diff(&to_commit.tree(), matcher.as_ref()) {
+        for item in from_tree.diff(&to_commit.tree(), matcher.as_ref()) {
+            let (repo_path, diff) = item?;
             match diff.into_options().0 {
                 Some(value) => {
                     tree_builder.set(repo_path, value);
@@ -2634,7 +2637,7 @@ fn cmd_restore(
                 }
             }
         }
-        tree_builder.write_tree()
+        tree_builder.write_tree()?
     };
     if &new_tree_id == to_commit.tree_id() {
         ui.write("Nothing changed.\n")?;
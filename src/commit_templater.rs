@@ -254,9 +254,9 @@ fn build_commit_keyword_opt<'repo>(
             let maybe_entries = repo.resolve_change_id(commit.change_id());
             maybe_entries.map_or(true, |entries| !entries.contains(commit.id()))
         })),
-        "conflict" => {
-            language.wrap_boolean(wrap_fn(property, |commit| commit.tree().has_conflict()))
-        }
+        "conflict" => language.wrap_boolean(wrap_fn(property, |commit| {
+            commit.tree().has_conflict().unwrap()
+        })),
         "empty" => language.wrap_boolean(wrap_fn(property, |commit| {
             commit.tree().id() == rewrite::merge_commit_trees(repo, &commit.parents()).id()
         })),
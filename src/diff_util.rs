@@ -336,7 +336,8 @@ pub fn show_color_words_diff(
 ) -> Result<(), CommandError> {
     let repo = workspace_command.repo();
     formatter.push_label("diff")?;
-    for (path, diff) in tree_diff {
+    for item in tree_diff {
+        let (path, diff) = item?;
         let ui_path = workspace_command.format_file_path(&path);
         match diff {
             tree::Diff::Added(right_value) => {
@@ -610,7 +611,8 @@ pub fn show_git_diff(
 ) -> Result<(), CommandError> {
     let repo = workspace_command.repo();
     formatter.push_label("diff")?;
-    for (path, diff) in tree_diff {
+    for item in tree_diff {
+        let (path, diff) = item?;
         let path_string = path.to_internal_file_string();
         match diff {
             tree::Diff::Added(right_value) => {
@@ -671,9 +673,10 @@ pub fn show_diff_summary(
     formatter: &mut dyn Formatter,
     workspace_command: &WorkspaceCommandHelper,
     tree_diff: TreeDiffIterator,
-) -> io::Result<()> {
+) -> Result<(), CommandError> {
+    let diffs: Vec<_> = tree_diff.try_collect()?;
     formatter.with_label("diff", |formatter| {
-        for (repo_path, diff) in tree_diff {
+        for (repo_path, diff) in diffs {
             match diff {
                 tree::Diff::Modified(_, _) => {
                     writeln!(
@@ -699,16 +702,18 @@ pub fn show_diff_summary(
             }
         }
         Ok(())
-    })
+    })?;
+    Ok(())
 }
 
 pub fn show_types(
     formatter: &mut dyn Formatter,
     workspace_command: &WorkspaceCommandHelper,
     tree_diff: TreeDiffIterator,
-) -> io::Result<()> {
+) -> Result<(), CommandError> {
+    let diffs: Vec<_> = tree_diff.try_collect()?;
     formatter.with_label("diff", |formatter| {
-        for (repo_path, diff) in tree_diff {
+        for (repo_path, diff) in diffs {
             let (before, after) = diff.as_options();
             writeln!(
                 formatter.labeled("modified"),
@@ -719,7 +724,8 @@ pub fn show_types(
             )?;
         }
         Ok(())
-    })
+    })?;
+    Ok(())
 }
 
 fn diff_summary_char(value: Option<&TreeValue>) -> char {
@@ -80,6 +80,8 @@ pub enum DiffEditError {
     CheckoutError(#[from] CheckoutError),
     #[error("Failed to snapshot changes: {0:?}")]
     SnapshotError(#[from] SnapshotError),
+    #[error("Backend error: {0:?}")]
+    BackendError(#[from] jujutsu_lib::backend::BackendError),
 }
 
 #[derive(Debug, Error)]
@@ -162,7 +164,7 @@ pub fn run_mergetool(
     repo_path: &RepoPath,
     settings: &UserSettings,
 ) -> Result<TreeId, ConflictResolveError> {
-    let conflict_id = match tree.path_value(repo_path) {
+    let conflict_id = match tree.path_value(repo_path)? {
         Some(TreeValue::Conflict(id)) => id,
         Some(_) => return Err(ConflictResolveError::NotAConflictError(repo_path.clone())),
         None => return Err(ConflictResolveError::PathNotFoundError(repo_path.clone())),
@@ -279,7 +281,7 @@ pub fn run_mergetool(
     });
     let mut tree_builder = tree.store().tree_builder(tree.id().clone());
     tree_builder.set(repo_path.clone(), new_tree_value);
-    Ok(tree_builder.write_tree())
+    Ok(tree_builder.write_tree()?)
 }
 
 fn interpolate_variables<V: AsRef<str>>(
@@ -312,10 +314,10 @@ pub fn edit_diff(
     settings: &UserSettings,
 ) -> Result<TreeId, DiffEditError> {
     let store = left_tree.store();
-    let changed_files = left_tree
+    let changed_files: Vec<_> = left_tree
         .diff(right_tree, &EverythingMatcher)
-        .map(|(path, _value)| path)
-        .collect_vec();
+        .map_ok(|(path, _value)| path)
+        .try_collect()?;
 
     // Check out the two trees in temporary directories. Only include changed files
     // in the sparse checkout patterns.